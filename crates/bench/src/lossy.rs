@@ -0,0 +1,99 @@
+//! Packet loss injection.
+//!
+//! [`LossySocket`] wraps the endpoint's UDP socket and silently drops a
+//! fraction of the packets the benchmark sends, so transports can be
+//! compared on a lossy path without touching the network or the server.
+
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A UDP socket that drops outgoing packets at random.
+#[derive(Debug)]
+pub struct LossySocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    /// Fraction of packets dropped, from 0 to 1.
+    loss: f64,
+    /// xorshift state for picking the packets to drop.
+    rng: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl LossySocket {
+    /// Drop `loss` (0 to 1) of the packets sent on `inner`.
+    pub fn new(inner: Arc<dyn AsyncUdpSocket>, loss: f64) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |now| now.as_nanos() as u64)
+            | 1;
+        Self {
+            inner,
+            loss,
+            rng: AtomicU64::new(seed),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Packets dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn drop_next(&self) -> bool {
+        if self.loss <= 0.0 {
+            return false;
+        }
+        let mut x = self.rng.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng.store(x, Ordering::Relaxed);
+        (x >> 11) as f64 / (1u64 << 53) as f64 <= self.loss
+    }
+}
+
+impl AsyncUdpSocket for LossySocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Arc::clone(&self.inner).create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        if self.drop_next() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.inner.try_send(transmit)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_recv(cx, bufs, meta)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        // One packet per transmit, so each is dropped on its own
+        1
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
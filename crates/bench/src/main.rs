@@ -0,0 +1,300 @@
+//! Datagram vs stream throughput benchmark
+//!
+//! Opens WebTransport sessions on a running server and has it echo
+//! payloads of each configured size, once as datagrams and once on a bidi
+//! stream, optionally dropping a share of the packets sent (see [`lossy`]).
+//! The results are printed as a Markdown table or CSV:
+//!
+//! ```text
+//! bench [--url https://localhost:4433/webtransport] [--sizes 64,1024]
+//!       [--count 1000] [--loss 0,0.01] [--format markdown|csv]
+//!       [--output <file>] [--baseline <csv>]
+//! ```
+//!
+//! With `--baseline`, a previous CSV report, the benchmark fails if any
+//! run's throughput fell more than [`REGRESSION_TOLERANCE`] below it.
+
+mod lossy;
+mod report;
+mod session;
+
+use bytes::Bytes;
+use common::tls::insecure_verifier;
+use http::Uri;
+use lossy::LossySocket;
+use quinn::{Endpoint, EndpointConfig, Runtime, TokioRuntime};
+use report::{Format, Sample};
+use rustls::ClientConfig as TlsClientConfig;
+use session::Session;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Server URL used when none is given.
+const DEFAULT_URL: &str = "https://localhost:4433/webtransport";
+
+/// How long to wait for more datagram echoes before counting the rest lost.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most bytes a queued datagram takes beyond its payload: the quarter
+/// stream ID.
+const DATAGRAM_OVERHEAD: usize = 8;
+
+/// Give up on a stream run after this long.
+const RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest throughput drop against `--baseline` that still passes.
+const REGRESSION_TOLERANCE: f64 = 0.2;
+
+/// Command line options.
+#[derive(Debug)]
+struct Options {
+    url: Uri,
+    /// Payload sizes in bytes.
+    sizes: Vec<usize>,
+    /// Messages sent per run.
+    count: usize,
+    /// Packet loss rates to run at, from 0 to 1.
+    loss: Vec<f64>,
+    format: Format,
+    output: Option<String>,
+    baseline: Option<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut options = Self {
+            url: DEFAULT_URL.parse()?,
+            sizes: vec![64, 256, 1024, 4096, 16384],
+            count: 1000,
+            loss: vec![0.0, 0.01, 0.05],
+            format: Format::Markdown,
+            output: None,
+            baseline: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--url" => options.url = value()?.parse()?,
+                "--sizes" => options.sizes = parse_list(value()?)?,
+                "--count" => options.count = value()?.parse()?,
+                "--loss" => options.loss = parse_list(value()?)?,
+                "--format" => options.format = Format::parse(value()?)?,
+                "--output" | "-o" => options.output = Some(value()?.clone()),
+                "--baseline" => options.baseline = Some(value()?.clone()),
+                _ => anyhow::bail!("unknown argument {}", arg),
+            }
+        }
+
+        if options.loss.iter().any(|loss| !(0.0..1.0).contains(loss)) {
+            anyhow::bail!("--loss rates must be at least 0 and below 1");
+        }
+        Ok(options)
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(list: &str) -> anyhow::Result<Vec<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    Ok(list
+        .split(',')
+        .map(|item| item.trim().parse())
+        .collect::<Result<_, _>>()?)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let options = Options::parse(&args[1..])?;
+
+    // Logs go to stderr so the report can be piped
+    tracing_subscriber::fmt()
+        .with_target(false)
+        .with_level(true)
+        .with_writer(std::io::stderr)
+        .init();
+
+    // Install the AWS LC crypto provider
+    rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .unwrap();
+
+    let mut samples = Vec::new();
+    for &loss in &options.loss {
+        let (endpoint, socket) = lossy_endpoint(loss)?;
+        for &size in &options.sizes {
+            if let Some(sample) = run_datagrams(&endpoint, &options, size, loss).await? {
+                samples.push(sample);
+            }
+            samples.push(run_stream(&endpoint, &options, size, loss).await?);
+        }
+        info!("Dropped {} packets at {:.1}% loss", socket.dropped(), loss * 100.0);
+        endpoint.wait_idle().await;
+    }
+
+    let report = report::render(&samples, options.format);
+    match &options.output {
+        Some(path) => {
+            std::fs::write(path, &report)?;
+            info!("Report written to {}", path);
+        }
+        None => print!("{}", report),
+    }
+
+    if let Some(path) = &options.baseline {
+        let baseline = report::parse_csv(&std::fs::read_to_string(path)?)?;
+        let regressions = report::regressions(&samples, &baseline, REGRESSION_TOLERANCE);
+        for (sample, base) in &regressions {
+            warn!(
+                "{} {} B at {:.1}% loss: {:.1} Mbit/s, baseline {:.1} Mbit/s",
+                sample.mode,
+                sample.size,
+                sample.loss * 100.0,
+                sample.throughput(),
+                base.throughput()
+            );
+        }
+        if !regressions.is_empty() {
+            anyhow::bail!("{} runs are slower than the baseline", regressions.len());
+        }
+        info!("No regressions against {}", path);
+    }
+    Ok(())
+}
+
+/// A client endpoint whose socket drops `loss` of the packets it sends.
+fn lossy_endpoint(loss: f64) -> anyhow::Result<(Endpoint, Arc<LossySocket>)> {
+    let mut tls_config = TlsClientConfig::builder_with_provider(common::tls::crypto_provider(true))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(insecure_verifier())
+        .with_no_client_auth();
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let client_config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(tls_config)?,
+    ));
+
+    let runtime = Arc::new(TokioRuntime);
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    let socket = Arc::new(LossySocket::new(runtime.wrap_udp_socket(socket)?, loss));
+    let mut endpoint = Endpoint::new_with_abstract_socket(
+        EndpointConfig::default(),
+        None,
+        socket.clone(),
+        runtime,
+    )?;
+    endpoint.set_default_client_config(client_config);
+    Ok((endpoint, socket))
+}
+
+/// Send `options.count` datagrams of `size` bytes and count the echoes.
+/// Returns `None` if datagrams of that size do not fit the path.
+async fn run_datagrams(
+    endpoint: &Endpoint,
+    options: &Options,
+    size: usize,
+    loss: f64,
+) -> anyhow::Result<Option<Sample>> {
+    let session = Session::open(endpoint, &options.url).await?;
+    let max = session.max_datagram_size().unwrap_or(0);
+    if size > max {
+        warn!("Skipping {} B datagrams: the path allows {} B", size, max);
+        session.close();
+        return Ok(None);
+    }
+
+    let payload = vec![b'x'; size];
+    let conn = session.connection();
+    let started = Instant::now();
+
+    let send = async {
+        for _ in 0..options.count {
+            // Let the connection drain its queue instead of overflowing it
+            while conn.datagram_send_buffer_space() < size + DATAGRAM_OVERHEAD {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            session.send_datagram(&payload)?;
+        }
+        anyhow::Ok(())
+    };
+    let receive = async {
+        let mut received = 0;
+        let mut last = started;
+        while received < options.count {
+            match tokio::time::timeout(REPLY_TIMEOUT, session.read_datagram()).await {
+                Ok(Ok(_)) => {
+                    received += 1;
+                    last = Instant::now();
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+        anyhow::Ok((received, last))
+    };
+    let ((), (received, last)) = tokio::try_join!(send, receive)?;
+    session.close();
+
+    Ok(Some(Sample {
+        mode: "datagram",
+        size,
+        loss,
+        sent: options.count,
+        received,
+        elapsed: last - started,
+    }))
+}
+
+/// Send `options.count` chunks of `size` bytes on one stream and read the
+/// echo to the end.
+async fn run_stream(
+    endpoint: &Endpoint,
+    options: &Options,
+    size: usize,
+    loss: f64,
+) -> anyhow::Result<Sample> {
+    let session = Session::open(endpoint, &options.url).await?;
+    let (mut send, mut recv) = session.open_bi().await?;
+
+    let payload = Bytes::from(vec![b'x'; size]);
+    let started = Instant::now();
+
+    let write = async {
+        for _ in 0..options.count {
+            send.write_chunk(payload.clone()).await?;
+        }
+        send.finish()?;
+        anyhow::Ok(())
+    };
+    let read = async {
+        let mut echoed = 0;
+        while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+            echoed += chunk.bytes.len();
+        }
+        anyhow::Ok(echoed)
+    };
+    let ((), echoed) = tokio::time::timeout(RUN_TIMEOUT, async { tokio::try_join!(write, read) })
+        .await
+        .map_err(|_| anyhow::anyhow!("stream run timed out after {:?}", RUN_TIMEOUT))??;
+    let elapsed = started.elapsed();
+    session.close();
+
+    // Every chunk comes back with an "[echo] " prefix
+    if echoed < options.count * size {
+        anyhow::bail!("stream echoed {} of {} bytes", echoed, options.count * size);
+    }
+    Ok(Sample {
+        mode: "stream",
+        size,
+        loss,
+        sent: options.count,
+        received: options.count,
+        elapsed,
+    })
+}
@@ -0,0 +1,144 @@
+//! Benchmark results as a Markdown table or CSV.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Output format of the report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "md" | "markdown" => Ok(Self::Markdown),
+            "csv" => Ok(Self::Csv),
+            _ => anyhow::bail!("unknown format {:?} (expected markdown or csv)", name),
+        }
+    }
+}
+
+/// The outcome of one run.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// `datagram` or `stream`.
+    pub mode: &'static str,
+    /// Payload bytes per message.
+    pub size: usize,
+    /// Fraction of packets dropped on the way out.
+    pub loss: f64,
+    /// Messages sent.
+    pub sent: usize,
+    /// Messages echoed back.
+    pub received: usize,
+    /// From the first send to the last echo.
+    pub elapsed: Duration,
+}
+
+impl Sample {
+    /// Share of messages that were echoed back, in percent.
+    pub fn delivered(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        100.0 * self.received as f64 / self.sent as f64
+    }
+
+    /// Echoed payload in megabits per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        (self.received * self.size) as f64 * 8.0 / secs / 1_000_000.0
+    }
+}
+
+/// Render `samples` in `format`.
+pub fn render(samples: &[Sample], format: Format) -> String {
+    let mut out = String::new();
+    match format {
+        Format::Markdown => {
+            out.push_str(concat!(
+                "| mode | size | loss | sent | received | delivered | time (ms) | Mbit/s |\n",
+                "|------|-----:|-----:|-----:|---------:|----------:|----------:|-------:|\n",
+            ));
+            for s in samples {
+                let _ = writeln!(
+                    out,
+                    "| {} | {} | {:.1}% | {} | {} | {:.1}% | {:.1} | {:.1} |",
+                    s.mode,
+                    s.size,
+                    s.loss * 100.0,
+                    s.sent,
+                    s.received,
+                    s.delivered(),
+                    s.elapsed.as_secs_f64() * 1000.0,
+                    s.throughput()
+                );
+            }
+        }
+        Format::Csv => {
+            out.push_str("mode,size,loss,sent,received,delivered_pct,elapsed_ms,mbit_per_sec\n");
+            for s in samples {
+                let _ = writeln!(
+                    out,
+                    "{},{},{},{},{},{:.2},{:.3},{:.3}",
+                    s.mode,
+                    s.size,
+                    s.loss,
+                    s.sent,
+                    s.received,
+                    s.delivered(),
+                    s.elapsed.as_secs_f64() * 1000.0,
+                    s.throughput()
+                );
+            }
+        }
+    }
+    out
+}
+
+/// Read samples back from a CSV report.
+pub fn parse_csv(csv: &str) -> anyhow::Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+    for line in csv.lines().skip(1).filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split(',').collect();
+        let [mode, size, loss, sent, received, _, elapsed_ms, _] = fields[..] else {
+            anyhow::bail!("malformed report line {:?}", line);
+        };
+        samples.push(Sample {
+            mode: match mode {
+                "datagram" => "datagram",
+                "stream" => "stream",
+                _ => anyhow::bail!("unknown mode {:?}", mode),
+            },
+            size: size.parse()?,
+            loss: loss.parse()?,
+            sent: sent.parse()?,
+            received: received.parse()?,
+            elapsed: Duration::from_secs_f64(elapsed_ms.parse::<f64>()? / 1000.0),
+        });
+    }
+    Ok(samples)
+}
+
+/// Samples whose throughput is more than `tolerance` (a fraction) below the
+/// baseline run with the same mode, size and loss, paired with that run.
+pub fn regressions<'a>(
+    samples: &'a [Sample],
+    baseline: &'a [Sample],
+    tolerance: f64,
+) -> Vec<(&'a Sample, &'a Sample)> {
+    samples
+        .iter()
+        .filter_map(|sample| {
+            let base = baseline.iter().find(|base| {
+                base.mode == sample.mode && base.size == sample.size && base.loss == sample.loss
+            })?;
+            (sample.throughput() < base.throughput() * (1.0 - tolerance)).then_some((sample, base))
+        })
+        .collect()
+}
@@ -0,0 +1,226 @@
+//! A minimal WebTransport client session.
+//!
+//! The h3 client cannot announce WebTransport support in its SETTINGS, so
+//! this speaks just enough HTTP/3 over a plain QUIC connection: a control
+//! stream with the SETTINGS the server checks, an extended CONNECT request,
+//! and then datagrams and bidi streams tagged with the session ID.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::Uri;
+use quinn::Endpoint;
+
+/// Unidirectional stream type of the HTTP/3 control stream.
+const CONTROL_STREAM: u64 = 0x00;
+
+/// Frame types used here.
+const FRAME_HEADERS: u64 = 0x01;
+const FRAME_SETTINGS: u64 = 0x04;
+
+/// Settings sent to the server: HTTP datagrams, WebTransport, and one
+/// session.
+const SETTINGS: [(u64, u64); 3] = [(0x33, 1), (0x2b60_3742, 1), (0x2b60_3743, 1)];
+
+/// Stream type signalling a WebTransport bidi stream.
+const WEBTRANSPORT_STREAM: u64 = 0x41;
+
+/// QPACK static table entry for `:status: 200`.
+const STATUS_200: u8 = 25;
+
+/// An established WebTransport session.
+pub struct Session {
+    conn: quinn::Connection,
+    session_id: u64,
+    /// Closing the control stream would be a connection error.
+    _control: quinn::SendStream,
+    /// The CONNECT stream; the session ends when it closes.
+    _connect: (quinn::SendStream, quinn::RecvStream),
+}
+
+impl Session {
+    /// Connect to `uri` and open a WebTransport session there.
+    pub async fn open(endpoint: &Endpoint, uri: &Uri) -> anyhow::Result<Self> {
+        let host = uri.host().ok_or_else(|| anyhow::anyhow!("{} has no host", uri))?;
+        let port = uri.port_u16().unwrap_or(443);
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("{} did not resolve", host))?;
+        let conn = endpoint.connect(addr, host)?.await?;
+
+        let mut control = conn.open_uni().await?;
+        let mut settings = BytesMut::new();
+        for (id, value) in SETTINGS {
+            put_varint(&mut settings, id);
+            put_varint(&mut settings, value);
+        }
+        let mut buf = BytesMut::new();
+        put_varint(&mut buf, CONTROL_STREAM);
+        put_frame(&mut buf, FRAME_SETTINGS, &settings);
+        control.write_all(&buf).await?;
+
+        let (mut send, mut recv) = conn.open_bi().await?;
+        let session_id = quinn::VarInt::from(send.id()).into_inner();
+        let authority = uri.authority().map_or(host, |a| a.as_str());
+        let mut buf = BytesMut::new();
+        put_frame(&mut buf, FRAME_HEADERS, &connect_headers(authority, uri.path()));
+        send.write_all(&buf).await?;
+
+        let status = read_status(&mut recv).await?;
+        if status != STATUS_200 {
+            anyhow::bail!("WebTransport CONNECT rejected (QPACK field {:#x})", status);
+        }
+
+        Ok(Self {
+            conn,
+            session_id,
+            _control: control,
+            _connect: (send, recv),
+        })
+    }
+
+    /// The QUIC connection carrying the session.
+    pub fn connection(&self) -> quinn::Connection {
+        self.conn.clone()
+    }
+
+    /// Largest datagram payload the path allows, if datagrams are supported.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let prefix = varint_len(self.session_id / 4);
+        self.conn.max_datagram_size().map(|max| max.saturating_sub(prefix))
+    }
+
+    /// Send `payload` as a datagram in the session.
+    pub fn send_datagram(&self, payload: &[u8]) -> anyhow::Result<()> {
+        let mut buf = BytesMut::with_capacity(8 + payload.len());
+        put_varint(&mut buf, self.session_id / 4);
+        buf.put_slice(payload);
+        self.conn.send_datagram(buf.freeze())?;
+        Ok(())
+    }
+
+    /// Receive the payload of the next datagram.
+    pub async fn read_datagram(&self) -> anyhow::Result<Bytes> {
+        let mut datagram = self.conn.read_datagram().await?;
+        get_varint(&mut datagram)?;
+        Ok(datagram)
+    }
+
+    /// Open a bidirectional stream in the session.
+    pub async fn open_bi(&self) -> anyhow::Result<(quinn::SendStream, quinn::RecvStream)> {
+        let (mut send, recv) = self.conn.open_bi().await?;
+        let mut header = BytesMut::new();
+        put_varint(&mut header, WEBTRANSPORT_STREAM);
+        put_varint(&mut header, self.session_id);
+        send.write_all(&header).await?;
+        Ok((send, recv))
+    }
+
+    /// Close the session and its connection.
+    pub fn close(self) {
+        self.conn.close(0u32.into(), b"done");
+    }
+}
+
+/// QPACK field section of the extended CONNECT request, using only the
+/// static table.
+fn connect_headers(authority: &str, path: &str) -> BytesMut {
+    let mut buf = BytesMut::new();
+    // Required insert count and base: no dynamic table
+    buf.put_slice(&[0, 0]);
+    // :method CONNECT and :scheme https, indexed
+    buf.put_u8(0xc0 | 15);
+    buf.put_u8(0xc0 | 23);
+    // :authority and :path, by static name reference
+    for (index, value) in [(0, authority), (1, path)] {
+        buf.put_u8(0x50 | index);
+        put_string(&mut buf, value, 7, 0);
+    }
+    // :protocol has no static entry
+    put_string(&mut buf, ":protocol", 3, 0x20);
+    put_string(&mut buf, "webtransport", 7, 0);
+    buf
+}
+
+/// Read frames until the response HEADERS and return its first field
+/// line, which the server sends as an indexed `:status`.
+async fn read_status(recv: &mut quinn::RecvStream) -> anyhow::Result<u8> {
+    let mut buf = BytesMut::new();
+    loop {
+        let mut frame = &buf[..];
+        if let (Ok(kind), Ok(len)) = (get_varint(&mut frame), get_varint(&mut frame))
+            && frame.len() as u64 >= len
+        {
+            // Skip the required insert count and base
+            if kind == FRAME_HEADERS && len > 2 {
+                return Ok(frame[2] & 0x3f);
+            }
+            let consumed = buf.len() - frame.len() + len as usize;
+            buf.advance(consumed);
+            continue;
+        }
+        match recv.read_chunk(usize::MAX, true).await? {
+            Some(chunk) => buf.extend_from_slice(&chunk.bytes),
+            None => anyhow::bail!("CONNECT stream closed before a response"),
+        }
+    }
+}
+
+fn put_frame(buf: &mut BytesMut, kind: u64, payload: &[u8]) {
+    put_varint(buf, kind);
+    put_varint(buf, payload.len() as u64);
+    buf.put_slice(payload);
+}
+
+/// Append a QPACK string literal (not Huffman coded) whose length prefix
+/// has `bits` bits, in a byte starting with `flags`.
+fn put_string(buf: &mut BytesMut, value: &str, bits: u32, flags: u8) {
+    let max = (1usize << bits) - 1;
+    let len = value.len();
+    if len < max {
+        buf.put_u8(flags | len as u8);
+    } else {
+        buf.put_u8(flags | max as u8);
+        let mut rest = len - max;
+        while rest >= 0x80 {
+            buf.put_u8(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        buf.put_u8(rest as u8);
+    }
+    buf.put_slice(value.as_bytes());
+}
+
+/// Append `value` as a QUIC variable-length integer.
+fn put_varint(buf: &mut BytesMut, value: u64) {
+    match varint_len(value) {
+        1 => buf.put_u8(value as u8),
+        2 => buf.put_u16(0x4000 | value as u16),
+        4 => buf.put_u32(0x8000_0000 | value as u32),
+        _ => buf.put_u64(0xc000_0000_0000_0000 | value),
+    }
+}
+
+fn varint_len(value: u64) -> usize {
+    match value {
+        0..0x40 => 1,
+        0x40..0x4000 => 2,
+        0x4000..0x4000_0000 => 4,
+        _ => 8,
+    }
+}
+
+/// Take a QUIC variable-length integer off the front of `buf`.
+fn get_varint(buf: &mut impl Buf) -> anyhow::Result<u64> {
+    if !buf.has_remaining() {
+        anyhow::bail!("truncated varint");
+    }
+    let len = 1 << (buf.chunk()[0] >> 6);
+    if buf.remaining() < len {
+        anyhow::bail!("truncated varint");
+    }
+    let mut value = u64::from(buf.get_u8() & 0x3f);
+    for _ in 1..len {
+        value = value << 8 | u64::from(buf.get_u8());
+    }
+    Ok(value)
+}
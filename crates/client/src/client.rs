@@ -0,0 +1,822 @@
+//! A small request/response client on top of an h3 connection.
+//!
+//! [`Client`] sends buffered requests (the whole response body is read into
+//! memory) and can fan many of them out concurrently on the one
+//! connection with [`Client::batch`]. [`Client::request`] hands back the
+//! response stream instead, for bodies too large to hold in memory.
+//!
+//! Buffered requests can keep cookies in a [`CookieJar`] and follow
+//! redirects according to a [`RedirectPolicy`]. Redirects to another
+//! server open a connection to it for the one request, which needs the
+//! endpoint the client was connected with (see [`Client::with_endpoint`]).
+//!
+//! Cross-cutting behaviour such as authentication headers, metrics or
+//! retries plugs in as [`Interceptor`]s (see [`crate::interceptors`]); a
+//! request is sent at most [`MAX_ATTEMPTS`] times, however often they ask
+//! for a retry.
+//!
+//! Buffered requests accept compressed responses; [`Response::bytes`],
+//! [`Response::text`] and [`Response::json`] decompress the body (see
+//! [`crate::encoding`]).
+//!
+//! Servers may send interim (1xx) responses, such as `100 Continue` or
+//! `103 Early Hints`, before the final one, and trailers after the body.
+//! Every way of receiving a response here skips the interim ones (see
+//! [`recv_response`]); buffered requests keep them in
+//! [`Response::informational`], show them to interceptors as they arrive
+//! and read the trailers into [`Response::trailers`].
+//!
+//! A server going away sends GOAWAY and turns away the requests it has not
+//! started on, which RFC 9114 makes safe to send again. Requests without a
+//! body are, up to [`DEFAULT_GOAWAY_RETRIES`] times, on a new connection
+//! that the client's clones share from then on (see
+//! [`Client::with_goaway_retries`]); [`Response::goaway_retries`] counts
+//! them.
+
+use crate::cookies::CookieJar;
+use crate::encoding;
+use crate::har::{Har, RequestInfo, ResponseInfo};
+use crate::interceptors::{Interceptor, Outcome};
+use crate::timings::RequestTimer;
+use bytes::{Buf, Bytes};
+use futures::StreamExt;
+use h3::client::{RequestStream, SendRequest};
+use h3::error::{Code, ConnectionError, StreamError};
+use h3_quinn::{BidiStream, OpenStreams};
+use http::header::{
+    ACCEPT_ENCODING, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HeaderValue, LOCATION,
+};
+use http::{HeaderMap, Method, Request, StatusCode, Uri, request};
+use quinn::Endpoint;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// Requests in flight at once in a batch, unless told otherwise.
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Times a request turned away after a GOAWAY is sent again, unless told
+/// otherwise.
+pub const DEFAULT_GOAWAY_RETRIES: u32 = 3;
+
+/// Interim responses accepted before the final one.
+pub const MAX_INFORMATIONAL: usize = 16;
+
+/// Times a buffered request is sent before interceptors asking for another
+/// retry get an error instead.
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// An interim (1xx) response, received before the final response.
+#[derive(Debug, Clone)]
+pub struct Informational {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+}
+
+/// A fully received response.
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// The body as received, before any `Content-Encoding` is undone.
+    pub body: Bytes,
+    /// Interim responses received before this one, in order.
+    pub informational: Vec<Informational>,
+    /// Trailer fields sent after the body; empty if there were none.
+    pub trailers: HeaderMap,
+    /// When the request was sent and its response headers arrived.
+    pub timer: RequestTimer,
+    /// Times the request was sent again on a new connection because the
+    /// server went away before processing it.
+    pub goaway_retries: u32,
+}
+
+impl Response {
+    /// The decompressed body.
+    pub fn bytes(&self) -> anyhow::Result<Bytes> {
+        encoding::decode(&self.headers, &self.body)
+    }
+
+    /// The body as text, in the `Content-Type` charset (UTF-8 by default).
+    /// Invalid sequences are replaced with U+FFFD.
+    pub fn text(&self) -> anyhow::Result<String> {
+        let body = self.bytes()?;
+        let (text, _, _) = encoding::charset(&self.headers).decode(&body);
+        Ok(text.into_owned())
+    }
+
+    /// The body parsed as JSON.
+    pub fn json<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        serde_json::from_slice(&self.bytes()?)
+            .map_err(|e| anyhow::anyhow!("invalid JSON response ({}): {}", self.status, e))
+    }
+}
+
+/// How [`Client::send`] follows redirects.
+#[derive(Debug, Clone)]
+pub struct RedirectPolicy {
+    /// Redirects followed before giving up with an error.
+    pub max_hops: usize,
+    /// Send a POST redirected with 301 or 302 as a GET, as browsers do.
+    /// A 303 always becomes a GET, and 307 and 308 keep the method.
+    pub post_to_get: bool,
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self {
+            max_hops: 10,
+            post_to_get: true,
+        }
+    }
+}
+
+impl RedirectPolicy {
+    /// The method to redirect a `method` request with after `status`.
+    fn method(&self, status: StatusCode, method: &Method) -> Method {
+        match status {
+            StatusCode::SEE_OTHER if method != Method::HEAD => Method::GET,
+            StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND
+                if self.post_to_get && method == Method::POST =>
+            {
+                Method::GET
+            }
+            _ => method.clone(),
+        }
+    }
+}
+
+/// Sends requests to one server over a shared connection.
+#[derive(Clone)]
+pub struct Client {
+    /// Replaced when the server goes away, for every clone.
+    current: Arc<Mutex<Current>>,
+    /// `https://host:port`, prefixed to request paths.
+    base: String,
+    /// Records buffered requests, if set.
+    har: Option<Har>,
+    /// Cookies sent with and stored from buffered requests, if set.
+    cookies: Option<CookieJar>,
+    /// Redirects are returned as they are unless set.
+    redirects: Option<RedirectPolicy>,
+    /// Used to connect to other servers when following redirects.
+    endpoint: Option<Endpoint>,
+    /// Run around every buffered request, in order.
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    /// Times a request turned away after a GOAWAY is sent again.
+    goaway_retries: u32,
+}
+
+/// The connection requests are sent on.
+struct Current {
+    /// Counts the connections, so concurrent retries reconnect once.
+    generation: u64,
+    send_request: SendRequest<OpenStreams, Bytes>,
+    /// Driver of a connection opened to replace the first one.
+    driver: Option<JoinHandle<ConnectionError>>,
+}
+
+/// A connection opened by [`Client::connect`].
+pub struct Connection {
+    pub client: Client,
+    driver: JoinHandle<h3::error::ConnectionError>,
+}
+
+impl Connection {
+    /// Close the connection gracefully once requests in flight complete.
+    /// Clones of the client must be dropped first.
+    pub async fn close(self) {
+        let replacement = self.client.current.lock().await.driver.take();
+        // Dropping the last request sender closes the connection
+        drop(self.client);
+        let _ = self.driver.await;
+        if let Some(driver) = replacement {
+            let _ = driver.await;
+        }
+    }
+}
+
+impl Client {
+    pub fn new(send_request: SendRequest<OpenStreams, Bytes>, host: &str, port: u16) -> Self {
+        let current = Current {
+            generation: 0,
+            send_request,
+            driver: None,
+        };
+        Self {
+            current: Arc::new(Mutex::new(current)),
+            base: format!("https://{}:{}", host, port),
+            har: None,
+            cookies: None,
+            redirects: None,
+            endpoint: None,
+            interceptors: Vec::new(),
+            goaway_retries: DEFAULT_GOAWAY_RETRIES,
+        }
+    }
+
+    /// Record requests sent with [`Client::send`] in `har`.
+    pub fn with_har(mut self, har: Har) -> Self {
+        self.har = Some(har);
+        self
+    }
+
+    /// Keep cookies for requests sent with [`Client::send`] in `jar`.
+    pub fn with_cookies(mut self, jar: CookieJar) -> Self {
+        self.cookies = Some(jar);
+        self
+    }
+
+    /// Follow redirects in [`Client::send`] according to `policy`.
+    pub fn with_redirects(mut self, policy: RedirectPolicy) -> Self {
+        self.redirects = Some(policy);
+        self
+    }
+
+    /// Run `interceptor` around requests sent with [`Client::send`], after
+    /// the interceptors already added.
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Send requests without a body that the server turned away after a
+    /// GOAWAY again, on a new connection, at most `max` times; 0 returns
+    /// the error instead. Needs the endpoint (see
+    /// [`Client::with_endpoint`]).
+    #[allow(dead_code)]
+    pub fn with_goaway_retries(mut self, max: u32) -> Self {
+        self.goaway_retries = max;
+        self
+    }
+
+    /// Use `endpoint` to follow redirects to other servers, and to
+    /// reconnect after a GOAWAY.
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Connect to the server at `uri`'s host and port (443 by default).
+    pub async fn connect(endpoint: &Endpoint, uri: &Uri) -> anyhow::Result<Connection> {
+        let (send_request, driver, host, port) = dial(endpoint, uri).await?;
+        Ok(Connection {
+            client: Self::new(send_request, &host, port).with_endpoint(endpoint.clone()),
+            driver,
+        })
+    }
+
+    /// Replace connection `generation` with a new one, unless that has
+    /// been done already.
+    async fn reconnect(&self, generation: u64) -> anyhow::Result<()> {
+        let mut current = self.current.lock().await;
+        if current.generation != generation {
+            return Ok(());
+        }
+        let Some(endpoint) = &self.endpoint else {
+            anyhow::bail!("cannot reconnect to {} without an endpoint", self.base);
+        };
+        let (send_request, driver, _, _) = dial(endpoint, &self.base.parse()?).await?;
+        debug!("Reconnected to {} after GOAWAY", self.base);
+        current.generation += 1;
+        current.send_request = send_request;
+        // The connection it replaces ends on its own
+        current.driver = Some(driver);
+        Ok(())
+    }
+
+    /// The absolute URI for `path` on the server.
+    pub fn uri(&self, path: &str) -> anyhow::Result<Uri> {
+        Ok(format!("{}{}", self.base, path).parse()?)
+    }
+
+    /// A GET request for `path`.
+    pub fn get(&self, path: &str) -> anyhow::Result<Request<()>> {
+        Ok(Request::builder().method("GET").uri(self.uri(path)?).body(())?)
+    }
+
+    /// Send the request headers and return the stream to send the body on.
+    pub async fn open(
+        &self,
+        req: Request<()>,
+    ) -> anyhow::Result<RequestStream<BidiStream<Bytes>, Bytes>> {
+        let mut send_request = self.current.lock().await.send_request.clone();
+        Ok(send_request.send_request(req).await?)
+    }
+
+    /// Send a request without a body and return the final response
+    /// headers and the stream to read the body from.
+    pub async fn request(
+        &self,
+        req: Request<()>,
+    ) -> anyhow::Result<(http::Response<()>, RequestStream<BidiStream<Bytes>, Bytes>)> {
+        let (response, _, stream, _) = self.start(&req, |_| {}).await?;
+        Ok((response, stream))
+    }
+
+    /// Send a request without a body and receive the final response
+    /// headers, sending it again on a new connection while the server turns
+    /// it away unprocessed after a GOAWAY. Returns the interim responses
+    /// too, and the number of retries.
+    async fn start(
+        &self,
+        req: &Request<()>,
+        mut on_informational: impl FnMut(&Informational),
+    ) -> anyhow::Result<(
+        http::Response<()>,
+        Vec<Informational>,
+        RequestStream<BidiStream<Bytes>, Bytes>,
+        u32,
+    )> {
+        let mut retries = 0;
+        loop {
+            let (generation, mut send_request) = {
+                let current = self.current.lock().await;
+                (current.generation, current.send_request.clone())
+            };
+            let attempt = async {
+                let mut stream = send_request.send_request(req.clone()).await?;
+                stream.finish().await?;
+                let (response, informational) =
+                    recv_response(&mut stream, &mut on_informational).await?;
+                anyhow::Ok((response, informational, stream))
+            };
+            match attempt.await {
+                Ok((response, informational, stream)) => {
+                    return Ok((response, informational, stream, retries));
+                }
+                Err(e) if retries < self.goaway_retries && is_unprocessed(&e) => {
+                    debug!("{} {} turned away ({}); retrying", req.method(), req.uri(), e);
+                    retries += 1;
+                    self.reconnect(generation).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send a request without a body and read the whole response,
+    /// following redirects if the client is set up to.
+    pub async fn send(&self, req: Request<()>) -> anyhow::Result<Response> {
+        let Some(policy) = &self.redirects else {
+            return self.send_once(req).await;
+        };
+
+        let (mut parts, ()) = req.into_parts();
+        for _ in 0..=policy.max_hops {
+            let req = Request::from_parts(parts.clone(), ());
+            let response = match self.is_same_origin(&parts.uri) {
+                true => self.send_once(req).await?,
+                false => self.send_elsewhere(req).await?,
+            };
+            let location = response
+                .headers
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok());
+            let (true, Some(location)) = (response.status.is_redirection(), location) else {
+                return Ok(response);
+            };
+            // 304 Not Modified and 300 Multiple Choices are not followed
+            if matches!(response.status, StatusCode::NOT_MODIFIED | StatusCode::MULTIPLE_CHOICES) {
+                return Ok(response);
+            }
+
+            let uri = resolve(&parts.uri, location)?;
+            debug!("{} {} redirected to {}", response.status, parts.uri, uri);
+            redirect(&mut parts, policy, response.status, uri);
+        }
+        anyhow::bail!("too many redirects (more than {})", policy.max_hops)
+    }
+
+    /// Send one request on this connection and read the whole response,
+    /// running the interceptors and retrying as they ask, up to
+    /// [`MAX_ATTEMPTS`] times in all.
+    async fn send_once(&self, req: Request<()>) -> anyhow::Result<Response> {
+        for attempt in 0..MAX_ATTEMPTS {
+            let mut req = req.clone();
+            if let Some(jar) = &self.cookies {
+                jar.apply(&req.uri().clone(), req.headers_mut());
+            }
+            req.headers_mut()
+                .entry(ACCEPT_ENCODING)
+                .or_insert(HeaderValue::from_static(encoding::ACCEPT_ENCODING));
+            for interceptor in &self.interceptors {
+                interceptor.request(&mut req)?;
+            }
+
+            let sent = req.clone();
+            let response = self.exchange(req).await?;
+            // Every interceptor sees the response; the first retry wins
+            let outcome = self
+                .interceptors
+                .iter()
+                .map(|interceptor| interceptor.response(&sent, &response, attempt))
+                .fold(Outcome::Done, |outcome, next| match outcome {
+                    Outcome::Done => next,
+                    retry => retry,
+                });
+            match outcome {
+                Outcome::Done => return Ok(response),
+                Outcome::Retry(_) if attempt + 1 == MAX_ATTEMPTS => {}
+                Outcome::Retry(delay) => tokio::time::sleep(delay).await,
+            }
+        }
+        anyhow::bail!(
+            "{} {} still asked to be retried after {} attempts",
+            req.method(),
+            req.uri(),
+            MAX_ATTEMPTS
+        )
+    }
+
+    /// Send a request as it is and read the whole response, trailers
+    /// included. Interceptors see interim responses as they arrive.
+    async fn exchange(&self, req: Request<()>) -> anyhow::Result<Response> {
+        let uri = req.uri().clone();
+        let request = self.har.as_ref().map(|_| RequestInfo::of(&req));
+        let sent = req.clone();
+        let mut timer = RequestTimer::start();
+        let (response, informational, mut stream, goaway_retries) = self
+            .start(&req, |interim| {
+                for interceptor in &self.interceptors {
+                    interceptor.informational(&sent, interim);
+                }
+            })
+            .await?;
+        timer.first_byte();
+        let body = read_to_end(&mut stream).await?;
+        let trailers = stream.recv_trailers().await?.unwrap_or_default();
+        timer.finish();
+
+        let (parts, ()) = response.into_parts();
+        if let Some(jar) = &self.cookies {
+            jar.store(&uri, &parts.headers);
+        }
+        if let (Some(har), Some(request)) = (&self.har, request) {
+            let response = ResponseInfo::new(parts.status, &parts.headers, &body);
+            har.record(&request, &response, &timer);
+        }
+        Ok(Response {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+            informational,
+            trailers,
+            timer,
+            goaway_retries,
+        })
+    }
+
+    /// Send one request on a new connection to its server, sharing this
+    /// client's cookies, interceptors and HAR recording.
+    async fn send_elsewhere(&self, req: Request<()>) -> anyhow::Result<Response> {
+        let endpoint = self.endpoint.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("cannot follow a redirect to {} without an endpoint", req.uri())
+        })?;
+        let conn = Client::connect(endpoint, req.uri()).await?;
+        let client = Client {
+            har: self.har.clone(),
+            cookies: self.cookies.clone(),
+            interceptors: self.interceptors.clone(),
+            goaway_retries: self.goaway_retries,
+            ..conn.client.clone()
+        };
+        let result = client.send_once(req).await;
+        drop(client);
+        conn.close().await;
+        result
+    }
+
+    /// Whether `uri` is on the server this client is connected to.
+    fn is_same_origin(&self, uri: &Uri) -> bool {
+        self.base.parse::<Uri>().is_ok_and(|base| origin(&base) == origin(uri))
+    }
+
+    /// Send `requests` concurrently, at most `concurrency` at a time, and
+    /// return their results in the same order.
+    pub async fn batch(
+        &self,
+        requests: Vec<Request<()>>,
+        concurrency: usize,
+    ) -> Vec<anyhow::Result<Response>> {
+        futures::stream::iter(requests)
+            .map(|req| self.send(req))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+/// Receive the final response headers on `stream`, passing each interim
+/// (1xx) response before it to `on_informational`. Returns the final
+/// response and the interim ones.
+pub async fn recv_response<S>(
+    stream: &mut RequestStream<S, Bytes>,
+    mut on_informational: impl FnMut(&Informational),
+) -> anyhow::Result<(http::Response<()>, Vec<Informational>)>
+where
+    S: h3::quic::RecvStream,
+{
+    let mut informational = Vec::new();
+    loop {
+        let response = stream.recv_response().await?;
+        let status = response.status();
+        if !status.is_informational() {
+            return Ok((response, informational));
+        }
+        // Upgrades do not exist in HTTP/3 (RFC 9114, section 4.5)
+        if status == StatusCode::SWITCHING_PROTOCOLS {
+            anyhow::bail!("101 Switching Protocols is not allowed over HTTP/3");
+        }
+        if informational.len() == MAX_INFORMATIONAL {
+            anyhow::bail!("more than {} interim responses", MAX_INFORMATIONAL);
+        }
+        debug!("Interim response: {}", status);
+        let interim = Informational {
+            status,
+            headers: response.into_parts().0.headers,
+        };
+        on_informational(&interim);
+        informational.push(interim);
+    }
+}
+
+/// Read the rest of a response body.
+pub async fn read_to_end<S>(stream: &mut RequestStream<S, Bytes>) -> anyhow::Result<Bytes>
+where
+    S: h3::quic::RecvStream,
+{
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            body.extend_from_slice(bytes);
+            chunk.advance(bytes.len());
+        }
+    }
+    Ok(body.into())
+}
+
+/// Open an HTTP/3 connection to `uri`'s host and port (443 by default),
+/// returning its request sender, the task driving it, and the host and
+/// port.
+async fn dial(
+    endpoint: &Endpoint,
+    uri: &Uri,
+) -> anyhow::Result<(
+    SendRequest<OpenStreams, Bytes>,
+    JoinHandle<ConnectionError>,
+    String,
+    u16,
+)> {
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("URL has no host: {}", uri))?;
+    let port = uri.port_u16().unwrap_or(443);
+    // The client endpoint is bound to an IPv4 address
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .find(|addr| addr.is_ipv4())
+        .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", host))?;
+
+    let conn = endpoint.connect(addr, host)?.await?;
+    let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn)).await?;
+    let driver = tokio::spawn(async move {
+        futures::future::poll_fn(|cx| driver.poll_close(cx)).await
+    });
+    Ok((send_request, driver, host.to_string(), port))
+}
+
+/// Point the request `parts` at `uri`, where a `status` response redirected
+/// it, with the method `policy` says to redirect with.
+fn redirect(parts: &mut request::Parts, policy: &RedirectPolicy, status: StatusCode, uri: Uri) {
+    let method = policy.method(status, &parts.method);
+    if method != parts.method {
+        parts.headers.remove(CONTENT_TYPE);
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.method = method;
+    }
+    // Credentials are not passed on to another server
+    if origin(&uri) != origin(&parts.uri) {
+        parts.headers.remove(AUTHORIZATION);
+        parts.headers.remove(COOKIE);
+    }
+    parts.uri = uri;
+}
+
+/// Whether a request failed because the server went away before processing
+/// it: sent after its GOAWAY, or rejected with `H3_REQUEST_REJECTED`.
+fn is_unprocessed(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<StreamError>() {
+        Some(StreamError::RemoteClosing) => true,
+        Some(StreamError::RemoteTerminate { code, .. }) => *code == Code::H3_REQUEST_REJECTED,
+        _ => false,
+    }
+}
+
+/// The scheme, lowercase host and port of `uri`.
+fn origin(uri: &Uri) -> Option<(&str, String, u16)> {
+    let host = uri.host()?.to_ascii_lowercase();
+    Some((uri.scheme_str()?, host, uri.port_u16().unwrap_or(443)))
+}
+
+/// The target of a `Location` header, relative to the request's `base`.
+fn resolve(base: &Uri, location: &str) -> anyhow::Result<Uri> {
+    let scheme = base.scheme_str().unwrap_or("https");
+    let authority = base.authority().map_or("", |a| a.as_str());
+    let target = if location.contains("://") {
+        location.to_string()
+    } else if location.starts_with("//") {
+        format!("{}:{}", scheme, location)
+    } else if location.starts_with('/') {
+        format!("{}://{}{}", scheme, authority, location)
+    } else if location.starts_with('?') {
+        format!("{}://{}{}{}", scheme, authority, base.path(), location)
+    } else {
+        // Relative to the directory of the request path
+        let dir = &base.path()[..base.path().rfind('/').map_or(0, |i| i + 1)];
+        format!("{}://{}{}{}", scheme, authority, dir, location)
+    };
+    let uri: Uri = target
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid redirect to {}: {}", location, e))?;
+    if uri.scheme_str() != Some("https") {
+        anyhow::bail!("cannot follow a redirect to {} over HTTP/3", uri);
+    }
+    Ok(uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    fn uri(uri: &str) -> Uri {
+        uri.parse().unwrap()
+    }
+
+    fn resolved(base: &str, location: &str) -> String {
+        resolve(&uri(base), location).unwrap().to_string()
+    }
+
+    #[test]
+    fn resolves_locations() {
+        let base = "https://example.com:4433/api/users?page=2";
+        assert_eq!(resolved(base, "https://other.example/x"), "https://other.example/x");
+        assert_eq!(resolved(base, "//other.example/x"), "https://other.example/x");
+        assert_eq!(resolved(base, "/login"), "https://example.com:4433/login");
+        assert_eq!(resolved(base, "?page=3"), "https://example.com:4433/api/users?page=3");
+        assert_eq!(resolved(base, "groups"), "https://example.com:4433/api/groups");
+        assert_eq!(resolved("https://example.com", "home"), "https://example.com/home");
+
+        assert!(resolve(&uri(base), "http://example.com/").is_err());
+        assert!(resolve(&uri(base), "https://exa mple.com/").is_err());
+    }
+
+    #[test]
+    fn compares_origins() {
+        let origin_of = |s| {
+            let uri = uri(s);
+            origin(&uri).map(|(scheme, host, port)| (scheme.to_string(), host, port))
+        };
+        assert_eq!(origin_of("https://Example.com/a"), origin_of("https://example.com:443/b"));
+        assert_ne!(origin_of("https://example.com/"), origin_of("https://example.com:4433/"));
+        assert_ne!(origin_of("https://example.com/"), origin_of("https://api.example.com/"));
+        assert_eq!(origin_of("/relative"), None);
+    }
+
+    #[test]
+    fn picks_redirect_methods() {
+        let policy = RedirectPolicy::default();
+        for status in [StatusCode::MOVED_PERMANENTLY, StatusCode::FOUND] {
+            assert_eq!(policy.method(status, &Method::POST), Method::GET);
+            assert_eq!(policy.method(status, &Method::PUT), Method::PUT);
+        }
+        assert_eq!(policy.method(StatusCode::SEE_OTHER, &Method::PUT), Method::GET);
+        assert_eq!(policy.method(StatusCode::SEE_OTHER, &Method::HEAD), Method::HEAD);
+        for status in [StatusCode::TEMPORARY_REDIRECT, StatusCode::PERMANENT_REDIRECT] {
+            assert_eq!(policy.method(status, &Method::POST), Method::POST);
+            assert_eq!(policy.method(status, &Method::DELETE), Method::DELETE);
+        }
+
+        let policy = RedirectPolicy {
+            post_to_get: false,
+            ..RedirectPolicy::default()
+        };
+        assert_eq!(policy.method(StatusCode::FOUND, &Method::POST), Method::POST);
+        assert_eq!(policy.method(StatusCode::SEE_OTHER, &Method::POST), Method::GET);
+    }
+
+    fn parts(method: Method, to: &str) -> request::Parts {
+        let (parts, ()) = Request::builder()
+            .method(method)
+            .uri(to)
+            .header(AUTHORIZATION, "Bearer secret")
+            .header(COOKIE, "id=1")
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+    }
+
+    #[test]
+    fn drops_credentials_across_origins() {
+        let policy = RedirectPolicy::default();
+        let mut same = parts(Method::POST, "https://example.com/a");
+        redirect(&mut same, &policy, StatusCode::SEE_OTHER, uri("https://example.com/b"));
+        assert_eq!(same.method, Method::GET);
+        assert_eq!(same.uri, "https://example.com/b");
+        assert!(same.headers.contains_key(AUTHORIZATION) && same.headers.contains_key(COOKIE));
+        // The body is not sent to a GET
+        assert!(!same.headers.contains_key(CONTENT_TYPE));
+
+        let mut other = parts(Method::POST, "https://example.com/a");
+        let to = uri("https://evil.example/b");
+        redirect(&mut other, &policy, StatusCode::TEMPORARY_REDIRECT, to);
+        assert_eq!(other.method, Method::POST);
+        assert!(!other.headers.contains_key(AUTHORIZATION) && !other.headers.contains_key(COOKIE));
+        assert!(other.headers.contains_key(CONTENT_TYPE));
+    }
+
+    /// Serve one connection on a local endpoint with `serve`.
+    async fn serve<F, Fut>(serve: F) -> SocketAddr
+    where
+        F: FnOnce(h3::server::Connection<h3_quinn::Connection, Bytes>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let cert = common::tls::generate_self_signed_cert(&["localhost".to_string()]).unwrap();
+        let mut tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(cert.cert_chain, cert.private_key)
+            .unwrap();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).unwrap();
+        let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        let endpoint = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            let conn = endpoint.accept().await.unwrap().await.unwrap();
+            let conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await;
+            serve(conn.unwrap()).await;
+        });
+        addr
+    }
+
+    fn client_endpoint() -> Endpoint {
+        let mut tls = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(common::tls::insecure_verifier())
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        endpoint
+    }
+
+    async fn get(send_request: &mut SendRequest<OpenStreams, Bytes>) -> anyhow::Result<()> {
+        let req = Request::get("https://localhost/").body(())?;
+        let mut stream = send_request.send_request(req).await?;
+        stream.finish().await?;
+        stream.recv_response().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recognizes_requests_turned_away() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let addr = serve(|mut conn| async move {
+            let resolver = conn.accept().await.unwrap().unwrap();
+            let (_, mut stream) = resolver.resolve_request().await.unwrap();
+            stream.stop_stream(Code::H3_REQUEST_REJECTED);
+            conn.shutdown(0).await.unwrap();
+            // Open until the client goes
+            while let Ok(Some(_)) = conn.accept().await {}
+        })
+        .await;
+        let to = uri(&format!("https://localhost:{}/", addr.port()));
+        let (mut send_request, _driver, _, _) = dial(&client_endpoint(), &to).await.unwrap();
+
+        let rejected = get(&mut send_request).await.unwrap_err();
+        assert!(is_unprocessed(&rejected), "{:?}", rejected);
+        // Sent once the client has seen the GOAWAY
+        let closing = async {
+            loop {
+                match get(&mut send_request).await {
+                    Err(e) if matches!(e.downcast_ref(), Some(StreamError::RemoteClosing)) => {
+                        break e;
+                    }
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        };
+        let closing = tokio::time::timeout(Duration::from_secs(5), closing).await.unwrap();
+        assert!(is_unprocessed(&closing));
+
+        assert!(!is_unprocessed(&anyhow::anyhow!("connection refused")));
+    }
+}
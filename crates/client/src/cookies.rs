@@ -0,0 +1,233 @@
+//! A cookie jar for the client.
+//!
+//! Cookies from `Set-Cookie` response headers are stored and sent back in
+//! the `Cookie` header of later requests whose host and path they match,
+//! until they expire. The rules follow RFC 6265 closely enough for
+//! multi-step flows such as logging in; there is no public suffix list, so
+//! a server can set a cookie for a whole top-level domain.
+
+use http::header::{COOKIE, SET_COOKIE};
+use http::{HeaderMap, HeaderValue, Uri};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase domain, without a leading dot.
+    domain: String,
+    /// Only sent to exactly `domain`, not its subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    /// Session cookies (no `Expires` or `Max-Age`) never expire here.
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// Parse a `Set-Cookie` value received from `uri`.
+    fn parse(header: &str, uri: &Uri) -> Option<Self> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let mut parts = header.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.trim().trim_matches('"').to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(uri),
+            secure: false,
+            expires: None,
+        };
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    // A server may only set cookies for its own domain
+                    if !domain_matches(&host, &domain) {
+                        debug!("Ignoring cookie {} for foreign domain {}", name, domain);
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_string(),
+                "secure" => cookie.secure = true,
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "expires" => {
+                    cookie.expires = chrono::DateTime::parse_from_rfc2822(value)
+                        .ok()
+                        .map(SystemTime::from)
+                }
+                _ => {}
+            }
+        }
+        // Max-Age takes precedence over Expires
+        if let Some(secs) = max_age {
+            cookie.expires = match u64::try_from(secs) {
+                // Past what a SystemTime can hold, it never expires
+                Ok(secs) => SystemTime::now().checked_add(Duration::from_secs(secs)),
+                Err(_) => Some(SystemTime::UNIX_EPOCH),
+            };
+        }
+        Some(cookie)
+    }
+
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, host: &str, path: &str, secure: bool) -> bool {
+        let domain = match self.host_only {
+            true => host == self.domain,
+            false => domain_matches(host, &self.domain),
+        };
+        domain && path_matches(path, &self.path) && (secure || !self.secure)
+    }
+}
+
+/// Cookies shared by every request made with a client.
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<Cookie>>>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store the cookies set by a response to a request for `uri`.
+    pub fn store(&self, uri: &Uri, headers: &HeaderMap) {
+        let now = SystemTime::now();
+        let mut cookies = self.cookies.lock().unwrap();
+        for value in headers.get_all(SET_COOKIE) {
+            let Some(cookie) = value.to_str().ok().and_then(|value| Cookie::parse(value, uri))
+            else {
+                continue;
+            };
+            // A new cookie replaces one with the same name, domain and path
+            cookies.retain(|c| {
+                (&c.name, &c.domain, &c.path) != (&cookie.name, &cookie.domain, &cookie.path)
+            });
+            if !cookie.expired(now) {
+                debug!("Storing cookie {} for {}{}", cookie.name, cookie.domain, cookie.path);
+                cookies.push(cookie);
+            }
+        }
+        cookies.retain(|cookie| !cookie.expired(now));
+    }
+
+    /// The `Cookie` header for a request to `uri`, if any cookies match.
+    pub fn header(&self, uri: &Uri) -> Option<HeaderValue> {
+        let host = uri.host()?.to_ascii_lowercase();
+        let path = uri.path();
+        let secure = uri.scheme_str() == Some("https");
+        let now = SystemTime::now();
+
+        let cookies = self.cookies.lock().unwrap();
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|cookie| !cookie.expired(now) && cookie.matches(&host, path, secure))
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        // More specific paths first
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        let header = matching
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        HeaderValue::from_str(&header).ok()
+    }
+
+    /// Set the `Cookie` header of a request to `uri` from the jar, unless
+    /// no cookies match.
+    pub fn apply(&self, uri: &Uri, headers: &mut HeaderMap) {
+        if let Some(value) = self.header(uri) {
+            headers.insert(COOKIE, value);
+        }
+    }
+}
+
+/// Whether `host` is `domain` or a subdomain of it.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// Whether a cookie with `cookie_path` applies to `path`.
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path.strip_prefix(cookie_path).is_some_and(|rest| {
+        rest.is_empty() || rest.starts_with('/') || cookie_path.ends_with('/')
+    })
+}
+
+/// The directory of `uri`'s path, used when a cookie sets no `Path`.
+fn default_path(uri: &Uri) -> String {
+    match uri.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => uri.path()[..i].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(uri: &str) -> Uri {
+        uri.parse().unwrap()
+    }
+
+    #[test]
+    fn survives_huge_max_age() {
+        let from = uri("https://example.com/");
+        let cookie = Cookie::parse("id=1; Max-Age=9223372036854775807", &from).unwrap();
+        assert_eq!(cookie.expires, None);
+
+        let cookie = Cookie::parse("id=1; Max-Age=60", &from).unwrap();
+        assert!(!cookie.expired(SystemTime::now()));
+        let cookie = Cookie::parse("id=1; Max-Age=-1", &from).unwrap();
+        assert!(cookie.expired(SystemTime::now()));
+    }
+
+    #[test]
+    fn matches_domains() {
+        assert!(domain_matches("example.com", "example.com"));
+        assert!(domain_matches("api.example.com", "example.com"));
+        assert!(!domain_matches("badexample.com", "example.com"));
+        assert!(!domain_matches("example.com", "api.example.com"));
+    }
+
+    #[test]
+    fn matches_paths() {
+        assert!(path_matches("/", "/"));
+        assert!(path_matches("/api", "/api"));
+        assert!(path_matches("/api/users", "/api"));
+        assert!(path_matches("/api/users", "/api/"));
+        assert!(!path_matches("/apis", "/api"));
+        assert!(!path_matches("/", "/api"));
+    }
+
+    #[test]
+    fn defaults_to_the_directory() {
+        assert_eq!(default_path(&uri("https://example.com")), "/");
+        assert_eq!(default_path(&uri("https://example.com/login")), "/");
+        assert_eq!(default_path(&uri("https://example.com/api/login")), "/api");
+        assert_eq!(default_path(&uri("https://example.com/api/v1/")), "/api/v1");
+    }
+}
@@ -0,0 +1,241 @@
+//! Downloads to a file, with resumption and integrity checks.
+//!
+//! `client download <url> -o <file> [--resume]` saves the response body to
+//! `file`. With `--resume`, an existing partial file is continued with a
+//! `Range` request. The ETag seen when the download started is kept next to
+//! the file (`<file>.etag`) and sent as `If-Range`, so a server whose copy
+//! changed in between sends the whole body again instead of a mismatched
+//! remainder. Servers that ignore `Range` answer 200 and the file is
+//! rewritten from the start.
+//!
+//! When the response carries a SHA-256 `Repr-Digest` (RFC 9530) or `Digest`
+//! (RFC 3230) header, the finished file is checked against it.
+
+use crate::client::Client;
+use base64::Engine;
+use bytes::Buf;
+use http::header::{CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use http::{HeaderMap, Request, StatusCode, Uri};
+use quinn::Endpoint;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+/// Arguments of the `download` subcommand.
+#[derive(Debug)]
+pub struct Options {
+    pub url: String,
+    pub output: PathBuf,
+    pub resume: bool,
+}
+
+impl Options {
+    /// Parse the arguments following `download`.
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let usage = "usage: client download <url> -o <file> [--resume]";
+        let mut url = None;
+        let mut output = None;
+        let mut resume = false;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" | "--output" => output = args.next().map(PathBuf::from),
+                "--resume" => resume = true,
+                arg if !arg.starts_with('-') && url.is_none() => url = Some(arg.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            url: url.ok_or_else(|| anyhow::anyhow!(usage))?,
+            output: output.ok_or_else(|| anyhow::anyhow!(usage))?,
+            resume,
+        })
+    }
+}
+
+/// Download `options.url` on a new connection.
+pub async fn run(endpoint: &Endpoint, options: &Options) -> anyhow::Result<()> {
+    let uri: Uri = options.url.parse()?;
+    let conn = Client::connect(endpoint, &uri).await?;
+    let result = download(&conn.client, uri, options).await;
+    conn.close().await;
+    result
+}
+
+async fn download(client: &Client, uri: Uri, options: &Options) -> anyhow::Result<()> {
+    let path = &options.output;
+    let etag_path = etag_path(path);
+    let existing = match options.resume {
+        true => std::fs::metadata(path).map_or(0, |m| m.len()),
+        false => 0,
+    };
+    let saved_etag = match existing {
+        0 => None,
+        _ => std::fs::read_to_string(&etag_path).ok(),
+    };
+
+    let mut req = Request::builder().method("GET").uri(uri.clone());
+    if existing > 0 {
+        req = req.header(RANGE, format!("bytes={}-", existing));
+        if let Some(etag) = &saved_etag {
+            req = req.header(IF_RANGE, etag.trim());
+        }
+    }
+    let (response, mut stream) = client.request(req.body(())?).await?;
+    let headers = response.headers();
+    let etag = headers.get(ETAG).and_then(|v| v.to_str().ok());
+
+    let (mut file, start, total) = match response.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let (from, total) = content_range(headers)?;
+            if from != existing {
+                anyhow::bail!("server resumed at byte {} instead of {}", from, existing);
+            }
+            if let (Some(saved), Some(etag)) = (&saved_etag, etag)
+                && saved.trim() != etag
+            {
+                anyhow::bail!("{} changed since the download started; retry without --resume", uri);
+            }
+            info!("Resuming {} at byte {}", path.display(), existing);
+            let file = tokio::fs::OpenOptions::new().append(true).open(path).await?;
+            (file, existing, total)
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => {
+            // Nothing left to fetch if the file is already complete
+            let (_, total) = content_range(headers)?;
+            if total != Some(existing) {
+                anyhow::bail!("{} is larger than {}", path.display(), uri);
+            }
+            let _ = std::fs::remove_file(&etag_path);
+            info!("{} is already complete", path.display());
+            return Ok(());
+        }
+        status if status.is_success() => {
+            if existing > 0 {
+                info!("Server sent the whole file; starting over");
+            }
+            let total = headers
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse().ok());
+            (tokio::fs::File::create(path).await?, 0, total)
+        }
+        status => anyhow::bail!("download of {} failed: {}", uri, status),
+    };
+
+    // Remember which version is being downloaded, for --resume
+    match etag {
+        Some(etag) => std::fs::write(&etag_path, etag)?,
+        None => {
+            let _ = std::fs::remove_file(&etag_path);
+        }
+    }
+    let expected_digest = sha256_digest(headers)?;
+
+    let mut received = start;
+    while let Some(mut chunk) = stream.recv_data().await? {
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            file.write_all(bytes).await?;
+            received += bytes.len() as u64;
+            chunk.advance(bytes.len());
+        }
+    }
+    file.flush().await?;
+    drop(file);
+
+    if let Some(total) = total
+        && received != total
+    {
+        anyhow::bail!(
+            "download incomplete: {} of {} bytes; run again with --resume",
+            received,
+            total
+        );
+    }
+    if let Some(expected) = expected_digest {
+        let path = path.clone();
+        let actual = tokio::task::spawn_blocking(move || file_sha256(&path)).await??;
+        if actual != expected {
+            let path = options.output.display();
+            anyhow::bail!("SHA-256 digest of {} does not match the server's", path);
+        }
+        info!("SHA-256 digest verified");
+    }
+
+    let _ = std::fs::remove_file(&etag_path);
+    info!("Saved {} bytes to {}", received, path.display());
+    Ok(())
+}
+
+/// Where the ETag of a partial download is kept.
+fn etag_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".etag");
+    PathBuf::from(name)
+}
+
+/// The first byte and total length from `Content-Range: bytes a-b/total`
+/// (or `bytes */total`).
+fn content_range(headers: &HeaderMap) -> anyhow::Result<(u64, Option<u64>)> {
+    let value = headers
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| anyhow::anyhow!("missing Content-Range"))?;
+    let invalid = || anyhow::anyhow!("invalid Content-Range: {}", value);
+
+    let (range, total) = value
+        .strip_prefix("bytes ")
+        .and_then(|rest| rest.split_once('/'))
+        .ok_or_else(invalid)?;
+    let from = match range {
+        "*" => 0,
+        range => range.split_once('-').ok_or_else(invalid)?.0.parse().map_err(|_| invalid())?,
+    };
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().map_err(|_| invalid())?),
+    };
+    Ok((from, total))
+}
+
+/// The SHA-256 digest of the whole representation, from `Repr-Digest:
+/// sha-256=:<base64>:` or `Digest: SHA-256=<base64>`.
+fn sha256_digest(headers: &HeaderMap) -> anyhow::Result<Option<Vec<u8>>> {
+    for name in ["repr-digest", "digest"] {
+        for value in headers.get_all(name) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for entry in value.split(',') {
+                let Some((algorithm, digest)) = entry.trim().split_once('=') else {
+                    continue;
+                };
+                if algorithm.eq_ignore_ascii_case("sha-256") {
+                    let digest = base64::engine::general_purpose::STANDARD
+                        .decode(digest.trim_matches(':'))
+                        .map_err(|e| anyhow::anyhow!("invalid {} header: {}", name, e))?;
+                    return Ok(Some(digest));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn file_sha256(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
@@ -0,0 +1,61 @@
+//! Response body decoding: content codings and charsets.
+//!
+//! Buffered requests advertise [`ACCEPT_ENCODING`], and [`decode`] undoes
+//! whatever `Content-Encoding` the server applied (gzip, deflate, br or
+//! zstd, in any combination). [`charset`] picks the text encoding from
+//! `Content-Type`, defaulting to UTF-8.
+
+use bytes::Bytes;
+use encoding_rs::Encoding;
+use http::HeaderMap;
+use http::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use std::io::Read;
+
+/// The content codings [`decode`] understands, for `Accept-Encoding`.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate, br, zstd";
+
+/// Decode `body` according to its `Content-Encoding` header.
+pub fn decode(headers: &HeaderMap, body: &Bytes) -> anyhow::Result<Bytes> {
+    let codings: Vec<String> = headers
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+
+    // Codings are listed in the order they were applied
+    let mut body = body.clone();
+    for coding in codings.iter().rev() {
+        let mut decoded = Vec::new();
+        let reader = body.as_ref();
+        match coding.as_str() {
+            "identity" => continue,
+            "gzip" | "x-gzip" => {
+                flate2::read::MultiGzDecoder::new(reader).read_to_end(&mut decoded)
+            }
+            "deflate" => flate2::read::ZlibDecoder::new(reader).read_to_end(&mut decoded),
+            "br" => brotli::Decompressor::new(reader, 4096).read_to_end(&mut decoded),
+            "zstd" => zstd::stream::read::Decoder::new(reader)?.read_to_end(&mut decoded),
+            coding => anyhow::bail!("unsupported content encoding: {}", coding),
+        }
+        .map_err(|e| anyhow::anyhow!("decoding {} body: {}", coding, e))?;
+        body = decoded.into();
+    }
+    Ok(body)
+}
+
+/// The text encoding named by the `charset` parameter of `Content-Type`,
+/// or UTF-8.
+pub fn charset(headers: &HeaderMap) -> &'static Encoding {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .into_iter()
+        .flat_map(|v| v.split(';').skip(1))
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, label)| Encoding::for_label(label.trim().trim_matches('"').as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8)
+}
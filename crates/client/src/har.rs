@@ -0,0 +1,189 @@
+//! HTTP Archive (HAR 1.2) export.
+//!
+//! With `--har <file>`, every request the demo makes is recorded with its
+//! headers, response body and timings, and written out when the client
+//! finishes, for loading into browser devtools or other HAR viewers.
+
+use crate::timings::RequestTimer;
+use http::{HeaderMap, Method, Request, StatusCode, Uri};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// What a HAR entry needs from a request, captured before it is sent.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    /// Bytes sent in the request body, if known.
+    body_size: Option<u64>,
+}
+
+impl RequestInfo {
+    pub fn of<T>(req: &Request<T>) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            body_size: Some(0),
+        }
+    }
+
+    pub fn with_body_size(mut self, size: Option<u64>) -> Self {
+        self.body_size = size;
+        self
+    }
+}
+
+/// What a HAR entry records of a response.
+#[derive(Debug, Clone)]
+pub struct ResponseInfo {
+    status: StatusCode,
+    headers: HeaderMap,
+    body_size: u64,
+    /// The body, if it was kept and is text.
+    text: Option<String>,
+}
+
+impl ResponseInfo {
+    /// A response whose whole body was read.
+    pub fn new(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+        Self {
+            status,
+            headers: headers.clone(),
+            body_size: body.len() as u64,
+            text: std::str::from_utf8(body).ok().map(str::to_string),
+        }
+    }
+
+    /// A response whose body was consumed as it arrived.
+    pub fn streamed(status: StatusCode, headers: &HeaderMap, body_size: u64) -> Self {
+        Self {
+            status,
+            headers: headers.clone(),
+            body_size,
+            text: None,
+        }
+    }
+}
+
+/// Recorded requests, shared by everything that sends them.
+#[derive(Clone, Default)]
+pub struct Har {
+    /// Entries with the instant their request was sent.
+    entries: Arc<Mutex<Vec<(Instant, Value)>>>,
+}
+
+impl Har {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a completed request.
+    pub fn record(&self, request: &RequestInfo, response: &ResponseInfo, timer: &RequestTimer) {
+        let total = timer.elapsed();
+        let wait = timer.time_to_first_byte().unwrap_or(total);
+        let started = SystemTime::now() - timer.sent().elapsed();
+
+        let entry = json!({
+            "startedDateTime": chrono::DateTime::<chrono::Utc>::from(started)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "time": ms(total),
+            "request": {
+                "method": request.method.as_str(),
+                "url": request.uri.to_string(),
+                "httpVersion": "HTTP/3",
+                "cookies": [],
+                "headers": headers(&request.headers),
+                "queryString": query_string(&request.uri),
+                "headersSize": -1,
+                "bodySize": request.body_size.map_or(-1, |size| size as i64),
+            },
+            "response": {
+                "status": response.status.as_u16(),
+                "statusText": response.status.canonical_reason().unwrap_or(""),
+                "httpVersion": "HTTP/3",
+                "cookies": [],
+                "headers": headers(&response.headers),
+                "content": content(response),
+                "redirectURL": response
+                    .headers
+                    .get(http::header::LOCATION)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or(""),
+                "headersSize": -1,
+                "bodySize": response.body_size,
+            },
+            "cache": {},
+            // Connection setup is shared by every request on the connection
+            "timings": {
+                "blocked": -1,
+                "dns": -1,
+                "connect": -1,
+                "ssl": -1,
+                "send": 0,
+                "wait": ms(wait),
+                "receive": ms(total.saturating_sub(wait)),
+            },
+        });
+        self.entries.lock().unwrap().push((timer.sent(), entry));
+    }
+
+    /// Write the recorded requests to `path`, oldest first.
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by_key(|(sent, _)| *sent);
+        let entries: Vec<Value> = entries.into_iter().map(|(_, entry)| entry).collect();
+
+        let har = json!({
+            "log": {
+                "version": "1.2",
+                "creator": {"name": "simple-http3", "version": env!("CARGO_PKG_VERSION")},
+                "pages": [],
+                "entries": entries,
+            }
+        });
+        std::fs::write(path, serde_json::to_vec_pretty(&har)?)
+            .map_err(|e| anyhow::anyhow!("writing {}: {}", path.display(), e))
+    }
+}
+
+fn headers(headers: &HeaderMap) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            json!({"name": name.as_str(), "value": String::from_utf8_lossy(value.as_bytes())})
+        })
+        .collect()
+}
+
+fn query_string(uri: &Uri) -> Vec<Value> {
+    uri.query()
+        .unwrap_or("")
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({"name": name, "value": value})
+        })
+        .collect()
+}
+
+fn content(response: &ResponseInfo) -> Value {
+    let mime_type = response
+        .headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let mut content = json!({"size": response.body_size, "mimeType": mime_type});
+    if let Some(text) = &response.text {
+        content["text"] = text.as_str().into();
+    }
+    content
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
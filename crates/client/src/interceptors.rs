@@ -0,0 +1,97 @@
+//! Request/response interceptors for [`Client`](crate::client::Client).
+//!
+//! An [`Interceptor`] sees every buffered request just before it is sent
+//! and every response as it comes back, in the order the interceptors were
+//! added with [`Client::with_interceptor`](crate::client::Client::with_interceptor).
+//! Request hooks can add or rewrite headers (authentication, signatures);
+//! response hooks can record what happened or ask for the request to be
+//! sent again. Interim (1xx) responses, such as `103 Early Hints`, are
+//! seen as they arrive, before the final response.
+
+use crate::client::{Informational, Response};
+use http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use http::{Request, StatusCode};
+use std::time::Duration;
+use tracing::debug;
+
+/// What to do after a response has been seen by the interceptors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Hand the response to the caller.
+    Done,
+    /// Send the request again after the delay.
+    Retry(Duration),
+}
+
+/// Hooks run around each buffered request a client sends.
+pub trait Interceptor: Send + Sync {
+    /// Called before `req` is sent. An error fails the request.
+    fn request(&self, _req: &mut Request<()>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called with each interim response to `req`, before the final one.
+    fn informational(&self, _req: &Request<()>, _response: &Informational) {}
+
+    /// Called with the response to `req`. `attempt` counts from 0 and goes
+    /// up each time the request is retried.
+    fn response(&self, _req: &Request<()>, _response: &Response, _attempt: u32) -> Outcome {
+        Outcome::Done
+    }
+}
+
+/// Sets a header on every request, replacing any value it already has.
+pub struct SetHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl SetHeader {
+    pub fn new(name: HeaderName, value: HeaderValue) -> Self {
+        Self { name, value }
+    }
+}
+
+impl Interceptor for SetHeader {
+    fn request(&self, req: &mut Request<()>) -> anyhow::Result<()> {
+        req.headers_mut().insert(self.name.clone(), self.value.clone());
+        Ok(())
+    }
+}
+
+/// Retries requests answered with 503 Service Unavailable, waiting as
+/// long as `Retry-After` asks (in seconds, up to `max_delay`) or doubling
+/// `backoff` each attempt.
+#[derive(Debug, Clone)]
+pub struct RetryUnavailable {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryUnavailable {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Interceptor for RetryUnavailable {
+    fn response(&self, req: &Request<()>, response: &Response, attempt: u32) -> Outcome {
+        if response.status != StatusCode::SERVICE_UNAVAILABLE || attempt >= self.max_retries {
+            return Outcome::Done;
+        }
+        let delay = response
+            .headers
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok()?.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| self.backoff.saturating_mul(1 << attempt.min(16)))
+            .min(self.max_delay);
+        debug!("{} {} unavailable; retrying in {:?}", req.method(), req.uri(), delay);
+        Outcome::Retry(delay)
+    }
+}
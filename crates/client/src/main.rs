@@ -1,20 +1,102 @@
 //! HTTP/3 Client
 //!
 //! Demonstrates:
-//! - REST-style requests (request/response)
+//! - REST-style requests (request/response), sent concurrently, keeping
+//!   cookies and following redirects
 //! - Streaming requests (receiving multiple chunks)
+//! - JSON-RPC 2.0 calls over a streaming request
 //! - Graceful connection shutdown
+//!
+//! Pass `--timings` to print a waterfall of connection setup and request
+//! timings at the end, `--har <file>` to save the requests as an HTTP
+//! Archive (see [`har`]), or `--probe [URL]` to only check that the server
+//! answers a GET (see [`probe`]). `client download <url> -o <file>
+//! [--resume]` saves a URL to a file instead (see [`download`]), and
+//! `client post <url> --data-file <file>` uploads one (see [`upload`]).
+
+mod client;
+mod cookies;
+mod download;
+mod encoding;
+mod har;
+mod interceptors;
+mod probe;
+mod rpc;
+mod timings;
+mod upload;
 
 use bytes::Buf;
-use common::{tls::insecure_verifier, ClientConfig};
+use common::{tls::insecure_verifier, ClientConfig, CloseCode};
 use http::{Request, Uri};
 use quinn::Endpoint;
+use client::{Client, Informational, RedirectPolicy};
+use cookies::CookieJar;
+use har::{Har, RequestInfo, ResponseInfo};
+use interceptors::{RetryUnavailable, SetHeader};
+use rpc::RpcClient;
 use rustls::ClientConfig as TlsClientConfig;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
+use timings::{RequestTimer, Timings};
 use tracing::{info, warn};
 
+/// Details of an established connection.
+#[derive(Debug)]
+struct ConnectionInfo {
+    remote_addr: SocketAddr,
+    /// Negotiated ALPN protocol.
+    alpn: Option<String>,
+    /// Negotiated TLS key exchange group.
+    key_exchange: Option<String>,
+}
+
+impl ConnectionInfo {
+    fn new(conn: &quinn::Connection) -> Self {
+        let alpn = conn
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .and_then(|data| data.protocol)
+            .map(|p| String::from_utf8_lossy(&p).into_owned());
+
+        Self {
+            remote_addr: conn.remote_address(),
+            alpn,
+            key_exchange: common::tls::last_negotiated_group().map(|g| format!("{:?}", g)),
+        }
+    }
+}
+
+/// A subcommand given on the command line.
+enum Command {
+    Download(download::Options),
+    Post(upload::Options),
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let mut timings = Timings::start();
+    let args: Vec<String> = std::env::args().collect();
+    let show_timings = args.iter().any(|arg| arg == "--timings");
+    let har_path = args
+        .iter()
+        .position(|arg| arg == "--har")
+        .map(|i| args.get(i + 1).ok_or_else(|| anyhow::anyhow!("--har needs a file name")))
+        .transpose()?;
+    let har = har_path.map(|_| Har::new());
+    let probe_url = args.iter().position(|arg| arg == "--probe").map(|i| {
+        args.get(i + 1)
+            .filter(|url| !url.starts_with("--"))
+            .map_or(probe::DEFAULT_URL, |url| url.as_str())
+    });
+    // Subcommands run instead of the demo
+    let command = match args.get(1).map(String::as_str) {
+        Some("download") => Some(Command::Download(download::Options::parse(&args[2..])?)),
+        Some("post") => Some(Command::Post(upload::Options::parse(&args[2..])?)),
+        _ => None,
+    };
+
     // Initialize logging
     tracing_subscriber::fmt()
         .with_target(false)
@@ -27,10 +109,18 @@ async fn main() -> anyhow::Result<()> {
         .unwrap();
 
     // Configure the client
-    let config = ClientConfig::default();
+    let mut config = ClientConfig::default();
+
+    // Write TLS secrets for Wireshark; never enable this in production
+    if std::env::var_os("SSLKEYLOGFILE").is_some() {
+        warn!("SSLKEYLOGFILE is set: logging TLS secrets");
+        config = config.with_key_log(true);
+    }
 
     // Create client TLS config
-    let mut tls_config = TlsClientConfig::builder()
+    let provider = common::tls::crypto_provider(config.post_quantum_kx);
+    let mut tls_config = TlsClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
         .dangerous()
         .with_custom_certificate_verifier(insecure_verifier())
         .with_no_client_auth();
@@ -40,6 +130,9 @@ async fn main() -> anyhow::Result<()> {
     }
 
     tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    if config.key_log {
+        tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
 
     let client_config = quinn::ClientConfig::new(Arc::new(
         quinn::crypto::rustls::QuicClientConfig::try_from(tls_config)?,
@@ -48,15 +141,45 @@ async fn main() -> anyhow::Result<()> {
     let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
     endpoint.set_default_client_config(client_config);
 
-    info!("Connecting to {}...", config.server_addr);
+    if let Some(url) = probe_url {
+        probe::run(&endpoint, url).await?;
+        endpoint.wait_idle().await;
+        return Ok(());
+    }
+    if let Some(command) = command {
+        match command {
+            Command::Download(options) => download::run(&endpoint, &options).await?,
+            Command::Post(options) => upload::run(&endpoint, &options).await?,
+        }
+        endpoint.wait_idle().await;
+        return Ok(());
+    }
 
-    let conn = endpoint
-        .connect(config.server_addr, &config.server_name)?
-        .await?;
+    let dns_start = Instant::now();
+    let server_addr = resolve(&config).await;
+    timings.phase("dns", dns_start);
+
+    info!("Connecting to {}...", server_addr);
+
+    let connect_start = Instant::now();
+    let mut connecting = endpoint.connect(server_addr, &config.server_name)?;
+    // Resolves once the server's handshake data (ServerHello, ALPN) arrives
+    connecting.handshake_data().await?;
+    timings.phase("connect", connect_start);
 
-    info!("Connected!\n");
+    let tls_start = Instant::now();
+    let conn = connecting.await?;
+    timings.phase("tls", tls_start);
 
-    let quinn_conn = h3_quinn::Connection::new(conn);
+    let conn_info = ConnectionInfo::new(&conn);
+    info!("Connected to {}", conn_info.remote_addr);
+    info!("  ALPN: {}", conn_info.alpn.as_deref().unwrap_or("none"));
+    info!(
+        "  Key exchange: {}\n",
+        conn_info.key_exchange.as_deref().unwrap_or("unknown")
+    );
+
+    let quinn_conn = h3_quinn::Connection::new(conn.clone());
     let (mut driver, mut send_request) = h3::client::new(quinn_conn).await?;
 
     // Spawn connection driver
@@ -69,29 +192,49 @@ async fn main() -> anyhow::Result<()> {
     // =========================================================================
     info!("=== REST Requests ===\n");
 
-    let rest_paths = vec!["/", "/health", "/api/info", "/not-found"];
-
-    for path in rest_paths {
-        let uri: Uri = format!(
-            "https://{}:{}{}",
-            config.server_name,
-            config.server_addr.port(),
-            path
-        )
-        .parse()?;
-
-        let req = Request::builder().method("GET").uri(uri).body(())?;
-
+    let mut client = Client::new(
+        send_request.clone(),
+        &config.server_name,
+        config.server_addr.port(),
+    )
+    .with_cookies(CookieJar::new())
+    .with_redirects(RedirectPolicy::default())
+    .with_interceptor(SetHeader::new(
+        http::header::USER_AGENT,
+        http::HeaderValue::from_static(concat!("simple-http3/", env!("CARGO_PKG_VERSION"))),
+    ))
+    .with_interceptor(RetryUnavailable::default())
+    .with_endpoint(endpoint.clone());
+    if let Some(har) = &har {
+        client = client.with_har(har.clone());
+    }
+    let rest_paths = ["/", "/health", "/api/info", "/api/connection", "/not-found"];
+    let requests = rest_paths
+        .iter()
+        .map(|path| client.get(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Sent concurrently on the one connection; results come back in order
+    let responses = client.batch(requests, client::DEFAULT_CONCURRENCY).await;
+    for (path, response) in rest_paths.iter().zip(responses) {
         info!("GET {}", path);
-        let mut stream = send_request.send_request(req).await?;
-        stream.finish().await?;
-
-        let response = stream.recv_response().await?;
-        info!("  Status: {}", response.status());
-
-        // Read response body
-        let body = read_body(&mut stream).await?;
-        info!("  Body: {}\n", body);
+        let response = response?;
+        for interim in &response.informational {
+            info!("  Interim: {} {:?}", interim.status, interim.headers);
+        }
+        info!("  Status: {}", response.status);
+        if response.goaway_retries > 0 {
+            info!("  Sent again {} times after GOAWAY", response.goaway_retries);
+        }
+        info!("  Content-Type: {:?}", response.headers.get("content-type"));
+        if !response.trailers.is_empty() {
+            info!("  Trailers: {:?}", response.trailers);
+        }
+        match response.json::<serde_json::Value>() {
+            Ok(json) => info!("  JSON: {}\n", json),
+            Err(_) => info!("  Body: {}\n", response.text()?),
+        }
+        timings.request(format!("GET {}", path), response.timer);
     }
 
     // =========================================================================
@@ -109,18 +252,25 @@ async fn main() -> anyhow::Result<()> {
     let req = Request::builder().method("GET").uri(uri).body(())?;
 
     info!("GET /stream/time (SSE stream)");
+    let request = RequestInfo::of(&req);
+    let mut timer = RequestTimer::start();
     let mut stream = send_request.send_request(req).await?;
     stream.finish().await?;
 
-    let response = stream.recv_response().await?;
+    let on_interim =
+        |interim: &Informational| info!("  Interim: {} {:?}", interim.status, interim.headers);
+    let (response, _) = client::recv_response(&mut stream, on_interim).await?;
+    timer.first_byte();
     info!("  Status: {}", response.status());
     info!("  Content-Type: {:?}", response.headers().get("content-type"));
     info!("  Receiving chunks:");
 
     // Read streaming chunks as they arrive
+    let mut received = 0;
     while let Some(mut chunk) = stream.recv_data().await? {
         while chunk.has_remaining() {
             let bytes = chunk.chunk();
+            received += bytes.len() as u64;
             let text = String::from_utf8_lossy(bytes);
             // Print each line
             for line in text.lines() {
@@ -131,6 +281,54 @@ async fn main() -> anyhow::Result<()> {
             chunk.advance(bytes.len());
         }
     }
+    if let Some(trailers) = stream.recv_trailers().await? {
+        info!("  Trailers: {:?}", trailers);
+    }
+    timer.finish();
+    if let Some(har) = &har {
+        let response = ResponseInfo::streamed(response.status(), response.headers(), received);
+        har.record(&request, &response, &timer);
+    }
+    timings.request("GET /stream/time", timer);
+    info!("");
+
+    // =========================================================================
+    // JSON-RPC
+    // =========================================================================
+    info!("=== JSON-RPC ===\n");
+
+    let uri: Uri = format!(
+        "https://{}:{}/rpc",
+        config.server_name,
+        config.server_addr.port()
+    )
+    .parse()?;
+
+    let timer = RequestTimer::start();
+    let rpc = RpcClient::open(&mut send_request, uri, har.clone()).await?;
+
+    let sum = rpc.call("add", Some(serde_json::json!([1, 2, 3]))).await?;
+    info!("  add(1, 2, 3) = {}", sum);
+
+    let results = rpc
+        .batch(vec![
+            ("echo".to_string(), Some(serde_json::json!({"hello": "h3"}))),
+            ("time".to_string(), None),
+            ("multiply".to_string(), Some(serde_json::json!([6, 7]))),
+            ("divide".to_string(), Some(serde_json::json!([1, 0]))),
+            ("missing".to_string(), None),
+        ])
+        .await?;
+    for result in results {
+        match result {
+            Ok(value) => info!("  batch result: {}", value),
+            Err(e) => info!("  batch error: {}", e),
+        }
+    }
+
+    rpc.notify("echo", Some(serde_json::json!("fire and forget"))).await?;
+    rpc.close().await?;
+    timings.request("RPC /rpc", timer);
     info!("");
 
     // =========================================================================
@@ -138,33 +336,53 @@ async fn main() -> anyhow::Result<()> {
     // =========================================================================
     info!("=== Closing Connection ===");
 
-    // Drop send_request to signal we're done sending
+    // Drop the request senders to signal we're done sending
+    drop(client);
     drop(send_request);
 
     // Wait for driver to finish (handles GOAWAY)
     let _ = driver_handle.await;
+    log_server_close(&conn);
 
     // Wait for endpoint to be fully idle
     endpoint.wait_idle().await;
 
     info!("Connection closed cleanly");
 
+    if show_timings {
+        println!("\n{}", timings.waterfall());
+    }
+    if let (Some(har), Some(path)) = (har, har_path) {
+        har.write(Path::new(path))?;
+        info!("Wrote HTTP Archive to {}", path);
+    }
+
     Ok(())
 }
 
-/// Read the entire response body into a string.
-async fn read_body<S, B>(stream: &mut h3::client::RequestStream<S, B>) -> anyhow::Result<String>
-where
-    S: h3::quic::RecvStream,
-    B: bytes::Buf,
-{
-    let mut body = Vec::new();
-    while let Some(mut chunk) = stream.recv_data().await? {
-        while chunk.has_remaining() {
-            let bytes = chunk.chunk();
-            body.extend_from_slice(bytes);
-            chunk.advance(bytes.len());
+/// Resolve the server name to an address of the same family as the
+/// configured address, falling back to the configured address.
+async fn resolve(config: &ClientConfig) -> SocketAddr {
+    let port = config.server_addr.port();
+    match tokio::net::lookup_host((config.server_name.as_str(), port)).await {
+        Ok(mut addrs) => addrs
+            .find(|addr| addr.is_ipv4() == config.server_addr.is_ipv4())
+            .unwrap_or(config.server_addr),
+        Err(e) => {
+            warn!("Failed to resolve {}: {}", config.server_name, e);
+            config.server_addr
         }
     }
-    Ok(String::from_utf8_lossy(&body).to_string())
+}
+
+/// Log why the server closed the connection, if it closed it with an
+/// application close code.
+fn log_server_close(conn: &quinn::Connection) {
+    if let Some(quinn::ConnectionError::ApplicationClosed(close)) = conn.close_reason()
+        && let Some(code) = u32::try_from(close.error_code.into_inner())
+            .ok()
+            .and_then(CloseCode::from_code)
+    {
+        warn!("Server closed the connection: {}", code);
+    }
 }
@@ -0,0 +1,42 @@
+//! Health probe: a single GET whose outcome becomes the exit code.
+//!
+//! `client --probe [URL]` fetches `URL` (default
+//! `https://localhost:4433/health`) and exits 0 on a 2xx response and 1 on
+//! anything else, including timeouts, so it can serve as a container
+//! healthcheck for the server.
+
+use crate::client::Client;
+use http::Uri;
+use quinn::Endpoint;
+use std::time::Duration;
+use tracing::info;
+
+/// URL probed when none is given.
+pub const DEFAULT_URL: &str = "https://localhost:4433/health";
+
+/// Give up on the probe after this long.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// GET `url` and fail unless the response status is 2xx.
+pub async fn run(endpoint: &Endpoint, url: &str) -> anyhow::Result<()> {
+    let uri: Uri = url.parse()?;
+    let status = tokio::time::timeout(PROBE_TIMEOUT, get(endpoint, uri))
+        .await
+        .map_err(|_| anyhow::anyhow!("probe timed out after {:?}", PROBE_TIMEOUT))??;
+
+    if !status.is_success() {
+        anyhow::bail!("probe of {} returned {}", url, status);
+    }
+    info!("Probe of {} OK: {}", url, status);
+    Ok(())
+}
+
+async fn get(endpoint: &Endpoint, uri: Uri) -> anyhow::Result<http::StatusCode> {
+    let conn = Client::connect(endpoint, &uri).await?;
+    let req = http::Request::builder().method("GET").uri(uri).body(())?;
+    let (response, stream) = conn.client.request(req).await?;
+
+    drop(stream);
+    conn.close().await;
+    Ok(response.status())
+}
@@ -0,0 +1,131 @@
+//! JSON-RPC 2.0 client proxy over an HTTP/3 request stream.
+//!
+//! The proxy keeps one request open to the server's RPC path for its whole
+//! lifetime: request lines are streamed in the request body and response
+//! lines arrive in the response body, matched to callers by id.
+
+use crate::client;
+use crate::har::{Har, RequestInfo, ResponseInfo};
+use crate::timings::RequestTimer;
+use bytes::{Buf, Bytes};
+use common::rpc::{Calls, LineBuffer, RpcError};
+use h3::client::{RequestStream, SendRequest};
+use http::{Request, Uri};
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+type SendHalf = RequestStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+/// A JSON-RPC client on a dedicated HTTP/3 request stream.
+pub struct RpcClient {
+    calls: Arc<Calls>,
+    send: Mutex<SendHalf>,
+    reader: JoinHandle<()>,
+}
+
+impl RpcClient {
+    /// Open an RPC stream to `uri`, recording it in `har` once it ends if
+    /// given.
+    pub async fn open(
+        send_request: &mut SendRequest<h3_quinn::OpenStreams, Bytes>,
+        uri: Uri,
+        har: Option<Har>,
+    ) -> anyhow::Result<Self> {
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json-seq")
+            .body(())?;
+        // The request body is streamed for the proxy's lifetime
+        let request = RequestInfo::of(&req).with_body_size(None);
+        let mut timer = RequestTimer::start();
+        let stream = send_request.send_request(req).await?;
+        let (send, mut recv) = stream.split();
+
+        let calls = Arc::new(Calls::new());
+        let reader_calls = Arc::clone(&calls);
+        let reader = tokio::spawn(async move {
+            let result: anyhow::Result<()> = async {
+                let (response, _) = client::recv_response(&mut recv, |_| {}).await?;
+                timer.first_byte();
+                anyhow::ensure!(
+                    response.status().is_success(),
+                    "RPC endpoint returned {}",
+                    response.status()
+                );
+
+                let mut buffer = LineBuffer::default();
+                let mut received = 0;
+                while let Some(mut chunk) = recv.recv_data().await? {
+                    let data = chunk.copy_to_bytes(chunk.remaining());
+                    received += data.len() as u64;
+                    for line in buffer.push(&data) {
+                        reader_calls.resolve(&line);
+                    }
+                }
+
+                timer.finish();
+                if let Some(har) = har {
+                    let response =
+                        ResponseInfo::streamed(response.status(), response.headers(), received);
+                    har.record(&request, &response, &timer);
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                debug!("RPC stream ended: {:?}", e);
+            }
+            reader_calls.fail_all();
+        });
+
+        Ok(Self {
+            calls,
+            send: Mutex::new(send),
+            reader,
+        })
+    }
+
+    /// Call a method and wait for its result.
+    pub async fn call(&self, method: &str, params: Option<Value>) -> anyhow::Result<Value> {
+        let (line, pending) = self.calls.request(method, params);
+        self.write(line).await?;
+        Ok(pending
+            .await
+            .unwrap_or_else(|_| Err(RpcError::disconnected()))?)
+    }
+
+    /// Send a notification.
+    pub async fn notify(&self, method: &str, params: Option<Value>) -> anyhow::Result<()> {
+        self.write(self.calls.notification(method, params)).await
+    }
+
+    /// Send several calls as one batch. Results are returned in call order.
+    pub async fn batch(
+        &self,
+        calls: Vec<(String, Option<Value>)>,
+    ) -> anyhow::Result<Vec<Result<Value, RpcError>>> {
+        let (line, pending) = self.calls.batch(calls);
+        self.write(line).await?;
+        let results = futures::future::join_all(pending).await;
+        Ok(results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|_| Err(RpcError::disconnected())))
+            .collect())
+    }
+
+    /// Finish the request body and wait for outstanding responses.
+    pub async fn close(self) -> anyhow::Result<()> {
+        self.send.into_inner().finish().await?;
+        let _ = self.reader.await;
+        Ok(())
+    }
+
+    async fn write(&self, line: String) -> anyhow::Result<()> {
+        self.send.lock().await.send_data(Bytes::from(line)).await?;
+        Ok(())
+    }
+}
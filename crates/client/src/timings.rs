@@ -0,0 +1,173 @@
+//! Request timing capture and waterfall rendering.
+//!
+//! Every phase is recorded as an offset from the moment the client started,
+//! so connection setup and individual requests can be drawn on one shared
+//! timeline (printed with `--timings`).
+//!
+//! QUIC performs the transport and TLS handshakes together. The "connect"
+//! phase ends when the server's handshake data (ServerHello, ALPN) arrives
+//! and the "tls" phase covers the rest of the handshake.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Width of the waterfall bars in characters.
+const BAR_WIDTH: usize = 40;
+
+/// A phase on the timeline.
+#[derive(Debug, Clone)]
+struct Span {
+    label: String,
+    start: Duration,
+    end: Duration,
+    /// When the first response byte arrived, for requests.
+    first_byte: Option<Duration>,
+}
+
+/// Timings collected over the lifetime of a client.
+#[derive(Debug)]
+pub struct Timings {
+    origin: Instant,
+    spans: Vec<Span>,
+}
+
+impl Timings {
+    /// Start a timeline at the current instant.
+    pub fn start() -> Self {
+        Self {
+            origin: Instant::now(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// Record a phase that started at `start` and has just finished.
+    pub fn phase(&mut self, label: impl Into<String>, start: Instant) {
+        let end = Instant::now();
+        self.spans.push(Span {
+            label: label.into(),
+            start: start - self.origin,
+            end: end - self.origin,
+            first_byte: None,
+        });
+    }
+
+    /// Record a completed request, ending now unless it was marked
+    /// finished earlier.
+    pub fn request(&mut self, label: impl Into<String>, timer: RequestTimer) {
+        let end = timer.finished.unwrap_or_else(Instant::now);
+        self.spans.push(Span {
+            label: label.into(),
+            start: timer.sent - self.origin,
+            end: end - self.origin,
+            first_byte: timer.first_byte.map(|t| t - self.origin),
+        });
+    }
+
+    /// Render the timeline as a text waterfall.
+    ///
+    /// Each row shows the phase start and duration; for requests the bar is
+    /// split into waiting (`░`) up to the first byte and receiving (`█`).
+    pub fn waterfall(&self) -> String {
+        let total = self
+            .spans
+            .iter()
+            .map(|s| s.end)
+            .max()
+            .unwrap_or_default()
+            .max(Duration::from_micros(1));
+        let label_width = self
+            .spans
+            .iter()
+            .map(|s| s.label.len())
+            .max()
+            .unwrap_or(0)
+            .max("phase".len());
+        let column = |d: Duration| {
+            ((d.as_secs_f64() / total.as_secs_f64()) * BAR_WIDTH as f64).round() as usize
+        };
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<label_width$}  {:>9}  {:>9}  {:>9}",
+            "phase", "start", "ttfb", "duration"
+        );
+        for span in &self.spans {
+            let start = column(span.start);
+            let end = column(span.end).max(start + 1);
+            let first_byte = span
+                .first_byte
+                .map_or(start, |t| column(t).clamp(start, end));
+
+            let bar = format!(
+                "{}{}{}",
+                " ".repeat(start),
+                "░".repeat(first_byte - start),
+                "█".repeat(end - first_byte)
+            );
+            let ttfb = span
+                .first_byte
+                .map_or_else(|| "-".to_string(), |t| format_ms(t - span.start));
+
+            let _ = writeln!(
+                out,
+                "{:<label_width$}  {:>9}  {:>9}  {:>9}  |{:<BAR_WIDTH$}|",
+                span.label,
+                format_ms(span.start),
+                ttfb,
+                format_ms(span.end - span.start),
+                bar
+            );
+        }
+        out
+    }
+}
+
+/// Timing of a single in-flight request.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestTimer {
+    sent: Instant,
+    first_byte: Option<Instant>,
+    finished: Option<Instant>,
+}
+
+impl RequestTimer {
+    /// Start timing a request that is about to be sent.
+    pub fn start() -> Self {
+        Self {
+            sent: Instant::now(),
+            first_byte: None,
+            finished: None,
+        }
+    }
+
+    /// Mark the arrival of the first response byte (the response headers).
+    pub fn first_byte(&mut self) {
+        self.first_byte.get_or_insert_with(Instant::now);
+    }
+
+    /// When the request was sent.
+    pub fn sent(&self) -> Instant {
+        self.sent
+    }
+
+    /// Time from sending the request to the response headers.
+    pub fn time_to_first_byte(&self) -> Option<Duration> {
+        self.first_byte.map(|t| t - self.sent)
+    }
+
+    /// Time from sending the request to its end, or to now if it has not
+    /// been marked finished.
+    pub fn elapsed(&self) -> Duration {
+        self.finished.unwrap_or_else(Instant::now) - self.sent
+    }
+
+    /// Mark the end of the response, for requests recorded later.
+    pub fn finish(&mut self) {
+        self.finished.get_or_insert_with(Instant::now);
+    }
+}
+
+fn format_ms(d: Duration) -> String {
+    format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+}
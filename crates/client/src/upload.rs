@@ -0,0 +1,241 @@
+//! Uploads from a file or stdin, with a progress bar.
+//!
+//! `client post <url> --data-file <file>` (`-` for stdin) streams the file
+//! as the request body in chunks. The next chunk is only read once the QUIC
+//! stream has accepted the previous one, so the upload goes at the pace
+//! flow control allows and never holds more than a chunk in memory.
+//! Progress is drawn on stderr when it is a terminal.
+//!
+//! The request asks for `100 Continue` first, so a server that refuses the
+//! upload (an API key missing, the file too large) says so before any of
+//! the body is sent. Servers that do not answer within
+//! [`CONTINUE_TIMEOUT`] are sent the body anyway.
+
+use crate::client::{self, Client};
+use bytes::{Bytes, BytesMut};
+use h3::client::RequestStream;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, EXPECT};
+use http::{Request, StatusCode, Uri};
+use quinn::Endpoint;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::{debug, info};
+
+/// Bytes read and sent at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How often the progress bar is redrawn.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of the progress bar in characters.
+const BAR_WIDTH: usize = 30;
+
+/// How long to wait for `100 Continue` before sending the body anyway.
+pub const CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Arguments of the `post` subcommand.
+#[derive(Debug)]
+pub struct Options {
+    pub url: String,
+    /// File to send, or `-` for stdin.
+    pub data_file: PathBuf,
+    pub content_type: String,
+}
+
+impl Options {
+    /// Parse the arguments following `post`.
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let usage = "usage: client post <url> --data-file <file|-> [--content-type <type>]";
+        let mut url = None;
+        let mut data_file = None;
+        let mut content_type = "application/octet-stream".to_string();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--data-file" => data_file = args.next().map(PathBuf::from),
+                "--content-type" => {
+                    content_type = args.next().ok_or_else(|| anyhow::anyhow!(usage))?.clone()
+                }
+                arg if !arg.starts_with('-') && url.is_none() => url = Some(arg.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            url: url.ok_or_else(|| anyhow::anyhow!(usage))?,
+            data_file: data_file.ok_or_else(|| anyhow::anyhow!(usage))?,
+            content_type,
+        })
+    }
+}
+
+/// Upload `options.data_file` on a new connection.
+pub async fn run(endpoint: &Endpoint, options: &Options) -> anyhow::Result<()> {
+    let uri: Uri = options.url.parse()?;
+    let conn = Client::connect(endpoint, &uri).await?;
+    let result = upload(&conn.client, uri, options).await;
+    conn.close().await;
+    result
+}
+
+async fn upload(client: &Client, uri: Uri, options: &Options) -> anyhow::Result<()> {
+    let (mut reader, total): (Box<dyn AsyncRead + Unpin + Send>, _) =
+        if options.data_file.as_os_str() == "-" {
+            (Box::new(tokio::io::stdin()), None)
+        } else {
+            let file = tokio::fs::File::open(&options.data_file)
+                .await
+                .map_err(|e| anyhow::anyhow!("opening {}: {}", options.data_file.display(), e))?;
+            let len = file.metadata().await?.len();
+            (Box::new(file), Some(len))
+        };
+
+    let mut req = Request::builder()
+        .method("POST")
+        .uri(uri.clone())
+        .header(CONTENT_TYPE, &options.content_type)
+        .header(EXPECT, "100-continue");
+    if let Some(total) = total {
+        req = req.header(CONTENT_LENGTH, total);
+    }
+    let (mut stream, mut recv) = client.open(req.body(())?).await?.split();
+
+    if let Some(response) = wait_for_continue(&mut recv).await? {
+        let body = client::read_to_end(&mut recv).await?;
+        info!("POST {}: {}", uri, response.status());
+        info!("  Body: {}", String::from_utf8_lossy(&body));
+        anyhow::bail!("upload to {} refused: {}", uri, response.status());
+    }
+
+    let mut progress = Progress::new(total);
+    let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+    loop {
+        buf.reserve(CHUNK_SIZE);
+        let n = reader.read_buf(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        // Resolves once the stream has room for the chunk
+        stream.send_data(buf.split().freeze()).await?;
+        progress.advance(n as u64);
+    }
+    stream.finish().await?;
+    progress.finish();
+
+    let (response, _) = client::recv_response(&mut recv, |_| {}).await?;
+    let body = client::read_to_end(&mut recv).await?;
+    info!("POST {}: {}", uri, response.status());
+    info!("  Body: {}", String::from_utf8_lossy(&body));
+    if !response.status().is_success() {
+        anyhow::bail!("upload to {} failed: {}", uri, response.status());
+    }
+    Ok(())
+}
+
+/// Wait for the server to accept the body. Returns its final response if
+/// it refuses it instead, and `None` once it sends `100 Continue` or after
+/// [`CONTINUE_TIMEOUT`] without an answer.
+async fn wait_for_continue(
+    recv: &mut RequestStream<h3_quinn::RecvStream, Bytes>,
+) -> anyhow::Result<Option<http::Response<()>>> {
+    let wait = async {
+        loop {
+            let response = recv.recv_response().await?;
+            match response.status() {
+                StatusCode::CONTINUE => return Ok(None),
+                status if status.is_informational() => continue,
+                _ => return Ok(Some(response)),
+            }
+        }
+    };
+    match tokio::time::timeout(CONTINUE_TIMEOUT, wait).await {
+        Ok(result) => result,
+        Err(_) => {
+            debug!("No 100 Continue after {:?}; sending the body", CONTINUE_TIMEOUT);
+            Ok(None)
+        }
+    }
+}
+
+/// A progress bar on stderr; does nothing unless stderr is a terminal.
+struct Progress {
+    total: Option<u64>,
+    sent: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(total: Option<u64>) -> Self {
+        Self {
+            total,
+            sent: 0,
+            started: Instant::now(),
+            drawn: None,
+            enabled: std::io::stderr().is_terminal(),
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.sent += bytes;
+        if self.drawn.is_none_or(|drawn| drawn.elapsed() >= PROGRESS_INTERVAL) {
+            self.draw();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.draw();
+        if self.enabled {
+            eprintln!();
+        }
+    }
+
+    fn draw(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.drawn = Some(Instant::now());
+
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = format_bytes((self.sent as f64 / elapsed) as u64);
+        let line = match self.total {
+            Some(total) => {
+                let fraction = if total == 0 { 1.0 } else { self.sent as f64 / total as f64 };
+                let filled = ((fraction * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+                format!(
+                    "[{}{}] {:>3}% {} / {}  {}/s",
+                    "=".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled),
+                    (fraction * 100.0) as u32,
+                    format_bytes(self.sent),
+                    format_bytes(total),
+                    rate
+                )
+            }
+            None => format!("{} sent  {}/s", format_bytes(self.sent), rate),
+        };
+
+        // Overwrite the previous line
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
@@ -0,0 +1,80 @@
+//! Application close codes shared by the server and clients.
+//!
+//! The server closes the QUIC connection carrying a WebTransport session
+//! with one of these codes, and clients close with them too, so either side
+//! can tell why a session ended. Codes are `u32` to fit the WebTransport
+//! `closeCode`.
+
+use std::fmt;
+
+/// Why a session or connection was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseCode {
+    /// The session finished normally.
+    NormalClosure,
+    /// The peer is shutting down or rotating certificates; reconnect.
+    GoingAway,
+    /// The peer sent something it should not have.
+    ProtocolError,
+    /// The peer could not be authenticated.
+    AuthFailed,
+    /// The peer exceeded a rate limit or quota.
+    RateLimited,
+    /// The peer failed unexpectedly.
+    InternalError,
+    /// An admin removed the session.
+    Kicked,
+    /// An admin removed the session and banned its address for a while.
+    Banned,
+}
+
+impl CloseCode {
+    /// The numeric code sent on the wire.
+    pub fn code(self) -> u32 {
+        match self {
+            CloseCode::NormalClosure => 0,
+            CloseCode::GoingAway => 1,
+            CloseCode::ProtocolError => 2,
+            CloseCode::AuthFailed => 3,
+            CloseCode::RateLimited => 4,
+            CloseCode::InternalError => 5,
+            CloseCode::Kicked => 6,
+            CloseCode::Banned => 7,
+        }
+    }
+
+    /// Look up a code received from the peer.
+    pub fn from_code(code: u32) -> Option<Self> {
+        match code {
+            0 => Some(CloseCode::NormalClosure),
+            1 => Some(CloseCode::GoingAway),
+            2 => Some(CloseCode::ProtocolError),
+            3 => Some(CloseCode::AuthFailed),
+            4 => Some(CloseCode::RateLimited),
+            5 => Some(CloseCode::InternalError),
+            6 => Some(CloseCode::Kicked),
+            7 => Some(CloseCode::Banned),
+            _ => None,
+        }
+    }
+
+    /// A short default reason to send along with the code.
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseCode::NormalClosure => "normal closure",
+            CloseCode::GoingAway => "going away",
+            CloseCode::ProtocolError => "protocol error",
+            CloseCode::AuthFailed => "authentication failed",
+            CloseCode::RateLimited => "rate limited",
+            CloseCode::InternalError => "internal error",
+            CloseCode::Kicked => "kicked",
+            CloseCode::Banned => "banned",
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.reason(), self.code())
+    }
+}
@@ -1,6 +1,7 @@
 //! Configuration types for server and client.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 /// Server configuration options.
 #[derive(Debug, Clone)]
@@ -11,6 +12,93 @@ pub struct ServerConfig {
     pub cert_hostnames: Vec<String>,
     /// Idle timeout in seconds.
     pub idle_timeout_secs: u64,
+    /// How long connections, requests and WebTransport sessions get to end
+    /// on their own when the server shuts down, in seconds.
+    pub drain_timeout_secs: u64,
+    /// Directory to record WebTransport session traffic into, if any.
+    pub recording_dir: Option<PathBuf>,
+    /// Directory to write a diagnostics bundle into when the server
+    /// panics, if any.
+    pub diagnostics_dir: Option<PathBuf>,
+    /// How long before the WebTransport certificate expires to rotate to
+    /// the next one, in seconds.
+    pub cert_rotation_margin_secs: u64,
+    /// Directory to keep the WebTransport certificates in, so restarts
+    /// serve the same certificate (and hash) until it is rotated out.
+    pub cert_cache_dir: Option<PathBuf>,
+    /// Prefer the X25519MLKEM768 post-quantum hybrid key exchange.
+    pub post_quantum_kx: bool,
+    /// PEM file of the CA certificates client certificates must chain to;
+    /// enables mutual TLS.
+    pub client_ca: Option<PathBuf>,
+    /// Also accept clients without a certificate when mutual TLS is on.
+    pub client_cert_optional: bool,
+    /// Write TLS secrets to the file named by `SSLKEYLOGFILE`, so captured
+    /// traffic can be decrypted in Wireshark. Development only.
+    pub key_log: bool,
+    /// Answer every connection attempt with a stateless retry, so clients
+    /// must prove they own their address before any TLS work is done.
+    pub require_retry: bool,
+    /// How long a retry token stays valid, in seconds.
+    pub retry_token_lifetime_secs: u64,
+    /// How long address validation tokens (sent in NEW_TOKEN frames, which
+    /// let returning clients skip the retry) stay valid, in seconds.
+    pub validation_token_lifetime_secs: u64,
+    /// Number of address validation tokens sent to each client; 0 disables
+    /// them.
+    pub validation_tokens_sent: u32,
+    /// Connection attempts allowed per second from one source prefix (/24
+    /// for IPv4, /56 for IPv6); unlimited if unset.
+    pub handshake_rate_limit: Option<u32>,
+    /// How long a prefix that exceeds the rate limit is ignored, in seconds.
+    pub handshake_ban_secs: u64,
+    /// Connections and requests handled at once; further connections are
+    /// refused and requests answered with 503. Unlimited if unset.
+    pub max_tasks: Option<usize>,
+    /// Connections handled at once, counted towards `max_tasks` as well;
+    /// further connections are refused. Unlimited if unset.
+    pub max_connections: Option<usize>,
+    /// Requests one connection may have running at once; the connection
+    /// takes no further requests until one ends, which leaves the client
+    /// waiting on QUIC stream limits. Unlimited if unset.
+    pub max_connection_requests: Option<usize>,
+    /// Requests allowed per second from each client, across every route;
+    /// unlimited if unset. See also `Router::rate_limit`.
+    pub rate_limit_per_sec: Option<u32>,
+    /// Requests a client may send in a burst above `rate_limit_per_sec`.
+    pub rate_limit_burst: u32,
+    /// Header telling clients apart for the rate limit, trusted on requests
+    /// from `rate_limit_proxies`; clients are told apart by their address
+    /// otherwise.
+    pub rate_limit_header: Option<String>,
+    /// Addresses of the proxies that set `rate_limit_header`.
+    pub rate_limit_proxies: Vec<IpAddr>,
+    /// Run every REST handler on its connection's task instead of spawning
+    /// a task per request; see also `Router::route_inline`.
+    pub inline_rest: bool,
+    /// How long a WebTransport stream read or write may make no progress
+    /// before the stream is reset; 0 disables the timeout.
+    pub stream_timeout_secs: u64,
+    /// Client-initiated bidi streams a WebTransport session may have open
+    /// at once; further ones are refused.
+    pub max_session_bidi_streams: usize,
+    /// Client-initiated uni streams a WebTransport session may have open
+    /// at once.
+    pub max_session_uni_streams: usize,
+    /// Threads running tasks; one per core if unset.
+    pub worker_threads: Option<usize>,
+    /// Threads for blocking work such as file IO; Tokio's default (512)
+    /// if unset.
+    pub max_blocking_threads: Option<usize>,
+    /// Receive packets and accept connections on a runtime of their own,
+    /// so handlers keeping the workers busy cannot delay them.
+    pub accept_runtime: bool,
+    /// Requests taking at least this long, in milliseconds, are logged with
+    /// a warning and counted; none are if unset.
+    pub slow_request_ms: Option<u64>,
+    /// Responses with a body of at least this many bytes are logged with a
+    /// warning and counted; none are if unset.
+    pub large_response_bytes: Option<u64>,
 }
 
 impl Default for ServerConfig {
@@ -19,6 +107,37 @@ impl Default for ServerConfig {
             bind_addr: "127.0.0.1:4433".parse().unwrap(),
             cert_hostnames: vec!["localhost".to_string()],
             idle_timeout_secs: 30,
+            drain_timeout_secs: 10,
+            recording_dir: None,
+            diagnostics_dir: None,
+            cert_rotation_margin_secs: 24 * 60 * 60,
+            cert_cache_dir: None,
+            post_quantum_kx: true,
+            client_ca: None,
+            client_cert_optional: false,
+            key_log: false,
+            require_retry: false,
+            retry_token_lifetime_secs: 15,
+            validation_token_lifetime_secs: 14 * 24 * 60 * 60,
+            validation_tokens_sent: 2,
+            handshake_rate_limit: None,
+            handshake_ban_secs: 60,
+            max_tasks: None,
+            max_connections: None,
+            max_connection_requests: None,
+            rate_limit_per_sec: None,
+            rate_limit_burst: 1,
+            rate_limit_header: None,
+            rate_limit_proxies: Vec::new(),
+            inline_rest: false,
+            stream_timeout_secs: 30,
+            max_session_bidi_streams: 50,
+            max_session_uni_streams: 50,
+            worker_threads: None,
+            max_blocking_threads: None,
+            accept_runtime: false,
+            slow_request_ms: None,
+            large_response_bytes: None,
         }
     }
 }
@@ -40,6 +159,179 @@ impl ServerConfig {
         self.idle_timeout_secs = secs;
         self
     }
+
+    /// Rotate the WebTransport certificate `secs` before it expires; the
+    /// the server refuses to start unless it is under 14 days less an hour.
+    pub fn with_cert_rotation_margin(mut self, secs: u64) -> Self {
+        self.cert_rotation_margin_secs = secs;
+        self
+    }
+
+    /// Keep the WebTransport certificates and their keys in `dir`, and
+    /// reuse them on restart.
+    pub fn with_cert_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cert_cache_dir = Some(dir.into());
+        self
+    }
+
+    /// Enable or disable the post-quantum hybrid key exchange.
+    pub fn with_post_quantum_kx(mut self, enabled: bool) -> Self {
+        self.post_quantum_kx = enabled;
+        self
+    }
+
+    /// Require clients to present a certificate issued by a CA in the PEM
+    /// file at `path`.
+    pub fn with_client_ca(mut self, path: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(path.into());
+        self
+    }
+
+    /// Let clients without a certificate connect too when a client CA is
+    /// set; handlers tell them apart.
+    pub fn with_client_cert_optional(mut self, optional: bool) -> Self {
+        self.client_cert_optional = optional;
+        self
+    }
+
+    /// Log TLS secrets to `SSLKEYLOGFILE` for decrypting captures.
+    pub fn with_key_log(mut self, enabled: bool) -> Self {
+        self.key_log = enabled;
+        self
+    }
+
+    /// Require address validation with a stateless retry.
+    pub fn with_retry(mut self, enabled: bool) -> Self {
+        self.require_retry = enabled;
+        self
+    }
+
+    /// Accept retry tokens for `secs` after they were issued.
+    pub fn with_retry_token_lifetime(mut self, secs: u64) -> Self {
+        self.retry_token_lifetime_secs = secs;
+        self
+    }
+
+    /// Send `sent` address validation tokens to each client, valid for
+    /// `lifetime_secs`.
+    pub fn with_validation_tokens(mut self, lifetime_secs: u64, sent: u32) -> Self {
+        self.validation_token_lifetime_secs = lifetime_secs;
+        self.validation_tokens_sent = sent;
+        self
+    }
+
+    /// Allow `per_sec` connection attempts per second from each source
+    /// prefix, ignoring prefixes that exceed it for `ban_secs`.
+    pub fn with_handshake_rate_limit(mut self, per_sec: u32, ban_secs: u64) -> Self {
+        self.handshake_rate_limit = Some(per_sec);
+        self.handshake_ban_secs = ban_secs;
+        self
+    }
+
+    /// Handle at most `max` connections and requests at once.
+    pub fn with_max_tasks(mut self, max: usize) -> Self {
+        self.max_tasks = Some(max);
+        self
+    }
+
+    /// Handle at most `max` connections at once.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Run at most `max` requests at once on each connection.
+    pub fn with_max_connection_requests(mut self, max: usize) -> Self {
+        self.max_connection_requests = Some(max);
+        self
+    }
+
+    /// Allow each client `per_sec` requests a second, in bursts of up to
+    /// `burst`; further ones are answered with 429.
+    pub fn with_rate_limit(mut self, per_sec: u32, burst: u32) -> Self {
+        self.rate_limit_per_sec = Some(per_sec);
+        self.rate_limit_burst = burst;
+        self
+    }
+
+    /// Tell clients apart for the rate limit by `header` on requests from
+    /// `proxies`, the proxies in front of the server that set it. Clients
+    /// sending the header themselves are still told apart by address.
+    pub fn with_rate_limit_header(mut self, header: impl Into<String>, proxies: &[IpAddr]) -> Self {
+        self.rate_limit_header = Some(header.into());
+        self.rate_limit_proxies = proxies.to_vec();
+        self
+    }
+
+    /// Handle REST requests inline on the connection's task. Saves a spawn
+    /// per request, but a connection waits for each REST handler before
+    /// taking its next request.
+    pub fn with_inline_rest(mut self, enabled: bool) -> Self {
+        self.inline_rest = enabled;
+        self
+    }
+
+    /// Give connections `secs` to drain when shutting down.
+    pub fn with_drain_timeout(mut self, secs: u64) -> Self {
+        self.drain_timeout_secs = secs;
+        self
+    }
+
+    /// Reset WebTransport streams stalled for `secs` (0 to never).
+    pub fn with_stream_timeout(mut self, secs: u64) -> Self {
+        self.stream_timeout_secs = secs;
+        self
+    }
+
+    /// Let each WebTransport session have at most `bidi` bidi and `uni`
+    /// uni streams of its own open at once.
+    pub fn with_session_stream_limits(mut self, bidi: usize, uni: usize) -> Self {
+        self.max_session_bidi_streams = bidi;
+        self.max_session_uni_streams = uni;
+        self
+    }
+
+    /// Record every WebTransport session into a file under `dir`.
+    pub fn with_recording_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.recording_dir = Some(dir.into());
+        self
+    }
+
+    /// Write a diagnostics bundle into `dir` when the server panics.
+    pub fn with_diagnostics_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.diagnostics_dir = Some(dir.into());
+        self
+    }
+
+    /// Run tasks on `threads` worker threads.
+    pub fn with_worker_threads(mut self, threads: usize) -> Self {
+        self.worker_threads = Some(threads);
+        self
+    }
+
+    /// Run blocking work on at most `threads` threads.
+    pub fn with_max_blocking_threads(mut self, threads: usize) -> Self {
+        self.max_blocking_threads = Some(threads);
+        self
+    }
+
+    /// Receive packets and accept connections on a dedicated thread.
+    pub fn with_accept_runtime(mut self, enabled: bool) -> Self {
+        self.accept_runtime = enabled;
+        self
+    }
+
+    /// Warn about requests taking `ms` milliseconds or longer.
+    pub fn with_slow_request_threshold(mut self, ms: u64) -> Self {
+        self.slow_request_ms = Some(ms);
+        self
+    }
+
+    /// Warn about responses with bodies of `bytes` or more.
+    pub fn with_large_response_threshold(mut self, bytes: u64) -> Self {
+        self.large_response_bytes = Some(bytes);
+        self
+    }
 }
 
 /// Client configuration options.
@@ -51,6 +343,11 @@ pub struct ClientConfig {
     pub server_name: String,
     /// Whether to skip certificate verification (for self-signed certs).
     pub insecure: bool,
+    /// Prefer the X25519MLKEM768 post-quantum hybrid key exchange.
+    pub post_quantum_kx: bool,
+    /// Write TLS secrets to the file named by `SSLKEYLOGFILE`, so captured
+    /// traffic can be decrypted in Wireshark. Development only.
+    pub key_log: bool,
 }
 
 impl Default for ClientConfig {
@@ -59,6 +356,8 @@ impl Default for ClientConfig {
             server_addr: "127.0.0.1:4433".parse().unwrap(),
             server_name: "localhost".to_string(),
             insecure: true,
+            post_quantum_kx: true,
+            key_log: false,
         }
     }
 }
@@ -76,4 +375,16 @@ impl ClientConfig {
         self.insecure = false;
         self
     }
+
+    /// Enable or disable the post-quantum hybrid key exchange.
+    pub fn with_post_quantum_kx(mut self, enabled: bool) -> Self {
+        self.post_quantum_kx = enabled;
+        self
+    }
+
+    /// Log TLS secrets to `SSLKEYLOGFILE` for decrypting captures.
+    pub fn with_key_log(mut self, enabled: bool) -> Self {
+        self.key_log = enabled;
+        self
+    }
 }
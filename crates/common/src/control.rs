@@ -0,0 +1,357 @@
+//! Control messages exchanged between WebTransport clients and the server.
+//!
+//! The sender opens a unidirectional stream per control message. A message
+//! is a single UTF-8 line: a verb, optionally followed by a space and an
+//! argument.
+//!
+//! Topic events are delivered on a long-lived server-initiated
+//! unidirectional stream per subscription, as a sequence of
+//! [`TopicFrame`] lines starting with a `TOPIC` header.
+//!
+//! Sessions in a room are told who else is there: the whole roster when
+//! they join or someone changes their name, and each join and leave. They
+//! can post messages to the room or one member, and publish a public key
+//! others seal messages with (see `common::e2e`); the server relays both.
+//!
+//! Posted messages get receipts: the server acknowledges storing one with
+//! an `ACK` carrying its message number, and the sender then gets a
+//! `RECEIPT` as it is delivered to and read by each recipient.
+
+/// How far a room message has got with one recipient.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReceiptStatus {
+    /// The server sent it to the recipient.
+    Delivered,
+    /// The recipient displayed it.
+    Read,
+}
+
+impl ReceiptStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptStatus::Delivered => "delivered",
+            ReceiptStatus::Read => "read",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "delivered" => Some(ReceiptStatus::Delivered),
+            "read" => Some(ReceiptStatus::Read),
+            _ => None,
+        }
+    }
+}
+
+/// Longest display name, in characters.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// A session in a room, by session ID and display name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub id: u64,
+    pub name: String,
+}
+
+impl Member {
+    fn encode(&self) -> String {
+        format!("{} {}", self.id, self.name)
+    }
+
+    fn decode(s: &str) -> Option<Self> {
+        let (id, name) = s.split_once(' ')?;
+        Some(Member {
+            id: id.parse().ok()?,
+            name: name.to_string(),
+        })
+    }
+}
+
+/// `name` made fit for a display name: control characters replaced by
+/// spaces, trimmed and cut to [`MAX_NAME_LEN`]. `None` if nothing is left.
+pub fn clean_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let name: String = name.trim().chars().take(MAX_NAME_LEN).collect();
+    let name = name.trim_end();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// A server-to-client control message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// The server will close this session shortly; the client should
+    /// reconnect. The reason is informational.
+    GoAway { reason: String },
+    /// A message pushed to the client from outside the session, e.g. by
+    /// the `/api/notify` endpoint. Messages are single lines of text.
+    Notify { message: String },
+    /// The session's affinity token, sent when the session starts. A client
+    /// that reconnects with it (as the `session` query parameter of the
+    /// CONNECT URL) gets its topic subscriptions back.
+    Session { token: String },
+    /// Reply to a client's [`ClientControl::Ping`].
+    Pong,
+    /// The client has `limit` streams of this `kind` (`bidi` or `uni`)
+    /// open, and the server refused the one it just opened.
+    StreamLimit { kind: String, limit: usize },
+    /// Everyone in the session's room, including itself.
+    Roster { members: Vec<Member> },
+    /// A session joined the session's room.
+    Joined(Member),
+    /// A session left the session's room.
+    Left(Member),
+    /// The public key, in hex, a member of the room seals messages with.
+    PublicKey { from: u64, key: String },
+    /// Message number `msg`, posted to the room or to this session by
+    /// member `from`. The body may be sealed.
+    Said { from: u64, msg: u64, body: String },
+    /// The server stored the client's message `id` as message number `msg`
+    /// and sent it to this many `recipients`.
+    Ack { id: u64, msg: u64, recipients: usize },
+    /// Member `by` got this far with message number `msg`.
+    Receipt { msg: u64, by: u64, status: ReceiptStatus },
+}
+
+impl ControlMessage {
+    /// Encode the message as a single line.
+    pub fn encode(&self) -> String {
+        match self {
+            ControlMessage::GoAway { reason } => format!("GOAWAY {}\n", reason),
+            ControlMessage::Notify { message } => format!("NOTIFY {}\n", message),
+            ControlMessage::Session { token } => format!("SESSION {}\n", token),
+            ControlMessage::Pong => "PONG\n".to_string(),
+            ControlMessage::StreamLimit { kind, limit } => {
+                format!("STREAMLIMIT {} {}\n", kind, limit)
+            }
+            // Names have no control characters, so tabs separate members
+            ControlMessage::Roster { members } => {
+                let members: Vec<String> = members.iter().map(Member::encode).collect();
+                format!("ROSTER {}\n", members.join("\t"))
+            }
+            ControlMessage::Joined(member) => format!("JOINED {}\n", member.encode()),
+            ControlMessage::Left(member) => format!("LEFT {}\n", member.encode()),
+            ControlMessage::PublicKey { from, key } => format!("PUBKEY {} {}\n", from, key),
+            ControlMessage::Said { from, msg, body } => {
+                format!("SAID {} {} {}\n", from, msg, body)
+            }
+            ControlMessage::Ack { id, msg, recipients } => {
+                format!("ACK {} {} {}\n", id, msg, recipients)
+            }
+            ControlMessage::Receipt { msg, by, status } => {
+                format!("RECEIPT {} {} {}\n", msg, by, status.as_str())
+            }
+        }
+    }
+
+    /// Decode a message produced by [`ControlMessage::encode`].
+    pub fn decode(line: &str) -> Option<Self> {
+        let (verb, arg) = split_line(line);
+        match verb {
+            "GOAWAY" => Some(ControlMessage::GoAway {
+                reason: arg.to_string(),
+            }),
+            "NOTIFY" => Some(ControlMessage::Notify {
+                message: arg.to_string(),
+            }),
+            "SESSION" => Some(ControlMessage::Session {
+                token: arg.to_string(),
+            }),
+            "PONG" => Some(ControlMessage::Pong),
+            "STREAMLIMIT" => {
+                let (kind, limit) = arg.split_once(' ')?;
+                Some(ControlMessage::StreamLimit {
+                    kind: kind.to_string(),
+                    limit: limit.parse().ok()?,
+                })
+            }
+            "ROSTER" => Some(ControlMessage::Roster {
+                members: arg
+                    .split('\t')
+                    .filter(|member| !member.is_empty())
+                    .map(Member::decode)
+                    .collect::<Option<_>>()?,
+            }),
+            "JOINED" => Member::decode(arg).map(ControlMessage::Joined),
+            "LEFT" => Member::decode(arg).map(ControlMessage::Left),
+            "PUBKEY" => {
+                let (from, key) = arg.split_once(' ')?;
+                Some(ControlMessage::PublicKey {
+                    from: from.parse().ok()?,
+                    key: key.to_string(),
+                })
+            }
+            "SAID" => {
+                let mut parts = arg.splitn(3, ' ');
+                Some(ControlMessage::Said {
+                    from: parts.next()?.parse().ok()?,
+                    msg: parts.next()?.parse().ok()?,
+                    body: parts.next()?.to_string(),
+                })
+            }
+            "ACK" => {
+                let mut parts = arg.split(' ');
+                Some(ControlMessage::Ack {
+                    id: parts.next()?.parse().ok()?,
+                    msg: parts.next()?.parse().ok()?,
+                    recipients: parts.next()?.parse().ok()?,
+                })
+            }
+            "RECEIPT" => {
+                let mut parts = arg.split(' ');
+                Some(ControlMessage::Receipt {
+                    msg: parts.next()?.parse().ok()?,
+                    by: parts.next()?.parse().ok()?,
+                    status: ReceiptStatus::parse(parts.next()?)?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Split a line into its verb and argument.
+fn split_line(line: &str) -> (&str, &str) {
+    let line = line.trim_end_matches(['\r', '\n']);
+    line.split_once(' ').unwrap_or((line, ""))
+}
+
+/// A client-to-server control message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientControl {
+    /// Start receiving events published to a topic.
+    Subscribe { topic: String },
+    /// Stop receiving events for a topic.
+    Unsubscribe { topic: String },
+    /// Ask the server for a [`ControlMessage::Pong`], to check the
+    /// connection is still alive.
+    Ping,
+    /// Set the session's display name in its room.
+    Name { name: String },
+    /// Publish the session's public key, in hex, to its room.
+    PublicKey { key: String },
+    /// Post a single-line message to the room, or only to member `to`.
+    /// `id` is the client's own number for it, echoed in the `ACK`.
+    Say { to: Option<u64>, id: u64, body: String },
+    /// The client displayed message number `msg`.
+    Read { msg: u64 },
+    /// Send the telemetry samples kept since `since` (milliseconds since
+    /// the Unix epoch), of one metric or all, on a backfill stream.
+    Backfill { since: u64, metric: Option<String> },
+}
+
+impl ClientControl {
+    /// Encode the message as a single line.
+    pub fn encode(&self) -> String {
+        match self {
+            ClientControl::Subscribe { topic } => format!("SUBSCRIBE {}\n", topic),
+            ClientControl::Unsubscribe { topic } => format!("UNSUBSCRIBE {}\n", topic),
+            ClientControl::Ping => "PING\n".to_string(),
+            ClientControl::Name { name } => format!("NAME {}\n", name),
+            ClientControl::PublicKey { key } => format!("PUBKEY {}\n", key),
+            ClientControl::Say { to, id, body } => match to {
+                Some(to) => format!("SAY {} {} {}\n", to, id, body),
+                None => format!("SAY * {} {}\n", id, body),
+            },
+            ClientControl::Read { msg } => format!("READ {}\n", msg),
+            ClientControl::Backfill { since, metric } => match metric {
+                Some(metric) => format!("BACKFILL {} {}\n", since, metric),
+                None => format!("BACKFILL {}\n", since),
+            },
+        }
+    }
+
+    /// Decode a message produced by [`ClientControl::encode`].
+    pub fn decode(line: &str) -> Option<Self> {
+        let (verb, arg) = split_line(line);
+        if verb == "PING" {
+            return Some(ClientControl::Ping);
+        }
+        if arg.is_empty() {
+            return None;
+        }
+        match verb {
+            "NAME" => {
+                return Some(ClientControl::Name {
+                    name: arg.to_string(),
+                });
+            }
+            "PUBKEY" => {
+                return Some(ClientControl::PublicKey {
+                    key: arg.to_string(),
+                });
+            }
+            "SAY" => {
+                let mut parts = arg.splitn(3, ' ');
+                let to = match parts.next()? {
+                    "*" => None,
+                    to => Some(to.parse().ok()?),
+                };
+                return Some(ClientControl::Say {
+                    to,
+                    id: parts.next()?.parse().ok()?,
+                    body: parts.next()?.to_string(),
+                });
+            }
+            "READ" => {
+                return Some(ClientControl::Read {
+                    msg: arg.parse().ok()?,
+                });
+            }
+            "BACKFILL" => {
+                let (since, metric) = match arg.split_once(' ') {
+                    Some((since, metric)) => (since, Some(metric.to_string())),
+                    None => (arg, None),
+                };
+                return Some(ClientControl::Backfill {
+                    since: since.parse().ok()?,
+                    metric,
+                });
+            }
+            _ => {}
+        }
+        let topic = arg.to_string();
+        match verb {
+            "SUBSCRIBE" => Some(ClientControl::Subscribe { topic }),
+            "UNSUBSCRIBE" => Some(ClientControl::Unsubscribe { topic }),
+            _ => None,
+        }
+    }
+}
+
+/// A line on a topic subscription stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TopicFrame {
+    /// First line of the stream, naming the topic.
+    Header { topic: String },
+    /// A published event. Events are single lines of text.
+    Event(String),
+    /// The subscriber fell behind and this many events were dropped.
+    Lagged(u64),
+}
+
+impl TopicFrame {
+    /// Encode the frame as a single line.
+    pub fn encode(&self) -> String {
+        match self {
+            TopicFrame::Header { topic } => format!("TOPIC {}\n", topic),
+            TopicFrame::Event(data) => format!("EVENT {}\n", data),
+            TopicFrame::Lagged(n) => format!("LAGGED {}\n", n),
+        }
+    }
+
+    /// Decode a frame produced by [`TopicFrame::encode`].
+    pub fn decode(line: &str) -> Option<Self> {
+        let (verb, arg) = split_line(line);
+        match verb {
+            "TOPIC" => Some(TopicFrame::Header {
+                topic: arg.to_string(),
+            }),
+            "EVENT" => Some(TopicFrame::Event(arg.to_string())),
+            "LAGGED" => arg.parse().ok().map(TopicFrame::Lagged),
+            _ => None,
+        }
+    }
+}
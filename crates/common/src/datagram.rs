@@ -0,0 +1,167 @@
+//! Datagram framing: the fallback stream, and sequence numbers.
+//!
+//! WebTransport datagrams need QUIC datagram support on both ends. When the
+//! peer did not negotiate it, each side sends its datagrams on a single
+//! long-lived unidirectional stream instead: the [`FALLBACK_HEADER`] line,
+//! then every datagram as a 2-byte big-endian length and the payload. The
+//! stream is opened, and its header sent, as soon as the session starts
+//! rather than along with the first datagram. Senders drop datagrams while
+//! the stream is backed up rather than queue them, so the channel stays as
+//! lossy as the one it stands in for.
+//!
+//! Datagrams can also carry a sequence number (see [`encode_sequenced`]),
+//! from which the receiver's [`SequenceTracker`] works out loss, reordering
+//! and gaps.
+
+use std::collections::BTreeSet;
+
+/// First line of a datagram fallback stream.
+pub const FALLBACK_HEADER: &[u8] = b"DATAGRAMS\n";
+
+/// Largest payload a frame can carry.
+pub const MAX_FRAME_PAYLOAD: usize = u16::MAX as usize;
+
+/// Frame `payload` for the fallback stream, or `None` if it is larger than
+/// [`MAX_FRAME_PAYLOAD`].
+pub fn encode_frame(payload: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::try_from(payload.len()).ok()?;
+    let mut frame = Vec::with_capacity(2 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Some(frame)
+}
+
+/// Splits the bytes read from a fallback stream (after its header) back
+/// into datagrams.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes read from the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete datagram, if one has been read.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let len = usize::from(u16::from_be_bytes([*self.buf.first()?, *self.buf.get(1)?]));
+        if self.buf.len() < 2 + len {
+            return None;
+        }
+        let payload = self.buf[2..2 + len].to_vec();
+        self.buf.drain(..2 + len);
+        Some(payload)
+    }
+}
+
+/// First byte of a sequence-numbered datagram, followed by the sequence
+/// number (4 bytes, big-endian) and the payload. Datagrams that do not
+/// start with it are not counted by a [`SequenceTracker`].
+pub const SEQUENCE_MARKER: u8 = 0xd5;
+
+/// Most missing sequence numbers remembered, so a datagram arriving after
+/// a big jump can still be told apart from a duplicate.
+const MAX_MISSING: usize = 1024;
+
+/// Prefix `payload` with the sequence marker and `seq`.
+pub fn encode_sequenced(seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(5 + payload.len());
+    datagram.push(SEQUENCE_MARKER);
+    datagram.extend_from_slice(&seq.to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// The sequence number and payload of a datagram made by
+/// [`encode_sequenced`].
+pub fn decode_sequenced(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    let (&marker, rest) = datagram.split_first()?;
+    if marker != SEQUENCE_MARKER || rest.len() < 4 {
+        return None;
+    }
+    let (seq, payload) = rest.split_at(4);
+    Some((u32::from_be_bytes(seq.try_into().ok()?), payload))
+}
+
+/// Loss and reordering seen in a stream of sequence-numbered datagrams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    /// Datagrams received, not counting duplicates.
+    pub received: u64,
+    /// Sequence numbers skipped that have not arrived (yet).
+    pub lost: u64,
+    /// Datagrams that arrived after a later one.
+    pub reordered: u64,
+    /// Times the sequence jumped ahead, each skipping one or more numbers.
+    pub gaps: u64,
+}
+
+impl SequenceStats {
+    /// Share of the datagrams sent so far that were lost, in percent.
+    pub fn loss_percent(&self) -> f64 {
+        percent(self.lost, self.received + self.lost)
+    }
+
+    /// Share of the received datagrams that arrived out of order, in
+    /// percent.
+    pub fn reorder_percent(&self) -> f64 {
+        percent(self.reordered, self.received)
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    part as f64 * 100.0 / total as f64
+}
+
+/// Tracks the sequence numbers of received datagrams.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    /// The sequence number expected next.
+    next: Option<u32>,
+    /// Skipped sequence numbers, oldest first.
+    missing: BTreeSet<u32>,
+    stats: SequenceStats,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a datagram with sequence number `seq`.
+    pub fn record(&mut self, seq: u32) {
+        let next = self.next.unwrap_or(seq);
+        if seq >= next {
+            if seq > next {
+                self.stats.gaps += 1;
+                self.stats.lost += u64::from(seq - next);
+                let skipped = seq.saturating_sub(MAX_MISSING as u32).max(next)..seq;
+                self.missing.extend(skipped);
+                while self.missing.len() > MAX_MISSING {
+                    self.missing.pop_first();
+                }
+            }
+            self.next = Some(seq.wrapping_add(1));
+        } else if self.missing.remove(&seq) {
+            self.stats.lost -= 1;
+            self.stats.reordered += 1;
+        } else {
+            // A duplicate, or too late to tell
+            return;
+        }
+        self.stats.received += 1;
+    }
+
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+}
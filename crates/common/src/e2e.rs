@@ -0,0 +1,143 @@
+//! End-to-end encryption of room messages.
+//!
+//! Each client makes an X25519 [`KeyPair`] per session and publishes the
+//! public half to its room with a `PUBKEY` control message. A sender seals
+//! a message separately for each member it goes to, with ChaCha20-Poly1305
+//! under a key both sides derive from their X25519 shared secret, and the
+//! server relays the sealed text without being able to read it.
+//!
+//! The server still hands out the public keys, so a malicious one could
+//! swap in its own and read along. Members who compare [`fingerprint`]s
+//! out of band rule that out.
+//!
+//! A sealed message is [`SEALED_PREFIX`] followed by the hex-encoded nonce
+//! and ciphertext. Everything here is pure Rust, so it also runs in the
+//! browser (with the `js` entropy source of `getrandom`).
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Start of a sealed message body.
+pub const SEALED_PREFIX: &str = "e2e:";
+
+/// Binds derived keys to this protocol and version.
+const KDF_INFO: &[u8] = b"simple-http3 e2e v1";
+
+const NONCE_LEN: usize = 12;
+
+/// A session's X25519 key pair.
+pub struct KeyPair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    /// A fresh key pair from the platform's entropy source.
+    ///
+    /// # Panics
+    ///
+    /// If the platform has no entropy source.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).expect("no entropy source");
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// The public key as hex, as sent in `PUBKEY` messages.
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public.as_bytes())
+    }
+
+    /// Seal `plaintext` for the holder of `their_key` (a public key in
+    /// hex). `None` if the key is not a valid public key.
+    pub fn seal(&self, their_key: &str, plaintext: &str) -> Option<String> {
+        let cipher = self.cipher(their_key)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).ok()?;
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .ok()?;
+        Some(format!("{}{}{}", SEALED_PREFIX, to_hex(&nonce), to_hex(&ciphertext)))
+    }
+
+    /// Open a message sealed by the holder of `their_key`. `None` if it is
+    /// not sealed, was sealed for someone else or was tampered with.
+    pub fn open(&self, their_key: &str, sealed: &str) -> Option<String> {
+        let data = from_hex(sealed.strip_prefix(SEALED_PREFIX)?)?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher(their_key)?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+
+    /// The cipher shared with the holder of `their_key`.
+    fn cipher(&self, their_key: &str) -> Option<ChaCha20Poly1305> {
+        let theirs = PublicKey::from(parse_public_key(their_key)?);
+        let shared = self.secret.diffie_hellman(&theirs);
+        // A low-order public key would make the secret predictable
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        // Both sides derive the same key: the public keys go in sorted
+        let (ours, theirs) = (self.public.as_bytes(), theirs.as_bytes());
+        let (first, second) = if ours <= theirs { (ours, theirs) } else { (theirs, ours) };
+        let mut info = KDF_INFO.to_vec();
+        info.extend_from_slice(first);
+        info.extend_from_slice(second);
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .ok()?;
+        Some(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+}
+
+/// Whether a message body is sealed.
+pub fn is_sealed(body: &str) -> bool {
+    body.starts_with(SEALED_PREFIX)
+}
+
+/// A public key in hex, if it is one.
+pub fn parse_public_key(hex: &str) -> Option<[u8; 32]> {
+    from_hex(hex)?.try_into().ok()
+}
+
+/// A short, human-comparable digest of a public key in hex, e.g.
+/// `3f2a 91c0 5be7 d411`.
+pub fn fingerprint(public_key: &str) -> String {
+    let digest = match parse_public_key(public_key) {
+        Some(key) => Sha256::digest(key),
+        None => Sha256::digest(public_key.as_bytes()),
+    };
+    digest[..8]
+        .chunks(2)
+        .map(to_hex)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
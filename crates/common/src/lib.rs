@@ -1,12 +1,37 @@
 //! Common utilities shared between HTTP/3 server and client.
 //!
 //! This crate provides:
-//! - TLS certificate generation and handling
+//! - TLS certificate generation and handling (`tls` feature), and export as
+//!   PKCS#12 bundles (`pkcs12` feature)
 //! - Configuration types
-//! - Common error types
+//! - WebTransport control messages and topic frames
+//! - Framing for the datagram fallback stream
+//! - Ephemeral signals, such as typing indicators, sent as datagrams
+//! - Application close codes
+//! - JSON-RPC 2.0 messages and call correlation
+//! - End-to-end encryption of room messages (`e2e` feature)
+//! - Binary diffs and framing for syncing a shared document
+//! - Telemetry samples sent as datagrams
+//!
+//! With default features disabled the crate has no native-only
+//! dependencies and can be used from the WASM web client.
 
+pub mod close;
 pub mod config;
+pub mod control;
+pub mod datagram;
+#[cfg(feature = "e2e")]
+pub mod e2e;
+pub mod rpc;
+pub mod signal;
+pub mod sync;
+pub mod telemetry;
+#[cfg(feature = "tls")]
 pub mod tls;
 
+pub use close::CloseCode;
 pub use config::{ClientConfig, ServerConfig};
-pub use tls::{generate_self_signed_cert, CertificateChain, InsecureCertVerifier};
+pub use control::{ClientControl, ControlMessage, Member, ReceiptStatus, TopicFrame};
+pub use signal::Signal;
+#[cfg(feature = "tls")]
+pub use tls::{generate_self_signed_cert, CertificateChain, InsecureCertVerifier, SniCertResolver};
@@ -0,0 +1,318 @@
+//! JSON-RPC 2.0 over byte streams.
+//!
+//! Messages are framed as one JSON value per line, so a single bidi stream
+//! (a WebTransport stream or an HTTP/3 request stream) can carry any number
+//! of interleaved requests and responses. Each line is either a single
+//! message or a batch (a JSON array of messages), as in the specification.
+//!
+//! [`Calls`] is the transport-independent half of a client proxy: it
+//! assigns request ids, encodes requests and matches response lines back to
+//! the callers waiting on them.
+
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// The protocol version carried in every message.
+pub const VERSION: &str = "2.0";
+
+/// A request id. Responses carry the id of the request they answer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Id {
+    Number(i64),
+    String(String),
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Id::Number(n) => write!(f, "{}", n),
+            Id::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+/// A request, or a notification when it has no id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Id>,
+}
+
+impl Request {
+    /// A request expecting a response with the given id.
+    pub fn new(method: impl Into<String>, params: Option<Value>, id: Id) -> Self {
+        Self {
+            jsonrpc: VERSION.to_string(),
+            method: method.into(),
+            params,
+            id: Some(id),
+        }
+    }
+
+    /// A notification; the server sends no response.
+    pub fn notification(method: impl Into<String>, params: Option<Value>) -> Self {
+        Self {
+            jsonrpc: VERSION.to_string(),
+            method: method.into(),
+            params,
+            id: None,
+        }
+    }
+
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+}
+
+/// A response to a request. Exactly one of `result` and `error` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    /// `None` (serialized as `null`) when the request id could not be read.
+    pub id: Option<Id>,
+}
+
+impl Response {
+    pub fn success(id: Id, result: Value) -> Self {
+        Self {
+            jsonrpc: VERSION.to_string(),
+            result: Some(result),
+            error: None,
+            id: Some(id),
+        }
+    }
+
+    pub fn error(id: Option<Id>, error: RpcError) -> Self {
+        Self {
+            jsonrpc: VERSION.to_string(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+
+    /// The call outcome carried by this response.
+    pub fn into_result(self) -> Result<Value, RpcError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.result.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub const PARSE_ERROR: i64 = -32700;
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn parse_error(detail: impl fmt::Display) -> Self {
+        Self::new(Self::PARSE_ERROR, format!("Parse error: {}", detail))
+    }
+
+    pub fn invalid_request(detail: impl fmt::Display) -> Self {
+        Self::new(
+            Self::INVALID_REQUEST,
+            format!("Invalid request: {}", detail),
+        )
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(
+            Self::METHOD_NOT_FOUND,
+            format!("Method not found: {}", method),
+        )
+    }
+
+    pub fn invalid_params(detail: impl fmt::Display) -> Self {
+        Self::new(Self::INVALID_PARAMS, format!("Invalid params: {}", detail))
+    }
+
+    pub fn internal_error(detail: impl fmt::Display) -> Self {
+        Self::new(Self::INTERNAL_ERROR, format!("Internal error: {}", detail))
+    }
+
+    /// The error reported to callers whose connection went away.
+    pub fn disconnected() -> Self {
+        Self::internal_error("connection closed")
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        // Preserve errors that already are RPC errors
+        match e.downcast::<RpcError>() {
+            Ok(error) => error,
+            Err(e) => Self::internal_error(e),
+        }
+    }
+}
+
+/// A single message or a batch, as sent on one line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Message<T> {
+    Batch(Vec<T>),
+    Single(T),
+}
+
+impl<T: Serialize> Message<T> {
+    /// Encode the message as a newline-terminated line.
+    pub fn encode(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("JSON-RPC messages always serialize");
+        line.push('\n');
+        line
+    }
+}
+
+/// Splits a byte stream into lines.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    buf: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Append received bytes and return every line they complete.
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(data);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if !line.is_empty() {
+                lines.push(line.to_string());
+            }
+        }
+        lines
+    }
+}
+
+/// The outcome of a call, delivered when its response arrives.
+pub type PendingCall = oneshot::Receiver<Result<Value, RpcError>>;
+
+/// Request id allocation and response correlation for a client proxy.
+///
+/// The proxy writes the lines returned by [`Calls::request`] and
+/// [`Calls::batch`] to its stream and feeds every received line to
+/// [`Calls::resolve`].
+#[derive(Debug, Default)]
+pub struct Calls {
+    next_id: AtomicI64,
+    pending: Mutex<HashMap<Id, oneshot::Sender<Result<Value, RpcError>>>>,
+}
+
+impl Calls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode a request and register it as pending.
+    pub fn request(&self, method: &str, params: Option<Value>) -> (String, PendingCall) {
+        let (request, pending) = self.register(method, params);
+        (Message::Single(request).encode(), pending)
+    }
+
+    /// Encode a notification. No response is expected.
+    pub fn notification(&self, method: &str, params: Option<Value>) -> String {
+        Message::Single(Request::notification(method, params)).encode()
+    }
+
+    /// Encode several requests as one batch and register them as pending.
+    ///
+    /// The pending calls are returned in the order of `calls`, whatever
+    /// order the server answers in.
+    pub fn batch(&self, calls: Vec<(String, Option<Value>)>) -> (String, Vec<PendingCall>) {
+        let (requests, pending): (Vec<_>, Vec<_>) = calls
+            .into_iter()
+            .map(|(method, params)| self.register(&method, params))
+            .unzip();
+        (Message::Batch(requests).encode(), pending)
+    }
+
+    fn register(&self, method: &str, params: Option<Value>) -> (Request, PendingCall) {
+        let id = Id::Number(self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id.clone(), tx);
+        (Request::new(method, params, id), rx)
+    }
+
+    /// Complete the calls answered by a received line.
+    pub fn resolve(&self, line: &str) {
+        let responses = match serde_json::from_str::<Message<Response>>(line) {
+            Ok(Message::Single(response)) => vec![response],
+            Ok(Message::Batch(responses)) => responses,
+            Err(e) => {
+                tracing::warn!("Ignoring malformed JSON-RPC response: {}", e);
+                return;
+            }
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        for response in responses {
+            let Some(id) = response.id.clone() else {
+                tracing::warn!("JSON-RPC error without id: {:?}", response.error);
+                continue;
+            };
+            match pending.remove(&id) {
+                // The caller may have given up waiting
+                Some(tx) => {
+                    let _ = tx.send(response.into_result());
+                }
+                None => tracing::warn!("JSON-RPC response for unknown id {}", id),
+            }
+        }
+    }
+
+    /// Fail every pending call, e.g. because the stream closed.
+    pub fn fail_all(&self) {
+        for (_, tx) in self.pending.lock().unwrap().drain() {
+            let _ = tx.send(Err(RpcError::disconnected()));
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Ephemeral signals: typing indicators, cursor positions and the like.
+//!
+//! Signals travel as datagrams, so they may be lost and are never
+//! retransmitted or stored. Each one stands on its own and supersedes the
+//! last of its kind: senders repeat a signal while it holds (e.g. every
+//! couple of seconds while typing) and receivers forget it when no repeat
+//! comes. Sent on a stream, a burst of stale cursor positions would queue
+//! up behind a lost packet; as datagrams the latest one gets through.
+//!
+//! A client sends a signal to the server, which relays it to the other
+//! sessions in the client's room with the sender's session ID filled in.
+//! A signal datagram is [`SIGNAL_MARKER`] followed by `<from> <kind>
+//! <value>`, where `from` is 0 in signals from clients.
+
+/// First byte of a signal datagram. Datagrams that do not start with it
+/// are echoed by the server as usual.
+pub const SIGNAL_MARKER: u8 = 0x1e;
+
+/// Kind of signal sent while the user is typing, with value `1`, and when
+/// they stop, with value `0`.
+pub const TYPING: &str = "typing";
+
+/// An ephemeral signal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signal {
+    /// Session ID of the sender; 0 until the server relays it.
+    pub from: u64,
+    /// What the signal is about, e.g. [`TYPING`]. A single word.
+    pub kind: String,
+    pub value: String,
+}
+
+impl Signal {
+    /// A signal to send to the server.
+    pub fn new(kind: &str, value: &str) -> Self {
+        Self {
+            from: 0,
+            kind: kind.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Encode the signal as a datagram payload.
+    pub fn encode(&self) -> String {
+        format!(
+            "{}{} {} {}",
+            char::from(SIGNAL_MARKER),
+            self.from,
+            self.kind,
+            self.value
+        )
+    }
+
+    /// Decode a datagram produced by [`Signal::encode`], or `None` if it is
+    /// not a signal.
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        let (&marker, rest) = datagram.split_first()?;
+        if marker != SIGNAL_MARKER {
+            return None;
+        }
+        let text = std::str::from_utf8(rest).ok()?;
+        let mut parts = text.splitn(3, ' ');
+        let from = parts.next()?.parse().ok()?;
+        let kind = parts.next().filter(|kind| !kind.is_empty())?;
+        Some(Self {
+            from,
+            kind: kind.to_string(),
+            value: parts.next().unwrap_or("").to_string(),
+        })
+    }
+}
@@ -0,0 +1,203 @@
+//! Binary state sync: a shared document per room, kept in step with diffs.
+//!
+//! The server holds each room's document, as bytes, with a version that
+//! goes up by one with every change. A session in a room is sent it on a
+//! long-lived server-initiated unidirectional stream: the `SYNC <room>`
+//! header line, a [`SyncFrame::Snapshot`] of the whole document, then a
+//! [`SyncFrame::Patch`] for each change. After reconnecting, or whenever
+//! the server cannot send the patches in order, the session is sent a new
+//! snapshot instead.
+//!
+//! Clients change the document by sending a patch against the version
+//! they have, on a uni stream of its own (see [`encode_edit`]). The server
+//! rebases patches made against an older version over the changes since,
+//! applies them and sends them to the whole room; the author gets theirs
+//! back as [`SyncFrame::Applied`]. A patch that cannot be rebased is
+//! dropped and its author sent a snapshot.
+//!
+//! Patches are produced and applied by a [`Diff`]; [`Splice`] replaces a
+//! single byte range, which is enough for a text field edited by hand.
+
+/// Start of a client's edit stream: `EDIT <version>\n`, then the patch.
+pub const EDIT_PREFIX: &[u8] = b"EDIT ";
+
+/// Start of the header line of a sync stream: `SYNC <room>\n`.
+pub const SYNC_PREFIX: &[u8] = b"SYNC ";
+
+const SNAPSHOT: u8 = b'S';
+const PATCH: u8 = b'P';
+const APPLIED: u8 = b'A';
+
+/// Kind (1 byte), version (8 bytes) and payload length (4 bytes).
+const FRAME_HEADER_LEN: usize = 13;
+
+/// Computes and applies patches between versions of a document.
+pub trait Diff: Send + Sync {
+    /// A patch that turns `old` into `new`.
+    fn diff(&self, old: &[u8], new: &[u8]) -> Vec<u8>;
+
+    /// Apply a patch made by [`Diff::diff`], or `None` if it does not fit
+    /// `base`.
+    fn apply(&self, base: &[u8], patch: &[u8]) -> Option<Vec<u8>>;
+
+    /// Rewrite `patch` to apply after `applied`, where both were made
+    /// against the same version. `None` if they conflict; by default
+    /// patches never rebase.
+    fn rebase(&self, patch: &[u8], applied: &[u8]) -> Option<Vec<u8>> {
+        let _ = (patch, applied);
+        None
+    }
+}
+
+/// Patches that replace one byte range: the start and the number of bytes
+/// removed (4 bytes each, big-endian), then the bytes inserted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Splice;
+
+impl Splice {
+    fn parse(patch: &[u8]) -> Option<(usize, usize, &[u8])> {
+        let start = u32::from_be_bytes(patch.get(..4)?.try_into().ok()?);
+        let removed = u32::from_be_bytes(patch.get(4..8)?.try_into().ok()?);
+        Some((start as usize, removed as usize, &patch[8..]))
+    }
+
+    fn encode(start: usize, removed: usize, inserted: &[u8]) -> Vec<u8> {
+        let mut patch = Vec::with_capacity(8 + inserted.len());
+        patch.extend_from_slice(&(start as u32).to_be_bytes());
+        patch.extend_from_slice(&(removed as u32).to_be_bytes());
+        patch.extend_from_slice(inserted);
+        patch
+    }
+}
+
+impl Diff for Splice {
+    fn diff(&self, old: &[u8], new: &[u8]) -> Vec<u8> {
+        let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+        let suffix = old[prefix..]
+            .iter()
+            .rev()
+            .zip(new[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        Self::encode(prefix, old.len() - prefix - suffix, &new[prefix..new.len() - suffix])
+    }
+
+    fn apply(&self, base: &[u8], patch: &[u8]) -> Option<Vec<u8>> {
+        let (start, removed, inserted) = Self::parse(patch)?;
+        let end = start.checked_add(removed).filter(|&end| end <= base.len())?;
+        let mut result = Vec::with_capacity(base.len() - removed + inserted.len());
+        result.extend_from_slice(&base[..start]);
+        result.extend_from_slice(inserted);
+        result.extend_from_slice(&base[end..]);
+        Some(result)
+    }
+
+    fn rebase(&self, patch: &[u8], applied: &[u8]) -> Option<Vec<u8>> {
+        let (start, removed, inserted) = Self::parse(patch)?;
+        let (a_start, a_removed, a_inserted) = Self::parse(applied)?;
+        if start + removed <= a_start && start < a_start {
+            // Entirely before the applied change
+            Some(patch.to_vec())
+        } else if start >= a_start + a_removed {
+            // Entirely after it (or an insertion at the same place, which
+            // goes second)
+            let start = start - a_removed + a_inserted.len();
+            Some(Self::encode(start, removed, inserted))
+        } else {
+            None
+        }
+    }
+}
+
+/// A frame on a sync stream, after the header line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncFrame {
+    /// The whole document at `version`.
+    Snapshot { version: u64, data: Vec<u8> },
+    /// Another member's change, which brings the document to `version`.
+    Patch { version: u64, patch: Vec<u8> },
+    /// This session's own change, as applied (rebased if need be).
+    Applied { version: u64, patch: Vec<u8> },
+}
+
+impl SyncFrame {
+    /// Encode the frame: its kind, version and payload length, then the
+    /// payload.
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, version, payload) = match self {
+            SyncFrame::Snapshot { version, data } => (SNAPSHOT, version, data),
+            SyncFrame::Patch { version, patch } => (PATCH, version, patch),
+            SyncFrame::Applied { version, patch } => (APPLIED, version, patch),
+        };
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.push(kind);
+        frame.extend_from_slice(&version.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+/// Splits the bytes read from a sync stream (after its header) back into
+/// frames.
+#[derive(Debug, Default)]
+pub struct SyncDecoder {
+    buf: Vec<u8>,
+}
+
+impl SyncDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add bytes read from the stream.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete frame, if one has been read. Frames of unknown
+    /// kinds are skipped.
+    pub fn next_frame(&mut self) -> Option<SyncFrame> {
+        loop {
+            let header = self.buf.get(..FRAME_HEADER_LEN)?;
+            let version = u64::from_be_bytes(header[1..9].try_into().ok()?);
+            let len = u32::from_be_bytes(header[9..13].try_into().ok()?) as usize;
+            let kind = header[0];
+            if self.buf.len() < FRAME_HEADER_LEN + len {
+                return None;
+            }
+            let payload = self.buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+            self.buf.drain(..FRAME_HEADER_LEN + len);
+            match kind {
+                SNAPSHOT => return Some(SyncFrame::Snapshot { version, data: payload }),
+                PATCH => return Some(SyncFrame::Patch { version, patch: payload }),
+                APPLIED => return Some(SyncFrame::Applied { version, patch: payload }),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// The header line of a sync stream for `room`.
+pub fn encode_header(room: &str) -> Vec<u8> {
+    let mut header = SYNC_PREFIX.to_vec();
+    header.extend_from_slice(room.as_bytes());
+    header.push(b'\n');
+    header
+}
+
+/// An edit stream's contents: `patch`, made against `version`.
+pub fn encode_edit(version: u64, patch: &[u8]) -> Vec<u8> {
+    let mut edit = EDIT_PREFIX.to_vec();
+    edit.extend_from_slice(format!("{}\n", version).as_bytes());
+    edit.extend_from_slice(patch);
+    edit
+}
+
+/// The version and patch of an edit stream, or `None` if it is not one.
+pub fn decode_edit(data: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = data.strip_prefix(EDIT_PREFIX)?;
+    let newline = rest.iter().position(|&b| b == b'\n')?;
+    let version = std::str::from_utf8(&rest[..newline]).ok()?.parse().ok()?;
+    Some((version, &rest[newline + 1..]))
+}
@@ -0,0 +1,105 @@
+//! Telemetry samples sent as datagrams.
+//!
+//! Devices report readings fire-and-forget: a lost datagram is one missing
+//! sample, and nothing is retransmitted or acknowledged. A telemetry
+//! datagram is [`TELEMETRY_MARKER`] followed by one or more samples, each
+//! the name length (1 byte), the name, the value (an `f64`) and the
+//! timestamp (milliseconds since the Unix epoch, a `u64`), both 8 bytes
+//! big-endian.
+//!
+//! A client that may have lost datagrams can reconcile over streams: a
+//! `BACKFILL <since> [<metric>]` control message asks the server for every
+//! sample it has kept since then. They come back on a uni stream, after a
+//! [`BACKFILL_HEADER`] line, in the same sample encoding. Samples the
+//! server is missing can be sent again reliably: a uni stream with the
+//! same contents as a telemetry datagram is taken as one.
+
+/// First byte of a telemetry datagram.
+pub const TELEMETRY_MARKER: u8 = 0x1d;
+
+/// Start of the header line of a backfill stream: `BACKFILL <since>\n`.
+pub const BACKFILL_HEADER: &[u8] = b"BACKFILL ";
+
+/// Longest metric name, in bytes.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// A reading of one metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// E.g. `temperature` or `sensor.3.humidity`: letters, digits and
+    /// `_.:-`.
+    pub name: String,
+    pub value: f64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Sample {
+    pub fn new(name: &str, value: f64, timestamp: u64) -> Self {
+        Self {
+            name: name.to_string(),
+            value,
+            timestamp,
+        }
+    }
+}
+
+/// Whether `name` can name a metric.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_.:-".contains(&b))
+}
+
+/// Encode samples as one datagram payload, or `None` if a name is not
+/// valid (see [`is_valid_name`]).
+pub fn encode(samples: &[Sample]) -> Option<Vec<u8>> {
+    let mut datagram = vec![TELEMETRY_MARKER];
+    datagram.extend(encode_samples(samples)?);
+    Some(datagram)
+}
+
+/// Decode a datagram produced by [`encode`], or `None` if it is not a
+/// telemetry datagram or is malformed.
+pub fn decode(datagram: &[u8]) -> Option<Vec<Sample>> {
+    let (&marker, rest) = datagram.split_first()?;
+    if marker != TELEMETRY_MARKER {
+        return None;
+    }
+    decode_samples(rest)
+}
+
+/// Encode samples without the marker, as on a backfill stream.
+pub fn encode_samples(samples: &[Sample]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    for sample in samples {
+        if !is_valid_name(&sample.name) {
+            return None;
+        }
+        data.push(sample.name.len() as u8);
+        data.extend_from_slice(sample.name.as_bytes());
+        data.extend_from_slice(&sample.value.to_be_bytes());
+        data.extend_from_slice(&sample.timestamp.to_be_bytes());
+    }
+    Some(data)
+}
+
+/// Decode samples produced by [`encode_samples`], or `None` if they are
+/// malformed.
+pub fn decode_samples(mut rest: &[u8]) -> Option<Vec<Sample>> {
+    let mut samples = Vec::new();
+    while let Some((&len, after)) = rest.split_first() {
+        let len = usize::from(len);
+        let name = std::str::from_utf8(after.get(..len)?).ok()?;
+        if !is_valid_name(name) {
+            return None;
+        }
+        let value = f64::from_be_bytes(after.get(len..len + 8)?.try_into().ok()?);
+        let timestamp = u64::from_be_bytes(after.get(len + 8..len + 16)?.try_into().ok()?);
+        samples.push(Sample::new(name, value, timestamp));
+        rest = &after[len + 16..];
+    }
+    Some(samples)
+}
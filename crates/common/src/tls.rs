@@ -1,13 +1,119 @@
 //! TLS certificate utilities.
 
+use rustls::crypto::{ActiveKeyExchange, CompletedKeyExchange, CryptoProvider, SharedSecret, SupportedKxGroup};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
-use std::sync::Arc;
-use std::time::Duration;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{NamedGroup, ProtocolVersion};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
 
 /// A certificate chain with its private key.
 pub struct CertificateChain {
     pub cert_chain: Vec<CertificateDer<'static>>,
     pub private_key: PrivateKeyDer<'static>,
+    /// End of the leaf certificate's validity period.
+    pub not_after: SystemTime,
+}
+
+impl Clone for CertificateChain {
+    fn clone(&self) -> Self {
+        Self {
+            cert_chain: self.cert_chain.clone(),
+            private_key: self.private_key.clone_key(),
+            not_after: self.not_after,
+        }
+    }
+}
+
+impl CertificateChain {
+    /// SHA-256 hash of the leaf certificate, as used by WebTransport's
+    /// `serverCertificateHashes`.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let leaf = self.cert_chain.first().map(|c| c.as_ref()).unwrap_or_default();
+        Sha256::digest(leaf).into()
+    }
+
+    /// The leaf certificate hash as a lowercase hex string.
+    pub fn fingerprint_hex(&self) -> String {
+        self.fingerprint().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The certificates, leaf first, followed by the private key, as PEM.
+    pub fn to_pem(&self) -> String {
+        let mut blocks = self.cert_blocks();
+        blocks.push(self.key_block());
+        pem::encode_many(&blocks)
+    }
+
+    /// Write the certificates, leaf first, to `cert.pem` in `dir` and the
+    /// private key to `key.pem`, readable by the owner only, creating `dir`
+    /// if needed. `cert.pem` is what OS trust stores and browsers import.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use common::tls::generate_self_signed_cert;
+    ///
+    /// let cert = generate_self_signed_cert(&["localhost".to_string()]).unwrap();
+    /// cert.write_pem("certs").unwrap();
+    /// ```
+    pub fn write_pem(&self, dir: impl AsRef<Path>) -> std::io::Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("cert.pem"), pem::encode_many(&self.cert_blocks()))?;
+        write_private(&dir.join("key.pem"), pem::encode(&self.key_block()).as_bytes())
+    }
+
+    /// Write the certificates and private key to `path` as a PKCS#12
+    /// (`.p12`/`.pfx`) bundle encrypted with `password`, for tools and key
+    /// stores that import one file. Encrypted with AES-256 and
+    /// HMAC-SHA256, which OpenSSL 1.1 and newer, current macOS and Windows
+    /// 10 1709 and newer read.
+    #[cfg(feature = "pkcs12")]
+    pub fn write_pkcs12(&self, path: impl AsRef<Path>, password: &str) -> anyhow::Result<()> {
+        use p12_keystore::{Certificate, KeyStore, KeyStoreEntry, PrivateKeyChain};
+
+        let chain = self
+            .cert_chain
+            .iter()
+            .map(|cert| Certificate::from_der(cert))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyChain::new(self.private_key.secret_der(), self.fingerprint(), chain);
+        let mut store = KeyStore::new();
+        store.add_entry("server", KeyStoreEntry::PrivateKeyChain(key));
+        write_private(path.as_ref(), &store.writer(password).write()?)?;
+        Ok(())
+    }
+
+    fn cert_blocks(&self) -> Vec<pem::Pem> {
+        self.cert_chain
+            .iter()
+            .map(|cert| pem::Pem::new("CERTIFICATE", cert.to_vec()))
+            .collect()
+    }
+
+    fn key_block(&self) -> pem::Pem {
+        let tag = match &self.private_key {
+            PrivateKeyDer::Pkcs1(_) => "RSA PRIVATE KEY",
+            PrivateKeyDer::Sec1(_) => "EC PRIVATE KEY",
+            _ => "PRIVATE KEY",
+        };
+        pem::Pem::new(tag, self.private_key.secret_der().to_vec())
+    }
+}
+
+/// Write `contents` to `path`, readable by the owner only.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
 }
 
 /// Generate a self-signed certificate for the given hostnames.
@@ -22,10 +128,12 @@ pub fn generate_self_signed_cert(hostnames: &[String]) -> anyhow::Result<Certifi
     let cert = rcgen::generate_simple_self_signed(hostnames.to_vec())?;
     let private_key = PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
     let cert_chain = vec![cert.cert.der().clone()];
+    let not_after = cert.cert.params().not_after.into();
 
     Ok(CertificateChain {
         cert_chain,
         private_key,
+        not_after,
     })
 }
 
@@ -36,6 +144,18 @@ pub fn generate_self_signed_cert(hostnames: &[String]) -> anyhow::Result<Certifi
 /// - Have a validity period of max 14 days
 /// - Have specific extensions
 pub fn generate_webtransport_cert(hostnames: &[String]) -> anyhow::Result<CertificateChain> {
+    generate_webtransport_cert_from(hostnames, SystemTime::now())
+}
+
+/// Generate a WebTransport-compliant certificate whose 14-day validity
+/// period starts at `not_before`.
+///
+/// Used to pre-generate the next certificate before the current one
+/// expires, so clients can pin its hash in advance.
+pub fn generate_webtransport_cert_from(
+    hostnames: &[String],
+    not_before: SystemTime,
+) -> anyhow::Result<CertificateChain> {
     use rcgen::{CertificateParams, KeyPair, PKCS_ECDSA_P256_SHA256};
     use time::{OffsetDateTime, Duration as TimeDuration};
     
@@ -45,9 +165,10 @@ pub fn generate_webtransport_cert(hostnames: &[String]) -> anyhow::Result<Certif
     let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
     
     // Set validity to 14 days (maximum allowed for serverCertificateHashes)
-    let now = OffsetDateTime::now_utc();
-    params.not_before = now;
-    params.not_after = now + TimeDuration::days(14);
+    let not_before = OffsetDateTime::from(not_before);
+    let not_after = not_before + TimeDuration::days(14);
+    params.not_before = not_before;
+    params.not_after = not_after;
     
     let cert = params.self_signed(&key_pair)?;
     let private_key = PrivateKeyDer::Pkcs8(key_pair.serialize_der().into());
@@ -56,9 +177,212 @@ pub fn generate_webtransport_cert(hostnames: &[String]) -> anyhow::Result<Certif
     Ok(CertificateChain {
         cert_chain,
         private_key,
+        not_after: not_after.into(),
     })
 }
 
+/// Whether `hostname` matches `pattern`, which is either an exact hostname
+/// or a wildcard like `*.example.com` matching a single label.
+///
+/// Matching is case-insensitive.
+pub fn hostname_matches(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let hostname = hostname.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => hostname
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == hostname,
+    }
+}
+
+/// Selects a certificate by the SNI hostname of the ClientHello.
+///
+/// Hostnames are matched exactly first, then against wildcard patterns.
+/// Clients that send no SNI, or an unknown hostname, get the default
+/// certificate.
+#[derive(Debug)]
+pub struct SniCertResolver {
+    default: Arc<CertifiedKey>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    /// Create a resolver serving `default` to unknown hostnames.
+    pub fn new(default: &CertificateChain, provider: &CryptoProvider) -> anyhow::Result<Self> {
+        Ok(Self {
+            default: Arc::new(certified_key(default, provider)?),
+            hosts: HashMap::new(),
+        })
+    }
+
+    /// Serve `cert` to clients requesting `hostname` (or a wildcard pattern).
+    pub fn add(
+        &mut self,
+        hostname: &str,
+        cert: &CertificateChain,
+        provider: &CryptoProvider,
+    ) -> anyhow::Result<()> {
+        let key = Arc::new(certified_key(cert, provider)?);
+        self.hosts.insert(hostname.to_ascii_lowercase(), key);
+        Ok(())
+    }
+
+    fn lookup(&self, server_name: &str) -> Option<&Arc<CertifiedKey>> {
+        let server_name = server_name.to_ascii_lowercase();
+        self.hosts.get(&server_name).or_else(|| {
+            self.hosts
+                .iter()
+                .find(|(pattern, _)| hostname_matches(pattern, &server_name))
+                .map(|(_, key)| key)
+        })
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.lookup(name))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(key))
+    }
+}
+
+fn certified_key(cert: &CertificateChain, provider: &CryptoProvider) -> anyhow::Result<CertifiedKey> {
+    let cert = cert.clone();
+    Ok(CertifiedKey::from_der(cert.cert_chain, cert.private_key, provider)?)
+}
+
+/// Key exchange groups that include a post-quantum component.
+const POST_QUANTUM_GROUPS: &[NamedGroup] = &[
+    NamedGroup::X25519MLKEM768,
+    NamedGroup::secp256r1MLKEM768,
+    NamedGroup::MLKEM768,
+];
+
+/// Build the crypto provider used by the server and client TLS configs.
+///
+/// With `post_quantum` set, the X25519MLKEM768 hybrid group is offered first
+/// if the provider supports it; otherwise only classical groups are offered.
+/// The key exchange groups record which one completes each handshake, see
+/// [`last_negotiated_group`].
+pub fn crypto_provider(post_quantum: bool) -> Arc<CryptoProvider> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+
+    provider.kx_groups.retain(|g| post_quantum || !POST_QUANTUM_GROUPS.contains(&g.name()));
+    if post_quantum {
+        match provider
+            .kx_groups
+            .iter()
+            .position(|g| g.name() == NamedGroup::X25519MLKEM768)
+        {
+            Some(i) => {
+                let hybrid = provider.kx_groups.remove(i);
+                provider.kx_groups.insert(0, hybrid);
+            }
+            None => tracing::warn!("Crypto provider does not support X25519MLKEM768"),
+        }
+    }
+
+    provider.kx_groups = provider.kx_groups.into_iter().map(recording_group).collect();
+    Arc::new(provider)
+}
+
+/// The key exchange group of the most recently completed handshake in this
+/// process.
+///
+/// quinn does not expose the underlying TLS session, so this is observed
+/// from the key exchange itself. It is only meaningful for processes that
+/// make one handshake at a time, like the demo client.
+pub fn last_negotiated_group() -> Option<NamedGroup> {
+    *NEGOTIATED_GROUP.lock().unwrap()
+}
+
+static NEGOTIATED_GROUP: Mutex<Option<NamedGroup>> = Mutex::new(None);
+
+fn record_group(group: NamedGroup) {
+    *NEGOTIATED_GROUP.lock().unwrap() = Some(group);
+}
+
+/// Wrap a key exchange group so completed handshakes are recorded.
+///
+/// Provider groups must be `'static`, so each wrapper is allocated once per
+/// group and reused.
+fn recording_group(group: &'static dyn SupportedKxGroup) -> &'static dyn SupportedKxGroup {
+    static WRAPPED: OnceLock<Mutex<Vec<&'static RecordingKxGroup>>> = OnceLock::new();
+
+    let mut wrapped = WRAPPED.get_or_init(Default::default).lock().unwrap();
+    if let Some(existing) = wrapped.iter().find(|w| w.0.name() == group.name()) {
+        return *existing;
+    }
+    let new: &'static RecordingKxGroup = Box::leak(Box::new(RecordingKxGroup(group)));
+    wrapped.push(new);
+    new
+}
+
+#[derive(Debug)]
+struct RecordingKxGroup(&'static dyn SupportedKxGroup);
+
+impl SupportedKxGroup for RecordingKxGroup {
+    fn start(&self) -> Result<Box<dyn ActiveKeyExchange>, rustls::Error> {
+        Ok(Box::new(RecordingKeyExchange(self.0.start()?)))
+    }
+
+    fn start_and_complete(&self, peer_pub_key: &[u8]) -> Result<CompletedKeyExchange, rustls::Error> {
+        let completed = self.0.start_and_complete(peer_pub_key)?;
+        record_group(completed.group);
+        Ok(completed)
+    }
+
+    fn name(&self) -> NamedGroup {
+        self.0.name()
+    }
+
+    fn fips(&self) -> bool {
+        self.0.fips()
+    }
+
+    fn usable_for_version(&self, version: ProtocolVersion) -> bool {
+        self.0.usable_for_version(version)
+    }
+}
+
+struct RecordingKeyExchange(Box<dyn ActiveKeyExchange>);
+
+impl ActiveKeyExchange for RecordingKeyExchange {
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Result<SharedSecret, rustls::Error> {
+        let group = self.0.group();
+        let secret = self.0.complete(peer_pub_key)?;
+        record_group(group);
+        Ok(secret)
+    }
+
+    fn hybrid_component(&self) -> Option<(NamedGroup, &[u8])> {
+        self.0.hybrid_component()
+    }
+
+    fn complete_hybrid_component(
+        self: Box<Self>,
+        peer_pub_key: &[u8],
+    ) -> Result<SharedSecret, rustls::Error> {
+        let group = self.0.hybrid_component().map(|(group, _)| group);
+        let secret = self.0.complete_hybrid_component(peer_pub_key)?;
+        if let Some(group) = group {
+            record_group(group);
+        }
+        Ok(secret)
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        self.0.pub_key()
+    }
+
+    fn group(&self) -> NamedGroup {
+        self.0.group()
+    }
+}
+
 /// Certificate verifier that skips verification (for development/testing only).
 ///
 /// # Warning
@@ -0,0 +1,141 @@
+//! Session affinity tokens.
+//!
+//! Every WebTransport session is given an opaque token in a `SESSION`
+//! control message. When the session ends, its topic subscriptions and the
+//! position reached in each topic are saved under the token in a
+//! [`SessionStore`]. A client that reconnects with
+//! `?session=<token>` on the CONNECT URL gets those subscriptions back,
+//! resumed after the last event it was sent.
+//!
+//! The server's [`MemoryStore`] keeps state in this process. With a
+//! backplane it relays what it saves to the other instances, so a client
+//! can resume on any of them, e.g. after its instance was restarted (see
+//! [`crate::backplane`]).
+
+use crate::api_keys::{hex, random_bytes};
+use crate::backplane::{Payload, Relay};
+use http::Uri;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a saved session can be resumed.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// What a session leaves behind for the next one with its token.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionState {
+    /// Subscribed topics, with the ID of the last event sent, if any (see
+    /// [`crate::topics::Event`]).
+    pub subscriptions: HashMap<String, Option<String>>,
+}
+
+/// Where session state is kept between connections.
+pub trait SessionStore: Send + Sync {
+    /// Take the state saved under `token`, if it has not expired.
+    fn take(&self, token: &str) -> Option<SessionState>;
+
+    /// Save `state` under `token`.
+    fn save(&self, token: &str, state: SessionState);
+}
+
+/// Session state kept in this process, expiring after a TTL.
+pub struct MemoryStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (SessionState, Instant)>>,
+    relay: Relay,
+}
+
+impl MemoryStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            relay: Relay::default(),
+        }
+    }
+
+    /// Take the state saved under `token` without telling other instances.
+    pub fn take_local(&self, token: &str) -> Option<SessionState> {
+        let (state, saved) = self.entries.lock().unwrap().remove(token)?;
+        (saved.elapsed() < self.ttl).then_some(state)
+    }
+
+    /// Save `state` under `token` without relaying it to other instances.
+    pub fn save_local(&self, token: &str, state: SessionState) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (_, saved)| saved.elapsed() < self.ttl);
+        entries.insert(token.to_string(), (state, Instant::now()));
+    }
+
+    /// Where saved state goes to reach other instances.
+    pub fn relay(&self) -> &Relay {
+        &self.relay
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL)
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn take(&self, token: &str) -> Option<SessionState> {
+        let state = self.take_local(token)?;
+        // Resumed here, so nowhere else
+        self.relay.send(Payload::SessionTaken {
+            token: token.to_string(),
+        });
+        Some(state)
+    }
+
+    fn save(&self, token: &str, state: SessionState) {
+        self.relay.send(Payload::Session {
+            token: token.to_string(),
+            state: state.clone(),
+        });
+        self.save_local(token, state);
+    }
+}
+
+/// A session's token, and the state it resumed with.
+pub struct Affinity {
+    pub token: String,
+    /// State saved by an earlier session with this token.
+    pub resumed: Option<SessionState>,
+    store: Arc<dyn SessionStore>,
+}
+
+impl Affinity {
+    /// Resume the session whose token was sent in the CONNECT `uri`, or
+    /// start a new one with a fresh token.
+    pub fn start(store: Arc<dyn SessionStore>, uri: &Uri) -> anyhow::Result<Self> {
+        let resumed = requested_token(uri)
+            .and_then(|token| store.take(&token).map(|state| (token, state)));
+        let (token, resumed) = match resumed {
+            Some((token, state)) => (token, Some(state)),
+            None => (hex(&random_bytes::<16>()?), None),
+        };
+        Ok(Self {
+            token,
+            resumed,
+            store,
+        })
+    }
+
+    /// Save the session's state for the next session with its token.
+    pub fn save(&self, state: SessionState) {
+        self.store.save(&self.token, state);
+    }
+}
+
+/// The token a client sent with its CONNECT request, if any.
+fn requested_token(uri: &Uri) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("session="))
+        .filter(|token| !token.is_empty() && token.bytes().all(|b| b.is_ascii_hexdigit()))
+        .map(str::to_string)
+}
@@ -0,0 +1,371 @@
+//! API keys with per-key route scopes and rate limits.
+//!
+//! When the server has an [`ApiKeyStore`], requests must carry a key in an
+//! `Authorization: Bearer <key>` or `x-api-key` header, except for public
+//! paths. A key only grants access to the paths matching its scopes, and is
+//! limited to a number of requests per minute and optionally a daily or
+//! monthly [`Quota`]. Keys are persisted to a JSON file; only a SHA-256 hash
+//! of each key is stored.
+
+use http::{Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use crate::sinks::{AuditEvent, AuditSink, TracingSink};
+use crate::i18n::Message;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+/// Prefix of generated keys, to make them recognizable in logs and config.
+const KEY_PREFIX: &str = "sh3_";
+
+/// A stored API key. The key itself is only known to its holder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    /// Public identifier, used to revoke the key.
+    pub id: String,
+    /// Who the key was issued to.
+    pub name: String,
+    /// Path prefixes the key may access; `*` allows every path.
+    pub scopes: Vec<String>,
+    /// Requests allowed per minute; unlimited if unset.
+    pub rate_limit_per_min: Option<u32>,
+    /// Requests and bytes allowed per day or month; unlimited if unset.
+    #[serde(default)]
+    pub quota: Option<Quota>,
+    /// Unix timestamp the key was created at.
+    pub created_at: u64,
+}
+
+/// The calendar period (UTC) a [`Quota`] applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+/// Requests and bytes (in and out) a key may use per period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub period: QuotaPeriod,
+    #[serde(default)]
+    pub requests: Option<u64>,
+    #[serde(default)]
+    pub bytes: Option<u64>,
+}
+
+impl ApiKey {
+    /// Whether the key may access `path`: `/api` covers `/api` and
+    /// `/api/...` but not `/apis`.
+    pub fn allows(&self, path: &str) -> bool {
+        self.scopes.iter().any(|scope| {
+            scope == "*"
+                || path.strip_prefix(scope.trim_end_matches('/')).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/')
+                })
+        })
+    }
+}
+
+/// A key as saved to the file.
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    /// Hex SHA-256 hash of the key.
+    key_hash: String,
+    #[serde(flatten)]
+    key: ApiKey,
+}
+
+/// Settings for a new key.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    #[serde(default = "all_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub rate_limit_per_min: Option<u32>,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+}
+
+fn all_scopes() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+/// The key a request was authenticated with, found in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Principal {
+    /// The key's id.
+    pub key_id: String,
+    /// The key's name.
+    pub name: String,
+}
+
+/// Why a request was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// No key, or an unknown one.
+    Unauthorized,
+    /// The key's scopes do not cover the path.
+    Forbidden,
+    /// The key's rate limit is exhausted.
+    RateLimited,
+}
+
+impl Denied {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Denied::Unauthorized => StatusCode::UNAUTHORIZED,
+            Denied::Forbidden => StatusCode::FORBIDDEN,
+            Denied::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// The message to respond with, looked up in the server's
+    /// [`crate::i18n::Catalog`].
+    pub fn message(self) -> Message {
+        match self {
+            Denied::Unauthorized => Message::Unauthorized,
+            Denied::Forbidden => Message::Forbidden,
+            Denied::RateLimited => Message::RateLimited,
+        }
+    }
+}
+
+/// Token bucket holding a minute's worth of requests.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_min: u32) -> Self {
+        Self {
+            tokens: per_min as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, per_min: u32) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.updated).as_secs_f64() * per_min as f64 / 60.0;
+        self.tokens = (self.tokens + refill).min(per_min as f64);
+        self.updated = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Keys by hash.
+    keys: HashMap<String, ApiKey>,
+    /// Rate limit state by key id.
+    buckets: HashMap<String, Bucket>,
+}
+
+/// API keys, shared by the server and the admin handlers.
+#[derive(Clone)]
+pub struct ApiKeyStore {
+    inner: Arc<Mutex<Inner>>,
+    path: Option<Arc<PathBuf>>,
+    public: Arc<HashSet<String>>,
+    audit: Arc<dyn AuditSink>,
+}
+
+impl ApiKeyStore {
+    /// A store that is not persisted.
+    pub fn in_memory() -> Self {
+        Self {
+            inner: Arc::default(),
+            path: None,
+            public: Arc::default(),
+            audit: Arc::new(TracingSink),
+        }
+    }
+
+    /// Load keys from `path`, saving changes back to it. The file is
+    /// created on the first change if it does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let keys: Vec<StoredKey> = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("parsing {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => anyhow::bail!("reading {}: {}", path.display(), e),
+        };
+        info!("Loaded {} API keys from {}", keys.len(), path.display());
+
+        let store = Self {
+            path: Some(Arc::new(path)),
+            ..Self::in_memory()
+        };
+        store.inner.lock().unwrap().keys = keys
+            .into_iter()
+            .map(|stored| (stored.key_hash, stored.key))
+            .collect();
+        Ok(store)
+    }
+
+    /// Let requests for these exact paths through without a key.
+    pub fn with_public(mut self, paths: &[&str]) -> Self {
+        self.public = Arc::new(paths.iter().map(|path| path.to_string()).collect());
+        self
+    }
+
+    /// Report created and revoked keys to `audit`.
+    pub fn with_audit(mut self, audit: Arc<dyn AuditSink>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Check a request's key against `path` and its rate limit.
+    ///
+    /// Returns `None` for public paths, and the key's principal otherwise.
+    pub fn authorize<T>(&self, req: &Request<T>) -> Result<Option<Principal>, Denied> {
+        let path = req.uri().path();
+        if self.public.contains(path) {
+            return Ok(None);
+        }
+
+        let key = request_key(req).ok_or(Denied::Unauthorized)?;
+        let mut inner = self.inner.lock().unwrap();
+        let api_key = inner.keys.get(&hash(key)).ok_or(Denied::Unauthorized)?;
+        if !api_key.allows(path) {
+            debug!("API key {} not scoped for {}", api_key.id, path);
+            return Err(Denied::Forbidden);
+        }
+
+        let principal = Principal {
+            key_id: api_key.id.clone(),
+            name: api_key.name.clone(),
+        };
+        if let Some(per_min) = api_key.rate_limit_per_min {
+            let bucket = inner
+                .buckets
+                .entry(principal.key_id.clone())
+                .or_insert_with(|| Bucket::new(per_min));
+            if !bucket.take(per_min) {
+                debug!("API key {} rate limited", principal.key_id);
+                return Err(Denied::RateLimited);
+            }
+        }
+        Ok(Some(principal))
+    }
+
+    /// The quota of the key with `key_id`, if it has one.
+    pub fn quota(&self, key_id: &str) -> Option<Quota> {
+        let inner = self.inner.lock().unwrap();
+        inner.keys.values().find(|key| key.id == key_id)?.quota
+    }
+
+    /// Issue a new key. Returns its settings and the key, which cannot be
+    /// recovered later.
+    pub fn create(&self, new: NewApiKey) -> anyhow::Result<(ApiKey, String)> {
+        let key = format!("{}{}", KEY_PREFIX, hex(&random_bytes::<24>()?));
+        let api_key = ApiKey {
+            id: format!("key_{}", hex(&random_bytes::<6>()?)),
+            name: new.name,
+            scopes: new.scopes,
+            rate_limit_per_min: new.rate_limit_per_min,
+            quota: new.quota,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.keys.insert(hash(&key), api_key.clone());
+        self.save(&inner)?;
+        self.audit.audit(&AuditEvent::ApiKeyCreated {
+            id: api_key.id.clone(),
+            name: api_key.name.clone(),
+        });
+        Ok((api_key, key))
+    }
+
+    /// Revoke the key with `id`. Returns whether it existed.
+    pub fn revoke(&self, id: &str) -> anyhow::Result<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.keys.len();
+        inner.keys.retain(|_, key| key.id != id);
+        inner.buckets.remove(id);
+        if inner.keys.len() == before {
+            return Ok(false);
+        }
+
+        self.save(&inner)?;
+        self.audit.audit(&AuditEvent::ApiKeyRevoked { id: id.to_string() });
+        Ok(true)
+    }
+
+    /// All keys, oldest first.
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys: Vec<ApiKey> = self.inner.lock().unwrap().keys.values().cloned().collect();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        keys
+    }
+
+    /// Write the keys to the backing file, if any.
+    fn save(&self, inner: &Inner) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut keys: Vec<StoredKey> = inner
+            .keys
+            .iter()
+            .map(|(key_hash, key)| StoredKey {
+                key_hash: key_hash.clone(),
+                key: key.clone(),
+            })
+            .collect();
+        keys.sort_by(|a, b| (a.key.created_at, &a.key.id).cmp(&(b.key.created_at, &b.key.id)));
+        write_atomic(path, &serde_json::to_vec_pretty(&keys)?)
+    }
+}
+
+/// The key from the `Authorization: Bearer` or `x-api-key` header.
+fn request_key<T>(req: &Request<T>) -> Option<&str> {
+    let headers = req.headers();
+    headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|value| value.to_str().ok()))
+        .map(str::trim)
+}
+
+/// Replace `path` with `contents` without leaving a partial file behind.
+fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn hash(key: &str) -> String {
+    hex(&Sha256::digest(key.as_bytes()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn random_bytes<const N: usize>() -> anyhow::Result<[u8; N]> {
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .ok_or_else(|| anyhow::anyhow!("no crypto provider installed"))?;
+    let mut bytes = [0; N];
+    provider
+        .secure_random
+        .fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("failed to generate random bytes"))?;
+    Ok(bytes)
+}
@@ -0,0 +1,575 @@
+//! Cluster backplane.
+//!
+//! Several server instances behind a load balancer each have their own
+//! [`Topics`] and [`Sessions`]. With a backplane, events published to a
+//! topic and messages pushed to every session are also relayed to the other
+//! instances through a shared pub/sub channel, so subscribers see the same
+//! events wherever their session landed.
+//!
+//! Saved session state is relayed too, so a client whose instance goes away
+//! can resume its session on another one with its affinity token (see
+//! [`crate::affinity`]).
+//!
+//! Every relayed message carries the ID of the instance it came from.
+//! Instances skip their own messages when the channel echoes them back, and
+//! deliver relayed messages locally only, so a message never goes round
+//! twice.
+//!
+//! [`Backplane`] is implemented for Redis pub/sub ([`RedisBackplane`]) and
+//! NATS ([`NatsBackplane`]), each speaking just enough of the protocol over
+//! a plain TCP connection.
+
+use crate::affinity::{MemoryStore, SessionState};
+use crate::sessions::{Delivery, Notification, Sessions};
+use crate::topics::Topics;
+use http::Uri;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufStream};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+/// Channel (Redis) or subject (NATS) the instances share.
+pub const CHANNEL: &str = "simple-http3.cluster";
+
+/// Messages waiting to be published before new ones are dropped.
+const OUTBOX_CAPACITY: usize = 1024;
+
+/// Relayed messages received but not yet delivered locally.
+const INBOX_CAPACITY: usize = 1024;
+
+/// Delay before subscribing again after losing the backplane.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Timeout for connecting to the backplane.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest message accepted from the backplane.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Future returned by [`Backplane`] methods.
+pub type BackplaneFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// A pub/sub channel shared by the instances of a cluster.
+pub trait Backplane: Send + Sync {
+    /// Publish `message` on `channel`.
+    fn publish<'a>(&'a self, channel: &'a str, message: Vec<u8>) -> BackplaneFuture<'a, ()>;
+
+    /// Subscribe to `channel`. Messages published there by any instance,
+    /// this one included, arrive on the receiver until the subscription is
+    /// lost, when it closes.
+    fn subscribe<'a>(&'a self, channel: &'a str) -> BackplaneFuture<'a, mpsc::Receiver<Vec<u8>>>;
+}
+
+/// Connect to the backplane at `url`: `redis://[:password@]host[:port]` or
+/// `nats://[user:password@]host[:port]`.
+pub fn from_url(url: &str) -> anyhow::Result<Arc<dyn Backplane>> {
+    let uri: Uri = url.parse()?;
+    let authority = uri
+        .authority()
+        .ok_or_else(|| anyhow::anyhow!("backplane URL {} has no host", url))?;
+    let credentials =
+        authority
+            .as_str()
+            .rsplit_once('@')
+            .map(|(userinfo, _)| match userinfo.split_once(':') {
+                Some((user, password)) => (user.to_string(), password.to_string()),
+                None => (userinfo.to_string(), String::new()),
+            });
+
+    match uri.scheme_str() {
+        Some("redis") => {
+            let addr = format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(6379)
+            );
+            let password = credentials.map(|(_, password)| password);
+            Ok(Arc::new(RedisBackplane::new(addr, password)))
+        }
+        Some("nats") => {
+            let addr = format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(4222)
+            );
+            Ok(Arc::new(NatsBackplane::new(addr, credentials)))
+        }
+        _ => anyhow::bail!(
+            "unsupported backplane {} (expected redis:// or nats://)",
+            url
+        ),
+    }
+}
+
+/// What instances relay to each other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Payload {
+    /// An event published to a topic, with its cluster-wide ID.
+    Topic {
+        topic: String,
+        id: String,
+        event: String,
+    },
+    /// A message pushed to every session.
+    Notify { message: String, delivery: Delivery },
+    /// State saved by a session that ended, for resuming it elsewhere.
+    Session { token: String, state: SessionState },
+    /// A session was resumed; other instances forget its saved state.
+    SessionTaken { token: String },
+}
+
+/// A [`Payload`] on the wire, as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    /// ID of the instance that published the message.
+    instance: String,
+    #[serde(flatten)]
+    payload: Payload,
+}
+
+/// A message for the publishing task.
+enum Outgoing {
+    Payload(Payload),
+    /// Acknowledged once everything queued before it has been published.
+    Flush(oneshot::Sender<()>),
+}
+
+struct Link {
+    instance: String,
+    outbox: mpsc::Sender<Outgoing>,
+}
+
+/// Where [`Topics`], [`Sessions`] and the [`MemoryStore`] hand messages to
+/// the backplane; does nothing until the backplane is started.
+#[derive(Clone, Default)]
+pub struct Relay {
+    link: Arc<OnceLock<Link>>,
+}
+
+impl Relay {
+    /// This instance's ID, once the backplane is started.
+    pub fn instance(&self) -> Option<&str> {
+        self.link.get().map(|link| link.instance.as_str())
+    }
+
+    /// Queue `payload` for the other instances.
+    pub fn send(&self, payload: Payload) {
+        if let Some(link) = self.link.get()
+            && link.outbox.try_send(Outgoing::Payload(payload)).is_err()
+        {
+            debug!("Backplane outbox full, dropping message");
+        }
+    }
+
+    /// Wait until everything queued so far has been published (or failed
+    /// to), e.g. before exiting.
+    pub async fn flush(&self) {
+        let Some(link) = self.link.get() else {
+            return;
+        };
+        let (done, wait) = oneshot::channel();
+        if link.outbox.send(Outgoing::Flush(done)).await.is_ok() {
+            let _ = wait.await;
+        }
+    }
+}
+
+/// Relay `topics`, `sessions` and the session state saved in `store` to the
+/// other instances on `backplane`, as instance `instance`.
+pub fn start(
+    backplane: Arc<dyn Backplane>,
+    instance: String,
+    topics: Topics,
+    sessions: Sessions,
+    store: Arc<MemoryStore>,
+) {
+    let (outbox, mut outbox_rx) = mpsc::channel(OUTBOX_CAPACITY);
+    for relay in [topics.relay(), sessions.relay(), store.relay()] {
+        let link = Link {
+            instance: instance.clone(),
+            outbox: outbox.clone(),
+        };
+        if relay.link.set(link).is_err() {
+            warn!("Backplane already started");
+            return;
+        }
+    }
+
+    let publisher = Arc::clone(&backplane);
+    let origin = instance.clone();
+    tokio::spawn(async move {
+        while let Some(outgoing) = outbox_rx.recv().await {
+            let payload = match outgoing {
+                Outgoing::Payload(payload) => payload,
+                Outgoing::Flush(done) => {
+                    let _ = done.send(());
+                    continue;
+                }
+            };
+            let message = Message {
+                instance: origin.clone(),
+                payload,
+            };
+            let bytes = match serde_json::to_vec(&message) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!("Failed to encode backplane message: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = publisher.publish(CHANNEL, bytes).await {
+                warn!("Failed to publish to the backplane: {:?}", e);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            match backplane.subscribe(CHANNEL).await {
+                Ok(mut messages) => {
+                    info!("Instance {} joined the backplane", instance);
+                    while let Some(bytes) = messages.recv().await {
+                        deliver(&bytes, &instance, &topics, &sessions, &store);
+                    }
+                    warn!("Lost the backplane subscription, reconnecting");
+                }
+                Err(e) => warn!("Failed to subscribe to the backplane: {:?}", e),
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+/// Deliver a message from another instance to this instance's topics,
+/// sessions or session store, without relaying it again.
+fn deliver(
+    bytes: &[u8],
+    instance: &str,
+    topics: &Topics,
+    sessions: &Sessions,
+    store: &MemoryStore,
+) {
+    let message: Message = match serde_json::from_slice(bytes) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("Ignoring malformed backplane message: {}", e);
+            return;
+        }
+    };
+    if message.instance == instance {
+        return;
+    }
+
+    match message.payload {
+        Payload::Topic { topic, id, event } => {
+            let delivered = topics.publish_relayed(&topic, id.into(), &event);
+            debug!(
+                "Relayed {} event from {} to {} subscribers",
+                topic, message.instance, delivered
+            );
+        }
+        Payload::Notify {
+            message: text,
+            delivery,
+        } => {
+            let notification = Notification {
+                message: text,
+                delivery,
+            };
+            let delivered = sessions.notify_local(None, notification);
+            debug!(
+                "Relayed message from {} to {} sessions",
+                message.instance, delivered
+            );
+        }
+        Payload::Session { token, state } => {
+            debug!("Saved session state relayed from {}", message.instance);
+            store.save_local(&token, state);
+        }
+        Payload::SessionTaken { token } => {
+            store.take_local(&token);
+        }
+    }
+}
+
+async fn connect(addr: &str) -> anyhow::Result<TcpStream> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out connecting to {}", addr))??;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Redis pub/sub.
+///
+/// Publishes on one connection and subscribes on another, since a
+/// subscribed Redis connection accepts no other commands.
+pub struct RedisBackplane {
+    addr: String,
+    password: Option<String>,
+    publisher: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+/// A Redis reply; arrays only hold scalars here.
+enum Reply {
+    /// A status or an integer.
+    Simple,
+    Error(String),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl RedisBackplane {
+    pub fn new(addr: String, password: Option<String>) -> Self {
+        Self {
+            addr,
+            password,
+            publisher: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> anyhow::Result<BufStream<TcpStream>> {
+        let mut stream = BufStream::new(connect(&self.addr).await?);
+        if let Some(password) = &self.password {
+            redis_command(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+        }
+        Ok(stream)
+    }
+}
+
+impl Backplane for RedisBackplane {
+    fn publish<'a>(&'a self, channel: &'a str, message: Vec<u8>) -> BackplaneFuture<'a, ()> {
+        Box::pin(async move {
+            let mut publisher = self.publisher.lock().await;
+            let stream = match publisher.as_mut() {
+                Some(stream) => stream,
+                None => publisher.insert(self.connect().await?),
+            };
+            let result = redis_command(stream, &[b"PUBLISH", channel.as_bytes(), &message]).await;
+            // Reconnect for the next message
+            if result.is_err() {
+                *publisher = None;
+            }
+            result.map(|_| ())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, channel: &'a str) -> BackplaneFuture<'a, mpsc::Receiver<Vec<u8>>> {
+        Box::pin(async move {
+            let mut stream = self.connect().await?;
+            redis_command(&mut stream, &[b"SUBSCRIBE", channel.as_bytes()]).await?;
+
+            let (tx, rx) = mpsc::channel(INBOX_CAPACITY);
+            tokio::spawn(async move {
+                loop {
+                    let reply = match read_reply(&mut stream).await {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            debug!("Redis subscription ended: {:?}", e);
+                            return;
+                        }
+                    };
+                    // ["message", channel, payload]
+                    if let Reply::Array(mut items) = reply
+                        && items.len() == 3
+                        && matches!(&items[0], Reply::Bulk(Some(kind)) if kind == b"message")
+                        && let Reply::Bulk(Some(payload)) = items.remove(2)
+                        && tx.send(payload).await.is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+            Ok(rx)
+        })
+    }
+}
+
+/// Send a command and read its reply, failing on an error reply.
+async fn redis_command(stream: &mut BufStream<TcpStream>, args: &[&[u8]]) -> anyhow::Result<Reply> {
+    let mut command = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        command.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        command.extend_from_slice(arg);
+        command.extend_from_slice(b"\r\n");
+    }
+    stream.write_all(&command).await?;
+    stream.flush().await?;
+
+    match read_reply(stream).await? {
+        Reply::Error(e) => anyhow::bail!("Redis error: {}", e),
+        reply => Ok(reply),
+    }
+}
+
+async fn read_reply(stream: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<Reply> {
+    let line = read_line(stream).await?;
+    match line.strip_prefix('*') {
+        Some(len) => {
+            let len: usize = len.parse()?;
+            let mut items = Vec::with_capacity(len.min(16));
+            for _ in 0..len {
+                let line = read_line(stream).await?;
+                items.push(read_scalar(stream, &line).await?);
+            }
+            Ok(Reply::Array(items))
+        }
+        None => read_scalar(stream, &line).await,
+    }
+}
+
+async fn read_scalar(
+    stream: &mut (impl AsyncBufRead + Unpin),
+    line: &str,
+) -> anyhow::Result<Reply> {
+    let (kind, rest) = line.split_at_checked(1).unwrap_or(("", ""));
+    match kind {
+        "+" | ":" => Ok(Reply::Simple),
+        "-" => Ok(Reply::Error(rest.to_string())),
+        "$" if rest == "-1" => Ok(Reply::Bulk(None)),
+        "$" => {
+            let data = read_payload(stream, rest.parse()?).await?;
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => anyhow::bail!("unexpected Redis reply {:?}", line),
+    }
+}
+
+/// NATS core pub/sub.
+///
+/// Publishes on the connection it subscribes on, which exists while a
+/// subscription is active.
+pub struct NatsBackplane {
+    addr: String,
+    credentials: Option<(String, String)>,
+    writer: Arc<Mutex<Option<OwnedWriteHalf>>>,
+}
+
+impl NatsBackplane {
+    pub fn new(addr: String, credentials: Option<(String, String)>) -> Self {
+        Self {
+            addr,
+            credentials,
+            writer: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Backplane for NatsBackplane {
+    fn publish<'a>(&'a self, channel: &'a str, message: Vec<u8>) -> BackplaneFuture<'a, ()> {
+        Box::pin(async move {
+            let mut writer = self.writer.lock().await;
+            let Some(stream) = writer.as_mut() else {
+                anyhow::bail!("not connected to NATS");
+            };
+            let mut command = format!("PUB {} {}\r\n", channel, message.len()).into_bytes();
+            command.extend_from_slice(&message);
+            command.extend_from_slice(b"\r\n");
+            stream.write_all(&command).await?;
+            Ok(())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, channel: &'a str) -> BackplaneFuture<'a, mpsc::Receiver<Vec<u8>>> {
+        Box::pin(async move {
+            let (reader, mut writer) = connect(&self.addr).await?.into_split();
+            let mut reader = BufReader::new(reader);
+
+            // The server introduces itself first
+            let info = read_line(&mut reader).await?;
+            if !info.starts_with("INFO ") {
+                anyhow::bail!("unexpected NATS greeting {:?}", info);
+            }
+            let mut connect = serde_json::json!({
+                "verbose": false,
+                "pedantic": false,
+                "name": "simple-http3",
+            });
+            if let Some((user, password)) = &self.credentials {
+                connect["user"] = user.as_str().into();
+                connect["pass"] = password.as_str().into();
+            }
+            let hello = format!("CONNECT {}\r\nSUB {} 1\r\nPING\r\n", connect, channel);
+            writer.write_all(hello.as_bytes()).await?;
+            // The PONG confirms the subscription
+            loop {
+                match read_line(&mut reader).await?.as_str() {
+                    "PONG" => break,
+                    line if line.starts_with("-ERR") => anyhow::bail!("NATS error: {}", line),
+                    _ => {}
+                }
+            }
+            *self.writer.lock().await = Some(writer);
+
+            let (tx, rx) = mpsc::channel(INBOX_CAPACITY);
+            let writer = Arc::clone(&self.writer);
+            tokio::spawn(async move {
+                if let Err(e) = read_nats(&mut reader, &writer, &tx).await {
+                    debug!("NATS subscription ended: {:?}", e);
+                }
+                *writer.lock().await = None;
+            });
+            Ok(rx)
+        })
+    }
+}
+
+/// Forward `MSG` payloads to `tx` and answer the server's `PING`s.
+async fn read_nats(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    writer: &Mutex<Option<OwnedWriteHalf>>,
+    tx: &mpsc::Sender<Vec<u8>>,
+) -> anyhow::Result<()> {
+    loop {
+        let line = read_line(reader).await?;
+        if let Some(args) = line.strip_prefix("MSG ") {
+            // MSG <subject> <sid> [reply-to] <bytes>
+            let len = args
+                .rsplit(' ')
+                .next()
+                .and_then(|len| len.parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("malformed NATS message {:?}", line))?;
+            let payload = read_payload(reader, len).await?;
+            if tx.send(payload).await.is_err() {
+                return Ok(());
+            }
+        } else if line == "PING" {
+            if let Some(writer) = writer.lock().await.as_mut() {
+                writer.write_all(b"PONG\r\n").await?;
+            }
+        } else if line.starts_with("-ERR") {
+            warn!("NATS error: {}", line);
+        }
+    }
+}
+
+/// Read a CRLF-terminated line, without the CRLF.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        anyhow::bail!("connection closed");
+    }
+    line.truncate(line.trim_end_matches(['\r', '\n']).len());
+    Ok(line)
+}
+
+/// Read `len` bytes followed by a CRLF.
+async fn read_payload(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    if len > MAX_MESSAGE_SIZE {
+        anyhow::bail!("backplane message of {} bytes is too large", len);
+    }
+    let mut payload = vec![0; len + 2];
+    reader.read_exact(&mut payload).await?;
+    payload.truncate(len);
+    Ok(payload)
+}
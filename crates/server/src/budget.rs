@@ -0,0 +1,181 @@
+//! Per-request deadlines.
+//!
+//! A client can say how long it is willing to wait with a `request-timeout`
+//! header, in milliseconds, or a `grpc-timeout` header in gRPC's syntax
+//! (e.g. `500m`, `2S`); given both, the sooner deadline wins. The server
+//! attaches the deadline to the request extensions, where handlers find it
+//! with [`RequestDeadline::of`], and cancels the request when it passes
+//! (see [`crate::context`]): REST requests are answered with 504 Gateway
+//! Timeout, and streaming responses end.
+//!
+//! REST responses report the budget left in [`BUDGET_REMAINING`].
+//! Handlers that call on other services pass what is left on with
+//! [`RequestDeadline::header_value`], so the deadline holds end to end.
+
+use http::{HeaderMap, HeaderValue, Request};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Request header with the timeout in milliseconds.
+pub const REQUEST_TIMEOUT: &str = "request-timeout";
+
+/// Request header with the timeout in gRPC's syntax: up to 8 digits and a
+/// unit, `H`, `M`, `S`, `m`, `u` or `n`.
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// Response header with the milliseconds left of the request's deadline.
+pub const BUDGET_REMAINING: &str = "request-budget-remaining";
+
+/// When the client stops waiting for a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestDeadline {
+    pub at: Instant,
+}
+
+impl RequestDeadline {
+    /// The deadline the request headers ask for, from now, if any. Fails
+    /// if a timeout header is malformed.
+    pub fn from_headers(headers: &HeaderMap) -> anyhow::Result<Option<Self>> {
+        let mut timeout = None;
+        if let Some(value) = headers.get(REQUEST_TIMEOUT) {
+            let millis = value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| anyhow::anyhow!("invalid {}: {:?}", REQUEST_TIMEOUT, value))?;
+            timeout = Some(Duration::from_millis(millis));
+        }
+        if let Some(value) = headers.get(GRPC_TIMEOUT) {
+            let grpc = parse_grpc_timeout(value)
+                .ok_or_else(|| anyhow::anyhow!("invalid {}: {:?}", GRPC_TIMEOUT, value))?;
+            timeout = Some(timeout.map_or(grpc, |timeout: Duration| timeout.min(grpc)));
+        }
+        // A timeout too long to add is no deadline at all
+        Ok(timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout))
+            .map(|at| Self { at }))
+    }
+
+    /// The deadline the server attached to `req`, found in its extensions.
+    #[allow(dead_code)]
+    pub fn of<B>(req: &Request<B>) -> Option<Self> {
+        req.extensions().get::<RequestDeadline>().copied()
+    }
+
+    /// Time left until the deadline; zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// The time left, in milliseconds, as a header value for
+    /// [`BUDGET_REMAINING`] or a [`REQUEST_TIMEOUT`] sent on.
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from(self.remaining().as_millis() as u64)
+    }
+}
+
+/// A `grpc-timeout` value as a duration.
+fn parse_grpc_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    if !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 3600),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grpc(value: &str) -> Option<Duration> {
+        parse_grpc_timeout(&HeaderValue::from_str(value).unwrap())
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    /// Whether the deadline `headers` ask for is `timeout` from when they
+    /// were parsed.
+    fn is_deadline(headers: &HeaderMap, timeout: Duration) -> bool {
+        let before = Instant::now();
+        let deadline = RequestDeadline::from_headers(headers).unwrap().unwrap();
+        let after = Instant::now();
+        before + timeout <= deadline.at && deadline.at <= after + timeout
+    }
+
+    #[test]
+    fn parses_grpc_units() {
+        assert_eq!(grpc("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(grpc("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(grpc("4S"), Some(Duration::from_secs(4)));
+        assert_eq!(grpc("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(grpc("250u"), Some(Duration::from_micros(250)));
+        assert_eq!(grpc("100n"), Some(Duration::from_nanos(100)));
+        assert_eq!(grpc("0S"), Some(Duration::ZERO));
+        // The most digits allowed, in the largest unit, does not overflow
+        assert_eq!(grpc("99999999H"), Some(Duration::from_secs(99_999_999 * 3600)));
+    }
+
+    #[test]
+    fn rejects_malformed_grpc_timeouts() {
+        for value in ["", "S", "5", "123456789S", "5s", "5h", "-5S", "+5S", " 5S", "5.0S", "5SS"] {
+            assert_eq!(grpc(value), None, "{:?}", value);
+        }
+        assert_eq!(parse_grpc_timeout(&HeaderValue::from_bytes(b"5\xffS").unwrap()), None);
+    }
+
+    #[test]
+    fn takes_the_sooner_deadline() {
+        assert_eq!(RequestDeadline::from_headers(&HeaderMap::new()).unwrap(), None);
+        let trimmed = headers(&[(REQUEST_TIMEOUT, " 250 ")]);
+        assert!(is_deadline(&trimmed, Duration::from_millis(250)));
+
+        let both = headers(&[(REQUEST_TIMEOUT, "1000"), (GRPC_TIMEOUT, "2S")]);
+        assert!(is_deadline(&both, Duration::from_secs(1)));
+        let both = headers(&[(REQUEST_TIMEOUT, "1000"), (GRPC_TIMEOUT, "20m")]);
+        assert!(is_deadline(&both, Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        for value in ["", "soon", "-1", "1.5", "18446744073709551616"] {
+            let malformed = headers(&[(REQUEST_TIMEOUT, value)]);
+            assert!(RequestDeadline::from_headers(&malformed).is_err(), "{:?}", value);
+        }
+        let malformed = headers(&[(GRPC_TIMEOUT, "1000")]);
+        assert!(RequestDeadline::from_headers(&malformed).is_err());
+        // One malformed header fails the request, even with a valid other
+        let mixed = headers(&[(REQUEST_TIMEOUT, "1000"), (GRPC_TIMEOUT, "soon")]);
+        assert!(RequestDeadline::from_headers(&mixed).is_err());
+    }
+
+    #[test]
+    fn takes_the_longest_timeouts() {
+        let longest = headers(&[(REQUEST_TIMEOUT, &u64::MAX.to_string())]);
+        assert!(RequestDeadline::from_headers(&longest).is_ok());
+        let longest = headers(&[(GRPC_TIMEOUT, "99999999H")]);
+        assert!(RequestDeadline::from_headers(&longest).is_ok());
+    }
+}
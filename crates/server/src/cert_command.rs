@@ -0,0 +1,166 @@
+//! `server cert show` and `server cert verify --hash <hex>`.
+//!
+//! Certificates are generated when the server starts, so both commands ask
+//! the running server: they connect to it like a client and look at the
+//! certificate it serves. `show` prints its SHA-256 fingerprint (the hash
+//! browsers pin with `serverCertificateHashes`), validity and SANs;
+//! `verify` fails unless its hash is the one given, e.g. the hash pasted
+//! into the browser demo.
+//!
+//! `--addr` is the server to ask (`BIND_ADDR` by default, or
+//! `127.0.0.1:4433`), and `--name` the SNI hostname to ask for, to see the
+//! certificate of a virtual host (`localhost` by default).
+
+use crate::api_keys::hex;
+use common::tls::insecure_verifier;
+use quinn::Endpoint;
+use quinn::crypto::rustls::QuicClientConfig;
+use rustls::pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+const USAGE: &str = "usage: server cert show [--addr HOST:PORT] [--name HOSTNAME]
+       server cert verify --hash HEX [--addr HOST:PORT] [--name HOSTNAME]";
+
+/// Longest validity browsers accept for a pinned certificate.
+const MAX_PINNED_VALIDITY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Run `server cert <args>`.
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    let (command, options) = args.split_first().ok_or_else(|| anyhow::anyhow!(USAGE))?;
+    let mut addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:4433".to_string());
+    let mut name = "localhost".to_string();
+    let mut hash = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.clone();
+        match option.as_str() {
+            "--addr" => addr = value,
+            "--name" => name = value,
+            "--hash" => hash = Some(value),
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+
+    match (command.as_str(), hash) {
+        ("show", None) => show(&served_cert(&addr, &name).await?),
+        ("verify", Some(hash)) => verify(&served_cert(&addr, &name).await?, &hash),
+        _ => anyhow::bail!(USAGE),
+    }
+}
+
+/// The certificate the server at `addr` serves for `name`.
+async fn served_cert(addr: &str, name: &str) -> anyhow::Result<CertificateDer<'static>> {
+    let mut remote = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} has no addresses", addr))?;
+    // A server listening on every interface is reachable on loopback
+    if remote.ip().is_unspecified() {
+        remote.set_ip(match remote {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+
+    // Nothing is trusted on the way: the certificate is only looked at
+    let provider = common::tls::crypto_provider(false);
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(insecure_verifier())
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let client = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+
+    let local: SocketAddr = match remote {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(client);
+    let conn = endpoint
+        .connect(remote, name)?
+        .await
+        .map_err(|e| anyhow::anyhow!("connecting to {}: {}", remote, e))?;
+    let certs = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+    conn.close(0u32.into(), b"done");
+    endpoint.wait_idle().await;
+    certs
+        .and_then(|certs| certs.into_iter().next())
+        .ok_or_else(|| anyhow::anyhow!("{} sent no certificate", remote))
+}
+
+fn show(der: &[u8]) -> anyhow::Result<()> {
+    let (_, cert) = parse_x509_certificate(der)
+        .map_err(|e| anyhow::anyhow!("parsing the certificate: {}", e))?;
+    let not_before = time(cert.validity().not_before.timestamp());
+    let not_after = time(cert.validity().not_after.timestamp());
+    let sans: Vec<String> = cert
+        .subject_alternative_name()?
+        .map(|san| san.value.general_names.iter().map(general_name).collect())
+        .unwrap_or_default();
+
+    println!("Fingerprint (SHA-256): {}", hex(&Sha256::digest(der)));
+    println!("Subject:     {}", cert.subject());
+    println!("Valid from:  {}", format_time(not_before));
+    match not_after.duration_since(SystemTime::now()) {
+        Ok(left) => println!(
+            "Valid until: {} ({}h left)",
+            format_time(not_after),
+            left.as_secs() / 3600
+        ),
+        Err(_) => println!("Valid until: {} (expired)", format_time(not_after)),
+    }
+    println!("SANs:        {}", sans.join(", "));
+    if not_after.duration_since(not_before).unwrap_or_default() > MAX_PINNED_VALIDITY {
+        println!("Note: valid for over 14 days, so browsers will not accept its hash");
+    }
+    Ok(())
+}
+
+fn verify(der: &[u8], expected: &str) -> anyhow::Result<()> {
+    // Accept the hash as printed by tools that separate bytes with colons
+    let expected = expected.replace(':', "").to_ascii_lowercase();
+    let served = hex(&Sha256::digest(der));
+    if expected != served {
+        anyhow::bail!("the server serves the certificate with hash {}, not {}", served, expected);
+    }
+    println!("OK: the server serves the certificate with hash {}", served);
+    Ok(())
+}
+
+/// `name` as `openssl` prints it, e.g. `DNS:localhost` or `IP:127.0.0.1`.
+pub fn general_name(name: &GeneralName<'_>) -> String {
+    match name {
+        GeneralName::DNSName(name) => format!("DNS:{}", name),
+        GeneralName::IPAddress(bytes) => {
+            if let Ok(octets) = <[u8; 4]>::try_from(*bytes) {
+                format!("IP:{}", Ipv4Addr::from(octets))
+            } else if let Ok(octets) = <[u8; 16]>::try_from(*bytes) {
+                format!("IP:{}", Ipv6Addr::from(octets))
+            } else {
+                name.to_string()
+            }
+        }
+        GeneralName::RFC822Name(email) => format!("email:{}", email),
+        GeneralName::URI(uri) => format!("URI:{}", uri),
+        name => name.to_string(),
+    }
+}
+
+fn time(timestamp: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64)
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
@@ -0,0 +1,323 @@
+//! WebTransport certificate rotation.
+//!
+//! Certificates pinned via `serverCertificateHashes` may be valid for at
+//! most 14 days. To let browsers survive a rotation, the server always holds
+//! the *next* certificate as well: its hash is logged at startup so clients
+//! can pin both, and it becomes valid shortly before the current one is
+//! rotated out. Handlers serve both hashes from a [`CertHashes`], kept up
+//! to date across rotations, so clients can fetch them rather than copy
+//! them from the log.
+//!
+//! Both are generated anew each time the server starts, unless it is given
+//! a cache directory (`ServerConfig::cert_cache_dir`): then they are kept
+//! there as `current.pem` and `next.pem`, and reused until the current one
+//! is due for rotation, so pinned hashes survive restarts. Certificates for
+//! other hostnames than configured are not reused.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use common::tls::{generate_webtransport_cert, generate_webtransport_cert_from, CertificateChain};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use x509_parser::extensions::GeneralName;
+use x509_parser::parse_x509_certificate;
+
+/// Slack before the rotation time at which the next certificate becomes
+/// valid, to tolerate clock skew between server and browser.
+const CLOCK_SKEW: Duration = Duration::from_secs(60 * 60);
+
+/// How long WebTransport certificates are valid for.
+const LIFETIME: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Metadata about the served certificates that clients need to connect.
+#[derive(Debug, Clone)]
+pub struct CertInfo {
+    /// SHA-256 hash (hex) of the certificate currently served.
+    pub hash: String,
+    /// SHA-256 hash (hex) of the certificate served after the next rotation.
+    pub next_hash: String,
+    /// SPKI pin of the current certificate, see [`spki_pin`].
+    pub spki: String,
+    /// SPKI pin of the next certificate.
+    pub next_spki: String,
+    /// When the current certificate expires.
+    pub not_after: SystemTime,
+    /// When the server will rotate to the next certificate.
+    pub rotates_at: SystemTime,
+}
+
+/// The hashes of the served certificates, as of the last rotation; clones
+/// share them.
+#[derive(Clone, Default)]
+pub struct CertHashes {
+    info: Arc<RwLock<Option<CertInfo>>>,
+}
+
+impl CertHashes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The served certificates, or `None` until a server publishes them.
+    pub fn get(&self) -> Option<CertInfo> {
+        self.info.read().unwrap().clone()
+    }
+
+    fn set(&self, info: CertInfo) {
+        *self.info.write().unwrap() = Some(info);
+    }
+}
+
+/// The current and next WebTransport certificates.
+pub struct CertRotation {
+    hostnames: Vec<String>,
+    margin: Duration,
+    current: CertificateChain,
+    next: CertificateChain,
+    /// Where the certificates are kept across restarts, if anywhere.
+    cache_dir: Option<PathBuf>,
+    published: CertHashes,
+}
+
+impl CertRotation {
+    /// Generate a current certificate and its successor, or reuse those
+    /// kept in `cache_dir` if they are still good.
+    ///
+    /// The current certificate is rotated out `margin` before it expires,
+    /// which must leave it in use for longer than [`CLOCK_SKEW`].
+    pub fn new(
+        hostnames: &[String],
+        margin: Duration,
+        cache_dir: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        if margin >= LIFETIME - CLOCK_SKEW {
+            anyhow::bail!(
+                "certificate rotation margin of {}s leaves no time to use the certificate",
+                margin.as_secs()
+            );
+        }
+        let now = SystemTime::now();
+        let in_use = |cert: &CertificateChain| {
+            cert.not_after.checked_sub(margin).is_some_and(|rotates_at| rotates_at > now)
+        };
+        let cached = cache_dir.and_then(|dir| restore(dir, hostnames));
+        let (current, next) = match cached {
+            Some((current, next)) if in_use(&current) => {
+                info!("Reusing cached certificates");
+                (current, next)
+            }
+            // Rotated while the server was stopped
+            Some((_, next)) if in_use(&next) => {
+                info!("Reusing cached next certificate");
+                let successor = generate_successor(hostnames, &next, margin)?;
+                (next, successor)
+            }
+            _ => {
+                let current = generate_webtransport_cert(hostnames)?;
+                let next = generate_successor(hostnames, &current, margin)?;
+                (current, next)
+            }
+        };
+        let rotation = Self {
+            hostnames: hostnames.to_vec(),
+            margin,
+            current,
+            next,
+            cache_dir: cache_dir.map(Path::to_path_buf),
+            published: CertHashes::new(),
+        };
+        rotation.published.set(rotation.info());
+        rotation.save();
+        Ok(rotation)
+    }
+
+    /// The certificate currently being served.
+    pub fn current(&self) -> &CertificateChain {
+        &self.current
+    }
+
+    /// Time remaining until the current certificate should be rotated out.
+    pub fn until_rotation(&self) -> Duration {
+        self.rotates_at()
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    fn rotates_at(&self) -> SystemTime {
+        self.current.not_after.checked_sub(self.margin).unwrap_or(UNIX_EPOCH)
+    }
+
+    /// Metadata about the current and next certificates.
+    pub fn info(&self) -> CertInfo {
+        CertInfo {
+            hash: self.current.fingerprint_hex(),
+            next_hash: self.next.fingerprint_hex(),
+            spki: spki_pin(&self.current),
+            next_spki: spki_pin(&self.next),
+            not_after: self.current.not_after,
+            rotates_at: self.rotates_at(),
+        }
+    }
+
+    /// Promote the next certificate to current and generate a new successor.
+    pub fn rotate(&mut self) -> anyhow::Result<()> {
+        let next = generate_successor(&self.hostnames, &self.next, self.margin)?;
+        self.current = std::mem::replace(&mut self.next, next);
+        self.published.set(self.info());
+        self.save();
+        Ok(())
+    }
+
+    /// Keep `hashes` up to date from now on.
+    pub fn publish_to(&mut self, hashes: CertHashes) {
+        hashes.set(self.info());
+        self.published = hashes;
+    }
+
+    /// Keep the certificates in the cache directory, if there is one. A
+    /// failure only costs the next restart its pinned hashes.
+    fn save(&self) {
+        let Some(dir) = &self.cache_dir else {
+            return;
+        };
+        let saved = std::fs::create_dir_all(dir)
+            .and_then(|()| write_private(&dir.join(CURRENT), &self.current.to_pem()))
+            .and_then(|()| write_private(&dir.join(NEXT), &self.next.to_pem()));
+        if let Err(e) = saved {
+            warn!("Failed to cache certificates in {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// The SHA-256 hash (base64) of the certificate's public key, as Chrome's
+/// `--ignore-certificate-errors-spki-list` takes it. Empty if the
+/// certificate cannot be parsed, which ours always can.
+pub fn spki_pin(cert: &CertificateChain) -> String {
+    let leaf = cert.cert_chain.first().map(|c| c.as_ref()).unwrap_or_default();
+    match parse_x509_certificate(leaf) {
+        Ok((_, cert)) => BASE64.encode(Sha256::digest(cert.public_key().raw)),
+        Err(_) => String::new(),
+    }
+}
+
+const CURRENT: &str = "current.pem";
+const NEXT: &str = "next.pem";
+
+/// The current and next certificates kept in `dir`, if both are there and
+/// are for `hostnames`.
+fn restore(dir: &Path, hostnames: &[String]) -> Option<(CertificateChain, CertificateChain)> {
+    let cached = |name: &str| {
+        let path = dir.join(name);
+        match load(&path, hostnames) {
+            Ok(cert) => cert,
+            Err(e) => {
+                warn!("Not reusing cached certificate {}: {:#}", path.display(), e);
+                None
+            }
+        }
+    };
+    Some((cached(CURRENT)?, cached(NEXT)?))
+}
+
+/// The certificate in the PEM file at `path`, or `None` if there is none or
+/// it is for other hostnames.
+fn load(path: &Path, hostnames: &[String]) -> anyhow::Result<Option<CertificateChain>> {
+    let pem = match std::fs::read(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let cert_chain = CertificateDer::pem_slice_iter(&pem).collect::<Result<Vec<_>, _>>()?;
+    let private_key = PrivateKeyDer::from_pem_slice(&pem)?;
+    let leaf = cert_chain
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("no certificate"))?;
+    let (_, cert) = parse_x509_certificate(leaf)?;
+
+    let mut names: Vec<String> = cert
+        .subject_alternative_name()?
+        .map(|san| san.value.general_names.iter().filter_map(san_name).collect())
+        .unwrap_or_default();
+    let mut wanted: Vec<String> = hostnames.iter().map(|name| normalize(name)).collect();
+    names.sort();
+    wanted.sort();
+    if names != wanted {
+        info!("Cached certificate {} is for other hostnames", path.display());
+        return Ok(None);
+    }
+
+    let not_after = cert.validity().not_after.timestamp().max(0) as u64;
+    Ok(Some(CertificateChain {
+        cert_chain,
+        private_key,
+        not_after: UNIX_EPOCH + Duration::from_secs(not_after),
+    }))
+}
+
+/// A DNS or IP subject alternative name, as [`normalize`] writes it.
+fn san_name(name: &GeneralName<'_>) -> Option<String> {
+    match name {
+        GeneralName::DNSName(name) => Some(normalize(name)),
+        GeneralName::IPAddress(bytes) => {
+            let ip = match bytes.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?),
+                _ => IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?),
+            };
+            Some(ip.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// A hostname lowercased, or an IP address in its canonical form.
+fn normalize(name: &str) -> String {
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => name.to_ascii_lowercase(),
+    }
+}
+
+/// Write `contents` to `path`, readable by the owner only since it holds a
+/// private key. Written beside it first, so a crash never leaves half a
+/// file.
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let partial = path.with_extension("pem.partial");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&partial)?.write_all(contents.as_bytes())?;
+    std::fs::rename(&partial, path)
+}
+
+/// Generate the certificate that takes over from `current`.
+fn generate_successor(
+    hostnames: &[String],
+    current: &CertificateChain,
+    margin: Duration,
+) -> anyhow::Result<CertificateChain> {
+    let not_before = current.not_after - margin - CLOCK_SKEW;
+    generate_webtransport_cert_from(hostnames, not_before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_margins_past_the_lifetime() {
+        let hostnames = ["localhost".to_string()];
+        for margin in [LIFETIME, LIFETIME - CLOCK_SKEW, Duration::from_secs(u64::MAX)] {
+            assert!(CertRotation::new(&hostnames, margin, None).is_err(), "{:?}", margin);
+        }
+        let margin = LIFETIME - CLOCK_SKEW - Duration::from_secs(60);
+        assert!(CertRotation::new(&hostnames, margin, None).is_ok());
+    }
+}
@@ -0,0 +1,163 @@
+//! Stream IO in `Bytes` chunks.
+//!
+//! `AsyncRead` and `AsyncWrite` copy every payload through a caller-owned
+//! buffer. WebTransport streams already hand out received data as `Bytes`
+//! and send from any `Buf`, so [`ChunkRead`] and [`ChunkWrite`] pass those
+//! chunks through untouched, and [`copy`] and [`copy_bidirectional`] move
+//! data between streams without an intermediate buffer. [`IoChunks`] adapts
+//! plain byte streams, such as the in-memory pipes used for replay.
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::poll_fn;
+use h3::quic::{self, SendStreamUnframed as _};
+use h3_webtransport::stream::{RecvStream, SendStream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Bytes [`IoChunks`] reads at a time.
+const READ_SIZE: usize = 16 * 1024;
+
+/// A stream that is read in chunks.
+pub trait ChunkRead {
+    /// Poll for the next chunk; `None` at the end of the stream.
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Bytes>>>;
+}
+
+/// A stream that is written in chunks.
+pub trait ChunkWrite {
+    /// Write some of `chunk`, advancing it past what was written.
+    fn poll_write_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+        chunk: &mut Bytes,
+    ) -> Poll<io::Result<()>>;
+
+    /// Finish the stream once everything written has been sent.
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// Read the next chunk from `stream`.
+pub async fn read_chunk<R: ChunkRead>(stream: &mut R) -> io::Result<Option<Bytes>> {
+    poll_fn(|cx| stream.poll_read_chunk(cx)).await
+}
+
+/// Write all of `chunk` to `stream`.
+pub async fn write_chunk<W: ChunkWrite>(stream: &mut W, mut chunk: Bytes) -> io::Result<()> {
+    while !chunk.is_empty() {
+        poll_fn(|cx| stream.poll_write_chunk(cx, &mut chunk)).await?;
+    }
+    Ok(())
+}
+
+/// Copy `recv` to `send` until `recv` ends, then finish `send`. Returns the
+/// number of bytes copied.
+#[allow(dead_code)]
+pub async fn copy<R, W>(recv: &mut R, send: &mut W) -> io::Result<u64>
+where
+    R: ChunkRead,
+    W: ChunkWrite,
+{
+    let mut copied = 0;
+    while let Some(chunk) = read_chunk(recv).await? {
+        copied += chunk.len() as u64;
+        write_chunk(send, chunk).await?;
+    }
+    poll_fn(|cx| send.poll_finish(cx)).await?;
+    Ok(copied)
+}
+
+/// Copy between two stream pairs in both directions at once, e.g. to proxy
+/// a stream to another server. Returns the bytes copied from `a` to `b` and
+/// from `b` to `a`.
+#[allow(dead_code)]
+pub async fn copy_bidirectional<RA, WA, RB, WB>(
+    a: (&mut RA, &mut WA),
+    b: (&mut RB, &mut WB),
+) -> io::Result<(u64, u64)>
+where
+    RA: ChunkRead,
+    WA: ChunkWrite,
+    RB: ChunkRead,
+    WB: ChunkWrite,
+{
+    let (a_recv, a_send) = a;
+    let (b_recv, b_send) = b;
+    tokio::try_join!(copy(a_recv, b_send), copy(b_recv, a_send))
+}
+
+impl<S, B> ChunkRead for RecvStream<S, B>
+where
+    S: quic::RecvStream,
+    B: Buf,
+{
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Bytes>>> {
+        quic::RecvStream::poll_data(self, cx).map_err(io::Error::other)
+    }
+}
+
+impl<S, B> ChunkWrite for SendStream<S, B>
+where
+    S: quic::SendStreamUnframed<B>,
+    B: Buf,
+{
+    fn poll_write_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+        chunk: &mut Bytes,
+    ) -> Poll<io::Result<()>> {
+        // Advances `chunk` past what was written
+        self.poll_send(cx, chunk).map_ok(|_| ()).map_err(io::Error::other)
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        quic::SendStream::poll_finish(self, cx).map_err(io::Error::other)
+    }
+}
+
+/// A byte stream read and written in chunks, copying through a buffer.
+pub struct IoChunks<T> {
+    io: T,
+    buf: BytesMut,
+}
+
+impl<T> IoChunks<T> {
+    pub fn new(io: T) -> Self {
+        Self {
+            io,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> ChunkRead for IoChunks<T> {
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Bytes>>> {
+        self.buf.reserve(READ_SIZE);
+        let n = std::task::ready!(tokio_util::io::poll_read_buf(
+            Pin::new(&mut self.io),
+            cx,
+            &mut self.buf
+        ))?;
+        Poll::Ready(Ok((n > 0).then(|| self.buf.split().freeze())))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> ChunkWrite for IoChunks<T> {
+    fn poll_write_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+        chunk: &mut Bytes,
+    ) -> Poll<io::Result<()>> {
+        let n = std::task::ready!(Pin::new(&mut self.io).poll_write(cx, chunk))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+        }
+        chunk.advance(n);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,157 @@
+//! Coalescing of small writes on streaming responses.
+//!
+//! Every `send_data` on an h3 stream becomes its own DATA frame, and chatty
+//! handlers (an SSE event line here, an NDJSON record there) end up sending
+//! many tiny frames and packets. A [`CoalescedStream`] collects chunks
+//! written in quick succession and sends them as one frame once they add
+//! up to a threshold, or once the oldest has waited for the flush delay, so
+//! a lone event is never held back for long.
+//!
+//! A stream can also be shaped (see [`crate::shaping`]): frames then go out
+//! no sooner than the delay after their first chunk was written, and no
+//! faster than the bandwidth limit.
+
+use crate::shaping::{Shaper, Shaping};
+use bytes::{Bytes, BytesMut};
+use h3::server::RequestStream;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Buffered bytes that are sent at once.
+pub const DEFAULT_THRESHOLD: usize = 16 * 1024;
+
+/// Longest a chunk waits for others to join it.
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(10);
+
+/// Chunks queued for the writer before `send` waits.
+const QUEUE_DEPTH: usize = 64;
+
+type Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// A response body writer that coalesces small chunks.
+pub struct CoalescedStream {
+    /// Chunks and when they were written.
+    tx: mpsc::Sender<(Instant, Bytes)>,
+    writer: JoinHandle<anyhow::Result<()>>,
+}
+
+impl CoalescedStream {
+    /// Coalesce writes to `stream` (whose response headers have been sent)
+    /// with the default threshold and delay.
+    #[allow(dead_code)]
+    pub fn new(stream: Stream) -> Self {
+        Self::with_limits(stream, DEFAULT_THRESHOLD, DEFAULT_DELAY)
+    }
+
+    /// Coalesce writes to `stream` into frames of up to `threshold` bytes,
+    /// holding a chunk back for at most `delay`.
+    #[allow(dead_code)]
+    pub fn with_limits(stream: Stream, threshold: usize, delay: Duration) -> Self {
+        Self::start(stream, threshold, delay, Shaping::default())
+    }
+
+    /// Coalesce writes to `stream` with the default threshold and delay,
+    /// and send them shaped by `shaping`.
+    pub fn shaped(stream: Stream, shaping: Shaping) -> Self {
+        Self::start(stream, DEFAULT_THRESHOLD, DEFAULT_DELAY, shaping)
+    }
+
+    fn start(stream: Stream, threshold: usize, delay: Duration, shaping: Shaping) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_DEPTH);
+        let shaper = Shaper::new(shaping);
+        Self {
+            tx,
+            writer: tokio::spawn(write(stream, rx, threshold, delay, shaper)),
+        }
+    }
+
+    /// Queue `data` to be sent.
+    pub async fn send(&self, data: impl Into<Bytes>) -> anyhow::Result<()> {
+        self.tx
+            .send((Instant::now(), data.into()))
+            .await
+            .map_err(|_| anyhow::anyhow!("response stream closed"))
+    }
+
+    /// Send what is still buffered and finish the stream.
+    pub async fn finish(self) -> anyhow::Result<()> {
+        drop(self.tx);
+        self.writer.await?
+    }
+}
+
+async fn write(
+    mut stream: Stream,
+    mut rx: mpsc::Receiver<(Instant, Bytes)>,
+    threshold: usize,
+    delay: Duration,
+    mut shaper: Shaper,
+) -> anyhow::Result<()> {
+    let mut buf = BytesMut::new();
+    let mut deadline: Option<Instant> = None;
+    // When the first chunk in `buf` was written
+    let mut written = Instant::now();
+
+    loop {
+        tokio::select! {
+            // Take everything already queued before the timer fires
+            biased;
+
+            data = rx.recv() => {
+                let Some((at, data)) = data else {
+                    break;
+                };
+                // Large chunks go out as they are, without a copy
+                if buf.is_empty() && data.len() >= threshold {
+                    send(&mut stream, &mut shaper, at, data).await?;
+                    continue;
+                }
+                // When shaped, chunks that queued up while the writer was
+                // waiting keep their own delays
+                if !buf.is_empty() && !shaper.is_none() && at > written + delay {
+                    send(&mut stream, &mut shaper, written, buf.split().freeze()).await?;
+                    deadline = None;
+                }
+                if buf.is_empty() {
+                    written = at;
+                }
+                buf.extend_from_slice(&data);
+                if buf.len() >= threshold {
+                    send(&mut stream, &mut shaper, written, buf.split().freeze()).await?;
+                    deadline = None;
+                } else if deadline.is_none() {
+                    deadline = Some(Instant::now() + delay);
+                }
+            }
+
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() =>
+            {
+                send(&mut stream, &mut shaper, written, buf.split().freeze()).await?;
+                deadline = None;
+            }
+        }
+    }
+
+    if !buf.is_empty() {
+        send(&mut stream, &mut shaper, written, buf.freeze()).await?;
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Send `data`, written at `at`, as paced by `shaper`.
+async fn send(
+    stream: &mut Stream,
+    shaper: &mut Shaper,
+    at: Instant,
+    data: Bytes,
+) -> anyhow::Result<()> {
+    for piece in shaper.pieces(data) {
+        shaper.pace(at, piece.len()).await;
+        stream.send_data(piece).await?;
+    }
+    Ok(())
+}
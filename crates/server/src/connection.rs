@@ -0,0 +1,54 @@
+//! Handle to the QUIC connection underneath a request or session.
+//!
+//! h3 takes ownership of the `quinn::Connection`, so the server keeps a
+//! clone and hands handlers this wrapper instead. It exposes what handlers
+//! need (stats, addresses, closing with an application close code) without
+//! letting them open streams behind h3's back.
+
+use common::CloseCode;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The QUIC connection a request or WebTransport session arrived on.
+///
+/// REST and stream handlers find it in the request extensions; WebTransport
+/// session handlers are passed it directly.
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    conn: quinn::Connection,
+}
+
+impl ConnectionHandle {
+    pub fn new(conn: quinn::Connection) -> Self {
+        Self { conn }
+    }
+
+    /// The peer's address.
+    pub fn remote_address(&self) -> SocketAddr {
+        self.conn.remote_address()
+    }
+
+    /// Current round-trip time estimate.
+    pub fn rtt(&self) -> Duration {
+        self.conn.rtt()
+    }
+
+    /// Transport statistics: congestion window, loss, path MTU, etc.
+    pub fn stats(&self) -> quinn::ConnectionStats {
+        self.conn.stats()
+    }
+
+    /// Whether the peer negotiated QUIC datagrams. h3 refuses WebTransport
+    /// sessions from clients that do not announce HTTP datagrams, but their
+    /// QUIC stack may still leave them out.
+    pub fn supports_datagrams(&self) -> bool {
+        self.conn.max_datagram_size().is_some()
+    }
+
+    /// Close the connection with an application close code. Does nothing
+    /// if it is already closed.
+    pub fn close(&self, code: CloseCode, reason: &str) {
+        self.conn
+            .close(quinn::VarInt::from_u32(code.code()), reason.as_bytes());
+    }
+}
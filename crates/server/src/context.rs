@@ -0,0 +1,95 @@
+//! Cancellation of requests in flight.
+//!
+//! Every request carries a [`RequestContext`] in its extensions. Its token
+//! is cancelled when the client disconnects, when the request's deadline
+//! (see [`crate::budget`]) passes, when the server starts shutting down, or
+//! when the handler fails, e.g. because the client reset the stream.
+//! Long-running handlers wait on [`RequestContext::cancelled`] alongside
+//! their work and wrap up early; tasks they spawn can hold a clone of
+//! [`RequestContext::token`].
+//!
+//! Handlers that do not check get [`CANCEL_GRACE`] to finish after the
+//! token fires before the server drops them.
+
+use crate::budget::RequestDeadline;
+use http::Request;
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// How long a streaming handler may keep running once its request is
+/// cancelled.
+pub const CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+/// What a handler needs to know to stop early.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    token: CancellationToken,
+    deadline: Option<RequestDeadline>,
+}
+
+impl RequestContext {
+    /// A context cancelled with `token`.
+    pub fn new(token: CancellationToken) -> Self {
+        Self {
+            token,
+            deadline: None,
+        }
+    }
+
+    /// Also cancel the request at `deadline`.
+    pub fn with_deadline(mut self, deadline: Option<RequestDeadline>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// The context the server attached to `req`, found in its extensions;
+    /// one that is never cancelled if there is none.
+    pub fn of<B>(req: &Request<B>) -> Self {
+        req.extensions().get::<RequestContext>().cloned().unwrap_or_default()
+    }
+
+    /// Fired when the request is cancelled, other than by its deadline
+    /// passing: wait on [`RequestContext::cancelled`] for both.
+    #[allow(dead_code)]
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn deadline(&self) -> Option<RequestDeadline> {
+        self.deadline
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled() || self.deadline.is_some_and(|d| d.is_expired())
+    }
+
+    /// Resolves when the request is cancelled or its deadline passes.
+    pub async fn cancelled(&self) {
+        match self.deadline {
+            Some(deadline) => tokio::select! {
+                () = self.token.cancelled() => {}
+                () = tokio::time::sleep_until(deadline.at) => {}
+            },
+            None => self.token.cancelled().await,
+        }
+    }
+
+    /// Cancel the request.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Run `future` until it ends or the request is cancelled; then it has
+    /// `grace` to end before it is dropped, and `None` is returned.
+    pub async fn run<F: Future>(&self, future: F, grace: Duration) -> Option<F::Output> {
+        tokio::pin!(future);
+        tokio::select! {
+            output = &mut future => return Some(output),
+            () = self.cancelled() => {}
+        }
+        // Tasks the handler spawned see the deadline as well
+        self.cancel();
+        tokio::time::timeout(grace, future).await.ok()
+    }
+}
@@ -0,0 +1,218 @@
+//! Read and write deadlines for WebTransport streams.
+//!
+//! A client that opens a stream and never finishes it, or never reads what
+//! the server writes back, would otherwise keep the task serving the stream
+//! waiting until the session ends. [`Deadline`] wraps a stream so that a
+//! read or write that makes no progress for the timeout fails with
+//! [`io::ErrorKind::TimedOut`].
+//!
+//! A write that times out resets the stream with [`TIMEOUT_CODE`]. A read
+//! that times out only fails: h3-quinn cannot stop a stream while a read on
+//! it is pending, so the client is told to stop sending (with code 0) when
+//! the handler drops the stream.
+
+use crate::chunks::{ChunkRead, ChunkWrite};
+use bytes::{Buf, Bytes};
+use h3::quic;
+use h3_webtransport::stream::{BidiStream, SendStream};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// WebTransport stream error code of a stream the server gave up on.
+pub const TIMEOUT_CODE: u32 = 1;
+
+/// The HTTP/3 error code carrying WebTransport stream error `code`, as
+/// mapped by the WebTransport over HTTP/3 draft (error codes the client
+/// would not recognize otherwise).
+pub fn http3_error_code(code: u32) -> u64 {
+    const FIRST: u64 = 0x52e4_a40f_a8db;
+    let code = u64::from(code);
+    FIRST + code + code / 0x1e
+}
+
+/// A stream whose sending side can be reset.
+pub trait Reset {
+    /// Abandon the sending side with `code`.
+    fn reset(&mut self, code: u64);
+}
+
+impl<S, B> Reset for SendStream<S, B>
+where
+    S: quic::SendStream<B>,
+    B: Buf,
+{
+    fn reset(&mut self, code: u64) {
+        quic::SendStream::reset(self, code);
+    }
+}
+
+impl<S, B> Reset for BidiStream<S, B>
+where
+    S: quic::SendStream<B>,
+    B: Buf,
+{
+    fn reset(&mut self, code: u64) {
+        quic::SendStream::reset(self, code);
+    }
+}
+
+/// A stream whose reads and writes time out.
+pub struct Deadline<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    /// Started when a read is first left pending, cleared when one completes.
+    read_timer: Option<Pin<Box<Sleep>>>,
+    write_timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Deadline<S> {
+    /// Wrap `stream`; without a `timeout` it never times out.
+    pub fn new(stream: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner: stream,
+            timeout,
+            read_timer: None,
+            write_timer: None,
+        }
+    }
+
+    /// Change the timeout, e.g. to none once a stream turns out to be one
+    /// that is meant to stay open.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.read_timer = None;
+        self.write_timer = None;
+    }
+
+}
+
+impl<S: Reset> Deadline<S> {
+    /// Reset the stream and fail the pending write.
+    fn time_out_write<T>(&mut self) -> Poll<io::Result<T>> {
+        self.inner.reset(http3_error_code(TIMEOUT_CODE));
+        timed_out()
+    }
+}
+
+fn timed_out<T>() -> Poll<io::Result<T>> {
+    Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "stream timed out")))
+}
+
+/// Whether an operation has been left pending for the whole `timeout`.
+/// `timer` runs while it is pending and is cleared once it completes.
+fn expired(
+    timer: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    pending: bool,
+    cx: &mut Context<'_>,
+) -> bool {
+    let Some(timeout) = timeout.filter(|_| pending) else {
+        *timer = None;
+        return false;
+    };
+    let timer = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    timer.as_mut().poll(cx).is_ready()
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deadline<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if expired(&mut this.read_timer, this.timeout, result.is_pending(), cx) {
+            return timed_out();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Reset + Unpin> AsyncWrite for Deadline<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if expired(&mut this.write_timer, this.timeout, result.is_pending(), cx) {
+            return this.time_out_write();
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_flush(cx);
+        if expired(&mut this.write_timer, this.timeout, result.is_pending(), cx) {
+            return this.time_out_write();
+        }
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_shutdown(cx);
+        if expired(&mut this.write_timer, this.timeout, result.is_pending(), cx) {
+            return this.time_out_write();
+        }
+        result
+    }
+}
+
+impl<S: ChunkRead + Unpin> ChunkRead for Deadline<S> {
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Option<Bytes>>> {
+        let result = self.inner.poll_read_chunk(cx);
+        if expired(&mut self.read_timer, self.timeout, result.is_pending(), cx) {
+            return timed_out();
+        }
+        result
+    }
+}
+
+impl<S: ChunkWrite + Reset + Unpin> ChunkWrite for Deadline<S> {
+    fn poll_write_chunk(&mut self, cx: &mut Context<'_>, chunk: &mut Bytes) -> Poll<io::Result<()>> {
+        let result = self.inner.poll_write_chunk(cx, chunk);
+        if expired(&mut self.write_timer, self.timeout, result.is_pending(), cx) {
+            return self.time_out_write();
+        }
+        result
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = self.inner.poll_finish(cx);
+        if expired(&mut self.write_timer, self.timeout, result.is_pending(), cx) {
+            return self.time_out_write();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_stream_error_codes() {
+        assert_eq!(http3_error_code(0), 0x52e4_a40f_a8db);
+        assert_eq!(http3_error_code(0x1d), 0x52e4_a40f_a8f8);
+        // 0x52e4a40fa8f9 is a reserved code, so the mapping skips it
+        assert_eq!(http3_error_code(0x1e), 0x52e4_a40f_a8fa);
+        assert_eq!(http3_error_code(u32::MAX), 0x52e5_ac98_3162);
+    }
+
+    #[test]
+    fn skips_reserved_codes() {
+        for code in 0..1000 {
+            let mapped = http3_error_code(code);
+            assert_ne!((mapped - 0x21) % 0x1f, 0, "{} maps to a reserved code", code);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Diagnostics bundles written when the server panics.
+//!
+//! With `ServerConfig::diagnostics_dir` set, a panic anywhere in the server
+//! writes `panic-<timestamp>.txt` there before it unwinds or aborts: the
+//! panic message, location and backtrace, the config, the open connections
+//! and WebTransport sessions, and the last [`LOG_LINES`] log lines, kept by
+//! [`LogRing`]. Attach it to bug reports.
+//!
+//! Panics in connection and request tasks are caught (see [`crate::tasks`])
+//! and the server carries on, so bundles are written at most once every
+//! [`BUNDLE_INTERVAL`]; a handler that panics on every request does not
+//! fill the disk.
+
+use crate::connection::ConnectionHandle;
+use crate::sessions::Sessions;
+use common::ServerConfig;
+use std::backtrace::Backtrace;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+/// Log lines kept for bundles.
+pub const LOG_LINES: usize = 500;
+
+/// Shortest time between two bundles.
+pub const BUNDLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The last [`LOG_LINES`] log lines, oldest first.
+static LOGS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// When the last bundle was written.
+static LAST_BUNDLE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// A tracing layer keeping the last [`LOG_LINES`] events for bundles.
+pub struct LogRing;
+
+impl<S: Subscriber> Layer<S> for LogRing {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut line = format!(
+            "{} {:>5} ",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            event.metadata().level()
+        );
+        event.record(&mut LineVisitor(&mut line));
+        let Ok(mut logs) = LOGS.lock() else {
+            return;
+        };
+        if logs.len() == LOG_LINES {
+            logs.pop_front();
+        }
+        logs.push_back(line);
+    }
+}
+
+/// Writes an event's message, then its other fields as `name=value`.
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let _ = match field.name() {
+            "message" => write!(self.0, "{:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        };
+    }
+}
+
+/// The server's open connections, for bundles.
+#[derive(Clone, Default)]
+pub struct Connections {
+    open: Arc<Mutex<HashMap<u64, (ConnectionHandle, Instant)>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+/// Keeps a connection listed until dropped.
+pub struct Registered {
+    connections: Connections,
+    id: u64,
+}
+
+impl Connections {
+    /// List `conn` until the returned guard is dropped.
+    pub fn register(&self, conn: ConnectionHandle) -> Registered {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open.lock().unwrap().insert(id, (conn, Instant::now()));
+        Registered {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    /// One line per connection: its address, age and RTT.
+    fn summary(&self) -> String {
+        let Ok(open) = self.open.try_lock() else {
+            return "(unavailable)\n".to_string();
+        };
+        let mut summary = format!("{} open\n", open.len());
+        for (conn, opened) in open.values() {
+            let _ = writeln!(
+                summary,
+                "{} open for {:.1?}, rtt {:?}",
+                conn.remote_address(),
+                opened.elapsed(),
+                conn.rtt()
+            );
+        }
+        summary
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.connections.open.lock() {
+            open.remove(&self.id);
+        }
+    }
+}
+
+/// Write a bundle to `dir` whenever the process panics, then run the
+/// panic hook that was installed before.
+pub fn install(
+    dir: PathBuf,
+    config: Arc<ServerConfig>,
+    connections: Connections,
+    sessions: Sessions,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(&dir)?;
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if due() {
+            let bundle = bundle(info, &config, &connections, &sessions);
+            match write(&dir, &bundle) {
+                // Not logged: the panic may have happened while logging
+                Ok(path) => eprintln!("Wrote diagnostics bundle to {}", path.display()),
+                Err(e) => eprintln!("Failed to write diagnostics bundle: {}", e),
+            }
+        }
+        previous(info);
+    }));
+    Ok(())
+}
+
+/// Whether a bundle may be written now; if so, counts it as written.
+fn due() -> bool {
+    let Ok(mut last) = LAST_BUNDLE.try_lock() else {
+        return false;
+    };
+    if last.is_some_and(|last| last.elapsed() < BUNDLE_INTERVAL) {
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
+
+fn bundle(
+    info: &PanicHookInfo<'_>,
+    config: &ServerConfig,
+    connections: &Connections,
+    sessions: &Sessions,
+) -> String {
+    let thread = std::thread::current();
+    let mut bundle = format!(
+        "simple-http3 server {} diagnostics bundle, {}\n",
+        env!("CARGO_PKG_VERSION"),
+        chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
+    );
+    let _ = writeln!(bundle, "thread: {}", thread.name().unwrap_or("(unnamed)"));
+    let _ = writeln!(bundle, "{}", info);
+    let _ = writeln!(bundle, "\n== backtrace ==\n{}", Backtrace::force_capture());
+    let _ = writeln!(bundle, "== config ==\n{:#?}\n", config);
+    let _ = writeln!(bundle, "== connections ==\n{}", connections.summary());
+    let _ = writeln!(bundle, "== sessions ==");
+    for session in sessions.try_list().unwrap_or_default() {
+        let _ = writeln!(
+            bundle,
+            "{} {:?} from {} in room {:?}",
+            session.id, session.name, session.remote, session.room
+        );
+    }
+    let _ = writeln!(bundle, "\n== recent logs ==");
+    if let Ok(logs) = LOGS.try_lock() {
+        for line in logs.iter() {
+            let _ = writeln!(bundle, "{}", line);
+        }
+    }
+    bundle
+}
+
+fn write(dir: &Path, bundle: &str) -> std::io::Result<PathBuf> {
+    let timestamp = chrono::Utc::now().format("%Y%m%d-%H%M%S%.3f");
+    let path = dir.join(format!("panic-{}-{}.txt", timestamp, std::process::id()));
+    std::fs::write(&path, bundle)?;
+    Ok(path)
+}
@@ -0,0 +1,101 @@
+//! Datagram fallback streams.
+//!
+//! When a client's QUIC stack did not negotiate datagrams, the session's
+//! datagrams travel on a unidirectional stream in each direction instead
+//! (see [`common::datagram`]). A [`FallbackSender`] writes outgoing ones,
+//! dropping them while the stream is backed up; [`read_frames`] turns a
+//! client's fallback stream back into datagrams.
+
+use bytes::{Bytes, BytesMut};
+use common::datagram::{FALLBACK_HEADER, FrameDecoder, encode_frame};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Datagrams queued for the fallback stream before new ones are dropped.
+const QUEUE_DEPTH: usize = 64;
+
+/// Sends datagrams on a fallback stream.
+pub struct FallbackSender {
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl FallbackSender {
+    /// Write the fallback header to `stream`, then every datagram sent.
+    pub fn start<S>(mut stream: S) -> Self
+    where
+        S: AsyncWriteExt + Unpin + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<Bytes>(QUEUE_DEPTH);
+        tokio::spawn(async move {
+            let result = async {
+                stream.write_all(FALLBACK_HEADER).await?;
+                while let Some(payload) = rx.recv().await {
+                    if let Some(frame) = encode_frame(&payload) {
+                        stream.write_all(&frame).await?;
+                    }
+                }
+                stream.shutdown().await
+            };
+            if let Err(e) = result.await {
+                debug!("Datagram fallback stream error: {:?}", e);
+            }
+        });
+        Self { tx }
+    }
+
+    /// Queue `payload`, or drop it like a lost datagram if the stream is
+    /// backed up.
+    pub fn send(&self, payload: Bytes) -> anyhow::Result<()> {
+        match self.tx.try_send(payload) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                debug!("Datagram fallback stream backed up; dropping datagram");
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                anyhow::bail!("datagram fallback stream closed")
+            }
+        }
+    }
+}
+
+/// Read the start of a client uni stream into `buf`, returning whether it
+/// is a fallback stream. Reads stop once that is known.
+pub async fn read_header<R>(stream: &mut R, buf: &mut BytesMut) -> std::io::Result<bool>
+where
+    R: AsyncReadExt + Unpin,
+{
+    while buf.len() < FALLBACK_HEADER.len() && FALLBACK_HEADER.starts_with(buf) {
+        if stream.read_buf(buf).await? == 0 {
+            return Ok(false);
+        }
+    }
+    Ok(buf.starts_with(FALLBACK_HEADER))
+}
+
+/// Pass the datagrams on a client's fallback stream to `tx`. `buf` holds
+/// what [`read_header`] read.
+pub async fn read_frames<R>(
+    mut stream: R,
+    buf: &mut BytesMut,
+    tx: mpsc::Sender<Bytes>,
+) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&buf[FALLBACK_HEADER.len()..]);
+    loop {
+        while let Some(payload) = decoder.next_frame() {
+            if tx.send(Bytes::from(payload)).await.is_err() {
+                return Ok(());
+            }
+        }
+        buf.clear();
+        if stream.read_buf(buf).await? == 0 {
+            return Ok(());
+        }
+        decoder.push(buf);
+    }
+}
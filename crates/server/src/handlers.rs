@@ -1,9 +1,33 @@
 //! Request handlers for REST and streaming endpoints.
 
-use crate::router::RestResponse;
-use bytes::Bytes;
+use crate::api_keys::{ApiKeyStore, NewApiKey};
+use crate::certs::CertHashes;
+use crate::coalesce::CoalescedStream;
+use crate::connection::ConnectionHandle;
+use crate::context::RequestContext;
+use crate::ndjson::NdjsonStream;
+use crate::peer_cert::PeerCertificate;
+use crate::pool::{self, PooledBuf};
+use crate::reload::LiveConfig;
+use crate::router::{HandlerError, Json, Query, RestResponse};
+use crate::rpc::RpcResult;
+use crate::schema::JsonBody;
+use crate::sessions::{Delivery, Notification, Sessions, MAX_BAN};
+use crate::shaping::Shaping;
+use crate::tasks::Tasks;
+use crate::telemetry::Telemetry;
+use crate::ticker::{stream_until_disconnect, ticker};
+use crate::topics::Topics;
+use crate::usage::Usage;
+use bytes::{Buf, BufMut, Bytes};
+use common::rpc::RpcError;
+use futures::StreamExt;
 use h3::server::RequestStream;
-use http::{Request, Response, StatusCode};
+use http::header::{CACHE_CONTROL, LOCATION};
+use http::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::time::Duration;
 use tracing::info;
 
@@ -16,16 +40,201 @@ pub async fn index(_req: Request<()>) -> RestResponse {
     RestResponse::text("Hello from HTTP/3!")
 }
 
-/// Health check handler.
+/// Health check handler; never cached, so probes see the server as it is.
 pub async fn health(_req: Request<()>) -> RestResponse {
     RestResponse::json(r#"{"status": "healthy", "protocol": "h3"}"#)
+        .header(CACHE_CONTROL, "no-store")
+}
+
+/// Connection stats handler: QUIC stats of the requesting connection.
+///
+/// Useful for debugging client network conditions. Counters are totals for
+/// the lifetime of the connection.
+pub async fn connection_stats(req: Request<()>) -> Result<RestResponse, HandlerError> {
+    let Some(conn) = req.extensions().get::<ConnectionHandle>() else {
+        let status = StatusCode::INTERNAL_SERVER_ERROR;
+        return Err(HandlerError::new(status, "connection unavailable"));
+    };
+
+    let stats = conn.stats();
+    let body = json!({
+        "remote_addr": conn.remote_address().to_string(),
+        "rtt_ms": stats.path.rtt.as_secs_f64() * 1000.0,
+        "cwnd": stats.path.cwnd,
+        "congestion_events": stats.path.congestion_events,
+        "sent_packets": stats.path.sent_packets,
+        "lost_packets": stats.path.lost_packets,
+        "lost_bytes": stats.path.lost_bytes,
+        "path_mtu": stats.path.current_mtu,
+        "black_holes_detected": stats.path.black_holes_detected,
+        "udp_tx": {"datagrams": stats.udp_tx.datagrams, "bytes": stats.udp_tx.bytes},
+        "udp_rx": {"datagrams": stats.udp_rx.datagrams, "bytes": stats.udp_rx.bytes},
+    });
+    Ok(RestResponse::json(body.to_string()))
+}
+
+/// `GET /admin/stats`: connections and requests running now, and the caps
+/// on them (`null` if uncapped). Loopback only.
+pub async fn server_stats(req: Request<()>) -> Result<RestResponse, HandlerError> {
+    if !is_local(&req) {
+        return Err(HandlerError::new(StatusCode::FORBIDDEN, "loopback clients only"));
+    }
+    let tasks = req.extensions().get::<Tasks>().ok_or_else(|| {
+        HandlerError::new(StatusCode::INTERNAL_SERVER_ERROR, "task counts unavailable")
+    })?;
+    Ok(RestResponse::from_serialize(&tasks.stats()).header(CACHE_CONTROL, "no-store"))
+}
+
+/// `GET /api/client-cert`: the certificate the client authenticated with
+/// over mutual TLS (times in Unix seconds); 404 if it presented none.
+pub async fn client_cert(req: Request<()>) -> Result<RestResponse, HandlerError> {
+    let cert = req.extensions().get::<PeerCertificate>().ok_or_else(|| {
+        HandlerError::not_found("the connection has no client certificate")
+    })?;
+    let seconds = |time| chrono::DateTime::<chrono::Utc>::from(time).timestamp();
+    Ok(RestResponse::from_serialize(&json!({
+        "subject": cert.subject,
+        "issuer": cert.issuer,
+        "sans": cert.sans,
+        "fingerprint": cert.fingerprint,
+        "not_before": seconds(cert.not_before),
+        "not_after": seconds(cert.not_after),
+    })))
+}
+
+/// The API host's root, which redirects to its description.
+pub async fn api_root(_req: Request<()>) -> RestResponse {
+    RestResponse::empty(StatusCode::PERMANENT_REDIRECT).header(LOCATION, "/api/info")
+}
+
+/// Fallback of the API host: unknown paths are answered with a problem
+/// document, like its other errors.
+pub async fn api_not_found(req: Request<()>) -> Result<RestResponse, HandlerError> {
+    Err(HandlerError::not_found("no such endpoint").with("path", req.uri().path()))
+}
+
+/// Error handler of the API host: failed handlers are answered with a
+/// problem document too, the error itself only logged.
+pub fn api_error(_e: &anyhow::Error) -> RestResponse {
+    let status = StatusCode::INTERNAL_SERVER_ERROR;
+    HandlerError::new(status, "the request failed; see the server log").into()
 }
 
 /// JSON API example.
-pub async fn api_info(_req: Request<()>) -> RestResponse {
-    RestResponse::json(
-        r#"{"name": "simple-http3", "version": "0.1.0", "endpoints": ["/", "/health", "/api/info", "/stream/time", "/stream/counter"]}"#,
-    )
+pub async fn api_info(_req: Request<()>) -> Json<Value> {
+    Json(json!({
+        "name": "simple-http3",
+        "version": "0.1.0",
+        "endpoints": [
+            "/", "/health", "/api/info", "/api/routes", "/api/connection", "/api/client-cert",
+            "/api/echo", "/api/notify", "/api/upload", "/stream/time", "/stream/counter",
+        ],
+    }))
+}
+
+/// `GET /api/connect-info`: everything a browser needs to reach
+/// `/webtransport` with the server's self-signed certificate, for the web
+/// UI to show: the certificate hashes for `serverCertificateHashes`, their
+/// SPKI pins for Chrome's command line, the ALPN protocols, and per-browser
+/// flags and a JavaScript snippet filled in with them. The hashes are in
+/// `hashes`, as in `/api/cert-hashes`, so either response can be pasted.
+pub async fn connect_info(
+    req: Request<()>,
+    hashes: CertHashes,
+) -> Result<RestResponse, HandlerError> {
+    let info = hashes.get().ok_or_else(no_certificate)?;
+    let authority = req
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| req.headers().get(http::header::HOST)?.to_str().ok())
+        .unwrap_or("localhost:4433");
+    let url = format!("https://{}/webtransport", authority);
+    let snippet = format!(
+        r#"const hashes = ["{}", "{}"];
+const transport = new WebTransport("{}", {{
+  serverCertificateHashes: hashes.map((hash) => ({{
+    algorithm: "sha-256",
+    value: new Uint8Array(hash.match(/../g).map((byte) => parseInt(byte, 16))),
+  }})),
+}});
+await transport.ready;"#,
+        info.hash, info.next_hash, url
+    );
+    let chrome_flags = format!(
+        "--origin-to-force-quic-on={} --ignore-certificate-errors-spki-list={},{}",
+        authority, info.spki, info.next_spki
+    );
+    let seconds = |time| chrono::DateTime::<chrono::Utc>::from(time).timestamp();
+    Ok(RestResponse::from_serialize(&json!({
+        "url": url,
+        "hashes": [info.hash, info.next_hash],
+        "spki": [info.spki, info.next_spki],
+        "alpn": crate::server::ALPN,
+        "rotates_at": seconds(info.rotates_at),
+        "not_after": seconds(info.not_after),
+        "browsers": [
+            {
+                "name": "Chrome / Edge",
+                "notes": "The snippet works as is. The flags are only needed to reach the \
+                          server without serverCertificateHashes, e.g. with fetch().",
+                "flags": chrome_flags,
+                "snippet": snippet,
+            },
+            {
+                "name": "Firefox",
+                "notes": "Firefox 125 or later; it has no flag to trust the certificate, \
+                          so serverCertificateHashes is the only way.",
+                "flags": null,
+                "snippet": snippet,
+            },
+        ],
+    }))
+    .header(CACHE_CONTROL, "no-store"))
+}
+
+/// Body of a `POST /api/echo` request.
+#[derive(Debug, Deserialize)]
+pub struct EchoRequest {
+    pub message: String,
+}
+
+/// `GET /api/cert-hashes`: the hashes of the current and next WebTransport
+/// certificates, in the order to pin them, with when the current one is
+/// rotated out and expires (Unix seconds).
+pub async fn cert_hashes(
+    _req: Request<()>,
+    hashes: CertHashes,
+) -> Result<RestResponse, HandlerError> {
+    let info = hashes.get().ok_or_else(no_certificate)?;
+    let seconds = |time| chrono::DateTime::<chrono::Utc>::from(time).timestamp();
+    Ok(RestResponse::from_serialize(&json!({
+        "hashes": [info.hash, info.next_hash],
+        "rotates_at": seconds(info.rotates_at),
+        "not_after": seconds(info.not_after),
+    }))
+    .header(CACHE_CONTROL, "no-store"))
+}
+
+/// Answer to certificate requests before the first certificate is made.
+fn no_certificate() -> HandlerError {
+    HandlerError::unavailable("no certificate has been generated yet")
+}
+
+/// Response to `POST /api/echo`.
+#[derive(Debug, Serialize)]
+pub struct EchoResponse {
+    pub message: String,
+    /// Length of the message in characters.
+    pub length: usize,
+}
+
+/// Typed JSON example: echoes the message it is sent with its length.
+pub async fn echo(_req: Request<()>, Json(request): Json<EchoRequest>) -> Json<EchoResponse> {
+    Json(EchoResponse {
+        length: request.message.chars().count(),
+        message: request.message,
+    })
 }
 
 // =============================================================================
@@ -35,9 +244,14 @@ pub async fn api_info(_req: Request<()>) -> RestResponse {
 /// Server-Sent Events style: pushes current time every second for 5 iterations.
 ///
 /// Demonstrates server-push pattern where client receives multiple data chunks
-/// over a single stream.
+/// over a single stream. Events are written through a [`CoalescedStream`], so
+/// the final event and the `done` marker go out in one frame.
+///
+/// The response is shaped as the server decided for the request (see
+/// [`crate::shaping`]). A cancelled request ends the stream early, still
+/// with the `done` marker.
 pub async fn time_stream(
-    _req: Request<()>,
+    req: Request<()>,
     mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
 ) -> anyhow::Result<()> {
     // Send response headers
@@ -48,52 +262,737 @@ pub async fn time_stream(
         .body(())?;
 
     stream.send_response(response).await?;
+    let stream = CoalescedStream::shaped(stream, Shaping::of(&req));
+    let ctx = RequestContext::of(&req);
 
     // Push time updates
-    for i in 1..=5 {
+    let ticks = stream_until_disconnect(&req, ticker(Duration::from_secs(1)).take(5));
+    let mut ticks = std::pin::pin!(ticks);
+    while let Some(i) = ticks.next().await {
         let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
         let event = format!("event: time\ndata: {}\nid: {}\n\n", now, i);
 
         info!("  Streaming chunk {}/5", i);
-        stream.send_data(Bytes::from(event)).await?;
-
-        if i < 5 {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
+        stream.send(event).await?;
+    }
+    if ctx.is_cancelled() {
+        info!("  Stream cancelled");
     }
 
     // Signal end of stream
-    stream.send_data(Bytes::from("event: done\ndata: stream complete\n\n")).await?;
+    stream.send("event: done\ndata: stream complete\n\n").await?;
     stream.finish().await?;
 
     info!("  Stream completed");
     Ok(())
 }
 
-/// Counter stream: demonstrates a simple counting stream.
+/// `GET /stream/countdown`: counts down from 5, a line a second, then says
+/// `liftoff`. A [`Router::body_stream`](crate::router::Router::body_stream)
+/// route: the router sends the lines as this stream yields them.
+pub fn countdown(_req: Request<()>) -> impl futures::Stream<Item = Bytes> + Send {
+    let lines = (1..=5).rev().map(|n| n.to_string()).chain(["liftoff".to_string()]);
+    futures::stream::iter(lines).enumerate().then(|(i, line)| async move {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Bytes::from(line + "\n")
+    })
+}
+
+/// Query parameters of `GET /stream/counter`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CounterQuery {
+    /// Numbers to count to.
+    pub limit: u32,
+    /// Pause between numbers.
+    pub interval_ms: u64,
+}
+
+impl Default for CounterQuery {
+    fn default() -> Self {
+        Self {
+            limit: 10,
+            interval_ms: 500,
+        }
+    }
+}
+
+/// Counter stream: demonstrates a simple counting stream, shaped like
+/// [`time_stream`] and ended early the same way. Counts to `limit` (at most
+/// 1000), one number every `interval_ms` milliseconds (at most 60000), both
+/// taken from the query string.
 pub async fn counter_stream(
-    _req: Request<()>,
+    req: Request<()>,
     mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
 ) -> anyhow::Result<()> {
+    let query = match Query::<CounterQuery>::from_request(&req) {
+        Ok(Query(query)) if query.limit <= 1000 && query.interval_ms <= 60_000 => query,
+        Ok(_) => {
+            let body = json!({"error": "limit must be at most 1000, interval_ms at most 60000"});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+        Err(e) => {
+            let body = json!({"error": format!("Invalid query: {}", e)});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+
     let response = Response::builder()
         .status(StatusCode::OK)
         .header("content-type", "application/x-ndjson")
         .body(())?;
 
     stream.send_response(response).await?;
+    let stream = CoalescedStream::shaped(stream, Shaping::of(&req));
+    let ctx = RequestContext::of(&req);
 
-    for i in 1..=10 {
+    let interval = Duration::from_millis(query.interval_ms);
+    let ticks = stream_until_disconnect(&req, ticker(interval).take(query.limit as usize));
+    let mut ticks = std::pin::pin!(ticks);
+    while let Some(i) = ticks.next().await {
         let json = format!(r#"{{"count": {}, "timestamp": {}}}"#, i, chrono::Utc::now().timestamp());
         let line = format!("{}\n", json);
 
-        stream.send_data(Bytes::from(line)).await?;
+        stream.send(line).await?;
+    }
+    if ctx.is_cancelled() {
+        info!("  Counter stream cancelled");
+    }
+
+    stream.finish().await?;
+    info!("  Counter stream completed");
+    Ok(())
+}
+
+/// Query parameters of `GET /stream/records`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RecordsQuery {
+    /// Records to send.
+    pub count: u64,
+}
 
-        if i < 10 {
-            tokio::time::sleep(Duration::from_millis(500)).await;
+impl Default for RecordsQuery {
+    fn default() -> Self {
+        Self { count: 1000 }
+    }
+}
+
+/// A record of `GET /stream/records`.
+#[derive(Debug, Serialize)]
+pub struct Record {
+    pub id: u64,
+    pub square: u64,
+}
+
+/// NDJSON stream of `count` records (at most 1000000, from the query
+/// string), sent as fast as the client reads them: unlike
+/// [`counter_stream`], the pace is set by flow control (see
+/// [`crate::ndjson`]). A cancelled request ends the stream early.
+pub async fn records_stream(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> anyhow::Result<()> {
+    let query = match Query::<RecordsQuery>::from_request(&req) {
+        Ok(Query(query)) if query.count <= 1_000_000 => query,
+        Ok(_) => {
+            let body = json!({"error": "count must be at most 1000000"});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+        Err(e) => {
+            let body = json!({"error": format!("Invalid query: {}", e)});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+
+    let ctx = RequestContext::of(&req);
+    let mut records = NdjsonStream::start(stream).await?;
+    let all = futures::stream::iter(1..=query.count).map(|id| Record {
+        id,
+        square: id.wrapping_mul(id),
+    });
+    records.send_all(stream_until_disconnect(&req, all)).await?;
+    if ctx.is_cancelled() {
+        info!("  Records stream cancelled after {} records", records.sent());
+        return Ok(());
+    }
+    records.finish().await?;
+    info!("  Records stream completed");
+    Ok(())
+}
+
+/// `POST /api/upload`: consume a request body of any size as it streams
+/// in, responding with its length and SHA-256 hash. A cancelled request
+/// stops the client sending.
+pub async fn upload(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> anyhow::Result<()> {
+    if req.method() != Method::POST {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+
+    let mut hasher = Sha256::new();
+    let mut bytes = 0u64;
+    let ctx = RequestContext::of(&req);
+    loop {
+        let chunk = tokio::select! {
+            chunk = stream.recv_data() => chunk?,
+            _ = ctx.cancelled() => {
+                stream.stop_sending(h3::error::Code::H3_REQUEST_CANCELLED);
+                anyhow::bail!("upload cancelled after {} bytes", bytes);
+            }
+        };
+        let Some(mut chunk) = chunk else {
+            break;
+        };
+        while chunk.has_remaining() {
+            let data = chunk.chunk();
+            hasher.update(data);
+            bytes += data.len() as u64;
+            chunk.advance(data.len());
+        }
+    }
+
+    let sha256: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+    info!("  Received {} byte upload", bytes);
+    send_json(&mut stream, StatusCode::OK, &json!({"bytes": bytes, "sha256": sha256})).await
+}
+
+// =============================================================================
+// Push Handlers
+// =============================================================================
+
+/// Largest accepted `/api/notify` request body.
+pub const MAX_NOTIFY_BODY: usize = 64 * 1024;
+
+/// Largest accepted `/admin` request body.
+const MAX_ADMIN_BODY: usize = 4 * 1024;
+
+/// Body of a `/api/notify` request.
+#[derive(Deserialize)]
+struct NotifyRequest {
+    message: String,
+    /// Target session id; every session when omitted.
+    session: Option<u64>,
+    #[serde(default)]
+    via: Delivery,
+}
+
+/// JSON Schema of a `/api/notify` request body.
+pub fn notify_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "message": {"type": "string", "minLength": 1},
+            "session": {"type": "integer", "minimum": 1},
+            "via": {"enum": ["stream", "datagram"]},
+        },
+        "required": ["message"],
+        "additionalProperties": false,
+    })
+}
+
+/// `POST /api/notify`: push a message to WebTransport sessions.
+///
+/// Takes `{"message": "...", "session": 1, "via": "stream"}`, where
+/// `session` (default: all sessions) and `via` (`stream` or `datagram`,
+/// default `stream`) are optional, and responds with the number of sessions
+/// the message was queued for. The body has already been read and checked
+/// if the route has [`notify_schema`].
+pub async fn notify(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    sessions: Sessions,
+) -> anyhow::Result<()> {
+    if req.method() != Method::POST {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+
+    let parsed = match req.extensions().get::<JsonBody>() {
+        Some(JsonBody(body)) => NotifyRequest::deserialize(body),
+        None => {
+            let Some(body) = read_body(&mut stream, MAX_NOTIFY_BODY).await? else {
+                let body = json!({"error": "Payload Too Large"});
+                return send_json(&mut stream, StatusCode::PAYLOAD_TOO_LARGE, &body).await;
+            };
+            serde_json::from_slice(&body)
         }
+    };
+    let request: NotifyRequest = match parsed {
+        Ok(request) => request,
+        Err(e) => {
+            let body = json!({"error": format!("Invalid request: {}", e)});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+    if let Some(id) = request.session
+        && !sessions.contains(id)
+    {
+        let body = json!({"error": format!("No session {}", id)});
+        return send_json(&mut stream, StatusCode::NOT_FOUND, &body).await;
     }
 
+    // Control messages are single lines
+    let notification = Notification {
+        message: request.message.replace(['\r', '\n'], " "),
+        delivery: request.via,
+    };
+    let delivered = sessions.notify(request.session, notification);
+    info!("  Pushed message to {} sessions", delivered);
+
+    send_json(&mut stream, StatusCode::OK, &json!({"delivered": delivered})).await
+}
+
+/// `POST /admin/reload`: re-read the config file.
+///
+/// Only accepted from loopback addresses. Responds with the changed
+/// settings that were not applied because they need a restart.
+pub async fn reload_config(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    live: LiveConfig,
+) -> anyhow::Result<()> {
+    if req.method() != Method::POST {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+
+    match live.reload() {
+        Ok(restart_required) => {
+            let body = json!({"reloaded": true, "restart_required": restart_required});
+            send_json(&mut stream, StatusCode::OK, &body).await
+        }
+        Err(e) => {
+            let body = json!({"error": format!("{:#}", e)});
+            send_json(&mut stream, StatusCode::UNPROCESSABLE_ENTITY, &body).await
+        }
+    }
+}
+
+/// `/admin/keys`: list API keys (`GET`) or create one (`POST`).
+///
+/// Creating takes `{"name": "...", "scopes": ["/api"], "rate_limit_per_min":
+/// 60}`, where `scopes` (default: every path) and `rate_limit_per_min`
+/// (default: unlimited) are optional, and responds with the key. The key is
+/// not stored and cannot be retrieved again. Only accepted from loopback
+/// addresses.
+pub async fn api_keys(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    store: ApiKeyStore,
+) -> anyhow::Result<()> {
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+
+    match *req.method() {
+        Method::GET => {
+            let body = json!({"keys": store.list()});
+            send_json(&mut stream, StatusCode::OK, &body).await
+        }
+        Method::POST => {
+            let Some(body) = read_body(&mut stream, MAX_ADMIN_BODY).await? else {
+                let body = json!({"error": "Payload Too Large"});
+                return send_json(&mut stream, StatusCode::PAYLOAD_TOO_LARGE, &body).await;
+            };
+            let new: NewApiKey = match serde_json::from_slice(&body) {
+                Ok(new) => new,
+                Err(e) => {
+                    let body = json!({"error": format!("Invalid request: {}", e)});
+                    return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+                }
+            };
+
+            let (api_key, key) = store.create(new)?;
+            let body = json!({"key": key, "api_key": api_key});
+            send_json(&mut stream, StatusCode::CREATED, &body).await
+        }
+        _ => {
+            let body = json!({"error": "Method Not Allowed"});
+            send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await
+        }
+    }
+}
+
+/// `POST /admin/keys/revoke`: revoke the API key `{"id": "..."}`.
+///
+/// Only accepted from loopback addresses.
+pub async fn revoke_api_key(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    store: ApiKeyStore,
+) -> anyhow::Result<()> {
+    if req.method() != Method::POST {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+
+    #[derive(Deserialize)]
+    struct Revoke {
+        id: String,
+    }
+    let Some(body) = read_body(&mut stream, MAX_ADMIN_BODY).await? else {
+        let body = json!({"error": "Payload Too Large"});
+        return send_json(&mut stream, StatusCode::PAYLOAD_TOO_LARGE, &body).await;
+    };
+    let revoke: Revoke = match serde_json::from_slice(&body) {
+        Ok(revoke) => revoke,
+        Err(e) => {
+            let body = json!({"error": format!("Invalid request: {}", e)});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+
+    if !store.revoke(&revoke.id)? {
+        let body = json!({"error": format!("No API key {}", revoke.id)});
+        return send_json(&mut stream, StatusCode::NOT_FOUND, &body).await;
+    }
+    send_json(&mut stream, StatusCode::OK, &json!({"revoked": revoke.id})).await
+}
+
+/// How long `/admin/sessions/ban` bans an address by default.
+const DEFAULT_BAN: Duration = Duration::from_secs(3600);
+
+/// Body of a `/admin/sessions/kick` or `/admin/sessions/ban` request.
+#[derive(Deserialize)]
+struct Moderate {
+    session: u64,
+    #[serde(default)]
+    reason: String,
+    /// How long to ban the session's address; bans only.
+    duration_secs: Option<u64>,
+}
+
+/// `GET /admin/sessions`: the live WebTransport sessions, with their room
+/// and address.
+///
+/// Only accepted from loopback addresses.
+pub async fn list_sessions(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    sessions: Sessions,
+) -> anyhow::Result<()> {
+    if req.method() != Method::GET {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+
+    send_json(&mut stream, StatusCode::OK, &json!({"sessions": sessions.list()})).await
+}
+
+/// `GET /api/telemetry`: the aggregate of every metric sent in telemetry
+/// datagrams, and the number of samples dropped.
+pub async fn telemetry(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    telemetry: Telemetry,
+) -> anyhow::Result<()> {
+    if req.method() != Method::GET {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    let body = serde_json::to_value(telemetry.report())?;
+    send_json(&mut stream, StatusCode::OK, &body).await
+}
+
+/// `POST /admin/sessions/kick`: close the session `{"session": 1, "reason":
+/// "..."}` with the `kicked` close code.
+///
+/// `reason` is optional and sent to the client as the close reason. Only
+/// accepted from loopback addresses.
+pub async fn kick_session(
+    req: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    sessions: Sessions,
+) -> anyhow::Result<()> {
+    moderate(req, stream, |request| {
+        sessions
+            .kick(request.session, &request.reason)
+            .then(|| json!({"kicked": request.session}))
+    })
+    .await
+}
+
+/// `POST /admin/sessions/ban`: close the session `{"session": 1, "reason":
+/// "...", "duration_secs": 3600}` with the `banned` close code and refuse
+/// new sessions from its address for `duration_secs` (default an hour, at
+/// most a year).
+///
+/// Only accepted from loopback addresses.
+pub async fn ban_session(
+    req: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    sessions: Sessions,
+) -> anyhow::Result<()> {
+    moderate(req, stream, |request| {
+        let duration = request.duration_secs.map_or(DEFAULT_BAN, Duration::from_secs).min(MAX_BAN);
+        let remote = sessions.ban(request.session, &request.reason, duration)?;
+        info!("  Banned {} for {:?}", remote, duration);
+        Some(json!({"banned": request.session, "remote": remote}))
+    })
+    .await
+}
+
+/// Read a [`Moderate`] request and apply it with `action`, which returns
+/// the response body, or `None` if the session is not live.
+async fn moderate(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    action: impl FnOnce(&Moderate) -> Option<Value>,
+) -> anyhow::Result<()> {
+    if req.method() != Method::POST {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+
+    let Some(body) = read_body(&mut stream, MAX_ADMIN_BODY).await? else {
+        let body = json!({"error": "Payload Too Large"});
+        return send_json(&mut stream, StatusCode::PAYLOAD_TOO_LARGE, &body).await;
+    };
+    let request: Moderate = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let body = json!({"error": format!("Invalid request: {}", e)});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+
+    match action(&request) {
+        Some(body) => send_json(&mut stream, StatusCode::OK, &body).await,
+        None => {
+            let body = json!({"error": format!("No session {}", request.session)});
+            send_json(&mut stream, StatusCode::NOT_FOUND, &body).await
+        }
+    }
+}
+
+/// Query parameters of `GET /admin/usage`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct UsageQuery {
+    format: UsageFormat,
+}
+
+/// The format `GET /admin/usage` responds in.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum UsageFormat {
+    #[default]
+    Json,
+    Prometheus,
+}
+
+/// `GET /admin/usage`: requests and bytes per API key.
+///
+/// Responds with JSON, or the Prometheus text format with
+/// `?format=prometheus`. Only accepted from loopback addresses.
+pub async fn usage(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    usage: Usage,
+) -> anyhow::Result<()> {
+    if req.method() != Method::GET {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+
+    let query = match Query::<UsageQuery>::from_request(&req) {
+        Ok(Query(query)) => query,
+        Err(e) => {
+            let body = json!({"error": format!("Invalid query: {}", e)});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+
+    if query.format == UsageFormat::Prometheus {
+        let body = usage.prometheus();
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain; version=0.0.4")
+            .header("content-length", body.len())
+            .body(())?;
+        stream.send_response(response).await?;
+        stream.send_data(Bytes::from(body)).await?;
+        stream.finish().await?;
+        return Ok(());
+    }
+
+    let keys: Vec<Value> = usage
+        .snapshot()
+        .into_iter()
+        .map(|(principal, counters)| {
+            json!({
+                "key_id": principal.key_id,
+                "name": principal.name,
+                "requests": counters.requests,
+                "bytes_in": counters.bytes_in,
+                "bytes_out": counters.bytes_out,
+            })
+        })
+        .collect();
+    send_json(&mut stream, StatusCode::OK, &json!({"usage": keys})).await
+}
+
+/// `GET /debug/pprof?seconds=N`: a CPU flamegraph of the server over the
+/// next N seconds (see [`crate::profiling`]).
+///
+/// Only accepted from loopback addresses.
+#[cfg(feature = "pprof")]
+pub async fn pprof(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> anyhow::Result<()> {
+    if req.method() != Method::GET {
+        let body = json!({"error": "Method Not Allowed"});
+        return send_json(&mut stream, StatusCode::METHOD_NOT_ALLOWED, &body).await;
+    }
+    if !is_local(&req) {
+        let body = json!({"error": "Forbidden"});
+        return send_json(&mut stream, StatusCode::FORBIDDEN, &body).await;
+    }
+    let duration = match crate::profiling::duration(req.uri().query()) {
+        Ok(duration) => duration,
+        Err(e) => {
+            let body = json!({"error": e.to_string()});
+            return send_json(&mut stream, StatusCode::BAD_REQUEST, &body).await;
+        }
+    };
+
+    info!("  Profiling for {:?}", duration);
+    let ctx = RequestContext::of(&req);
+    let Some(svg) = crate::profiling::flamegraph(duration, &ctx).await? else {
+        let body = json!({"error": "A profile is already being taken"});
+        return send_json(&mut stream, StatusCode::CONFLICT, &body).await;
+    };
+    if ctx.is_cancelled() {
+        return Ok(());
+    }
+    if svg.is_empty() {
+        // Idle throughout, so nothing was sampled
+        let response = Response::builder().status(StatusCode::NO_CONTENT).body(())?;
+        stream.send_response(response).await?;
+        stream.finish().await?;
+        return Ok(());
+    }
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "image/svg+xml")
+        .header("content-length", svg.len())
+        .body(())?;
+    stream.send_response(response).await?;
+    stream.send_data(Bytes::from(svg)).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Whether the request came from a loopback address.
+fn is_local(req: &Request<()>) -> bool {
+    req.extensions()
+        .get::<ConnectionHandle>()
+        .is_some_and(|conn| conn.remote_address().ip().to_canonical().is_loopback())
+}
+
+/// Read the whole request body into a pooled buffer, or `None` if it is
+/// longer than `limit`.
+async fn read_body(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    limit: usize,
+) -> anyhow::Result<Option<PooledBuf>> {
+    let mut body = pool::buffers().get();
+    while let Some(chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > limit {
+            return Ok(None);
+        }
+        body.put(chunk);
+    }
+    Ok(Some(body))
+}
+
+/// Send a complete JSON response.
+async fn send_json(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    status: StatusCode,
+    body: &Value,
+) -> anyhow::Result<()> {
+    let body = body.to_string();
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(())?;
+
+    stream.send_response(response).await?;
+    stream.send_data(Bytes::from(body)).await?;
     stream.finish().await?;
-    info!("  Counter stream completed");
     Ok(())
 }
+
+// =============================================================================
+// Topic Publishers
+// =============================================================================
+
+/// Publish the current time to the `time` topic every second.
+pub async fn publish_time(topics: Topics) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let now = chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC");
+        topics.publish("time", &now.to_string());
+    }
+}
+
+// =============================================================================
+// JSON-RPC Methods
+// =============================================================================
+
+/// `echo`: returns its params unchanged.
+pub async fn rpc_echo(params: Option<Value>) -> RpcResult {
+    Ok(params.unwrap_or(Value::Null))
+}
+
+/// `add`: sums an array of numbers.
+pub async fn rpc_add(numbers: Vec<f64>) -> Result<f64, RpcError> {
+    Ok(numbers.iter().sum())
+}
+
+/// `multiply`: multiplies two numbers.
+pub async fn rpc_multiply(a: f64, b: f64) -> Result<f64, RpcError> {
+    Ok(a * b)
+}
+
+/// `divide`: divides two numbers, rejecting division by zero.
+pub async fn rpc_divide(a: f64, b: f64) -> anyhow::Result<f64> {
+    anyhow::ensure!(b != 0.0, "division by zero");
+    Ok(a / b)
+}
+
+/// `time`: the current server time.
+pub async fn rpc_time() -> Result<String, RpcError> {
+    Ok(chrono::Utc::now().to_rfc3339())
+}
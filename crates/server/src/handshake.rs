@@ -0,0 +1,269 @@
+//! Hooks for deciding whether to accept a connection.
+//!
+//! A [`HandshakeHook`] is consulted twice per connection attempt: when the
+//! first packet arrives, before any TLS work, and again once the ClientHello
+//! has been read and the SNI hostname is known. The first check can accept,
+//! refuse or ignore the attempt, or answer with a stateless retry so the
+//! client has to prove it owns its address before the server does anything
+//! expensive.
+//!
+//! A [`HandshakeLimiter`] caps connection attempts per source prefix ahead
+//! of both.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Prefix lengths connection attempts are counted by, so a client cannot
+/// dodge the limit by rotating through nearby addresses.
+const IPV4_PREFIX: u32 = 24;
+const IPV6_PREFIX: u32 = 56;
+
+/// How often prefixes that are neither banned nor in a window are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Prefixes tracked at most; until the next prune, attempts from new
+/// prefixes beyond it are ignored.
+const MAX_PREFIXES: usize = 100_000;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// A connection attempt, before the handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct IncomingInfo {
+    pub remote: SocketAddr,
+    /// The local address the attempt arrived on, if known.
+    pub local_ip: Option<IpAddr>,
+    /// Whether the client has proven it owns `remote`, i.e. it answered a
+    /// retry.
+    pub validated: bool,
+}
+
+/// What the client sent in its ClientHello.
+#[derive(Debug, Clone)]
+pub struct ClientHello {
+    pub remote: SocketAddr,
+    /// The SNI hostname, if any.
+    pub server_name: Option<String>,
+    /// The negotiated ALPN protocol, e.g. `h3`.
+    pub alpn: Option<Vec<u8>>,
+}
+
+/// What to do with a connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    /// Send a stateless retry; the client comes back with a token that
+    /// validates its address. Attempts that are already validated are
+    /// accepted.
+    Retry,
+    /// Reject with a CONNECTION_REFUSED error.
+    Refuse,
+    /// Drop silently, sending nothing.
+    Ignore,
+}
+
+/// Decides which connections the server accepts.
+pub trait HandshakeHook: Send + Sync {
+    /// Called for every connection attempt before any TLS work.
+    fn incoming(&self, _info: &IncomingInfo) -> Decision {
+        Decision::Accept
+    }
+
+    /// Called once the ClientHello has been read; returning `false` closes
+    /// the connection before the handshake completes.
+    fn client_hello(&self, _hello: &ClientHello) -> bool {
+        true
+    }
+}
+
+/// The outcome of [`HandshakeLimiter::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    /// The prefix just went over the limit and is now banned.
+    Limited,
+    /// The prefix is banned.
+    Banned,
+    /// Too many prefixes are tracked to count a new one.
+    Full,
+}
+
+impl Admission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Admission::Allowed => "allowed",
+            Admission::Limited => "limited",
+            Admission::Banned => "banned",
+            Admission::Full => "full",
+        }
+    }
+}
+
+struct PrefixState {
+    window_start: Instant,
+    attempts: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Limits connection attempts per second from each source prefix, banning
+/// prefixes that go over for a while.
+pub struct HandshakeLimiter {
+    per_sec: u32,
+    ban: Duration,
+    prefixes: Mutex<Prefixes>,
+}
+
+struct Prefixes {
+    by_prefix: HashMap<Prefix, PrefixState>,
+    /// When expired prefixes are dropped next.
+    next_prune: Instant,
+}
+
+impl HandshakeLimiter {
+    pub fn new(per_sec: u32, ban: Duration) -> Self {
+        Self {
+            per_sec,
+            ban,
+            prefixes: Mutex::new(Prefixes {
+                by_prefix: HashMap::new(),
+                next_prune: Instant::now() + PRUNE_INTERVAL,
+            }),
+        }
+    }
+
+    /// Count an attempt from `ip`. Retried attempts count again.
+    pub fn check(&self, ip: IpAddr) -> Admission {
+        self.check_at(Prefix::of(ip), Instant::now())
+    }
+
+    fn check_at(&self, prefix: Prefix, now: Instant) -> Admission {
+        let mut prefixes = self.prefixes.lock().unwrap();
+        if now >= prefixes.next_prune {
+            prefixes.by_prefix.retain(|_, state| {
+                state.banned_until.is_some_and(|until| until > now)
+                    || now.duration_since(state.window_start) < WINDOW
+            });
+            prefixes.next_prune = now + PRUNE_INTERVAL;
+        }
+
+        if !prefixes.by_prefix.contains_key(&prefix) && prefixes.by_prefix.len() >= MAX_PREFIXES {
+            return Admission::Full;
+        }
+        let state = prefixes.by_prefix.entry(prefix).or_insert(PrefixState {
+            window_start: now,
+            attempts: 0,
+            banned_until: None,
+        });
+        match state.banned_until {
+            Some(until) if until > now => return Admission::Banned,
+            Some(_) => state.banned_until = None,
+            None => {}
+        }
+        if now.duration_since(state.window_start) >= WINDOW {
+            state.window_start = now;
+            state.attempts = 0;
+        }
+
+        state.attempts += 1;
+        if state.attempts > self.per_sec {
+            state.banned_until = Some(now + self.ban);
+            return Admission::Limited;
+        }
+        Admission::Allowed
+    }
+
+    pub fn ban_duration(&self) -> Duration {
+        self.ban
+    }
+}
+
+/// A source prefix connection attempts are counted under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Prefix(IpAddr);
+
+impl Prefix {
+    pub fn of(ip: IpAddr) -> Self {
+        Prefix(match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                IpAddr::V4(Ipv4Addr::from_bits(ip.to_bits() & (u32::MAX << (32 - IPV4_PREFIX))))
+            }
+            IpAddr::V6(ip) => {
+                IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & (u128::MAX << (128 - IPV6_PREFIX))))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let len = if self.0.is_ipv4() { IPV4_PREFIX } else { IPV6_PREFIX };
+        write!(f, "{}/{}", self.0, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(addr: &str) -> Prefix {
+        Prefix::of(addr.parse().unwrap())
+    }
+
+    fn tracked(limiter: &HandshakeLimiter) -> usize {
+        limiter.prefixes.lock().unwrap().by_prefix.len()
+    }
+
+    #[test]
+    fn bans_prefixes_over_the_limit() {
+        let limiter = HandshakeLimiter::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        let ip = prefix("192.0.2.7");
+        assert_eq!(limiter.check_at(ip, now), Admission::Allowed);
+        assert_eq!(limiter.check_at(ip, now), Admission::Allowed);
+        assert_eq!(limiter.check_at(ip, now), Admission::Limited);
+        // A neighbour in the same /24 shares the ban
+        assert_eq!(limiter.check_at(prefix("192.0.2.200"), now), Admission::Banned);
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(limiter.check_at(ip, later), Admission::Allowed);
+    }
+
+    #[test]
+    fn prunes_expired_prefixes_on_an_interval() {
+        let limiter = HandshakeLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(prefix("192.0.2.1"), now), Admission::Allowed);
+        limiter.check_at(prefix("198.51.100.1"), now);
+        assert_eq!(limiter.check_at(prefix("198.51.100.1"), now), Admission::Limited);
+
+        // Not yet due, though the first window is over
+        let soon = now + Duration::from_secs(2);
+        limiter.check_at(prefix("203.0.113.1"), soon);
+        assert_eq!(tracked(&limiter), 3);
+
+        // Expired windows go, bans stay
+        let due = now + PRUNE_INTERVAL;
+        limiter.check_at(prefix("203.0.113.1"), due);
+        let prefixes = limiter.prefixes.lock().unwrap();
+        assert!(!prefixes.by_prefix.contains_key(&prefix("192.0.2.1")));
+        assert!(prefixes.by_prefix.contains_key(&prefix("198.51.100.1")));
+        assert!(prefixes.by_prefix.contains_key(&prefix("203.0.113.1")));
+    }
+
+    #[test]
+    fn ignores_new_prefixes_when_full() {
+        let limiter = HandshakeLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        for n in 0..MAX_PREFIXES as u32 {
+            let ip = IpAddr::V4(Ipv4Addr::from_bits(n << 8));
+            assert_eq!(limiter.check_at(Prefix::of(ip), now), Admission::Allowed);
+        }
+        let new = IpAddr::V4(Ipv4Addr::from_bits((MAX_PREFIXES as u32) << 8));
+        assert_eq!(limiter.check_at(Prefix::of(new), now), Admission::Full);
+        // Prefixes already tracked are counted as usual
+        assert_eq!(limiter.check_at(prefix("0.0.0.1"), now), Admission::Limited);
+    }
+}
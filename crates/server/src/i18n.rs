@@ -0,0 +1,226 @@
+//! Localized messages for built-in error responses.
+//!
+//! Errors the server responds with itself (unknown and disabled routes, a
+//! full task limit, API key and quota rejections, banned clients, a second
+//! WebTransport session on a connection, missed deadlines, failed handlers)
+//! carry a message in the language the request prefers by its
+//! `Accept-Language` header, looked up in a [`Catalog`]. The catalog ships
+//! with English, German, French and Spanish; applications add languages or
+//! reword messages with [`Catalog::with_translation`] and
+//! `Server::with_catalog`. English is used when no preferred language has
+//! the message.
+
+use std::collections::HashMap;
+
+/// Language used when none of the preferred ones has a message.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// A built-in error message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Message {
+    /// 404: no route for the path.
+    NotFound,
+    /// 503: the route is disabled in the config file.
+    RouteDisabled,
+    /// 503: the task limit has been reached.
+    ServerBusy,
+    /// 401: no API key, or an unknown one.
+    Unauthorized,
+    /// 403: the API key does not cover the path.
+    Forbidden,
+    /// 429: the API key's rate limit is exhausted.
+    RateLimited,
+    /// 429: the API key's quota is used up. Takes `{limit}` and
+    /// `{resource}`.
+    QuotaExceeded,
+    /// 403: the client's address is banned from opening sessions.
+    Banned,
+    /// 429: the connection already carries a WebTransport session.
+    SessionLimit,
+    /// 413: the request body is over the route's limit. Takes `{limit}`.
+    PayloadTooLarge,
+    /// 417: the `expect` header asks for something other than
+    /// `100-continue`.
+    ExpectationFailed,
+    /// 504: the request's deadline passed before its handler finished.
+    DeadlineExceeded,
+    /// 500: the handler failed or panicked.
+    InternalError,
+}
+
+/// Built-in translations, by language.
+const BUILT_IN: &[(&str, &[(Message, &str)])] = &[
+    (
+        "en",
+        &[
+            (Message::NotFound, "Not Found"),
+            (Message::RouteDisabled, "Route Disabled"),
+            (Message::ServerBusy, "Server Busy"),
+            (Message::Unauthorized, "Missing or invalid API key"),
+            (Message::Forbidden, "API key not valid for this path"),
+            (Message::RateLimited, "Rate limit exceeded"),
+            (Message::QuotaExceeded, "Quota of {limit} {resource} exceeded"),
+            (Message::Banned, "Banned from this server"),
+            (Message::SessionLimit, "One WebTransport session per connection"),
+            (Message::PayloadTooLarge, "Request body over {limit} bytes"),
+            (Message::ExpectationFailed, "Unsupported expectation"),
+            (Message::DeadlineExceeded, "Deadline exceeded"),
+            (Message::InternalError, "Internal Server Error"),
+        ],
+    ),
+    (
+        "de",
+        &[
+            (Message::NotFound, "Nicht gefunden"),
+            (Message::RouteDisabled, "Route deaktiviert"),
+            (Message::ServerBusy, "Server ausgelastet"),
+            (Message::Unauthorized, "API-Schlüssel fehlt oder ist ungültig"),
+            (Message::Forbidden, "API-Schlüssel gilt nicht für diesen Pfad"),
+            (Message::RateLimited, "Ratenlimit überschritten"),
+            (Message::QuotaExceeded, "Kontingent von {limit} {resource} überschritten"),
+            (Message::Banned, "Von diesem Server gesperrt"),
+            (Message::SessionLimit, "Eine WebTransport-Sitzung pro Verbindung"),
+            (Message::PayloadTooLarge, "Anfragetext über {limit} Bytes"),
+            (Message::ExpectationFailed, "Nicht unterstützte Erwartung"),
+            (Message::DeadlineExceeded, "Frist überschritten"),
+            (Message::InternalError, "Interner Serverfehler"),
+        ],
+    ),
+    (
+        "fr",
+        &[
+            (Message::NotFound, "Introuvable"),
+            (Message::RouteDisabled, "Route désactivée"),
+            (Message::ServerBusy, "Serveur occupé"),
+            (Message::Unauthorized, "Clé d'API manquante ou invalide"),
+            (Message::Forbidden, "Clé d'API non valide pour ce chemin"),
+            (Message::RateLimited, "Limite de requêtes dépassée"),
+            (Message::QuotaExceeded, "Quota de {limit} {resource} dépassé"),
+            (Message::Banned, "Banni de ce serveur"),
+            (Message::SessionLimit, "Une session WebTransport par connexion"),
+            (Message::PayloadTooLarge, "Corps de requête de plus de {limit} octets"),
+            (Message::ExpectationFailed, "Attente non prise en charge"),
+            (Message::DeadlineExceeded, "Délai dépassé"),
+            (Message::InternalError, "Erreur interne du serveur"),
+        ],
+    ),
+    (
+        "es",
+        &[
+            (Message::NotFound, "No encontrado"),
+            (Message::RouteDisabled, "Ruta desactivada"),
+            (Message::ServerBusy, "Servidor ocupado"),
+            (Message::Unauthorized, "Falta la clave de API o no es válida"),
+            (Message::Forbidden, "La clave de API no es válida para esta ruta"),
+            (Message::RateLimited, "Límite de solicitudes superado"),
+            (Message::QuotaExceeded, "Cuota de {limit} {resource} superada"),
+            (Message::Banned, "Bloqueado en este servidor"),
+            (Message::SessionLimit, "Una sesión WebTransport por conexión"),
+            (Message::PayloadTooLarge, "Cuerpo de la solicitud de más de {limit} bytes"),
+            (Message::ExpectationFailed, "Expectativa no admitida"),
+            (Message::DeadlineExceeded, "Plazo excedido"),
+            (Message::InternalError, "Error interno del servidor"),
+        ],
+    ),
+];
+
+/// A message in the language picked for a request.
+#[derive(Debug, Clone)]
+pub struct Localized {
+    pub text: String,
+    /// Sent as `content-language`.
+    pub language: String,
+}
+
+/// Error messages by language.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    languages: HashMap<String, HashMap<Message, String>>,
+}
+
+impl Catalog {
+    /// A catalog with the built-in translations.
+    pub fn new() -> Self {
+        let languages = BUILT_IN
+            .iter()
+            .map(|(language, messages)| {
+                let messages = messages
+                    .iter()
+                    .map(|(message, text)| (*message, text.to_string()))
+                    .collect();
+                (language.to_string(), messages)
+            })
+            .collect();
+        Self { languages }
+    }
+
+    /// Use `text` for `message` in `language` (a tag such as `pt` or
+    /// `pt-BR`). `{limit}`-style placeholders are filled in as for the
+    /// built-in text.
+    pub fn with_translation(mut self, language: &str, message: Message, text: &str) -> Self {
+        self.languages
+            .entry(language.to_ascii_lowercase())
+            .or_default()
+            .insert(message, text.to_string());
+        self
+    }
+
+    /// `message` in the language `accept_language` (an `Accept-Language`
+    /// header) prefers, with `{name}` placeholders replaced from `args`.
+    pub fn localize(
+        &self,
+        message: Message,
+        accept_language: Option<&str>,
+        args: &[(&str, &str)],
+    ) -> Localized {
+        let (language, text) = preferred_languages(accept_language.unwrap_or(""))
+            .into_iter()
+            .find_map(|tag| self.lookup(&tag, message))
+            .or_else(|| self.lookup(DEFAULT_LANGUAGE, message))
+            .unwrap_or((DEFAULT_LANGUAGE, "Error"));
+
+        let mut text = text.to_string();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        Localized {
+            text,
+            language: language.to_string(),
+        }
+    }
+
+    /// The message for `tag`, or else for its primary language (`de` for
+    /// `de-CH`), with the language it was found under.
+    fn lookup(&self, tag: &str, message: Message) -> Option<(&str, &str)> {
+        let primary = tag.split('-').next().unwrap_or(tag);
+        [tag, primary].into_iter().find_map(|language| {
+            let (language, messages) = self.languages.get_key_value(language)?;
+            Some((language.as_str(), messages.get(&message)?.as_str()))
+        })
+    }
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Language tags from an `Accept-Language` header, lowercased, most
+/// preferred first. Tags with `q=0` and the `*` wildcard are left out.
+fn preferred_languages(header: &str) -> Vec<String> {
+    let mut tags: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let tag = parts.next()?.trim().to_ascii_lowercase();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    // Stable, so equally preferred tags keep their order
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
@@ -6,23 +6,129 @@
 //! - WebTransport bidirectional streams and datagrams
 //! - QUIC transport with Quinn
 //! - Self-signed TLS certificates
+//!
+//! Run `server replay <file>` to replay a recorded WebTransport session
+//! through the current handlers instead of starting the server, and
+//! `server cert show` or `server cert verify --hash <hex>` to inspect the
+//! certificate a running server serves.
 
+mod affinity;
+mod api_keys;
+mod backplane;
+mod budget;
+mod cert_command;
+mod certs;
+mod chunks;
+mod coalesce;
+mod connection;
+mod context;
+mod deadline;
+mod diagnostics;
+mod fallback;
 mod handlers;
+mod handshake;
+mod i18n;
+mod ndjson;
+mod peer_cert;
+mod pool;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod rate_limit;
+mod recorder;
+mod reload;
 mod router;
+mod rpc;
+mod runtime;
+mod schema;
 mod server;
+mod sessions;
+mod shaping;
+mod sinks;
+mod sync;
+mod systemd;
+mod tasks;
+mod telemetry;
+mod ticker;
+mod topics;
+mod usage;
+mod vhost;
+mod webhooks;
 mod webtransport;
+#[cfg(feature = "web-ui")]
+mod webui;
 
+use api_keys::ApiKeyStore;
+use certs::CertHashes;
 use common::ServerConfig;
+use http::Method;
+use i18n::{Catalog, Message};
+use reload::{LiveConfig, LogLevelHandle};
+use rate_limit::RateLimit;
 use router::Router;
-use tracing::info;
+use rpc::Dispatcher;
+use schema::Schema;
+use server::Server;
+use sessions::Sessions;
+use sinks::{JsonLinesSink, Sinks};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use telemetry::Telemetry;
+use usage::Usage;
+use vhost::VirtualHost;
+use webhooks::Webhooks;
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload as log_reload};
+
+fn main() -> anyhow::Result<()> {
+    // Initialize logging; the level can be changed by reloading the config
+    let (level, log_level) = log_reload::Layer::new(LevelFilter::INFO);
+    let logging = tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .with_target(false)
+                .with_level(true)
+                // Recent lines for diagnostics bundles
+                .and_then(diagnostics::LogRing)
+                .with_filter(level),
+        );
+    // tokio-console sees every task, whatever the log level
+    #[cfg(feature = "console")]
+    let logging = logging.with(console_subscriber::spawn());
+    logging.init();
+
+    // Runtime settings, read before the runtime starts
+    let mut config = ServerConfig::default();
+    // e.g. WORKER_THREADS=4 to leave cores to other processes
+    if let Ok(threads) = std::env::var("WORKER_THREADS") {
+        config = config.with_worker_threads(threads.parse()?);
+    }
+    // e.g. MAX_BLOCKING_THREADS=64 to bound the threads for file IO
+    if let Ok(threads) = std::env::var("MAX_BLOCKING_THREADS") {
+        config = config.with_max_blocking_threads(threads.parse()?);
+    }
+    // Receive packets and accept connections on a thread of their own
+    if std::env::var_os("ACCEPT_RUNTIME").is_some() {
+        config = config.with_accept_runtime(true);
+    }
+
+    runtime::build(&config)?.block_on(serve(config, log_level))
+}
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .with_level(true)
-        .init();
+async fn serve(mut config: ServerConfig, log_level: LogLevelHandle) -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, path] = args.as_slice()
+        && command == "replay"
+    {
+        return replay(path).await;
+    }
+    if let [_, command, args @ ..] = args.as_slice()
+        && command == "cert"
+    {
+        return cert_command::run(args).await;
+    }
 
     // Install the AWS LC crypto provider
     rustls::crypto::aws_lc_rs::default_provider()
@@ -30,25 +136,404 @@ async fn main() -> anyhow::Result<()> {
         .unwrap();
 
     // Configure the server
-    let config = ServerConfig::default()
+    config = config
         .with_hostnames(vec![
             "localhost".to_string(),
             "127.0.0.1".to_string(),
         ])
         .with_idle_timeout(10); // 10 seconds for demo
 
+    // e.g. BIND_ADDR=0.0.0.0:4433 to listen on all interfaces in a container
+    if let Ok(addr) = std::env::var("BIND_ADDR") {
+        config.bind_addr = addr.parse()?;
+    }
+
+    // Make clients validate their address before the handshake, e.g. when
+    // exposed to the internet
+    if std::env::var_os("REQUIRE_RETRY").is_some() {
+        config = config.with_retry(true);
+    }
+    // e.g. HANDSHAKE_RATE_LIMIT=20 to allow 20 connection attempts per second
+    // from each /24 (IPv4) or /56 (IPv6) prefix
+    if let Ok(per_sec) = std::env::var("HANDSHAKE_RATE_LIMIT") {
+        config = config.with_handshake_rate_limit(per_sec.parse()?, 60);
+    }
+    // e.g. MAX_TASKS=10000 to cap connections plus requests in flight
+    if let Ok(max) = std::env::var("MAX_TASKS") {
+        config = config.with_max_tasks(max.parse()?);
+    }
+    // e.g. MAX_CONNECTIONS=2000 to refuse clients beyond that many
+    if let Ok(max) = std::env::var("MAX_CONNECTIONS") {
+        config = config.with_max_connections(max.parse()?);
+    }
+    // e.g. MAX_CONNECTION_REQUESTS=32 to hold back clients with more in flight
+    if let Ok(max) = std::env::var("MAX_CONNECTION_REQUESTS") {
+        config = config.with_max_connection_requests(max.parse()?);
+    }
+    // e.g. RATE_LIMIT=20 RATE_LIMIT_BURST=40 to answer clients sending more
+    // with 429
+    if let Ok(per_sec) = std::env::var("RATE_LIMIT") {
+        let per_sec = per_sec.parse()?;
+        let burst = match std::env::var("RATE_LIMIT_BURST") {
+            Ok(burst) => burst.parse()?,
+            Err(_) => per_sec,
+        };
+        config = config.with_rate_limit(per_sec, burst);
+    }
+    // e.g. RATE_LIMIT_HEADER=x-real-ip RATE_LIMIT_PROXIES=10.0.0.2,10.0.0.3
+    // behind proxies setting that header
+    if let Ok(header) = std::env::var("RATE_LIMIT_HEADER") {
+        let proxies = std::env::var("RATE_LIMIT_PROXIES").unwrap_or_default();
+        let proxies = proxies
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        config = config.with_rate_limit_header(header, &proxies);
+    }
+
+    // Handle every REST request on its connection's task, e.g. for
+    // microbenchmarks of tiny responses
+    if std::env::var_os("INLINE_REST").is_some() {
+        config = config.with_inline_rest(true);
+    }
+
+    // e.g. STREAM_TIMEOUT_SECS=120 for clients on slow links, or 0 to let
+    // WebTransport streams stall forever
+    if let Ok(secs) = std::env::var("STREAM_TIMEOUT_SECS") {
+        config = config.with_stream_timeout(secs.parse()?);
+    }
+
+    // e.g. DRAIN_TIMEOUT_SECS=60 to let long requests finish on shutdown
+    if let Ok(secs) = std::env::var("DRAIN_TIMEOUT_SECS") {
+        config = config.with_drain_timeout(secs.parse()?);
+    }
+
+    // e.g. MAX_SESSION_STREAMS=10 to cap each session's open bidi and uni
+    // streams at 10 each
+    if let Ok(max) = std::env::var("MAX_SESSION_STREAMS") {
+        let max = max.parse()?;
+        config = config.with_session_stream_limits(max, max);
+    }
+
+    // e.g. SLOW_REQUEST_MS=500 and LARGE_RESPONSE_BYTES=1048576 to warn
+    // about requests taking half a second or returning a MiB
+    if let Ok(ms) = std::env::var("SLOW_REQUEST_MS") {
+        config = config.with_slow_request_threshold(ms.parse()?);
+    }
+    if let Ok(bytes) = std::env::var("LARGE_RESPONSE_BYTES") {
+        config = config.with_large_response_threshold(bytes.parse()?);
+    }
+
+    // Settings from a config file; some can be reloaded at runtime
+    let live = match std::env::var_os("CONFIG_FILE") {
+        Some(path) => {
+            let (live, file) = LiveConfig::from_file(path, Some(log_level))?;
+            config = file.apply_startup(config);
+            tokio::spawn(live.clone().watch());
+            live
+        }
+        None => LiveConfig::new(Some(log_level)),
+    };
+    let reload_live = live.clone();
+
+    // Write access logs and audit events as JSON lines to ACCESS_LOG_FILE
+    let sinks = match std::env::var_os("ACCESS_LOG_FILE") {
+        Some(path) => {
+            let sink = JsonLinesSink::append(Path::new(&path))?;
+            Sinks::new().with_access_log(sink.clone()).with_audit(sink)
+        }
+        None => Sinks::new(),
+    };
+    // e.g. ACCESS_LOG_SAMPLE=100 to log 1 in 100 successful requests; failed
+    // requests, and with ACCESS_LOG_SLOW_MS those that slow or slower, are
+    // always logged
+    let sinks = match std::env::var("ACCESS_LOG_SAMPLE") {
+        Ok(every) => {
+            let slow = match std::env::var("ACCESS_LOG_SLOW_MS") {
+                Ok(ms) => Some(Duration::from_millis(ms.parse()?)),
+                Err(_) => None,
+            };
+            sinks.with_access_sampling(every.parse()?, slow)
+        }
+        Err(_) => sinks,
+    };
+
+    // Require API keys, managed at /admin/keys; the admin endpoints only
+    // accept loopback clients instead
+    let api_keys = match std::env::var_os("API_KEYS_FILE") {
+        Some(path) => Some(ApiKeyStore::open(path)?.with_public(&[
+            "/",
+            "/health",
+            "/admin/reload",
+            "/admin/keys",
+            "/admin/keys/revoke",
+            "/admin/usage",
+            "/admin/stats",
+            "/admin/sessions",
+            "/admin/sessions/kick",
+            "/admin/sessions/ban",
+        ])
+        .with_audit(Arc::clone(&sinks.audit))),
+        None => None,
+    };
+
+    // Record WebTransport sessions for later replay
+    if let Ok(dir) = std::env::var("RECORD_DIR") {
+        config = config.with_recording_dir(dir);
+    }
+    // Keep the certificate hash stable across restarts
+    if let Ok(dir) = std::env::var("CERT_CACHE_DIR") {
+        config = config.with_cert_cache_dir(dir);
+    }
+    // Write a diagnostics bundle for bug reports when the server panics
+    if let Ok(dir) = std::env::var("DIAGNOSTICS_DIR") {
+        config = config.with_diagnostics_dir(dir);
+    }
+
+    // e.g. CLIENT_CA=certs/clients-ca.pem to require client certificates
+    // issued by that CA (mutual TLS); CLIENT_CERT_OPTIONAL=1 to also let
+    // clients without one connect
+    if let Ok(path) = std::env::var("CLIENT_CA") {
+        config = config.with_client_ca(path);
+    }
+    if std::env::var_os("CLIENT_CERT_OPTIONAL").is_some() {
+        config = config.with_client_cert_optional(true);
+    }
+
+    // Write TLS secrets for Wireshark; never enable this in production
+    if std::env::var_os("SSLKEYLOGFILE").is_some() {
+        warn!("SSLKEYLOGFILE is set: logging TLS secrets");
+        config = config.with_key_log(true);
+    }
+
     info!("Starting HTTP/3 server");
 
+    // Live WebTransport sessions, for pushing messages from REST handlers
+    let sessions = Sessions::new();
+    let notify_sessions = sessions.clone();
+    let (list_sessions, kick_sessions, ban_sessions) =
+        (sessions.clone(), sessions.clone(), sessions.clone());
+
+    // Telemetry datagrams, aggregated for /api/telemetry and the metrics sink
+    let telemetry = Telemetry::new().with_metrics(Arc::clone(&sinks.metrics));
+    let telemetry_api = telemetry.clone();
+
+    // Certificate hashes for clients to pin, kept current across rotations
+    let cert_hashes = CertHashes::new();
+    let cert_hashes_api = cert_hashes.clone();
+    let connect_info_hashes = cert_hashes.clone();
+
     // Create router with REST and streaming routes
-    let router = Router::new()
+    let mut router = Router::new()
         // REST endpoints (request → response → done)
         .route("/", handlers::index)
-        .route("/health", handlers::health)
+        // Health checks skip the spawn per request
+        .route_inline("/health", handlers::health)
         .route("/api/info", handlers::api_info)
+        .route("/api/connection", handlers::connection_stats)
+        .route("/api/client-cert", handlers::client_cert)
+        .route("/api/cert-hashes", move |req| {
+            handlers::cert_hashes(req, cert_hashes_api.clone())
+        })
+        .route("/api/connect-info", move |req| {
+            handlers::connect_info(req, connect_info_hashes.clone())
+        })
+        // Typed JSON in and out
+        .json("/api/echo", handlers::echo)
+        // Push a message to WebTransport sessions
+        .stream("/api/notify", move |req, stream| {
+            handlers::notify(req, stream, notify_sessions.clone())
+        })
+        .schema("/api/notify", Schema::new(&handlers::notify_schema())?)
+        .body_limit("/api/notify", handlers::MAX_NOTIFY_BODY as u64)
+        .methods("/api/notify", &[Method::POST])
+        // Every notification is pushed to each session, so limit senders
+        .rate_limit("/api/notify", RateLimit::per_second(5, 10))
+        .stream("/api/telemetry", move |req, stream| {
+            handlers::telemetry(req, stream, telemetry_api.clone())
+        })
+        // Admin endpoints (loopback only)
+        .nest(
+            "/admin",
+            Router::new()
+                // Re-read the config file
+                .stream("/reload", move |req, stream| {
+                    handlers::reload_config(req, stream, reload_live.clone())
+                })
+                .methods("/reload", &[Method::POST])
+                // Running connections and requests, against their caps
+                .route("/stats", handlers::server_stats)
+                // Kick or ban WebTransport sessions
+                .stream("/sessions", move |req, stream| {
+                    handlers::list_sessions(req, stream, list_sessions.clone())
+                })
+                .stream("/sessions/kick", move |req, stream| {
+                    handlers::kick_session(req, stream, kick_sessions.clone())
+                })
+                .methods("/sessions/kick", &[Method::POST])
+                .stream("/sessions/ban", move |req, stream| {
+                    handlers::ban_session(req, stream, ban_sessions.clone())
+                })
+                .methods("/sessions/ban", &[Method::POST]),
+        )
         // Streaming endpoints (server pushes multiple chunks)
         .stream("/stream/time", handlers::time_stream)
-        .stream("/stream/counter", handlers::counter_stream);
+        .stream("/stream/counter", handlers::counter_stream)
+        .stream("/stream/records", handlers::records_stream)
+        .body_stream("/stream/countdown", "text/plain", handlers::countdown)
+        // Streaming request body (uploads of any size)
+        .stream("/api/upload", handlers::upload)
+        .methods("/api/upload", &[Method::POST])
+        // JSON-RPC 2.0 (HTTP/3 request stream or WebTransport session)
+        .rpc(
+            "/rpc",
+            Dispatcher::new()
+                .method("echo", handlers::rpc_echo)
+                .register("add", handlers::rpc_add)
+                .register("multiply", handlers::rpc_multiply)
+                .register("divide", handlers::rpc_divide)
+                .register("time", handlers::rpc_time),
+        )
+        // Routes for requests with `:authority` status.localhost
+        .host(
+            "status.localhost",
+            Router::new().route("/", handlers::health),
+        );
+    // e.g. MAX_UPLOAD_BYTES=1073741824 to refuse uploads over 1 GiB, before
+    // they are sent when the client asks for 100 Continue
+    if let Ok(max) = std::env::var("MAX_UPLOAD_BYTES") {
+        router = router.body_limit("/api/upload", max.parse()?);
+    }
+    let usage = Usage::new();
+    if let Some(store) = &api_keys {
+        let (keys, revoke) = (store.clone(), store.clone());
+        let key_usage = usage.clone();
+        router = router.nest(
+            "/admin",
+            Router::new()
+                .stream("/keys", move |req, stream| {
+                    handlers::api_keys(req, stream, keys.clone())
+                })
+                .methods("/keys", &[Method::GET, Method::POST])
+                .stream("/keys/revoke", move |req, stream| {
+                    handlers::revoke_api_key(req, stream, revoke.clone())
+                })
+                .methods("/keys/revoke", &[Method::POST])
+                .stream("/usage", move |req, stream| {
+                    handlers::usage(req, stream, key_usage.clone())
+                }),
+        );
+    }
+    // CPU flamegraphs (loopback only)
+    #[cfg(feature = "pprof")]
+    {
+        router = router
+            .stream("/debug/pprof", handlers::pprof)
+            .methods("/debug/pprof", &[Method::GET]);
+    }
+
+    // The web client, at / instead of the greeting
+    #[cfg(feature = "web-ui")]
+    {
+        router = webui::routes(router);
+    }
+
+    // Virtual host with its own certificate, selected by SNI; its errors
+    // are all problem documents
+    let api = VirtualHost::self_signed(
+        "api.localhost",
+        Router::new()
+            .route("/", handlers::api_root)
+            .route("/health", handlers::health)
+            .route("/api/info", handlers::api_info)
+            .fallback(handlers::api_not_found)
+            .error_handler(handlers::api_error),
+    )?;
+
+    // The route table, with methods and kinds; added last to list them all
+    let router = router.route_index("/api/routes");
+
+    let mut server = Server::bind(config, router, vec![api])?
+        .with_sessions(sessions)
+        .with_cert_hashes(cert_hashes)
+        .with_telemetry(telemetry)
+        .with_live_config(live)
+        .with_usage(usage)
+        .with_sinks(sinks)
+        .with_catalog(catalog());
+
+    // POST server events to WEBHOOK_URLS (comma-separated)
+    if let Ok(urls) = std::env::var("WEBHOOK_URLS") {
+        let urls = urls.split(',').map(|url| url.trim().to_string()).collect();
+        server = server.with_webhooks(Webhooks::new(urls)?);
+    }
+    if let Some(store) = api_keys {
+        server = server.with_api_keys(store);
+    }
+
+    // Share topics and notifications with other instances, e.g.
+    // BACKPLANE_URL=redis://localhost:6379 or nats://localhost:4222
+    if let Ok(url) = std::env::var("BACKPLANE_URL") {
+        server = server.with_backplane(backplane::from_url(&url)?);
+    }
+    if let Ok(id) = std::env::var("INSTANCE_ID") {
+        server = server.with_instance_id(id);
+    }
+
+    // Publish the time to the "time" topic for WebTransport subscribers
+    tokio::spawn(handlers::publish_time(server.topics()));
 
     // Start the server
-    server::run(config, router).await
+    server::run(server).await
+}
+
+/// The built-in error messages, with Portuguese added.
+fn catalog() -> Catalog {
+    [
+        (Message::NotFound, "Não encontrado"),
+        (Message::RouteDisabled, "Rota desativada"),
+        (Message::ServerBusy, "Servidor ocupado"),
+        (Message::Unauthorized, "Chave de API ausente ou inválida"),
+        (Message::Forbidden, "Chave de API não é válida para esta rota"),
+        (Message::RateLimited, "Limite de requisições excedido"),
+        (Message::QuotaExceeded, "Cota de {limit} {resource} excedida"),
+        (Message::Banned, "Bloqueado neste servidor"),
+        (Message::SessionLimit, "Uma sessão WebTransport por conexão"),
+        (Message::PayloadTooLarge, "Corpo da requisição com mais de {limit} bytes"),
+        (Message::ExpectationFailed, "Expectativa não suportada"),
+        (Message::DeadlineExceeded, "Prazo excedido"),
+        (Message::InternalError, "Erro interno do servidor"),
+    ]
+    .into_iter()
+    .fold(Catalog::new(), |catalog, (message, text)| {
+        catalog.with_translation("pt", message, text)
+    })
+}
+
+/// Replay a recorded WebTransport session and report any differences.
+async fn replay(path: &str) -> anyhow::Result<()> {
+    info!("Replaying session recording {}", path);
+
+    let report = recorder::replay(std::path::Path::new(path)).await?;
+    for mismatch in &report.mismatches {
+        warn!(
+            "Mismatch on {}: expected {:?}, got {:?}",
+            mismatch.channel,
+            String::from_utf8_lossy(&mismatch.expected),
+            String::from_utf8_lossy(&mismatch.actual)
+        );
+    }
+
+    if !report.is_ok() {
+        anyhow::bail!(
+            "{} of {} channels differ from the recording",
+            report.mismatches.len(),
+            report.channels
+        );
+    }
+
+    info!("Replay OK: {} channels match the recording", report.channels);
+    Ok(())
 }
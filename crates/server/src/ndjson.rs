@@ -0,0 +1,95 @@
+//! Newline-delimited JSON responses.
+//!
+//! An [`NdjsonStream`] writes one JSON record per line straight to the
+//! request stream. Each write waits until QUIC flow control lets the data
+//! out, so a handler producing records faster than the client reads them
+//! is slowed to the client's pace instead of buffering without bound or
+//! guessing at a delay. [`NdjsonStream::send_all`] drains a
+//! [`futures::Stream`] of records, batching those that are ready at once
+//! into one DATA frame.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use h3::server::RequestStream;
+use http::{Response, StatusCode};
+use serde::Serialize;
+use std::marker::PhantomData;
+
+/// Records sent in one frame at most, when more are ready.
+pub const MAX_BATCH: usize = 64;
+
+type H3Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// A response of `T` records, one JSON document per line.
+pub struct NdjsonStream<T> {
+    stream: H3Stream,
+    /// Records sent so far.
+    sent: u64,
+    _records: PhantomData<fn(&T)>,
+}
+
+impl<T: Serialize> NdjsonStream<T> {
+    /// Respond `200 OK` with `content-type: application/x-ndjson` on
+    /// `stream`, ready for records.
+    pub async fn start(mut stream: H3Stream) -> anyhow::Result<Self> {
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/x-ndjson")
+            .body(())?;
+        stream.send_response(response).await?;
+        Ok(Self {
+            stream,
+            sent: 0,
+            _records: PhantomData,
+        })
+    }
+
+    /// Send `record` as a line, once flow control allows.
+    #[allow(dead_code)]
+    pub async fn send(&mut self, record: &T) -> anyhow::Result<()> {
+        let mut line = BytesMut::new();
+        encode(&mut line, record)?;
+        self.stream.send_data(line.freeze()).await?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    /// Send every record of `records` until it ends, each batch once flow
+    /// control allows. Returns the number sent.
+    pub async fn send_all<S>(&mut self, records: S) -> anyhow::Result<u64>
+    where
+        S: Stream<Item = T>,
+    {
+        let mut batches = std::pin::pin!(records.ready_chunks(MAX_BATCH));
+        let mut sent = 0;
+        while let Some(batch) = batches.next().await {
+            let mut lines = BytesMut::new();
+            for record in &batch {
+                encode(&mut lines, record)?;
+            }
+            self.stream.send_data(lines.freeze()).await?;
+            sent += batch.len() as u64;
+        }
+        self.sent += sent;
+        Ok(sent)
+    }
+
+    /// Records sent so far.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// End the response once everything sent has been delivered.
+    pub async fn finish(mut self) -> anyhow::Result<()> {
+        self.stream.finish().await?;
+        Ok(())
+    }
+}
+
+/// Append `record` to `buf` as a line of JSON.
+fn encode<T: Serialize>(buf: &mut BytesMut, record: &T) -> anyhow::Result<()> {
+    let mut writer = buf.writer();
+    serde_json::to_writer(&mut writer, record)?;
+    writer.into_inner().put_u8(b'\n');
+    Ok(())
+}
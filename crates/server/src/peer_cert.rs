@@ -0,0 +1,109 @@
+//! Client certificates, with mutual TLS.
+//!
+//! Given a client CA (`ServerConfig::client_ca`), the server asks clients
+//! for a certificate during the handshake and accepts only those issued by
+//! the CA (or, with `client_cert_optional`, none at all). The certificate a
+//! client presented is parsed once per connection into a
+//! [`PeerCertificate`], which every request on the connection carries in
+//! its extensions and WebTransport sessions in their
+//! [`ConnectRequest`](crate::webtransport::ConnectRequest), so handlers can
+//! authorize by subject, SAN or fingerprint:
+//!
+//! ```ignore
+//! let Some(cert) = req.extensions().get::<PeerCertificate>() else {
+//!     return Err(HandlerError::new(StatusCode::FORBIDDEN, "no client certificate"));
+//! };
+//! if !cert.sans.iter().any(|san| san == "DNS:billing.internal") { ... }
+//! ```
+
+use crate::api_keys::hex;
+use crate::cert_command::general_name;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use x509_parser::parse_x509_certificate;
+
+/// The certificate a client authenticated with.
+#[derive(Debug, Clone)]
+pub struct PeerCertificate {
+    /// Distinguished name of the subject, e.g. `CN=alice, O=Example`.
+    pub subject: String,
+    /// Distinguished name of the CA that issued it.
+    pub issuer: String,
+    /// Subject alternative names, e.g. `DNS:worker-1.internal` or
+    /// `IP:10.0.0.7`.
+    pub sans: Vec<String>,
+    /// SHA-256 fingerprint of the certificate, in hex.
+    pub fingerprint: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+impl PeerCertificate {
+    /// The certificate the client of `conn` presented, if it did.
+    pub fn of(conn: &quinn::Connection) -> Option<Self> {
+        let certs = conn
+            .peer_identity()?
+            .downcast::<Vec<CertificateDer<'static>>>()
+            .ok()?;
+        match Self::parse(certs.first()?) {
+            Ok(cert) => Some(cert),
+            Err(e) => {
+                warn!("Unreadable client certificate from {}: {}", conn.remote_address(), e);
+                None
+            }
+        }
+    }
+
+    /// The details of the DER certificate `der`.
+    pub fn parse(der: &[u8]) -> anyhow::Result<Self> {
+        let (_, cert) = parse_x509_certificate(der)
+            .map_err(|e| anyhow::anyhow!("parsing the certificate: {}", e))?;
+        let sans = cert
+            .subject_alternative_name()?
+            .map(|san| san.value.general_names.iter().map(general_name).collect())
+            .unwrap_or_default();
+        let time = |timestamp: i64| UNIX_EPOCH + Duration::from_secs(timestamp.max(0) as u64);
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            sans,
+            fingerprint: hex(&Sha256::digest(der)),
+            not_before: time(cert.validity().not_before.timestamp()),
+            not_after: time(cert.validity().not_after.timestamp()),
+        })
+    }
+}
+
+/// Verifies client certificates against the CA certificates in the PEM
+/// file at `path`; lets clients without one through if `optional`.
+pub fn client_verifier(
+    path: &Path,
+    optional: bool,
+    provider: &Arc<CryptoProvider>,
+) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let pem = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("reading {}: {}", path.display(), e))?;
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        roots.add(cert?)?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("{} holds no CA certificates", path.display());
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+    let verifier = if optional {
+        verifier.allow_unauthenticated()
+    } else {
+        verifier
+    };
+    Ok(verifier.build()?)
+}
@@ -0,0 +1,146 @@
+//! Reusable buffers for body and stream IO.
+//!
+//! Request bodies read by handlers and WebTransport unidirectional streams
+//! borrow a buffer from the process-wide [`buffers`] pool and hand it back
+//! when dropped, instead of allocating one per message. Datagram payloads
+//! and bidirectional stream echoes need no pool: they pass received `Bytes`
+//! straight back (see [`crate::chunks`]).
+//!
+//! Hits, misses and discarded buffers are reported to the metrics sink as
+//! `h3_buffer_pool_*_total` counters by [`report_stats`].
+
+use crate::sinks::MetricsSink;
+use bytes::BytesMut;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+/// Capacity of a new buffer.
+pub const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Buffers kept for reuse; more are freed when returned.
+const MAX_POOLED: usize = 256;
+
+/// Buffers that grew beyond this are freed rather than kept.
+const MAX_RETAINED: usize = 1024 * 1024;
+
+/// How often pool stats are reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+static BUFFERS: LazyLock<BufferPool> = LazyLock::new(BufferPool::default);
+
+/// The process-wide buffer pool.
+pub fn buffers() -> &'static BufferPool {
+    &BUFFERS
+}
+
+/// A pool of `BytesMut` buffers.
+#[derive(Default)]
+pub struct BufferPool {
+    free: Mutex<Vec<BytesMut>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discarded: AtomicU64,
+}
+
+/// Counts since the pool was created.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolStats {
+    /// Buffers handed out from the pool.
+    pub hits: u64,
+    /// Buffers allocated because the pool was empty.
+    pub misses: u64,
+    /// Returned buffers freed because the pool was full or they were too big.
+    pub discarded: u64,
+}
+
+impl BufferPool {
+    /// An empty buffer with at least [`BUFFER_SIZE`] bytes of capacity.
+    pub fn get(&'static self) -> PooledBuf {
+        let buf = match self.free.lock().unwrap().pop() {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(BUFFER_SIZE)
+            }
+        };
+        PooledBuf {
+            buf: Some(buf),
+            pool: self,
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        if buf.capacity() <= MAX_RETAINED {
+            let mut free = self.free.lock().unwrap();
+            if free.len() < MAX_POOLED {
+                free.push(buf);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A buffer borrowed from a [`BufferPool`], returned to it on drop.
+pub struct PooledBuf {
+    buf: Option<BytesMut>,
+    pool: &'static BufferPool,
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        self.buf.as_ref().expect("buffer taken before drop")
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        self.buf.as_mut().expect("buffer taken before drop")
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+/// Report what the pool did since the last report to `metrics`, every
+/// [`REPORT_INTERVAL`].
+pub async fn report_stats(metrics: Arc<dyn MetricsSink>) {
+    let mut last = PoolStats::default();
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let stats = buffers().stats();
+        let deltas = [
+            ("h3_buffer_pool_hits_total", stats.hits - last.hits),
+            ("h3_buffer_pool_misses_total", stats.misses - last.misses),
+            ("h3_buffer_pool_discarded_total", stats.discarded - last.discarded),
+        ];
+        for (name, delta) in deltas {
+            if delta > 0 {
+                metrics.counter(name, delta, &[]);
+            }
+        }
+        last = stats;
+    }
+}
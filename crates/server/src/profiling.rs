@@ -0,0 +1,78 @@
+//! CPU profiles of the running server, with the `pprof` feature.
+//!
+//! `GET /debug/pprof?seconds=N` samples the stacks of every thread
+//! [`FREQUENCY`] times a second for N seconds ([`DEFAULT_SECONDS`] if not
+//! given, at most [`MAX_SECONDS`]) and responds with a flamegraph as SVG,
+//! or 204 No Content if the server sat idle and nothing was sampled.
+//! Sampling is process-wide, so one profile runs at a time; a request made
+//! meanwhile is refused with 409 Conflict.
+
+use crate::context::RequestContext;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Samples per second; off a round number so as not to run in lockstep
+/// with timers.
+pub const FREQUENCY: i32 = 99;
+
+pub const DEFAULT_SECONDS: u64 = 10;
+
+pub const MAX_SECONDS: u64 = 60;
+
+/// Frames from these libraries say little and confuse unwinding.
+const BLOCKLIST: &[&str] = &["libc", "libgcc", "pthread", "vdso"];
+
+/// Whether a profile is being taken.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// How long to profile for, from a `seconds=N` query parameter.
+pub fn duration(query: Option<&str>) -> anyhow::Result<Duration> {
+    let seconds = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(name, _)| name == "seconds")
+        .map(|(_, value)| value);
+    let Some(seconds) = seconds else {
+        return Ok(Duration::from_secs(DEFAULT_SECONDS));
+    };
+    match seconds.parse() {
+        Ok(seconds @ 1..=MAX_SECONDS) => Ok(Duration::from_secs(seconds)),
+        _ => anyhow::bail!("seconds must be from 1 to {}, not {:?}", MAX_SECONDS, seconds),
+    }
+}
+
+/// Profile the process for `duration`, or until `ctx` is cancelled, and
+/// render the samples as a flamegraph SVG. `None` if a profile is already
+/// being taken.
+pub async fn flamegraph(
+    duration: Duration,
+    ctx: &RequestContext,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if PROFILING.swap(true, Ordering::AcqRel) {
+        return Ok(None);
+    }
+    let _profiling = Profiling;
+    sample(duration, ctx).await.map(Some)
+}
+
+/// Clears [`PROFILING`] when dropped, even if the request is.
+struct Profiling;
+
+impl Drop for Profiling {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Release);
+    }
+}
+
+async fn sample(duration: Duration, ctx: &RequestContext) -> anyhow::Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(BLOCKLIST)
+        .build()?;
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = ctx.cancelled() => {}
+    }
+    let report = guard.report().build()?;
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg)?;
+    Ok(svg)
+}
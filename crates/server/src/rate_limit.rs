@@ -0,0 +1,261 @@
+//! Request rate limits, per client.
+//!
+//! A [`RateLimit`] is a token bucket per client: it holds up to `burst`
+//! requests and refills at `per_sec`. Clients are told apart by their
+//! address. Behind a proxy, which sends every request from its own address,
+//! they can be told apart by a header the proxy sets instead, such as
+//! `x-forwarded-for`; the header is only trusted on requests from the
+//! proxy's addresses, so clients cannot pick their own bucket. A limit
+//! applies to every request when set in `ServerConfig::with_rate_limit`, or
+//! to one route with [`Router::rate_limit`](crate::router::Router::rate_limit);
+//! a request must pass both. Requests over the limit are answered with 429
+//! Too Many Requests and a `retry-after` header.
+//!
+//! ```ignore
+//! let proxy = "10.0.0.2".parse()?;
+//! Router::new()
+//!     .json("/api/echo", handlers::echo)
+//!     .rate_limit("/api/echo", RateLimit::per_second(5, 10).by_header("x-real-ip", &[proxy])?)
+//! ```
+
+use crate::connection::ConnectionHandle;
+use http::header::HeaderName;
+use http::{HeaderMap, Request};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often buckets that have filled up again are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Clients tracked at most; until the next prune, new clients beyond it are
+/// limited as if their bucket were empty.
+const MAX_CLIENTS: usize = 100_000;
+
+/// Token buckets for every client, shared by all connections.
+#[derive(Clone)]
+pub struct RateLimit {
+    per_sec: f64,
+    burst: f64,
+    /// Header telling clients apart, and the proxies trusted to set it.
+    header: Option<(HeaderName, Arc<[IpAddr]>)>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    by_client: HashMap<String, Bucket>,
+    /// When full buckets are dropped next.
+    next_prune: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimit {
+    /// `per_sec` requests a second per client, in bursts of up to `burst`.
+    pub fn per_second(per_sec: u32, burst: u32) -> Self {
+        Self {
+            per_sec: per_sec.max(1) as f64,
+            burst: burst.max(1) as f64,
+            header: None,
+            buckets: Arc::new(Mutex::new(Buckets {
+                by_client: HashMap::new(),
+                next_prune: Instant::now() + PRUNE_INTERVAL,
+            })),
+        }
+    }
+
+    /// Tell clients apart by the value of `header` on requests from
+    /// `proxies`, which set it; other requests are still told apart by
+    /// their address.
+    pub fn by_header(mut self, header: &str, proxies: &[IpAddr]) -> anyhow::Result<Self> {
+        let name = HeaderName::try_from(header)
+            .map_err(|_| anyhow::anyhow!("invalid header name {:?}", header))?;
+        if proxies.is_empty() {
+            anyhow::bail!("rate limiting by {} needs the addresses of proxies that set it", name);
+        }
+        let proxies = proxies.iter().map(|ip| ip.to_canonical()).collect();
+        self.header = Some((name, proxies));
+        Ok(self)
+    }
+
+    /// Take a token for the client that sent `req`, or return how long it
+    /// has to wait for one.
+    pub fn check<B>(&self, req: &Request<B>) -> Result<(), Duration> {
+        let remote = req
+            .extensions()
+            .get::<ConnectionHandle>()
+            .map(|conn| conn.remote_address().ip());
+        self.take(&self.client_key(req.headers(), remote), Instant::now())
+    }
+
+    /// The bucket of a request with `headers` from `remote`: the trusted
+    /// header's value if a proxy sent it, and the address otherwise.
+    fn client_key(&self, headers: &HeaderMap, remote: Option<IpAddr>) -> String {
+        let remote = remote.map(|ip| ip.to_canonical());
+        if let Some((header, proxies)) = &self.header
+            && remote.is_some_and(|ip| proxies.contains(&ip))
+            && let Some(value) = headers.get(header).and_then(|value| value.to_str().ok())
+        {
+            return format!("{}={}", header, value);
+        }
+        remote.map_or("-".to_string(), |ip| ip.to_string())
+    }
+
+    fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if now >= buckets.next_prune {
+            let (per_sec, burst) = (self.per_sec, self.burst);
+            buckets.by_client.retain(|_, bucket| bucket.level(now, per_sec) < burst);
+            buckets.next_prune = now + PRUNE_INTERVAL;
+        }
+
+        if !buckets.by_client.contains_key(key) && buckets.by_client.len() >= MAX_CLIENTS {
+            return Err(buckets.next_prune.saturating_duration_since(now));
+        }
+        let bucket = buckets.by_client.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.level(now, self.per_sec).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / self.per_sec;
+            return Err(Duration::from_secs_f64(wait));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Number of clients with a bucket.
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap().by_client.len()
+    }
+}
+
+impl Bucket {
+    /// Tokens in the bucket at `now`, before capping at the burst size.
+    fn level(&self, now: Instant, per_sec: f64) -> f64 {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * per_sec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn allows_a_burst_then_limits() {
+        let limit = RateLimit::per_second(2, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limit.take("a", now).is_ok());
+        }
+        assert!(limit.take("a", now).is_err());
+        // Other clients have buckets of their own
+        assert!(limit.take("b", now).is_ok());
+    }
+
+    #[test]
+    fn waits_for_the_next_token() {
+        let limit = RateLimit::per_second(4, 1);
+        let now = Instant::now();
+        assert!(limit.take("a", now).is_ok());
+        assert_eq!(limit.take("a", now), Err(Duration::from_millis(250)));
+
+        let later = now + Duration::from_millis(100);
+        let wait = limit.take("a", later).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.15).abs() < 1e-6, "{:?}", wait);
+    }
+
+    #[test]
+    fn refills_up_to_the_burst() {
+        let limit = RateLimit::per_second(10, 2);
+        let now = Instant::now();
+        assert!(limit.take("a", now).is_ok());
+        assert!(limit.take("a", now).is_ok());
+        assert!(limit.take("a", now).is_err());
+
+        // Long idle, but the bucket holds no more than the burst
+        let later = now + Duration::from_secs(60);
+        assert!(limit.take("a", later).is_ok());
+        assert!(limit.take("a", later).is_ok());
+        assert!(limit.take("a", later).is_err());
+    }
+
+    #[test]
+    fn prunes_full_buckets_on_an_interval() {
+        let limit = RateLimit::per_second(1, 100);
+        let now = Instant::now();
+        assert!(limit.take("idle", now).is_ok());
+        while limit.take("busy", now).is_ok() {}
+        assert_eq!(limit.tracked(), 2);
+
+        // Not yet due, however many requests come in
+        let soon = now + Duration::from_secs(2);
+        assert!(limit.take("other", soon).is_ok());
+        assert_eq!(limit.tracked(), 3);
+
+        // By then "idle" has filled up again, while "busy" has not
+        let due = now + PRUNE_INTERVAL;
+        assert!(limit.take("new", due).is_ok());
+        let buckets = limit.buckets.lock().unwrap();
+        assert!(!buckets.by_client.contains_key("idle"));
+        assert!(buckets.by_client.contains_key("busy"));
+        assert!(buckets.by_client.contains_key("new"));
+    }
+
+    #[test]
+    fn refuses_new_clients_when_full() {
+        let limit = RateLimit::per_second(1, 1);
+        let now = Instant::now();
+        for n in 0..MAX_CLIENTS {
+            assert!(limit.take(&n.to_string(), now).is_ok());
+        }
+        assert!(limit.take("one too many", now).is_err());
+        // Clients already tracked are limited as usual
+        let later = now + Duration::from_secs(1);
+        assert!(limit.take("0", later).is_ok());
+    }
+
+    #[test]
+    fn keys_by_address() {
+        let limit = RateLimit::per_second(1, 1);
+        let headers = HeaderMap::new();
+        assert_eq!(limit.client_key(&headers, ip("192.0.2.7")), "192.0.2.7");
+        // IPv4-mapped IPv6 addresses are the same client
+        assert_eq!(limit.client_key(&headers, ip("::ffff:192.0.2.7")), "192.0.2.7");
+    }
+
+    #[test]
+    fn trusts_the_header_from_proxies_only() {
+        let proxy: IpAddr = "10.0.0.2".parse().unwrap();
+        let limit = RateLimit::per_second(1, 1)
+            .by_header("x-real-ip", &[proxy])
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "198.51.100.4".parse().unwrap());
+
+        let key = limit.client_key(&headers, Some(proxy));
+        assert_eq!(key, "x-real-ip=198.51.100.4");
+        // Clients cannot choose their bucket by sending the header themselves
+        assert_eq!(limit.client_key(&headers, ip("192.0.2.7")), "192.0.2.7");
+        // A proxy request without it counts against the proxy
+        assert_eq!(limit.client_key(&HeaderMap::new(), Some(proxy)), "10.0.0.2");
+    }
+
+    #[test]
+    fn needs_proxies_to_trust_a_header() {
+        assert!(RateLimit::per_second(1, 1).by_header("x-real-ip", &[]).is_err());
+        let proxy = "10.0.0.2".parse().unwrap();
+        assert!(RateLimit::per_second(1, 1).by_header("bad header", &[proxy]).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+//! Record/replay of WebTransport session traffic.
+//!
+//! A [`SessionRecorder`] captures every datagram and stream chunk of a
+//! session to a line-oriented log file, timestamped relative to the start of
+//! the session. Records are written by a task of their own, so streams never
+//! wait on the file. [`replay`] feeds the inbound half of a recording back
+//! through the session handlers, at the times it was recorded, and compares
+//! their output with the recorded outbound traffic, so application protocols
+//! can be regression-tested without a live browser.
+//!
+//! Each line of a recording has the form:
+//!
+//! ```text
+//! <elapsed µs> <in|out> <datagram|bidi:N|server-bidi:N|uni:N> <hex payload>
+//! ```
+
+use crate::chunks::{ChunkRead, ChunkWrite, IoChunks};
+use crate::deadline::Reset;
+use crate::shaping::{Shaper, Shaping};
+use crate::webtransport;
+use bytes::Bytes;
+use pin_project_lite::pin_project;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// How often a recording is flushed to disk while the session runs.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Direction of a recorded payload, from the server's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// The channel a payload travelled on.
+///
+/// Stream numbers are assigned by the session in the order streams are
+/// seen, so they are stable across recordings of the same interaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Channel {
+    Datagram,
+    /// Client-initiated bidirectional stream.
+    Bidi(u64),
+    /// Server-initiated bidirectional stream (the welcome stream).
+    ServerBidi(u64),
+    /// Client-initiated unidirectional stream and its echo stream.
+    Uni(u64),
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Datagram => write!(f, "datagram"),
+            Channel::Bidi(n) => write!(f, "bidi:{}", n),
+            Channel::ServerBidi(n) => write!(f, "server-bidi:{}", n),
+            Channel::Uni(n) => write!(f, "uni:{}", n),
+        }
+    }
+}
+
+impl Channel {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        if s == "datagram" {
+            return Ok(Channel::Datagram);
+        }
+        let (kind, n) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid channel: {}", s))?;
+        let n: u64 = n.parse()?;
+        match kind {
+            "bidi" => Ok(Channel::Bidi(n)),
+            "server-bidi" => Ok(Channel::ServerBidi(n)),
+            "uni" => Ok(Channel::Uni(n)),
+            _ => anyhow::bail!("invalid channel: {}", s),
+        }
+    }
+}
+
+/// A single recorded payload.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub channel: Channel,
+    pub payload: Bytes,
+}
+
+impl Record {
+    fn to_line(&self) -> String {
+        let direction = match self.direction {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        };
+        format!(
+            "{} {} {} {}",
+            self.elapsed.as_micros(),
+            direction,
+            self.channel,
+            encode_hex(&self.payload)
+        )
+    }
+
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let mut parts = line.split(' ');
+        let mut next = || {
+            parts
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("truncated record: {}", line))
+        };
+
+        let elapsed = Duration::from_micros(next()?.parse()?);
+        let direction = match next()? {
+            "in" => Direction::Inbound,
+            "out" => Direction::Outbound,
+            other => anyhow::bail!("invalid direction: {}", other),
+        };
+        let channel = Channel::parse(next()?)?;
+        let payload = decode_hex(next().unwrap_or(""))?;
+
+        Ok(Self {
+            elapsed,
+            direction,
+            channel,
+            payload: Bytes::from(payload),
+        })
+    }
+}
+
+/// Captures the traffic of one WebTransport session to a file.
+pub struct SessionRecorder {
+    start: Instant,
+    /// Records for the writer task, which finishes the file once the
+    /// recorder is dropped.
+    records: mpsc::UnboundedSender<Record>,
+}
+
+impl SessionRecorder {
+    /// Create a recorder writing to `path`, truncating any existing file.
+    pub fn create(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let file = tokio::fs::File::from_std(File::create(path)?);
+        let (records, queued) = mpsc::unbounded_channel();
+        tokio::spawn(write_records(file, queued));
+        Ok(Arc::new(Self {
+            start: Instant::now(),
+            records,
+        }))
+    }
+
+    /// Append a payload to the recording.
+    pub fn record(&self, direction: Direction, channel: Channel, payload: &[u8]) {
+        let record = Record {
+            elapsed: self.start.elapsed(),
+            direction,
+            channel,
+            payload: Bytes::copy_from_slice(payload),
+        };
+
+        // Only fails once the writer gave up, which it has logged
+        let _ = self.records.send(record);
+    }
+}
+
+/// Write `records` to `file` until the session's recorder is dropped,
+/// flushing every [`FLUSH_INTERVAL`].
+async fn write_records(file: tokio::fs::File, mut records: mpsc::UnboundedReceiver<Record>) {
+    let mut out = BufWriter::new(file);
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        let written = tokio::select! {
+            record = records.recv() => match record {
+                Some(record) => out.write_all(format!("{}\n", record.to_line()).as_bytes()).await,
+                None => break,
+            },
+            _ = flush.tick() => out.flush().await,
+        };
+        if let Err(e) = written {
+            warn!("Failed to write session recording: {:?}", e);
+            return;
+        }
+    }
+    if let Err(e) = out.flush().await {
+        warn!("Failed to write session recording: {:?}", e);
+    }
+}
+
+pin_project! {
+    /// A stream whose traffic is captured by a [`SessionRecorder`].
+    ///
+    /// Without a recorder this is a transparent pass-through, so handlers
+    /// see the same stream type whether or not recording is enabled.
+    pub struct Recorded<S> {
+        #[pin]
+        inner: S,
+        recorder: Option<Arc<SessionRecorder>>,
+        channel: Channel,
+    }
+}
+
+impl<S> Recorded<S> {
+    /// Wrap a stream so everything read from or written to it is recorded.
+    pub fn new(stream: S, recorder: Option<&Arc<SessionRecorder>>, channel: Channel) -> Self {
+        Self {
+            inner: stream,
+            recorder: recorder.cloned(),
+            channel,
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Recorded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if let Some(recorder) = this.recorder {
+            let read = &buf.filled()[before..];
+            if !read.is_empty() {
+                recorder.record(Direction::Inbound, *this.channel, read);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let result = this.inner.poll_write(cx, buf);
+        if let (Some(recorder), Poll::Ready(Ok(n))) = (this.recorder, &result)
+            && *n > 0
+        {
+            recorder.record(Direction::Outbound, *this.channel, &buf[..*n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+impl<S: ChunkRead> ChunkRead for Recorded<S> {
+    fn poll_read_chunk(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Bytes>>> {
+        let result = self.inner.poll_read_chunk(cx);
+        if let (Some(recorder), Poll::Ready(Ok(Some(chunk)))) = (&self.recorder, &result)
+            && !chunk.is_empty()
+        {
+            recorder.record(Direction::Inbound, self.channel, chunk);
+        }
+        result
+    }
+}
+
+impl<S: ChunkWrite> ChunkWrite for Recorded<S> {
+    fn poll_write_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+        chunk: &mut Bytes,
+    ) -> Poll<std::io::Result<()>> {
+        // A cheap handle on the data, for recording what gets written
+        let unwritten = chunk.clone();
+        let result = self.inner.poll_write_chunk(cx, chunk);
+        let written = unwritten.len() - chunk.len();
+        if let Some(recorder) = &self.recorder
+            && written > 0
+        {
+            recorder.record(Direction::Outbound, self.channel, &unwritten[..written]);
+        }
+        result
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.inner.poll_finish(cx)
+    }
+}
+
+impl<S: Reset> Reset for Recorded<S> {
+    fn reset(&mut self, code: u64) {
+        self.inner.reset(code);
+    }
+}
+
+/// Load all records from a recording file.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(Record::parse(&line)?);
+    }
+    Ok(records)
+}
+
+/// Result of replaying a recording against the current handlers.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// Number of channels replayed.
+    pub channels: usize,
+    /// Channels whose output differed from the recording.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// A channel whose replayed output differs from the recorded output.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub channel: Channel,
+    pub expected: Bytes,
+    pub actual: Bytes,
+}
+
+impl ReplayReport {
+    /// Whether every channel reproduced its recorded output.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Replay a recording through the session handlers.
+///
+/// Inbound payloads are fed to the handler that served each channel, each
+/// at the time it was recorded, with all channels replayed side by side;
+/// the bytes the handlers produce are compared with the recorded outbound
+/// payloads. Stream output is compared as a whole, since chunk boundaries
+/// depend on the transport.
+pub async fn replay(path: &Path) -> anyhow::Result<ReplayReport> {
+    let mut channels: BTreeMap<Channel, Vec<Record>> = BTreeMap::new();
+    for record in load(path)? {
+        channels.entry(record.channel).or_default().push(record);
+    }
+
+    let start = tokio::time::Instant::now();
+    let replayed = futures::future::try_join_all(
+        channels
+            .into_iter()
+            .map(|(channel, records)| replay_channel(channel, records, start)),
+    )
+    .await?;
+
+    let mut report = ReplayReport::default();
+    for (channel, expected, actual) in replayed {
+        debug!(
+            "Replayed {}: expected {} bytes, got {} bytes",
+            channel,
+            expected.len(),
+            actual.len()
+        );
+
+        report.channels += 1;
+        if actual != expected {
+            report.mismatches.push(Mismatch {
+                channel,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+/// Replay one channel of a recording that started at `start`, returning
+/// its recorded and replayed output.
+async fn replay_channel(
+    channel: Channel,
+    records: Vec<Record>,
+    start: tokio::time::Instant,
+) -> anyhow::Result<(Channel, Bytes, Bytes)> {
+    let expected = concat(
+        records
+            .iter()
+            .filter(|r| r.direction == Direction::Outbound)
+            .map(|r| &r.payload),
+    );
+    let inbound: Vec<Record> = records
+        .into_iter()
+        .filter(|r| r.direction == Direction::Inbound)
+        .collect();
+
+    let actual = match channel {
+        Channel::Datagram => {
+            let mut replies = Vec::new();
+            for record in inbound {
+                tokio::time::sleep_until(start + record.elapsed).await;
+                replies.extend(webtransport::datagram_reply(record.payload));
+            }
+            concat(replies.iter())
+        }
+        Channel::Bidi(_) => {
+            replay_stream(inbound, start, |server| async move {
+                let (recv, send) = tokio::io::split(server);
+                let (send, recv) = (IoChunks::new(send), IoChunks::new(recv));
+                webtransport::echo_bidi(send, recv, Shaper::new(Shaping::default())).await
+            })
+            .await?
+        }
+        Channel::ServerBidi(_) => replay_stream(inbound, start, webtransport::send_welcome).await?,
+        Channel::Uni(_) => {
+            replay_stream(inbound, start, |server| async move {
+                let (recv, send) = tokio::io::split(server);
+                webtransport::echo_uni(send, recv, Shaper::new(Shaping::default())).await
+            })
+            .await?
+        }
+    };
+
+    Ok((channel, expected, actual))
+}
+
+/// Run a stream handler against an in-memory pipe, feeding it `inbound` at
+/// the times recorded since `start` and collecting everything it writes.
+async fn replay_stream<F, Fut>(
+    inbound: Vec<Record>,
+    start: tokio::time::Instant,
+    handler: F,
+) -> anyhow::Result<Bytes>
+where
+    F: FnOnce(tokio::io::DuplexStream) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let task = tokio::spawn(handler(server));
+
+    let (mut client_recv, mut client_send) = tokio::io::split(&mut client);
+    let feed = async {
+        for record in &inbound {
+            tokio::time::sleep_until(start + record.elapsed).await;
+            client_send.write_all(&record.payload).await?;
+        }
+        client_send.shutdown().await
+    };
+    let mut output = Vec::new();
+    let collect = client_recv.read_to_end(&mut output);
+
+    let (fed, collected) = tokio::join!(feed, collect);
+    fed?;
+    collected?;
+    task.await??;
+
+    Ok(Bytes::from(output))
+}
+
+fn concat<'a>(payloads: impl Iterator<Item = &'a Bytes>) -> Bytes {
+    let mut out = Vec::new();
+    for payload in payloads {
+        out.extend_from_slice(payload);
+    }
+    Bytes::from(out)
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("odd-length hex payload");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
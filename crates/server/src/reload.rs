@@ -0,0 +1,344 @@
+//! Config file loading and hot reload.
+//!
+//! The server can read its settings from a JSON file (`CONFIG_FILE`). Some
+//! of them can change at runtime: the file is watched and re-read when it
+//! changes, on SIGHUP, or on `POST /admin/reload`. Settings that need a
+//! restart (the bind address, certificate hostnames, idle timeout) keep
+//! their startup values and the change is logged and reported.
+
+use crate::handshake::{ClientHello, Decision, HandshakeHook, IncomingInfo};
+use crate::shaping::Shaping;
+use common::ServerConfig;
+use http::Uri;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Handle for changing the log level at runtime.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Contents of the config file. Every field is optional.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    // Applied at startup only
+    pub bind_addr: Option<SocketAddr>,
+    pub hostnames: Option<Vec<String>>,
+    pub idle_timeout_secs: Option<u64>,
+
+    // Reloadable
+    /// `trace`, `debug`, `info`, `warn`, `error` or `off`.
+    pub log_level: Option<String>,
+    /// Only accept connections from these addresses; all if unset.
+    pub allowed_ips: Option<Vec<IpAddr>>,
+    /// Make clients validate their address with a stateless retry before
+    /// the TLS handshake.
+    pub require_retry: bool,
+    /// Only accept connections for these SNI hostnames; all if unset.
+    /// Clients that send no SNI are refused when this is set.
+    pub allowed_server_names: Option<Vec<String>>,
+    /// Paths that respond with 503 Service Unavailable.
+    pub disabled_routes: Vec<String>,
+    /// Delay, jitter and bandwidth limits of streaming routes and
+    /// WebTransport echoes, by path (see [`crate::shaping`]).
+    pub shaping: HashMap<String, Shaping>,
+    /// Origins whose CORS preflights are allowed, e.g.
+    /// `https://app.example.com`, or `*` for any; none if empty.
+    pub cors_origins: Vec<String>,
+}
+
+impl FileConfig {
+    /// Read and parse a config file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("reading {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("parsing {}: {}", path.display(), e))
+    }
+
+    /// Apply the startup-only settings to `config`.
+    pub fn apply_startup(&self, mut config: ServerConfig) -> ServerConfig {
+        if let Some(addr) = self.bind_addr {
+            config.bind_addr = addr;
+        }
+        if let Some(hostnames) = &self.hostnames {
+            config = config.with_hostnames(hostnames.clone());
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            config = config.with_idle_timeout(secs);
+        }
+        config
+    }
+
+    /// Names of the startup-only settings that differ from `other`.
+    fn restart_required(&self, other: &FileConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.bind_addr != other.bind_addr {
+            changed.push("bind_addr");
+        }
+        if self.hostnames != other.hostnames {
+            changed.push("hostnames");
+        }
+        if self.idle_timeout_secs != other.idle_timeout_secs {
+            changed.push("idle_timeout_secs");
+        }
+        changed
+    }
+
+    fn log_level(&self) -> anyhow::Result<Option<LevelFilter>> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                level
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid log_level {:?}", level))
+            })
+            .transpose()
+    }
+}
+
+/// The reloadable settings currently in effect.
+#[derive(Debug, Default)]
+struct Settings {
+    allowed_ips: Option<HashSet<IpAddr>>,
+    require_retry: bool,
+    allowed_server_names: Option<HashSet<String>>,
+    disabled_routes: HashSet<String>,
+    shaping: HashMap<String, Shaping>,
+    cors_origins: HashSet<String>,
+}
+
+/// Reloadable settings, shared by the server, handlers and the watcher.
+///
+/// Without a config file everything is allowed and enabled.
+#[derive(Clone)]
+pub struct LiveConfig {
+    settings: Arc<RwLock<Settings>>,
+    source: Option<Arc<Source>>,
+    log_level: Option<LogLevelHandle>,
+}
+
+/// The config file and the contents it was started with.
+struct Source {
+    path: PathBuf,
+    startup: FileConfig,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl LiveConfig {
+    /// Settings that never change, with the log level controlled by
+    /// `log_level` if given.
+    pub fn new(log_level: Option<LogLevelHandle>) -> Self {
+        Self {
+            settings: Arc::default(),
+            source: None,
+            log_level,
+        }
+    }
+
+    /// Load settings from `path`, which can later be reloaded.
+    ///
+    /// Returns the parsed file so the caller can apply its startup-only
+    /// settings.
+    pub fn from_file(
+        path: impl Into<PathBuf>,
+        log_level: Option<LogLevelHandle>,
+    ) -> anyhow::Result<(Self, FileConfig)> {
+        let path = path.into();
+        let file = FileConfig::load(&path)?;
+        let source = Source {
+            modified: Mutex::new(modified(&path)),
+            startup: file.clone(),
+            path,
+        };
+        let live = Self {
+            source: Some(Arc::new(source)),
+            ..Self::new(log_level)
+        };
+        live.apply(&file)?;
+        Ok((live, file))
+    }
+
+    /// Whether connections from `ip` are accepted.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let settings = self.settings.read().unwrap();
+        settings
+            .allowed_ips
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(&ip.to_canonical()))
+    }
+
+    /// Whether requests for `path` are turned away.
+    pub fn is_disabled(&self, path: &str) -> bool {
+        self.settings.read().unwrap().disabled_routes.contains(path)
+    }
+
+    /// How to shape the response to a request for `uri`: as set for its
+    /// path, or as asked for in its query. Fails if the query asks for
+    /// values out of bounds.
+    pub fn shaping(&self, uri: &Uri) -> anyhow::Result<Shaping> {
+        let settings = self.settings.read().unwrap();
+        let shaping = settings.shaping.get(uri.path()).copied().unwrap_or_default();
+        shaping.with_query(uri)
+    }
+
+    /// Whether cross-origin requests from `origin` are allowed.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        let settings = self.settings.read().unwrap();
+        settings.cors_origins.contains("*") || settings.cors_origins.contains(origin)
+    }
+
+    /// Re-read the config file and apply its reloadable settings.
+    ///
+    /// Returns the names of changed settings that need a restart; those
+    /// keep their startup values. Nothing is applied if the file is invalid.
+    pub fn reload(&self) -> anyhow::Result<Vec<&'static str>> {
+        let Some(source) = &self.source else {
+            anyhow::bail!("no config file to reload");
+        };
+        *source.modified.lock().unwrap() = modified(&source.path);
+
+        let file = FileConfig::load(&source.path)?;
+        self.apply(&file)?;
+
+        let rejected = source.startup.restart_required(&file);
+        for name in &rejected {
+            warn!("Config reload: {} changed; restart the server to apply it", name);
+        }
+        info!("Reloaded config from {}", source.path.display());
+        Ok(rejected)
+    }
+
+    /// Reload when the config file changes or the process receives SIGHUP.
+    pub async fn watch(self) {
+        let Some(source) = self.source.clone() else {
+            return;
+        };
+        info!("Watching {} for config changes", source.path.display());
+
+        let mut hangup = hangup_signals();
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("SIGHUP received, reloading config"),
+                _ = interval.tick() => {
+                    let changed = modified(&source.path);
+                    if changed.is_none() || changed == *source.modified.lock().unwrap() {
+                        continue;
+                    }
+                }
+            }
+
+            if let Err(e) = self.reload() {
+                error!("Config reload failed: {:#}", e);
+            }
+        }
+    }
+
+    fn apply(&self, file: &FileConfig) -> anyhow::Result<()> {
+        // Validate everything before changing anything
+        let level = file.log_level()?;
+        for (path, shaping) in &file.shaping {
+            shaping
+                .check()
+                .map_err(|e| anyhow::anyhow!("shaping for {}: {}", path, e))?;
+        }
+
+        if let (Some(level), Some(handle)) = (level, &self.log_level) {
+            handle.modify(|filter| *filter = level)?;
+        }
+        *self.settings.write().unwrap() = Settings {
+            allowed_ips: file
+                .allowed_ips
+                .as_ref()
+                .map(|ips| ips.iter().map(|ip| ip.to_canonical()).collect()),
+            require_retry: file.require_retry,
+            allowed_server_names: file
+                .allowed_server_names
+                .as_ref()
+                .map(|names| names.iter().map(|name| name.to_ascii_lowercase()).collect()),
+            disabled_routes: file.disabled_routes.iter().cloned().collect(),
+            shaping: file.shaping.clone(),
+            cors_origins: file.cors_origins.iter().cloned().collect(),
+        };
+        Ok(())
+    }
+}
+
+impl HandshakeHook for LiveConfig {
+    fn incoming(&self, info: &IncomingInfo) -> Decision {
+        if !self.allows(info.remote.ip()) {
+            debug!("Refusing connection from {}: not in allowed_ips", info.remote);
+            return Decision::Refuse;
+        }
+        if !info.validated && self.settings.read().unwrap().require_retry {
+            return Decision::Retry;
+        }
+        Decision::Accept
+    }
+
+    fn client_hello(&self, hello: &ClientHello) -> bool {
+        let settings = self.settings.read().unwrap();
+        let Some(allowed) = &settings.allowed_server_names else {
+            return true;
+        };
+        let name = hello.server_name.as_deref().map(str::to_ascii_lowercase);
+        let allowed = name.is_some_and(|name| allowed.contains(&name));
+        if !allowed {
+            debug!(
+                "Closing connection from {}: server name {:?} not allowed",
+                hello.remote, hello.server_name
+            );
+        }
+        allowed
+    }
+}
+
+/// The file's modification time, if it can be read.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// A stream of SIGHUP notifications; never fires where SIGHUP does not
+/// exist or cannot be listened for.
+fn hangup_signals() -> Hangup {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::hangup()) {
+            Ok(signal) => Hangup(Some(signal)),
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP: {}", e);
+                Hangup(None)
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    Hangup
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(not(unix))]
+struct Hangup;
+
+impl Hangup {
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.0 {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
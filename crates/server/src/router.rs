@@ -1,40 +1,297 @@
 //! Router for HTTP/3 requests with REST and streaming support.
 
+use crate::rate_limit::RateLimit;
+use crate::rpc::Dispatcher;
+use crate::schema::{self, JsonBody, Schema};
 use bytes::Bytes;
+use common::tls::hostname_matches;
+use futures::{Stream, StreamExt};
 use h3::server::RequestStream;
-use http::Request;
-use std::collections::HashMap;
+use http::header::{CONTENT_TYPE, HeaderName};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
 /// Response type for REST handlers.
+///
+/// `200 OK` with the body's content type unless changed with
+/// [`RestResponse::status`] and [`RestResponse::header`], which chain:
+///
+/// ```ignore
+/// RestResponse::json(body)
+///     .status(StatusCode::CREATED)
+///     .header(LOCATION, "/api/users/1")
+///     .header(CACHE_CONTROL, "no-store")
+/// ```
 pub struct RestResponse {
+    pub status: StatusCode,
+    /// Headers to send; `content-length` is set by the server.
+    pub headers: HeaderMap,
     pub body: String,
-    pub content_type: &'static str,
 }
 
 impl RestResponse {
     pub fn text(body: impl Into<String>) -> Self {
+        Self::with_body(body.into(), "text/plain")
+    }
+
+    pub fn json(body: impl Into<String>) -> Self {
+        Self::with_body(body.into(), "application/json")
+    }
+
+    /// A response without a body, e.g. `204 No Content` or a redirect
+    /// given a `location` header.
+    pub fn empty(status: StatusCode) -> Self {
         Self {
-            body: body.into(),
-            content_type: "text/plain",
+            status,
+            headers: HeaderMap::new(),
+            body: String::new(),
         }
     }
 
-    pub fn json(body: impl Into<String>) -> Self {
+    fn with_body(body: String, content_type: &'static str) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Self {
+            status: StatusCode::OK,
+            headers,
+            body,
+        }
+    }
+
+    /// `value` serialized as JSON. A value that cannot be, such as a map
+    /// with other than string keys, is answered with an error object.
+    pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self::json(body),
+            Err(e) => {
+                tracing::warn!("Failed to encode response: {}", e);
+                Self::json(json!({"error": "response unavailable"}).to_string())
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Respond with `status` instead.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Set header `name` to `value`, replacing any value it had, e.g. the
+    /// content type. A name or value that is not valid turns the response
+    /// into a 500 error, so the mistake shows.
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: TryInto<HeaderName>,
+        V: TryInto<HeaderValue>,
+    {
+        match (name.try_into(), value.try_into()) {
+            (Ok(name), Ok(value)) => {
+                self.headers.insert(name, value);
+                self
+            }
+            _ => {
+                tracing::warn!("Invalid header in response; answering 500");
+                Self::json(json!({"error": "response unavailable"}).to_string())
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+/// A JSON body as a Rust value.
+///
+/// Handlers of [`Router::json`] routes are given the request body as a
+/// `Json<T>`, and any REST handler can return one to respond with `T`
+/// serialized as `application/json`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    /// The body of `req`, read by the server for a [`Router::json`] or
+    /// [`Router::schema`] route, as a `T`; JSON `null` if it has none.
+    pub fn from_request<B>(req: &Request<B>) -> anyhow::Result<Self> {
+        let body = req.extensions().get::<JsonBody>().map_or(&Value::Null, |JsonBody(body)| body);
+        Ok(Json(T::deserialize(body)?))
+    }
+}
+
+impl<T: Serialize> From<Json<T>> for RestResponse {
+    fn from(Json(value): Json<T>) -> Self {
+        RestResponse::from_serialize(&value)
+    }
+}
+
+/// A query string as a Rust value.
+///
+/// Any handler can parse its request's query string into a `T` with
+/// [`Query::from_request`]; the fields of `T` are taken from the query
+/// parameters by name, and a `HashMap<String, String>` takes them all:
+///
+/// ```ignore
+/// #[derive(Deserialize)]
+/// struct Page { limit: u32, #[serde(default)] offset: u32 }
+///
+/// let Query(page) = Query::<Page>::from_request(&req)?;
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> Query<T> {
+    /// The query string of `req` as a `T`; an empty one if it has none.
+    pub fn from_request<B>(req: &Request<B>) -> anyhow::Result<Self> {
+        let query = req.uri().query().unwrap_or_default();
+        Ok(Query(serde_urlencoded::from_str(query)?))
+    }
+}
+
+/// An error a REST handler answers with: a status and an RFC 9457 problem
+/// document (`application/problem+json`) detailing it. Handlers returning
+/// `Result<R, HandlerError>` fail with one using `?`:
+///
+/// ```ignore
+/// let Query(page) = Query::<Page>::from_request(&req).map_err(HandlerError::bad_request)?;
+/// let user = users.get(id).ok_or_else(|| HandlerError::not_found("no such user"))?;
+/// ```
+///
+/// Any other error converts into one too, as an internal error: it is
+/// logged and answered like a failing handler (see
+/// [`Router::error_handler`]), so its details are not sent to the client.
+#[derive(Debug)]
+pub struct HandlerError {
+    status: StatusCode,
+    /// The problem's `detail`.
+    detail: String,
+    /// Members of the problem document besides the standard ones.
+    members: serde_json::Map<String, Value>,
+    /// The error behind an internal error.
+    source: Option<anyhow::Error>,
+}
+
+impl HandlerError {
+    /// A `status` error, with `detail` telling the client what went wrong.
+    pub fn new(status: StatusCode, detail: impl ToString) -> Self {
         Self {
-            body: body.into(),
-            content_type: "application/json",
+            status,
+            detail: detail.to_string(),
+            members: serde_json::Map::new(),
+            source: None,
+        }
+    }
+
+    pub fn bad_request(detail: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, detail)
+    }
+
+    pub fn not_found(detail: impl ToString) -> Self {
+        Self::new(StatusCode::NOT_FOUND, detail)
+    }
+
+    pub fn unavailable(detail: impl ToString) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, detail)
+    }
+
+    /// Add `name` to the problem document, e.g. the invalid field.
+    pub fn with(mut self, name: &str, value: impl Serialize) -> Self {
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.members.insert(name.to_string(), value);
+        self
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for HandlerError {
+    fn from(e: E) -> Self {
+        let status = StatusCode::INTERNAL_SERVER_ERROR;
+        let mut error = Self::new(status, status.canonical_reason().unwrap_or("Error"));
+        error.source = Some(e.into());
+        error
+    }
+}
+
+impl From<HandlerError> for RestResponse {
+    fn from(error: HandlerError) -> Self {
+        let mut problem = schema::problem(error.status, &error.detail);
+        if let Value::Object(members) = &mut problem {
+            for (name, value) in error.members {
+                members.entry(name).or_insert(value);
+            }
+        }
+        RestResponse::json(problem.to_string())
+            .status(error.status)
+            .header(CONTENT_TYPE, "application/problem+json")
+    }
+}
+
+/// What a REST handler returns: anything that converts into a
+/// [`RestResponse`], or an `anyhow::Result` of one, whose errors are
+/// answered by the router's error handler (see [`Router::error_handler`]),
+/// or a `Result` with a [`HandlerError`].
+pub trait IntoRestResult {
+    fn into_rest_result(self) -> anyhow::Result<RestResponse>;
+}
+
+impl<R: Into<RestResponse>> IntoRestResult for R {
+    fn into_rest_result(self) -> anyhow::Result<RestResponse> {
+        Ok(self.into())
+    }
+}
+
+impl<R: Into<RestResponse>> IntoRestResult for anyhow::Result<R> {
+    fn into_rest_result(self) -> anyhow::Result<RestResponse> {
+        self.map(Into::into)
+    }
+}
+
+impl<R: Into<RestResponse>> IntoRestResult for Result<R, HandlerError> {
+    fn into_rest_result(self) -> anyhow::Result<RestResponse> {
+        match self {
+            Ok(response) => Ok(response.into()),
+            Err(HandlerError {
+                source: Some(e), ..
+            }) => Err(e),
+            Err(error) => Ok(error.into()),
         }
     }
 }
 
+/// An item of the body a [`Router::body_stream`] route streams: a chunk,
+/// or the error that cuts the body short.
+pub trait IntoChunk {
+    fn into_chunk(self) -> anyhow::Result<Bytes>;
+}
+
+impl IntoChunk for Bytes {
+    fn into_chunk(self) -> anyhow::Result<Bytes> {
+        Ok(self)
+    }
+}
+
+impl IntoChunk for anyhow::Result<Bytes> {
+    fn into_chunk(self) -> anyhow::Result<Bytes> {
+        self
+    }
+}
+
+/// Whether a request body fits the type a [`Router::json`] route takes;
+/// if not, why.
+pub type JsonCheck = Arc<dyn Fn(&Value) -> Result<(), String> + Send + Sync>;
+
 /// A boxed async REST handler function.
 pub type BoxedRestHandler = Arc<
-    dyn Fn(Request<()>) -> Pin<Box<dyn Future<Output = RestResponse> + Send>> + Send + Sync,
+    dyn Fn(Request<()>) -> Pin<Box<dyn Future<Output = anyhow::Result<RestResponse>> + Send>>
+        + Send
+        + Sync,
 >;
 
+/// Turns a REST handler's error, or its panic, into the response sent.
+pub type ErrorHandler = Arc<dyn Fn(&anyhow::Error) -> RestResponse + Send + Sync>;
+
 /// A boxed async stream handler function.
 pub type BoxedStreamHandler = Arc<
     dyn Fn(
@@ -49,11 +306,43 @@ pub type BoxedStreamHandler = Arc<
 pub enum Handler {
     Rest(BoxedRestHandler),
     Stream(BoxedStreamHandler),
+    /// JSON-RPC over HTTP/3 request streams and WebTransport sessions.
+    Rpc(Arc<Dispatcher>),
+}
+
+/// A route as listed by the route index (see [`Router::route_index`]).
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub path: String,
+    /// `rest`, `stream`, `rpc`, or `webtransport` for sessions at any path.
+    pub kind: &'static str,
+    pub methods: Vec<String>,
 }
 
 /// A path-based router supporting REST and streaming handlers.
+///
+/// Requests can also be dispatched to nested routers by the `:authority`
+/// pseudo-header (see [`Router::host`]); requests for unknown hosts are
+/// served by this router's own routes.
 pub struct Router {
     routes: HashMap<String, Handler>,
+    hosts: HashMap<String, Router>,
+    /// REST routes handled without spawning a task.
+    inline: HashSet<String>,
+    /// Schemas request bodies are validated against, by path.
+    schemas: HashMap<String, Schema>,
+    /// Largest request bodies accepted, in bytes, by path.
+    body_limits: HashMap<String, u64>,
+    /// Rate limits on top of the server-wide one, by path.
+    rate_limits: HashMap<String, RateLimit>,
+    /// Methods declared for routes that take other than their kind's
+    /// default, by path.
+    methods: HashMap<String, Vec<Method>>,
+    /// Checks of the bodies [`Router::json`] routes take, by path.
+    json_checks: HashMap<String, JsonCheck>,
+    /// Serves paths without a route.
+    fallback: Option<Handler>,
+    error_handler: Option<ErrorHandler>,
 }
 
 impl Router {
@@ -61,9 +350,89 @@ impl Router {
     pub fn new() -> Self {
         Self {
             routes: HashMap::new(),
+            hosts: HashMap::new(),
+            inline: HashSet::new(),
+            schemas: HashMap::new(),
+            body_limits: HashMap::new(),
+            rate_limits: HashMap::new(),
+            methods: HashMap::new(),
+            json_checks: HashMap::new(),
+            fallback: None,
+            error_handler: None,
         }
     }
 
+    /// Serve requests whose `:authority` matches `host` with `router`.
+    ///
+    /// The port is ignored when matching, and `host` may be a wildcard
+    /// pattern such as `*.example.com`.
+    ///
+    /// # Example
+    /// ```ignore
+    /// Router::new()
+    ///     .route("/", handlers::index)
+    ///     .host("api.example.com", Router::new().route("/", handlers::api_info))
+    /// ```
+    pub fn host(mut self, host: &str, router: Router) -> Self {
+        self.hosts.insert(host.to_ascii_lowercase(), router);
+        self
+    }
+
+    /// Mount the routes of `router` under `prefix`, so that its `/users`
+    /// is served at `/api/users` and its `/` at `/api` itself. Schemas,
+    /// body and rate limits, methods and JSON checks move with their routes,
+    /// and the route index lists the full paths. `router`'s own host
+    /// routers, fallback and error handler are not carried over; add host
+    /// routers with [`Router::host`].
+    ///
+    /// # Example
+    /// ```ignore
+    /// Router::new()
+    ///     .route("/", handlers::index)
+    ///     .nest("/api", Router::new()
+    ///         .route("/info", handlers::api_info)
+    ///         .json("/echo", handlers::echo))
+    /// ```
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        let nested = |path: String| match path.as_str() {
+            "/" if !prefix.is_empty() => prefix.to_string(),
+            _ => format!("{}{}", prefix, path),
+        };
+        self.routes
+            .extend(router.routes.into_iter().map(|(path, h)| (nested(path), h)));
+        self.inline.extend(router.inline.into_iter().map(nested));
+        self.schemas
+            .extend(router.schemas.into_iter().map(|(path, s)| (nested(path), s)));
+        self.body_limits
+            .extend(router.body_limits.into_iter().map(|(path, max)| (nested(path), max)));
+        self.rate_limits
+            .extend(router.rate_limits.into_iter().map(|(path, l)| (nested(path), l)));
+        self.methods
+            .extend(router.methods.into_iter().map(|(path, m)| (nested(path), m)));
+        self.json_checks
+            .extend(router.json_checks.into_iter().map(|(path, c)| (nested(path), c)));
+        self
+    }
+
+    /// The router serving requests for `authority`.
+    ///
+    /// Falls back to this router when no host matches.
+    pub fn for_authority(&self, authority: Option<&str>) -> &Router {
+        let Some(host) = authority.map(|a| strip_port(a).to_ascii_lowercase()) else {
+            return self;
+        };
+        self.hosts
+            .get(&host)
+            .or_else(|| {
+                self.hosts
+                    .iter()
+                    .find(|(pattern, _)| hostname_matches(pattern, &host))
+                    .map(|(_, router)| router)
+            })
+            .unwrap_or(self)
+    }
+
     /// Add a REST route (request/response pattern).
     ///
     /// # Example
@@ -72,19 +441,135 @@ impl Router {
     ///     RestResponse::json(r#"[{"id": 1}]"#)
     /// })
     /// ```
-    pub fn route<F, Fut>(mut self, path: &str, handler: F) -> Self
+    pub fn route<F, Fut, R>(mut self, path: &str, handler: F) -> Self
     where
         F: Fn(Request<()>) -> Fut + Send + Sync + 'static,
-        Fut: Future<Output = RestResponse> + Send + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoRestResult,
     {
-        let handler = Arc::new(move |req: Request<()>| {
-            let fut = handler(req);
-            Box::pin(fut) as Pin<Box<dyn Future<Output = RestResponse> + Send>>
-        });
-        self.routes.insert(path.to_string(), Handler::Rest(handler));
+        self.routes
+            .insert(path.to_string(), Handler::Rest(boxed_rest(handler)));
         self
     }
 
+    /// Serve requests to paths without a route with `handler`, instead of
+    /// answering 404 Not Found. Host routers (see [`Router::host`]) have
+    /// fallbacks of their own.
+    ///
+    /// # Example
+    /// ```ignore
+    /// router.fallback(|req| async move {
+    ///     let path = req.uri().path().to_string();
+    ///     RestResponse::from_serialize(&json!({"error": "no such page", "path": path}))
+    ///         .status(StatusCode::NOT_FOUND)
+    /// })
+    /// ```
+    pub fn fallback<F, Fut, R>(mut self, handler: F) -> Self
+    where
+        F: Fn(Request<()>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoRestResult,
+    {
+        self.fallback = Some(Handler::Rest(boxed_rest(handler)));
+        self
+    }
+
+    /// Answer REST handlers that fail or panic with `handler`'s response
+    /// rather than `500 Internal Server Error` with an `{"error": ...}`
+    /// body. Streaming handlers own their stream, so the server cannot
+    /// respond for them.
+    ///
+    /// # Example
+    /// ```ignore
+    /// router.error_handler(|e| {
+    ///     RestResponse::json(json!({"error": e.to_string()}).to_string())
+    ///         .status(StatusCode::INTERNAL_SERVER_ERROR)
+    /// })
+    /// ```
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&anyhow::Error) -> RestResponse + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// The response to a REST handler failing with `e`, if this router
+    /// has an error handler.
+    pub fn error_response(&self, e: &anyhow::Error) -> Option<RestResponse> {
+        self.error_handler.as_ref().map(|handler| handler(e))
+    }
+
+    /// Add a REST route that is handled inline on the connection's task
+    /// instead of in a task of its own.
+    ///
+    /// Meant for tiny responses that never wait on anything, such as health
+    /// checks: the connection takes no further requests until the handler
+    /// returns.
+    pub fn route_inline<F, Fut, R>(mut self, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request<()>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoRestResult,
+    {
+        self.inline.insert(path.to_string());
+        self.route(path, handler)
+    }
+
+    /// Add a REST route taking a JSON body, which is read and deserialized
+    /// into a `T` before the handler runs. Bodies that are not JSON, or not
+    /// a `T`, are answered with a 400 problem document, as for
+    /// [`Router::schema`], and never reach the handler. The route takes
+    /// `POST` unless declared otherwise with [`Router::methods`].
+    ///
+    /// Only `POST`, `PUT` and `PATCH` bodies are read. Requests with other
+    /// methods, e.g. a `GET` declared for the route, get a `T` from JSON
+    /// `null`, so routes that take them use an `Option`; for other `T`s
+    /// they are answered with 400.
+    ///
+    /// # Example
+    /// ```ignore
+    /// #[derive(Deserialize)]
+    /// struct NewUser { name: String }
+    ///
+    /// router.json("/api/users", |_req, Json(user): Json<NewUser>| async move {
+    ///     Json(json!({"id": 1, "name": user.name}))
+    /// })
+    /// ```
+    pub fn json<T, F, Fut, R>(mut self, path: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Request<()>, Json<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: IntoRestResult,
+    {
+        let check: JsonCheck =
+            Arc::new(|body: &Value| T::deserialize(body).map(drop).map_err(|e| e.to_string()));
+        self.json_checks.insert(path.to_string(), check);
+        self.methods
+            .entry(path.to_string())
+            .or_insert_with(|| vec![Method::POST]);
+        self.route(path, move |req: Request<()>| {
+            // The server has read and checked the body by now, if it has one
+            let fut = Json::<T>::from_request(&req).map(|body| handler(req, body));
+            async move {
+                match fut {
+                    Ok(fut) => fut.await.into_rest_result(),
+                    Err(e) => {
+                        let detail = format!("Request body is not valid: {}", e);
+                        Ok(HandlerError::bad_request(detail).into())
+                    }
+                }
+            }
+        })
+    }
+
+    /// The check of the request bodies the route at `path` takes, if it was
+    /// added with [`Router::json`].
+    pub fn json_check(&self, path: &str) -> Option<&JsonCheck> {
+        self.json_checks.get(path)
+    }
+
     /// Add a streaming route (handler manages the stream directly).
     ///
     /// # Example
@@ -113,13 +598,201 @@ impl Router {
         self
     }
 
-    /// Get handler for a path.
+    /// Add a streaming route whose body is the [`Stream`] `handler`
+    /// returns, of [`Bytes`] or `anyhow::Result<Bytes>`. The router sends
+    /// `200 OK` with `content_type`, then each chunk as flow control allows,
+    /// and finishes the response when the stream ends. An error item resets
+    /// the stream instead, so the client sees the body was cut short, and
+    /// is logged as a failed stream handler's error would be.
+    ///
+    /// # Example
+    /// ```ignore
+    /// router.body_stream("/stream/lines", "text/plain", |_req| {
+    ///     futures::stream::iter(["one\n", "two\n"]).map(Bytes::from_static)
+    /// })
+    /// ```
+    pub fn body_stream<F, S>(self, path: &str, content_type: &'static str, handler: F) -> Self
+    where
+        F: Fn(Request<()>) -> S + Send + Sync + 'static,
+        S: Stream + Send + 'static,
+        S::Item: IntoChunk + Send,
+    {
+        self.stream(path, move |req, stream| send_body(handler(req), content_type, stream))
+    }
+
+    /// Add a JSON-RPC endpoint.
+    ///
+    /// Clients can either stream request lines over an HTTP/3 request to
+    /// `path`, or open a WebTransport session at `path` and use its bidi
+    /// streams.
+    pub fn rpc(mut self, path: &str, dispatcher: Dispatcher) -> Self {
+        self.routes
+            .insert(path.to_string(), Handler::Rpc(Arc::new(dispatcher)));
+        self
+    }
+
+    /// Validate the bodies of POST, PUT and PATCH requests to `path`
+    /// against `schema` before they reach its handler, which finds the
+    /// parsed body in the [`crate::schema::JsonBody`] request extension.
+    ///
+    /// # Example
+    /// ```ignore
+    /// router
+    ///     .stream("/api/notify", handlers::notify)
+    ///     .schema("/api/notify", Schema::new(&json!({
+    ///         "type": "object",
+    ///         "required": ["message"],
+    ///     }))?)
+    /// ```
+    pub fn schema(mut self, path: &str, schema: Schema) -> Self {
+        self.schemas.insert(path.to_string(), schema);
+        self
+    }
+
+    /// The schema request bodies to `path` are validated against.
+    pub fn schema_for(&self, path: &str) -> Option<&Schema> {
+        self.schemas.get(path)
+    }
+
+    /// Refuse requests to `path` whose `content-length` is over `max` bytes
+    /// with 413 Payload Too Large, before their handler runs. A client that
+    /// sent `expect: 100-continue` is refused before it sends the body.
+    pub fn body_limit(mut self, path: &str, max: u64) -> Self {
+        self.body_limits.insert(path.to_string(), max);
+        self
+    }
+
+    /// The largest request body accepted at `path`, if limited.
+    pub fn body_limit_for(&self, path: &str) -> Option<u64> {
+        self.body_limits.get(path).copied()
+    }
+
+    /// Answer clients sending requests to `path` faster than `limit` allows
+    /// with 429 Too Many Requests, before their handler runs. Applies on
+    /// top of `ServerConfig::with_rate_limit`.
+    pub fn rate_limit(mut self, path: &str, limit: RateLimit) -> Self {
+        self.rate_limits.insert(path.to_string(), limit);
+        self
+    }
+
+    /// The rate limit on requests to `path`, if any.
+    pub fn rate_limit_for(&self, path: &str) -> Option<&RateLimit> {
+        self.rate_limits.get(path)
+    }
+
+    /// Declare the methods the handler at `path` accepts, which are
+    /// advertised in responses to `OPTIONS` requests and the route index.
+    ///
+    /// REST and streaming routes default to `GET`, JSON-RPC endpoints to
+    /// `POST` (and `CONNECT` for WebTransport sessions). Routes that take
+    /// `GET` answer `HEAD` too. Handlers still check the method themselves.
+    pub fn methods(mut self, path: &str, methods: &[Method]) -> Self {
+        self.methods.insert(path.to_string(), methods.to_vec());
+        self
+    }
+
+    /// The methods allowed at `path`, `OPTIONS` included, and `HEAD` where
+    /// `GET` is, or `None` if there is no route. For `*`, those of every
+    /// route.
+    pub fn allowed_methods(&self, path: &str) -> Option<Vec<Method>> {
+        let allowed: Vec<Method> = match path {
+            "*" => self
+                .routes
+                .keys()
+                .flat_map(|path| self.declared_methods(path))
+                .collect(),
+            _ => self.declared_methods(path),
+        };
+        if allowed.is_empty() {
+            return None;
+        }
+        let head = allowed.contains(&Method::GET).then_some(Method::HEAD);
+        let mut unique = Vec::with_capacity(allowed.len() + 2);
+        for method in allowed.into_iter().chain(head).chain([Method::OPTIONS]) {
+            if !unique.contains(&method) {
+                unique.push(method);
+            }
+        }
+        Some(unique)
+    }
+
+    fn declared_methods(&self, path: &str) -> Vec<Method> {
+        if let Some(methods) = self.methods.get(path) {
+            return methods.clone();
+        }
+        match self.routes.get(path) {
+            Some(Handler::Rest(_) | Handler::Stream(_)) => vec![Method::GET],
+            Some(Handler::Rpc(_)) => vec![Method::POST, Method::CONNECT],
+            None => Vec::new(),
+        }
+    }
+
+    /// Every route, sorted by path.
+    pub fn describe(&self) -> Vec<RouteInfo> {
+        let mut routes: Vec<RouteInfo> = self
+            .routes
+            .iter()
+            .map(|(path, handler)| RouteInfo {
+                path: path.clone(),
+                kind: match handler {
+                    Handler::Rest(_) => "rest",
+                    Handler::Stream(_) => "stream",
+                    Handler::Rpc(_) => "rpc",
+                },
+                methods: self
+                    .allowed_methods(path)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+            })
+            .collect();
+        routes.sort_by(|a, b| a.path.cmp(&b.path));
+        routes
+    }
+
+    /// Serve the route table as JSON at `path`: `{"routes": [...], "hosts":
+    /// {...}}`, with the routes of each `:authority` host router.
+    ///
+    /// The table is taken when this is called, so add it last.
+    pub fn route_index(self, path: &str) -> Self {
+        // Registered first so the index lists itself
+        let router = self.route(path, |_req| async { RestResponse::json("") });
+        let mut routes = router.describe();
+        routes.push(RouteInfo {
+            path: "*".to_string(),
+            kind: "webtransport",
+            methods: vec![Method::CONNECT.to_string()],
+        });
+        let hosts: HashMap<&str, Vec<RouteInfo>> = router
+            .hosts
+            .iter()
+            .map(|(host, router)| (host.as_str(), router.describe()))
+            .collect();
+        let index = serde_json::json!({"routes": routes, "hosts": hosts}).to_string();
+        let index: Arc<str> = index.into();
+        router.route(path, move |_req| {
+            let index = Arc::clone(&index);
+            async move { RestResponse::json(&*index) }
+        })
+    }
+
+    /// Get handler for a path, or the fallback if it has no route.
     pub fn get(&self, path: &str) -> Option<&Handler> {
-        self.routes.get(path)
+        self.routes.get(path).or(self.fallback.as_ref())
+    }
+
+    /// Whether the REST route at `path` is handled inline, either because
+    /// it was added with [`Router::route_inline`] or because `inline_rest`
+    /// applies to every REST route.
+    pub fn is_inline(&self, path: &str, inline_rest: bool) -> bool {
+        match self.routes.get(path) {
+            Some(Handler::Rest(_)) => inline_rest || self.inline.contains(path),
+            _ => false,
+        }
     }
 
     /// Check if path exists.
-    #[allow(dead_code)]
     pub fn contains(&self, path: &str) -> bool {
         self.routes.contains_key(path)
     }
@@ -128,6 +801,11 @@ impl Router {
     pub fn routes(&self) -> Vec<&str> {
         self.routes.keys().map(|s| s.as_str()).collect()
     }
+
+    /// List hosts with their own routers.
+    pub fn hosts(&self) -> Vec<&str> {
+        self.hosts.keys().map(|s| s.as_str()).collect()
+    }
 }
 
 impl Default for Router {
@@ -135,3 +813,230 @@ impl Default for Router {
         Self::new()
     }
 }
+
+/// Box a REST handler returning anything [`IntoRestResult`].
+fn boxed_rest<F, Fut, R>(handler: F) -> BoxedRestHandler
+where
+    F: Fn(Request<()>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = R> + Send + 'static,
+    R: IntoRestResult,
+{
+    Arc::new(move |req: Request<()>| {
+        let fut = handler(req);
+        Box::pin(async move { fut.await.into_rest_result() })
+            as Pin<Box<dyn Future<Output = anyhow::Result<RestResponse>> + Send>>
+    })
+}
+
+/// Respond on `stream` with `body`, for [`Router::body_stream`].
+async fn send_body<S>(
+    body: S,
+    content_type: &'static str,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+) -> anyhow::Result<()>
+where
+    S: Stream,
+    S::Item: IntoChunk,
+{
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(())?;
+    stream.send_response(response).await?;
+    let mut body = std::pin::pin!(body);
+    while let Some(item) = body.next().await {
+        match item.into_chunk() {
+            Ok(chunk) if chunk.is_empty() => {}
+            Ok(chunk) => stream.send_data(chunk).await?,
+            Err(e) => {
+                stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                return Err(e);
+            }
+        }
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Strip the port from an authority (`host:port` or `[v6]:port`).
+fn strip_port(authority: &str) -> &str {
+    if let Some(rest) = authority.strip_prefix('[') {
+        return rest.split_once(']').map_or(authority, |(host, _)| host);
+    }
+    authority.split_once(':').map_or(authority, |(host, _)| host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    async fn ok(_req: Request<()>) -> RestResponse {
+        RestResponse::text("ok")
+    }
+
+    async fn call(router: &Router, path: &str) -> RestResponse {
+        let Some(Handler::Rest(handler)) = router.get(path) else {
+            panic!("no REST route at {}", path);
+        };
+        handler(Request::get(path).body(()).unwrap()).await.unwrap()
+    }
+
+    #[test]
+    fn nests_routes_under_a_prefix() {
+        let api = Router::new()
+            .route("/", ok)
+            .route("/users", ok)
+            .methods("/users", &[Method::POST])
+            .body_limit("/users", 1024)
+            .json("/echo", |_req, Json(_): Json<Value>| async { RestResponse::text("ok") });
+        let router = Router::new().route("/", ok).nest("/api/", api);
+
+        let mut routes = router.routes();
+        routes.sort();
+        assert_eq!(routes, ["/", "/api", "/api/echo", "/api/users"]);
+        assert_eq!(router.body_limit_for("/api/users"), Some(1024));
+        assert_eq!(router.allowed_methods("/api/users"), Some(vec![Method::POST, Method::OPTIONS]));
+        assert!(router.json_check("/api/echo").is_some());
+        assert!(router.json_check("/echo").is_none());
+
+        // Nested at the root, paths stay as they were
+        let router = Router::new().nest("/", Router::new().route("/", ok).route("/a", ok));
+        let mut routes = router.routes();
+        routes.sort();
+        assert_eq!(routes, ["/", "/a"]);
+    }
+
+    #[test]
+    fn matches_hosts_with_and_without_a_port() {
+        let router = Router::new()
+            .route("/", ok)
+            .host("API.example.com", Router::new().route("/api", ok))
+            .host("*.example.org", Router::new().route("/org", ok));
+
+        for authority in ["api.example.com", "api.example.com:4433", "API.EXAMPLE.COM"] {
+            assert!(router.for_authority(Some(authority)).contains("/api"), "{}", authority);
+        }
+        assert!(router.for_authority(Some("www.example.org:443")).contains("/org"));
+        // Unknown hosts, and requests without an authority, get the default
+        for authority in [None, Some("example.com"), Some("[::1]:4433")] {
+            assert!(std::ptr::eq(router.for_authority(authority), &router));
+        }
+    }
+
+    #[test]
+    fn strips_ports() {
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("example.com:443"), "example.com");
+        assert_eq!(strip_port("[::1]:4433"), "::1");
+        assert_eq!(strip_port("[::1]"), "::1");
+    }
+
+    #[test]
+    fn lists_allowed_methods() {
+        let router = Router::new()
+            .route("/get", ok)
+            .route("/post", ok)
+            .methods("/post", &[Method::POST])
+            .route("/both", ok)
+            .methods("/both", &[Method::GET, Method::HEAD, Method::DELETE])
+            .rpc("/rpc", Dispatcher::new());
+
+        assert_eq!(
+            router.allowed_methods("/get"),
+            Some(vec![Method::GET, Method::HEAD, Method::OPTIONS])
+        );
+        assert_eq!(router.allowed_methods("/post"), Some(vec![Method::POST, Method::OPTIONS]));
+        assert_eq!(
+            router.allowed_methods("/both"),
+            Some(vec![Method::GET, Method::HEAD, Method::DELETE, Method::OPTIONS])
+        );
+        assert_eq!(
+            router.allowed_methods("/rpc"),
+            Some(vec![Method::POST, Method::CONNECT, Method::OPTIONS])
+        );
+        assert_eq!(router.allowed_methods("/missing"), None);
+
+        let all = router.allowed_methods("*").unwrap();
+        for method in [Method::GET, Method::HEAD, Method::POST, Method::DELETE, Method::CONNECT] {
+            assert_eq!(all.iter().filter(|m| **m == method).count(), 1, "{}", method);
+        }
+        assert_eq!(all.last(), Some(&Method::OPTIONS));
+        assert_eq!(Router::new().allowed_methods("*"), None);
+    }
+
+    #[tokio::test]
+    async fn indexes_routes() {
+        let router = Router::new()
+            .route("/", ok)
+            .route("/submit", ok)
+            .methods("/submit", &[Method::POST])
+            .host("api.example.com", Router::new().route("/api", ok))
+            .route_index("/routes");
+
+        let response = call(&router, "/routes").await;
+        assert_eq!(response.status, StatusCode::OK);
+        let index: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(
+            index["routes"],
+            json!([
+                {"path": "/", "kind": "rest", "methods": ["GET", "HEAD", "OPTIONS"]},
+                {"path": "/routes", "kind": "rest", "methods": ["GET", "HEAD", "OPTIONS"]},
+                {"path": "/submit", "kind": "rest", "methods": ["POST", "OPTIONS"]},
+                {"path": "*", "kind": "webtransport", "methods": ["CONNECT"]},
+            ])
+        );
+        assert_eq!(index["hosts"]["api.example.com"][0]["path"], "/api");
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Page {
+        limit: u32,
+        #[serde(default)]
+        offset: u32,
+        #[serde(default)]
+        name: Option<String>,
+    }
+
+    fn query(uri: &str) -> anyhow::Result<Page> {
+        let req = Request::get(uri).body(()).unwrap();
+        Query::<Page>::from_request(&req).map(|Query(page)| page)
+    }
+
+    #[test]
+    fn parses_queries() {
+        assert_eq!(
+            query("/items?limit=5&offset=10&name=a%20b").unwrap(),
+            Page { limit: 5, offset: 10, name: Some("a b".to_string()) }
+        );
+        assert_eq!(
+            query("/items?limit=5").unwrap(),
+            Page { limit: 5, offset: 0, name: None }
+        );
+        assert!(query("/items").is_err());
+        assert!(query("/items?limit=many").is_err());
+        assert!(query("/items?limit=-1").is_err());
+
+        // No query string parses as an empty one
+        let req = Request::get("/items").body(()).unwrap();
+        let Query(params) = Query::<HashMap<String, String>>::from_request(&req).unwrap();
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn answers_handler_errors_with_problems() {
+        let result: Result<RestResponse, HandlerError> =
+            Err(HandlerError::not_found("no such user"));
+        let response = result.into_rest_result().unwrap();
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.headers[CONTENT_TYPE], "application/problem+json");
+        let problem: Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(problem["status"], 404);
+        assert_eq!(problem["detail"], "no such user");
+
+        // Other errors are internal, and go to the error handler
+        let result: Result<RestResponse, HandlerError> = Err(anyhow::anyhow!("disk full").into());
+        let e = result.into_rest_result().err().unwrap();
+        assert_eq!(e.to_string(), "disk full");
+    }
+}
@@ -0,0 +1,335 @@
+//! JSON-RPC 2.0 method dispatch.
+//!
+//! A [`Dispatcher`] holds the registered methods and serves newline-framed
+//! JSON-RPC over any stream: WebTransport bidi streams of a session opened
+//! at an RPC path, or HTTP/3 request streams to the same path. Requests on a
+//! stream are handled concurrently and responses are written as they
+//! complete, so clients correlate them by id.
+//!
+//! Methods are either raw ([`Dispatcher::method`], taking the params as
+//! JSON) or typed ([`Dispatcher::register`], taking deserialized arguments
+//! and returning any serializable value).
+
+use bytes::{Buf, Bytes};
+use common::rpc::{Id, LineBuffer, Message, Request, Response, RpcError, VERSION};
+use futures::stream::{FuturesUnordered, StreamExt};
+use h3::server::RequestStream;
+use http::Response as HttpResponse;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::debug;
+
+/// Result of an RPC method.
+pub type RpcResult = Result<Value, RpcError>;
+
+/// A boxed async RPC method.
+pub type BoxedMethod =
+    Arc<dyn Fn(Option<Value>) -> Pin<Box<dyn Future<Output = RpcResult> + Send>> + Send + Sync>;
+
+type LineFuture = Pin<Box<dyn Future<Output = Option<String>> + Send>>;
+
+/// Registered JSON-RPC methods.
+#[derive(Clone, Default)]
+pub struct Dispatcher {
+    methods: HashMap<String, BoxedMethod>,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher with no methods.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a method.
+    ///
+    /// # Example
+    /// ```ignore
+    /// dispatcher.method("add", |params| async move {
+    ///     let [a, b]: [i64; 2] = serde_json::from_value(params.unwrap_or_default())
+    ///         .map_err(RpcError::invalid_params)?;
+    ///     Ok((a + b).into())
+    /// })
+    /// ```
+    pub fn method<F, Fut>(mut self, name: &str, handler: F) -> Self
+    where
+        F: Fn(Option<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = RpcResult> + Send + 'static,
+    {
+        let handler = Arc::new(move |params: Option<Value>| {
+            Box::pin(handler(params)) as Pin<Box<dyn Future<Output = RpcResult> + Send>>
+        });
+        self.methods.insert(name.to_string(), handler);
+        self
+    }
+
+    /// Register a typed method.
+    ///
+    /// Positional params are deserialized into the handler's arguments;
+    /// a single-argument handler may instead take the whole params value
+    /// (e.g. a by-name object). Deserialization failures are reported as
+    /// invalid params, and handler errors are converted with
+    /// `Into<RpcError>` (`anyhow::Error` becomes an internal error).
+    ///
+    /// # Example
+    /// ```ignore
+    /// dispatcher.register("multiply", |a: f64, b: f64| async move {
+    ///     Ok::<_, RpcError>(a * b)
+    /// })
+    /// ```
+    pub fn register<H, Args>(mut self, name: &str, handler: H) -> Self
+    where
+        H: RpcHandler<Args>,
+    {
+        let handler = Arc::new(move |params: Option<Value>| handler.call(params));
+        self.methods.insert(name.to_string(), handler);
+        self
+    }
+
+    /// List all registered methods.
+    pub fn methods(&self) -> Vec<&str> {
+        self.methods.keys().map(|s| s.as_str()).collect()
+    }
+
+    /// Handle one line (a message or a batch) and return the response line,
+    /// if any. Notifications produce no response.
+    pub async fn handle_line(&self, line: &str) -> Option<String> {
+        let value: Value = match serde_json::from_str(line) {
+            Ok(value) => value,
+            Err(e) => {
+                return Some(
+                    Message::Single(Response::error(None, RpcError::parse_error(e))).encode(),
+                );
+            }
+        };
+
+        match value {
+            Value::Array(batch) if batch.is_empty() => Some(
+                Message::Single(Response::error(
+                    None,
+                    RpcError::invalid_request("empty batch"),
+                ))
+                .encode(),
+            ),
+            Value::Array(batch) => {
+                let calls = batch.into_iter().map(|value| self.handle_value(value));
+                let responses: Vec<Response> = futures::future::join_all(calls)
+                    .await
+                    .into_iter()
+                    .flatten()
+                    .collect();
+                // A batch of notifications gets no response at all
+                (!responses.is_empty()).then(|| Message::Batch(responses).encode())
+            }
+            value => self
+                .handle_value(value)
+                .await
+                .map(|response| Message::Single(response).encode()),
+        }
+    }
+
+    async fn handle_value(&self, value: Value) -> Option<Response> {
+        let request: Request = match serde_json::from_value(value.clone()) {
+            Ok(request) => request,
+            Err(e) => {
+                let id = value
+                    .get("id")
+                    .cloned()
+                    .and_then(|id| serde_json::from_value::<Id>(id).ok());
+                return Some(Response::error(id, RpcError::invalid_request(e)));
+            }
+        };
+        if request.jsonrpc != VERSION {
+            return Some(Response::error(
+                request.id,
+                RpcError::invalid_request(format!("unsupported version {:?}", request.jsonrpc)),
+            ));
+        }
+
+        let result = match self.methods.get(&request.method) {
+            Some(handler) => handler(request.params).await,
+            None => Err(RpcError::method_not_found(&request.method)),
+        };
+        debug!(
+            "RPC {} -> {}",
+            request.method,
+            if result.is_ok() { "ok" } else { "error" }
+        );
+
+        let id = request.id?;
+        Some(match result {
+            Ok(value) => Response::success(id, value),
+            Err(error) => Response::error(Some(id), error),
+        })
+    }
+
+    fn spawn_line(self: &Arc<Self>, line: String) -> LineFuture {
+        let dispatcher = Arc::clone(self);
+        Box::pin(async move { dispatcher.handle_line(&line).await })
+    }
+
+    /// Serve JSON-RPC on a stream until the peer finishes sending and every
+    /// request has been answered.
+    pub async fn serve<R, W>(self: Arc<Self>, recv: R, mut send: W) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut lines = BufReader::new(recv).lines();
+        let mut in_flight = FuturesUnordered::new();
+        let mut open = true;
+
+        while open || !in_flight.is_empty() {
+            tokio::select! {
+                line = lines.next_line(), if open => match line? {
+                    Some(line) if !line.trim().is_empty() => in_flight.push(self.spawn_line(line)),
+                    Some(_) => {}
+                    None => open = false,
+                },
+                Some(response) = in_flight.next(), if !in_flight.is_empty() => {
+                    if let Some(response) = response {
+                        send.write_all(response.as_bytes()).await?;
+                    }
+                }
+            }
+        }
+
+        send.shutdown().await?;
+        Ok(())
+    }
+
+    /// Serve JSON-RPC on an HTTP/3 request stream.
+    ///
+    /// The request body carries request lines and the response body carries
+    /// response lines, both streamed for the lifetime of the request.
+    pub async fn serve_request(
+        self: Arc<Self>,
+        stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    ) -> anyhow::Result<()> {
+        let (mut send, mut recv) = stream.split();
+        let response = HttpResponse::builder()
+            .status(200)
+            .header("content-type", "application/json-seq")
+            .body(())?;
+        send.send_response(response).await?;
+
+        let mut buffer = LineBuffer::default();
+        let mut in_flight = FuturesUnordered::new();
+        let mut open = true;
+
+        while open || !in_flight.is_empty() {
+            tokio::select! {
+                chunk = recv.recv_data(), if open => match chunk? {
+                    Some(mut chunk) => {
+                        let data = chunk.copy_to_bytes(chunk.remaining());
+                        for line in buffer.push(&data) {
+                            in_flight.push(self.spawn_line(line));
+                        }
+                    }
+                    None => open = false,
+                },
+                Some(response) = in_flight.next(), if !in_flight.is_empty() => {
+                    if let Some(response) = response {
+                        send.send_data(Bytes::from(response)).await?;
+                    }
+                }
+            }
+        }
+
+        send.finish().await?;
+        Ok(())
+    }
+}
+
+/// An async function usable as a typed RPC method.
+///
+/// Implemented for `Fn` closures and functions of up to four arguments
+/// that deserialize from JSON, returning a future of `Result<T, E>` where
+/// `T` serializes to JSON and `E` converts into an [`RpcError`].
+pub trait RpcHandler<Args>: Send + Sync + 'static {
+    fn call(&self, params: Option<Value>) -> Pin<Box<dyn Future<Output = RpcResult> + Send>>;
+}
+
+/// Convert a handler's output into the method result.
+fn into_result<T, E>(result: Result<T, E>) -> RpcResult
+where
+    T: Serialize,
+    E: Into<RpcError>,
+{
+    let value = result.map_err(Into::into)?;
+    serde_json::to_value(value).map_err(RpcError::internal_error)
+}
+
+impl<F, Fut, T, E> RpcHandler<()> for F
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, E>> + Send + 'static,
+    T: Serialize,
+    E: Into<RpcError>,
+{
+    fn call(&self, _params: Option<Value>) -> Pin<Box<dyn Future<Output = RpcResult> + Send>> {
+        let fut = self();
+        Box::pin(async move { into_result(fut.await) })
+    }
+}
+
+impl<F, Fut, T, E, A> RpcHandler<(A,)> for F
+where
+    F: Fn(A) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T, E>> + Send + 'static,
+    T: Serialize,
+    E: Into<RpcError>,
+    A: DeserializeOwned,
+{
+    fn call(&self, params: Option<Value>) -> Pin<Box<dyn Future<Output = RpcResult> + Send>> {
+        let params = params.unwrap_or(Value::Null);
+        // `[a]` positionally, otherwise the whole params value
+        let arg = serde_json::from_value::<(A,)>(params.clone())
+            .map(|(a,)| a)
+            .or_else(|_| serde_json::from_value::<A>(params));
+        match arg {
+            Ok(a) => {
+                let fut = self(a);
+                Box::pin(async move { into_result(fut.await) })
+            }
+            Err(e) => Box::pin(std::future::ready(Err(RpcError::invalid_params(e)))),
+        }
+    }
+}
+
+macro_rules! impl_rpc_handler {
+    ($($arg:ident),+) => {
+        impl<F, Fut, T, E, $($arg),+> RpcHandler<($($arg,)+)> for F
+        where
+            F: Fn($($arg),+) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = Result<T, E>> + Send + 'static,
+            T: Serialize,
+            E: Into<RpcError>,
+            $($arg: DeserializeOwned,)+
+        {
+            #[allow(non_snake_case)]
+            fn call(
+                &self,
+                params: Option<Value>,
+            ) -> Pin<Box<dyn Future<Output = RpcResult> + Send>> {
+                let params = params.unwrap_or(Value::Null);
+                match serde_json::from_value::<($($arg,)+)>(params) {
+                    Ok(($($arg,)+)) => {
+                        let fut = self($($arg),+);
+                        Box::pin(async move { into_result(fut.await) })
+                    }
+                    Err(e) => Box::pin(std::future::ready(Err(RpcError::invalid_params(e)))),
+                }
+            }
+        }
+    };
+}
+
+impl_rpc_handler!(A, B);
+impl_rpc_handler!(A, B, C);
+impl_rpc_handler!(A, B, C, D);
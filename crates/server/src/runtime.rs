@@ -0,0 +1,56 @@
+//! The Tokio runtimes the server runs on.
+//!
+//! Handlers, connections and the server's own tasks run on the runtime
+//! [`build`] makes from `ServerConfig`. With `ServerConfig::accept_runtime`
+//! the QUIC endpoint gets an [`AcceptRuntime`] as well: one thread that
+//! receives every packet and runs the accept loop, so a flood of
+//! connection attempts is still turned away, and packets for established
+//! connections still read, while handlers keep the workers busy.
+
+use common::ServerConfig;
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// The runtime for everything but the endpoint, tuned by `config`.
+pub fn build(config: &ServerConfig) -> std::io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+    if let Some(threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(threads);
+    }
+    builder.build()
+}
+
+/// A single-threaded runtime of its own for the QUIC endpoint.
+pub struct AcceptRuntime {
+    /// Only `None` once dropped.
+    runtime: Option<Runtime>,
+}
+
+impl AcceptRuntime {
+    pub fn new() -> std::io::Result<Self> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("h3-accept")
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            runtime: Some(runtime),
+        })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        self.runtime.as_ref().expect("accept runtime dropped").handle()
+    }
+}
+
+impl Drop for AcceptRuntime {
+    fn drop(&mut self) {
+        // Dropped from async code, which may not block on the thread
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
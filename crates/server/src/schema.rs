@@ -0,0 +1,163 @@
+//! JSON Schema validation of request bodies.
+//!
+//! A route registered with [`crate::router::Router::schema`] has the body
+//! of its POST, PUT and PATCH requests read and checked by the server
+//! before its handler runs. Bodies that are not JSON or do not match the
+//! schema are rejected with a `400` `application/problem+json` response
+//! (RFC 9457) listing every violation:
+//!
+//! ```json
+//! {
+//!   "type": "about:blank",
+//!   "title": "Bad Request",
+//!   "status": 400,
+//!   "detail": "Request body does not match the schema",
+//!   "errors": [{"pointer": "/message", "detail": "42 is not of type \"string\""}]
+//! }
+//! ```
+//!
+//! Valid bodies reach the handler parsed, as a [`JsonBody`] request
+//! extension.
+
+use crate::pool::{self, PooledBuf};
+use bytes::{Buf, BufMut, Bytes};
+use h3::server::RequestStream;
+use http::StatusCode;
+use jsonschema::Validator;
+use serde_json::{Value, json};
+
+/// Largest body read for validation, unless set with
+/// [`Schema::with_max_body`].
+pub const DEFAULT_MAX_BODY: usize = 64 * 1024;
+
+/// A request body that passed its route's schema.
+#[derive(Debug, Clone)]
+pub struct JsonBody(pub Value);
+
+/// Why a request body was rejected.
+pub struct Rejection {
+    pub status: StatusCode,
+    /// Problem document to respond with.
+    pub problem: Value,
+}
+
+/// A compiled JSON Schema for a route's request bodies.
+pub struct Schema {
+    validator: Validator,
+    max_body: usize,
+}
+
+impl Schema {
+    /// Compile `schema`; fails if it is not a valid JSON Schema.
+    pub fn new(schema: &Value) -> anyhow::Result<Self> {
+        let validator = jsonschema::validator_for(schema)
+            .map_err(|e| anyhow::anyhow!("invalid JSON Schema: {}", e))?;
+        Ok(Self {
+            validator,
+            max_body: DEFAULT_MAX_BODY,
+        })
+    }
+
+    /// Reject bodies longer than `max_body` bytes with `413`.
+    #[allow(dead_code)]
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Read the request body from `stream` and validate it.
+    pub async fn check(
+        &self,
+        stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    ) -> anyhow::Result<Result<Value, Rejection>> {
+        let body = match read_body(stream, self.max_body).await? {
+            Ok(body) => body,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        Ok(self.validate(&body).map_err(|problem| Rejection {
+            status: StatusCode::BAD_REQUEST,
+            problem,
+        }))
+    }
+
+    /// Parse and validate `body`, or describe what is wrong with it as a
+    /// problem document.
+    pub fn validate(&self, body: &[u8]) -> Result<Value, Value> {
+        let value = parse(body)?;
+
+        let errors: Vec<Value> = self
+            .validator
+            .iter_errors(&value)
+            .map(|error| {
+                json!({
+                    "pointer": error.instance_path().as_str(),
+                    "detail": error.to_string(),
+                })
+            })
+            .collect();
+        if !errors.is_empty() {
+            let mut problem = problem(
+                StatusCode::BAD_REQUEST,
+                "Request body does not match the schema",
+            );
+            problem["errors"] = errors.into();
+            return Err(problem);
+        }
+        Ok(value)
+    }
+}
+
+/// Read a JSON request body of up to `max_body` bytes from `stream`,
+/// without validating it against a schema.
+pub async fn read_json(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    max_body: usize,
+) -> anyhow::Result<Result<Value, Rejection>> {
+    let body = match read_body(stream, max_body).await? {
+        Ok(body) => body,
+        Err(rejection) => return Ok(Err(rejection)),
+    };
+    Ok(parse(&body).map_err(|problem| Rejection {
+        status: StatusCode::BAD_REQUEST,
+        problem,
+    }))
+}
+
+/// Read the request body, or reject it with `413` if it is longer than
+/// `max_body` bytes.
+async fn read_body(
+    stream: &mut RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    max_body: usize,
+) -> anyhow::Result<Result<PooledBuf, Rejection>> {
+    let mut body = pool::buffers().get();
+    while let Some(chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > max_body {
+            let status = StatusCode::PAYLOAD_TOO_LARGE;
+            let detail = format!("Request body is larger than {} bytes", max_body);
+            return Ok(Err(Rejection {
+                status,
+                problem: problem(status, &detail),
+            }));
+        }
+        body.put(chunk);
+    }
+    Ok(Ok(body))
+}
+
+/// Parse `body` as JSON, or describe why it is not as a problem document.
+fn parse(body: &[u8]) -> Result<Value, Value> {
+    serde_json::from_slice(body).map_err(|e| {
+        let detail = format!("Request body is not valid JSON: {}", e);
+        problem(StatusCode::BAD_REQUEST, &detail)
+    })
+}
+
+/// An RFC 9457 problem document.
+pub fn problem(status: StatusCode, detail: &str) -> Value {
+    json!({
+        "type": "about:blank",
+        "title": status.canonical_reason().unwrap_or("Error"),
+        "status": status.as_u16(),
+        "detail": detail,
+    })
+}
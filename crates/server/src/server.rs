@@ -1,46 +1,681 @@
 //! HTTP/3 server implementation with WebTransport support.
 
-use crate::router::{Handler, Router};
-use crate::webtransport;
+use crate::affinity::{Affinity, MemoryStore, SessionStore};
+use crate::api_keys::{ApiKeyStore, hex, random_bytes};
+use crate::backplane::{self, Backplane};
+use crate::budget::{RequestDeadline, BUDGET_REMAINING};
+use crate::certs::{CertHashes, CertInfo, CertRotation};
+use crate::connection::ConnectionHandle;
+use crate::context::{RequestContext, CANCEL_GRACE};
+use crate::diagnostics::{self, Connections};
+use crate::handshake::{
+    Admission, ClientHello, Decision, HandshakeHook, HandshakeLimiter, IncomingInfo, Prefix,
+};
+use crate::i18n::{Catalog, Localized, Message};
+use crate::peer_cert::{self, PeerCertificate};
+use crate::pool;
+use crate::rate_limit::RateLimit;
+use crate::recorder::SessionRecorder;
+use crate::reload::LiveConfig;
+use crate::runtime::AcceptRuntime;
+use crate::router::{Handler, RestResponse, Router};
+use crate::rpc::Dispatcher;
+use crate::schema::{self, JsonBody};
+use crate::sessions::Sessions;
+use crate::shaping::Shaping;
+use crate::sinks::{AccessLog, AuditEvent, Sinks};
+use crate::systemd;
+use crate::tasks::{Tasks, panic_message};
+use crate::telemetry::Telemetry;
+use crate::topics::Topics;
+use crate::usage::{ConnectionMeter, QuotaExceeded, Usage};
+use crate::vhost::{HostRouters, VirtualHost};
+use crate::webhooks::{Event, Webhooks};
+use crate::webtransport::{self, ConnectRequest, SessionContext, SessionRequests, StreamLimits};
 use bytes::Bytes;
-use common::{tls::generate_webtransport_cert, ServerConfig};
+use futures::FutureExt;
+use common::{CertificateChain, CloseCode, ControlMessage, ServerConfig, SniCertResolver};
 use h3::ext::Protocol;
 use h3::server::RequestStream;
 use h3_webtransport::server::WebTransportSession;
-use http::{Method, Request, Response, StatusCode};
-use quinn::{Endpoint, ServerConfig as QuinnServerConfig};
+use http::header::{
+    ACCEPT_LANGUAGE, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+    ACCESS_CONTROL_REQUEST_METHOD, ALLOW, CONTENT_LENGTH, EXPECT, ORIGIN, VARY,
+};
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use quinn::{Endpoint, EndpointConfig, ServerConfig as QuinnServerConfig};
 use rustls::ServerConfig as TlsServerConfig;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{debug, error, info};
-
-/// Run the HTTP/3 server with the given configuration and router.
-pub async fn run(config: ServerConfig, router: Router) -> anyhow::Result<()> {
-    // Use WebTransport-compliant cert (ECDSA P-256, 14-day validity)
-    let cert = generate_webtransport_cert(&config.cert_hostnames)?;
-
-    // Print the certificate hash for WebTransport clients
-    if let Some(cert_der) = cert.cert_chain.first() {
-        use sha2::{Sha256, Digest};
-        let hash = Sha256::digest(cert_der.as_ref());
-        info!("Certificate SHA-256 hash (for WebTransport): {:02x?}", hash.as_slice());
-        // Print in a format that can be directly used in code
-        let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
-        info!("Certificate hash (hex): {}", hex);
-    }
-
-    let mut tls_config = TlsServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert.cert_chain, cert.private_key)?;
-    // Support multiple h3 ALPN versions for WebTransport compatibility
-    tls_config.alpn_protocols = vec![
-        b"h3".to_vec(),
-        b"h3-32".to_vec(),
-        b"h3-31".to_vec(),
-        b"h3-30".to_vec(),
-        b"h3-29".to_vec(),
-    ];
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::{AbortOnDropHandle, TaskTracker};
+use tracing::{debug, error, info, trace, warn};
+
+/// How long a drained connection is kept open after its last request, for
+/// the client to close it.
+const DRAIN_LINGER: Duration = Duration::from_secs(1);
+
+/// How long browsers may cache an allowed CORS preflight.
+const CORS_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// The ALPN protocols offered, most preferred first: final h3 and, for
+/// WebTransport compatibility, the drafts browsers still ask for.
+pub const ALPN: &[&str] = &["h3", "h3-32", "h3-31", "h3-30", "h3-29"];
+
+/// Log the server's address and certificates, then serve until the
+/// endpoint is closed. SIGTERM and Ctrl-C shut the server down gracefully.
+///
+/// When started by systemd with `Type=notify`, readiness is reported once
+/// the server is listening.
+pub async fn run(server: Server) -> anyhow::Result<()> {
+    let addr = server.local_addr();
+    info!("HTTP/3 server listening on {}", addr);
+    log_cert_info(&server.cert_info());
+    if server.config.post_quantum_kx {
+        info!("Key exchange: X25519MLKEM768 (post-quantum hybrid) preferred");
+    }
+
+    let shutdown = server.shutdown_handle();
+    tokio::spawn(async move {
+        let cause = tokio::select! {
+            _ = terminated() => "SIGTERM received",
+            _ = interrupted() => "Interrupted",
+        };
+        info!("{}", cause);
+        shutdown.shutdown();
+    });
+
+    systemd::notify_ready(&format!("Listening on {}", addr));
+    let result = server.serve().await;
+    systemd::notify_stopping();
+    result
+}
+
+/// A bound HTTP/3 server that has not started accepting connections yet.
+///
+/// Binding and serving are separate so callers can discover the actual
+/// listen address (e.g. when binding to port 0) and the certificate hashes
+/// before the accept loop starts.
+pub struct Server {
+    endpoint: Endpoint,
+    routers: Arc<HostRouters>,
+    vhost_certs: Arc<Vec<(String, CertificateChain)>>,
+    config: Arc<ServerConfig>,
+    rotation: CertRotation,
+    topics: Topics,
+    sessions: Sessions,
+    telemetry: Telemetry,
+    live: LiveConfig,
+    api_keys: Option<ApiKeyStore>,
+    usage: Usage,
+    webhooks: Webhooks,
+    sinks: Sinks,
+    /// Saved session state, relayed to other instances with a backplane.
+    session_store: Arc<MemoryStore>,
+    backplane: Option<Arc<dyn Backplane>>,
+    instance_id: String,
+    catalog: Arc<Catalog>,
+    /// Where the endpoint runs, if not on the caller's runtime.
+    accept_runtime: Option<AcceptRuntime>,
+    /// Cancelled by [`Shutdown::shutdown`].
+    shutdown_requested: CancellationToken,
+}
+
+/// Shuts a server down gracefully, as SIGTERM does; clones share it.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: CancellationToken,
+}
+
+impl Shutdown {
+    /// Stop accepting connections and drain the open ones (see
+    /// [`drain_on_shutdown`]); `serve` returns once they are done.
+    pub fn shutdown(&self) {
+        self.requested.cancel();
+    }
+}
+
+/// State shared by every connection.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<ServerConfig>,
+    topics: Topics,
+    sessions: Sessions,
+    telemetry: Telemetry,
+    live: LiveConfig,
+    api_keys: Option<ApiKeyStore>,
+    usage: Usage,
+    webhooks: Webhooks,
+    sinks: Sinks,
+    limiter: Option<Arc<HandshakeLimiter>>,
+    /// The server-wide request rate limit, shared by every connection.
+    rate_limit: Option<RateLimit>,
+    session_store: Arc<dyn SessionStore>,
+    /// Messages for built-in error responses.
+    catalog: Arc<Catalog>,
+    /// Connection and request tasks.
+    tasks: Tasks,
+    /// Open connections, for diagnostics bundles.
+    connections: Connections,
+    /// Cancelled when the server starts shutting down; the parent of every
+    /// request's token.
+    shutdown: CancellationToken,
+}
+
+impl Server {
+    /// Generate certificates and bind the QUIC endpoint.
+    ///
+    /// Each virtual host is served with its own certificate and router,
+    /// selected by SNI; other hostnames get the WebTransport certificate and
+    /// `router`.
+    pub fn bind(
+        config: ServerConfig,
+        router: Router,
+        hosts: Vec<VirtualHost>,
+    ) -> anyhow::Result<Self> {
+        // Use WebTransport-compliant certs (ECDSA P-256, 14-day validity),
+        // pre-generating the next one so clients can pin both hashes
+        let rotation = CertRotation::new(
+            &config.cert_hostnames,
+            Duration::from_secs(config.cert_rotation_margin_secs),
+            config.cert_cache_dir.as_deref(),
+        )?;
+
+        let mut routers = HostRouters::new(router);
+        let mut vhost_certs = Vec::with_capacity(hosts.len());
+        for host in hosts {
+            routers.insert(&host.hostname, host.router);
+            vhost_certs.push((host.hostname, host.cert));
+        }
+
+        let server_config = build_server_config(rotation.current(), &vhost_certs, &config)?;
+        let accept_runtime = config.accept_runtime.then(AcceptRuntime::new).transpose()?;
+        // The endpoint's driver is spawned on the runtime entered here
+        let _entered = accept_runtime.as_ref().map(|runtime| runtime.handle().enter());
+        // Under systemd socket activation the socket is already bound
+        let endpoint = match systemd::listen_socket()? {
+            Some(socket) => {
+                let runtime = quinn::default_runtime()
+                    .ok_or_else(|| anyhow::anyhow!("no async runtime found"))?;
+                Endpoint::new(EndpointConfig::default(), Some(server_config), socket, runtime)?
+            }
+            None => Endpoint::server(server_config, config.bind_addr)?,
+        };
+
+        Ok(Self {
+            endpoint,
+            routers: Arc::new(routers),
+            vhost_certs: Arc::new(vhost_certs),
+            config: Arc::new(config),
+            rotation,
+            topics: Topics::new(),
+            sessions: Sessions::new(),
+            telemetry: Telemetry::new(),
+            live: LiveConfig::new(None),
+            api_keys: None,
+            usage: Usage::new(),
+            webhooks: Webhooks::disabled(),
+            sinks: Sinks::default(),
+            session_store: Arc::new(MemoryStore::default()),
+            backplane: None,
+            instance_id: hex(&random_bytes::<4>()?),
+            catalog: Arc::new(Catalog::new()),
+            accept_runtime,
+            shutdown_requested: CancellationToken::new(),
+        })
+    }
+
+    /// A handle to shut the server down once it is serving.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown {
+            requested: self.shutdown_requested.clone(),
+        }
+    }
+
+    /// Register WebTransport sessions in `sessions`, e.g. a registry shared
+    /// with handlers that push messages to them.
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Apply reloadable settings (allowed IPs, disabled routes) from `live`.
+    pub fn with_live_config(mut self, live: LiveConfig) -> Self {
+        self.live = live;
+        self
+    }
+
+    /// Require an API key from `api_keys` for HTTP/3 requests.
+    pub fn with_api_keys(mut self, api_keys: ApiKeyStore) -> Self {
+        self.api_keys = Some(api_keys);
+        self
+    }
+
+    /// Account usage per API key in `usage`, e.g. one shared with the
+    /// handlers that report it.
+    pub fn with_usage(mut self, usage: Usage) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Send server events (sessions, certificate rotations, error spikes) to
+    /// `webhooks`.
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    /// Report access logs, audit events and metrics to `sinks` instead of
+    /// `tracing`.
+    pub fn with_sinks(mut self, sinks: Sinks) -> Self {
+        self.sinks = sinks;
+        self
+    }
+
+    /// Relay topic events and messages pushed to every session to the other
+    /// server instances on `backplane`.
+    pub fn with_backplane(mut self, backplane: Arc<dyn Backplane>) -> Self {
+        self.backplane = Some(backplane);
+        self
+    }
+
+    /// Identify this instance on the backplane as `id` instead of a random
+    /// ID.
+    pub fn with_instance_id(mut self, id: impl Into<String>) -> Self {
+        self.instance_id = id.into();
+        self
+    }
+
+    /// Word built-in error responses with `catalog`, e.g. one with the
+    /// application's own translations.
+    #[allow(dead_code)]
+    pub fn with_catalog(mut self, catalog: Catalog) -> Self {
+        self.catalog = Arc::new(catalog);
+        self
+    }
+
+    /// The address the server is actually listening on.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.endpoint
+            .local_addr()
+            .unwrap_or(self.config.bind_addr)
+    }
+
+    /// Hashes and validity of the served certificates.
+    pub fn cert_info(&self) -> CertInfo {
+        self.rotation.info()
+    }
+
+    /// Publish the hashes of the served certificates in `hashes`, and
+    /// again after every rotation, e.g. shared with the handler that
+    /// serves them.
+    pub fn with_cert_hashes(mut self, hashes: CertHashes) -> Self {
+        self.rotation.publish_to(hashes);
+        self
+    }
+
+    /// Topics WebTransport sessions can subscribe to; publish events here.
+    pub fn topics(&self) -> Topics {
+        self.topics.clone()
+    }
+
+    /// Aggregate the telemetry datagrams of WebTransport sessions in
+    /// `telemetry`, e.g. shared with the handler that serves it.
+    pub fn with_telemetry(mut self, telemetry: Telemetry) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Accept and serve connections until the endpoint is closed, then wait
+    /// for the connection tasks to finish.
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let Self {
+            endpoint,
+            routers,
+            vhost_certs,
+            config,
+            rotation,
+            topics,
+            sessions,
+            telemetry,
+            live,
+            api_keys,
+            usage,
+            webhooks,
+            sinks,
+            session_store,
+            backplane,
+            instance_id,
+            catalog,
+            accept_runtime,
+            shutdown_requested,
+        } = self;
+
+        // Control messages broadcast to every connection (e.g. GOAWAY)
+        let (control_tx, _) = broadcast::channel(16);
+
+        info!("WebTransport enabled at /webtransport");
+        info!("Routes: {:?}", routers.default_router().routes());
+        for host in routers.default_router().hosts() {
+            info!("Host routes for {}", host);
+        }
+        for hostname in routers.hostnames() {
+            info!("Virtual host: {}", hostname);
+        }
+        if let Some(dir) = &config.recording_dir {
+            std::fs::create_dir_all(dir)?;
+            info!("Recording WebTransport sessions to {}", dir.display());
+        }
+        let connections = Connections::default();
+        if let Some(dir) = &config.diagnostics_dir {
+            let (config, connections) = (Arc::clone(&config), connections.clone());
+            diagnostics::install(dir.clone(), config, connections, sessions.clone())?;
+            info!("Writing diagnostics bundles on panic to {}", dir.display());
+        }
+
+        tokio::spawn(rotate_certs(
+            endpoint.clone(),
+            rotation,
+            vhost_certs,
+            Arc::clone(&config),
+            control_tx.clone(),
+            webhooks.clone(),
+        ));
+
+        tokio::spawn(pool::report_stats(Arc::clone(&sinks.metrics)));
+
+        if let Some(backplane) = backplane {
+            info!("Relaying to other instances as instance {}", instance_id);
+            backplane::start(
+                backplane,
+                instance_id,
+                topics.clone(),
+                sessions.clone(),
+                Arc::clone(&session_store),
+            );
+        }
+        let relay = topics.relay().clone();
+
+        let limiter = config.handshake_rate_limit.map(|per_sec| {
+            let ban = Duration::from_secs(config.handshake_ban_secs);
+            info!("Limiting handshakes to {}/s per source prefix", per_sec);
+            Arc::new(HandshakeLimiter::new(per_sec, ban))
+        });
+        let rate_limit = match config.rate_limit_per_sec {
+            Some(per_sec) => {
+                info!("Limiting requests to {}/s per client", per_sec);
+                let limit = RateLimit::per_second(per_sec, config.rate_limit_burst);
+                Some(match &config.rate_limit_header {
+                    Some(header) => limit.by_header(header, &config.rate_limit_proxies)?,
+                    None => limit,
+                })
+            }
+            None => None,
+        };
+        if let Some(max) = config.max_tasks {
+            info!("Handling at most {} connections and requests at once", max);
+        }
+        if let Some(max) = config.max_connections {
+            info!("Handling at most {} connections at once", max);
+        }
+        if let Some(max) = config.max_connection_requests {
+            info!("Running at most {} requests at once per connection", max);
+        }
+        let tasks = Tasks::new(&config);
+        let shutdown = CancellationToken::new();
+
+        let drain_timeout = Duration::from_secs(config.drain_timeout_secs);
+        tokio::spawn(drain_on_shutdown(
+            endpoint.clone(),
+            control_tx.clone(),
+            tasks.clone(),
+            shutdown.clone(),
+            shutdown_requested,
+            drain_timeout,
+        ));
+
+        let shared = Shared {
+            config,
+            topics,
+            sessions,
+            telemetry,
+            live,
+            api_keys,
+            usage,
+            webhooks,
+            sinks,
+            limiter,
+            rate_limit,
+            session_store,
+            catalog,
+            tasks: tasks.clone(),
+            connections,
+            shutdown,
+        };
+
+        let accepting = accept_loop(endpoint, routers, control_tx, shared, Handle::current());
+        match &accept_runtime {
+            Some(runtime) => {
+                info!("Accepting connections on a dedicated thread");
+                runtime.handle().spawn(accepting).await?;
+            }
+            None => accepting.await,
+        }
+
+        info!("Endpoint closed; waiting for {} tasks to finish", tasks.len());
+        tasks.wait().await;
+        // Pass the state of the sessions that just ended to other instances
+        if tokio::time::timeout(drain_timeout, relay.flush()).await.is_err() {
+            warn!("Timed out relaying session state to other instances");
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    /// The rate limiter's and config's decision on a connection attempt.
+    fn on_incoming(&self, info: &IncomingInfo) -> Decision {
+        // Before anything else, so floods cost as little as possible
+        if let Some(limiter) = &self.limiter {
+            let admission = limiter.check(info.remote.ip());
+            let labels = [("result", admission.as_str())];
+            self.sinks.metrics.counter("h3_handshake_attempts_total", 1, &labels);
+            match admission {
+                Admission::Allowed => {}
+                Admission::Limited => {
+                    warn!(
+                        "Handshake rate limit exceeded by {}; ignoring it for {:?}",
+                        Prefix::of(info.remote.ip()),
+                        limiter.ban_duration()
+                    );
+                    self.sinks.metrics.counter("h3_handshake_bans_total", 1, &[]);
+                    return Decision::Ignore;
+                }
+                Admission::Banned | Admission::Full => return Decision::Ignore,
+            }
+        }
+
+        let live = self.live.incoming(info);
+        if live == Decision::Accept && self.config.require_retry && !info.validated {
+            return Decision::Retry;
+        }
+        live
+    }
+
+    /// `message` in the language `req` prefers.
+    fn localize<T>(&self, req: &Request<T>, message: Message, args: &[(&str, &str)]) -> Localized {
+        let accept_language = req
+            .headers()
+            .get(http::header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+        self.catalog.localize(message, accept_language, args)
+    }
+}
+
+/// Accept connections until the endpoint is closed, handling each in a
+/// task on `main`.
+async fn accept_loop(
+    endpoint: Endpoint,
+    routers: Arc<HostRouters>,
+    control_tx: broadcast::Sender<ControlMessage>,
+    shared: Shared,
+    main: Handle,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let info = IncomingInfo {
+            remote: incoming.remote_address(),
+            local_ip: incoming.local_ip(),
+            validated: incoming.remote_address_validated(),
+        };
+        trace!(
+            "Connection attempt from {} to {:?} (validated: {})",
+            info.remote, info.local_ip, info.validated
+        );
+        match shared.on_incoming(&info) {
+            Decision::Accept => {}
+            Decision::Retry if incoming.may_retry() => {
+                debug!("Sending retry to {}", info.remote);
+                if let Err(e) = incoming.retry() {
+                    debug!("Retry failed: {}", e);
+                }
+                continue;
+            }
+            // Already validated by an earlier retry
+            Decision::Retry => {}
+            Decision::Refuse => {
+                incoming.refuse();
+                continue;
+            }
+            Decision::Ignore => {
+                incoming.ignore();
+                continue;
+            }
+        }
+
+        let Some(slot) = shared.tasks.reserve_connection() else {
+            warn!("Connection or task limit reached; refusing connection from {}", info.remote);
+            let labels = [("kind", "connection")];
+            shared.sinks.metrics.counter("h3_tasks_rejected_total", 1, &labels);
+            incoming.refuse();
+            continue;
+        };
+        let routers = Arc::clone(&routers);
+        let control_rx = control_tx.subscribe();
+        let shared = shared.clone();
+
+        // On the server's runtime, wherever this loop runs
+        let _main = main.enter();
+        slot.spawn("connection", async move {
+            let webhooks = shared.webhooks.clone();
+            match accept(incoming, &shared).await {
+                Ok(Some(conn)) => {
+                    let remote = conn.remote_address();
+                    debug!("New connection from {}", remote);
+                    let router = routers.select(server_name(&conn).as_deref());
+
+                    let result = handle_connection(conn, router, control_rx, shared).await;
+                    if let Err(e) = result {
+                        error!("Connection error from {}: {:?}", remote, e);
+                        webhooks.record_error();
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Failed to accept connection: {:?}", e);
+                    webhooks.record_error();
+                }
+            }
+        });
+    }
+}
+
+/// Complete the handshake, closing the connection early if the ClientHello
+/// is not accepted.
+async fn accept(
+    incoming: quinn::Incoming,
+    shared: &Shared,
+) -> anyhow::Result<Option<quinn::Connection>> {
+    let mut connecting = incoming.accept()?;
+    let data = connecting.handshake_data().await?;
+    let data = data
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .map_err(|_| anyhow::anyhow!("unexpected handshake data"))?;
+    let hello = ClientHello {
+        remote: connecting.remote_address(),
+        server_name: data.server_name,
+        alpn: data.protocol,
+    };
+    trace!(
+        "ClientHello from {}: server name {:?}, ALPN {:?}",
+        hello.remote,
+        hello.server_name,
+        hello.alpn.as_deref().map(String::from_utf8_lossy)
+    );
+    if !shared.live.client_hello(&hello) {
+        // Dropping the handshake closes the connection
+        return Ok(None);
+    }
+    Ok(Some(connecting.await?))
+}
+
+/// Log the certificate hashes clients should pin.
+fn log_cert_info(info: &CertInfo) {
+    info!("Certificate hash (hex): {}", info.hash);
+    info!("Next certificate hash (hex): {}", info.next_hash);
+    let until = info
+        .rotates_at
+        .duration_since(std::time::SystemTime::now())
+        .unwrap_or_default();
+    info!(
+        "Certificate valid until {} (rotates in {}h)",
+        chrono::DateTime::<chrono::Utc>::from(info.not_after).format("%Y-%m-%d %H:%M:%S UTC"),
+        until.as_secs() / 3600
+    );
+}
+
+/// The SNI hostname the client sent in its handshake, if any.
+fn server_name(conn: &quinn::Connection) -> Option<String> {
+    conn.handshake_data()?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .ok()?
+        .server_name
+}
+
+/// Build the QUIC server configuration for the default certificate and the
+/// virtual host certificates.
+fn build_server_config(
+    cert: &CertificateChain,
+    vhost_certs: &[(String, CertificateChain)],
+    config: &ServerConfig,
+) -> anyhow::Result<QuinnServerConfig> {
+    let provider = common::tls::crypto_provider(config.post_quantum_kx);
+    let mut resolver = SniCertResolver::new(cert, &provider)?;
+    for (hostname, cert) in vhost_certs {
+        resolver.add(hostname, cert, &provider)?;
+    }
+    let tls_config = TlsServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let tls_config = match &config.client_ca {
+        Some(ca) => {
+            let optional = config.client_cert_optional;
+            let verifier = peer_cert::client_verifier(ca, optional, &provider)?;
+            tls_config.with_client_cert_verifier(verifier)
+        }
+        None => tls_config.with_no_client_auth(),
+    };
+    let mut tls_config = tls_config.with_cert_resolver(Arc::new(resolver));
+    tls_config.alpn_protocols = ALPN.iter().map(|alpn| alpn.as_bytes().to_vec()).collect();
     tls_config.max_early_data_size = u32::MAX;
+    if config.key_log {
+        tls_config.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
 
     let mut server_config = QuinnServerConfig::with_crypto(Arc::new(
         quinn::crypto::rustls::QuicServerConfig::try_from(tls_config)?,
@@ -56,41 +691,153 @@ pub async fn run(config: ServerConfig, router: Router) -> anyhow::Result<()> {
     transport_config.keep_alive_interval(Some(Duration::from_secs(2)));
     server_config.transport_config(Arc::new(transport_config));
 
-    let endpoint = Endpoint::server(server_config, config.bind_addr)?;
-    let router = Arc::new(router);
+    // Address validation: retry tokens prove the client owns its address
+    // right away, NEW_TOKEN tokens let it skip the retry next time
+    server_config.retry_token_lifetime(Duration::from_secs(config.retry_token_lifetime_secs));
+    let mut validation_tokens = quinn::ValidationTokenConfig::default();
+    validation_tokens
+        .lifetime(Duration::from_secs(config.validation_token_lifetime_secs))
+        .sent(config.validation_tokens_sent);
+    server_config.validation_token_config(validation_tokens);
 
-    info!("HTTP/3 server listening on {}", config.bind_addr);
-    info!("WebTransport enabled at /webtransport");
-    info!("Routes: {:?}", router.routes());
+    Ok(server_config)
+}
 
-    while let Some(incoming) = endpoint.accept().await {
-        let router = Arc::clone(&router);
+/// Rotate the WebTransport certificate before it expires.
+///
+/// New connections are served with the next certificate, and existing
+/// connections are told to go away so clients reconnect using the hash of
+/// the new certificate they pinned in advance.
+async fn rotate_certs(
+    endpoint: Endpoint,
+    mut rotation: CertRotation,
+    vhost_certs: Arc<Vec<(String, CertificateChain)>>,
+    config: Arc<ServerConfig>,
+    control_tx: broadcast::Sender<ControlMessage>,
+    webhooks: Webhooks,
+) {
+    loop {
+        tokio::time::sleep(rotation.until_rotation()).await;
 
-        tokio::spawn(async move {
-            match incoming.await {
-                Ok(conn) => {
-                    let remote = conn.remote_address();
-                    debug!("New connection from {}", remote);
+        if let Err(e) = rotation.rotate() {
+            error!("Failed to generate next certificate: {:?}", e);
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            continue;
+        }
 
-                    if let Err(e) = handle_connection(conn, router).await {
-                        error!("Connection error from {}: {:?}", remote, e);
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to accept connection: {:?}", e);
-                }
+        match build_server_config(rotation.current(), &vhost_certs, &config) {
+            Ok(server_config) => endpoint.set_server_config(Some(server_config)),
+            Err(e) => {
+                error!("Failed to apply rotated certificate: {:?}", e);
+                continue;
             }
+        }
+
+        info!("Rotated WebTransport certificate");
+        let info = rotation.info();
+        log_cert_info(&info);
+        webhooks.emit(Event::CertRotated {
+            not_after: chrono::DateTime::<chrono::Utc>::from(info.not_after).timestamp(),
+            hash: info.hash,
+            next_hash: info.next_hash,
         });
+
+        // Ignore the error when no connections are subscribed
+        let _ = control_tx.send(ControlMessage::GoAway {
+            reason: "certificate rotation".to_string(),
+        });
+    }
+}
+
+/// On [`Shutdown::shutdown`], which [`run`] calls on SIGTERM or Ctrl-C,
+/// stop accepting connections and send every connection a GOAWAY, so
+/// clients reconnect to another instance (where they resume their
+/// WebTransport sessions with their affinity tokens).
+///
+/// Requests in flight and WebTransport sessions get `drain_timeout` to end.
+/// Those still running are then cancelled through `shutdown`, and get
+/// [`CANCEL_GRACE`] to wrap up before the endpoint is closed. A second
+/// Ctrl-C closes it at once.
+async fn drain_on_shutdown(
+    endpoint: Endpoint,
+    control_tx: broadcast::Sender<ControlMessage>,
+    tasks: Tasks,
+    shutdown: CancellationToken,
+    requested: CancellationToken,
+    drain_timeout: Duration,
+) {
+    requested.cancelled().await;
+    info!("Shutting down; draining {} connections and requests", tasks.len());
+    systemd::notify_stopping();
+
+    endpoint.set_server_config(None);
+    let _ = control_tx.send(ControlMessage::GoAway {
+        reason: "server restarting".to_string(),
+    });
+    let drain = async {
+        if tokio::time::timeout(drain_timeout, tasks.wait()).await.is_ok() {
+            return;
+        }
+        info!("Cancelling {} tasks still running after {:?}", tasks.len(), drain_timeout);
+        shutdown.cancel();
+        if tokio::time::timeout(CANCEL_GRACE, tasks.wait()).await.is_err() {
+            warn!("Closing {} tasks still running", tasks.len());
+        }
+    };
+    tokio::select! {
+        _ = drain => {}
+        _ = interrupted() => warn!("Interrupted again; closing {} tasks now", tasks.len()),
     }
 
-    Ok(())
+    shutdown.cancel();
+    let code = CloseCode::GoingAway;
+    endpoint.close(code.code().into(), code.reason().as_bytes());
 }
 
-async fn handle_connection(conn: quinn::Connection, router: Arc<Router>) -> anyhow::Result<()> {
+/// Resolves on Ctrl-C; never if it cannot be listened for.
+async fn interrupted() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Cannot listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await
+    }
+}
+
+/// Resolves when the process receives SIGTERM; never where SIGTERM does
+/// not exist or cannot be listened for.
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+                return;
+            }
+            Err(e) => warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+    std::future::pending::<()>().await
+}
+
+async fn handle_connection(
+    conn: quinn::Connection,
+    router: Arc<Router>,
+    mut control_rx: broadcast::Receiver<ControlMessage>,
+    shared: Shared,
+) -> anyhow::Result<()> {
     let remote = conn.remote_address();
+    // h3 takes the connection; handlers get this handle to it instead
+    let handle = ConnectionHandle::new(conn.clone());
+    let client_cert = PeerCertificate::of(&conn);
+    let _registered = shared.connections.register(handle.clone());
+    // Bills the connection's traffic to the API keys used on it
+    let meter = shared
+        .api_keys
+        .as_ref()
+        .map(|_| Arc::new(ConnectionMeter::new(handle.clone(), shared.usage.clone())));
 
     // Build h3 connection with WebTransport support enabled
-    let mut h3_conn = h3::server::builder()
+    let h3_conn = h3::server::builder()
         .enable_webtransport(true)
         .enable_extended_connect(true)
         .enable_datagram(true)
@@ -99,133 +846,1122 @@ async fn handle_connection(conn: quinn::Connection, router: Arc<Router>) -> anyh
         .build(h3_quinn::Connection::new(conn))
         .await?;
 
-    loop {
-        match h3_conn.accept().await {
-            Ok(Some(req_resolver)) => {
-                let (req, stream) = match req_resolver.resolve_request().await {
-                    Ok(resolved) => resolved,
-                    Err(e) => {
-                        error!("Failed to resolve request: {:?}", e);
-                        continue;
-                    }
-                };
+    // Cancels the requests still running when the connection ends
+    let closed = shared.shutdown.child_token();
+    let _closed = closed.clone().drop_guard();
 
-                // Check if this is a WebTransport CONNECT request
-                let ext = req.extensions();
-                if req.method() == Method::CONNECT
-                    && ext.get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT)
-                {
-                    info!("WebTransport CONNECT request from {}", remote);
-
-                    // Accept WebTransport session - this takes ownership of the connection
-                    match WebTransportSession::accept(req, stream, h3_conn).await {
-                        Ok(session) => {
-                            if let Err(e) = webtransport::handle_session(session).await {
-                                debug!("WebTransport session error: {:?}", e);
-                            }
-                        }
+    // Requests running on this connection; once it is draining and they are
+    // done, the connection is closed rather than left to the client
+    let requests = TaskTracker::new();
+    let mut draining = false;
+
+    // Room for one more request; the connection waits for it before taking
+    // the next, so a client with too many running is held back by QUIC's
+    // stream limits instead of piling up tasks
+    let in_flight = shared.tasks.per_connection();
+    let mut permit = None;
+
+    // A WebTransport session takes the h3 connection over and runs in a task
+    // of its own; the requests arriving on the connection after that are
+    // accepted by the session and passed back here, to be served alongside
+    // it. They wait in `waiting` for room to run, rather than holding up
+    // the session; each holds a request stream, so QUIC's stream limit caps
+    // how many can
+    let mut h3_conn = Some(h3_conn);
+    let mut session: Option<AbortOnDropHandle<CloseCode>> = None;
+    let (session_requests, mut passed_on) = mpsc::unbounded_channel();
+    let mut waiting = VecDeque::new();
+
+    loop {
+        let (req, stream) = tokio::select! {
+            acquired = in_flight.acquire(), if permit.is_none() => {
+                permit = Some(acquired);
+                continue;
+            }
+            accepted = async { h3_conn.as_mut().unwrap().accept().await },
+                if h3_conn.is_some() && permit.is_some() =>
+            {
+                match accepted {
+                    Ok(Some(req_resolver)) => match req_resolver.resolve_request().await {
+                        Ok(resolved) => resolved,
                         Err(e) => {
-                            error!("Failed to accept WebTransport session: {:?}", e);
+                            error!("Failed to resolve request: {:?}", e);
+                            continue;
                         }
+                    },
+                    Ok(None) => {
+                        // Client closed connection gracefully (GOAWAY)
+                        debug!("Connection closed by client: {}", remote);
+                        break;
+                    }
+                    Err(e) => {
+                        // Check error type
+                        let err_str = format!("{:?}", e);
+                        if err_str.contains("Timeout") {
+                            debug!("Connection timed out: {}", remote);
+                        } else if err_str.contains("H3_NO_ERROR")
+                            || err_str.contains("ApplicationClose")
+                        {
+                            // H3_NO_ERROR is a graceful close initiated by client
+                            debug!("Connection closed gracefully: {}", remote);
+                        } else if err_str.contains("Reset") || err_str.contains("Closed") {
+                            debug!("Connection reset: {}", remote);
+                        } else {
+                            error!("Connection error from {}: {:?}", remote, e);
+                        }
+                        break;
                     }
-                    // WebTransport takes over the connection, exit loop
-                    return Ok(());
                 }
-
-                // Regular HTTP/3 request
-                let router = Arc::clone(&router);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_request(req, stream, &router).await {
-                        debug!("Request handling ended: {:?}", e);
+            }
+            Some(passed) = passed_on.recv() => {
+                waiting.push_back(passed);
+                continue;
+            }
+            Some(resolved) = async { waiting.pop_front() }, if permit.is_some() => resolved,
+            ended = async { session.as_mut().unwrap().await }, if session.is_some() => {
+                let code = ended.unwrap_or_else(|e| {
+                    if let Ok(panic) = e.try_into_panic() {
+                        error!("WebTransport session panicked: {}", panic_message(&*panic));
                     }
+                    CloseCode::InternalError
                 });
+                // The session ends the connection, requests still running
+                // on it included. No-op if the client closed first
+                debug!("Closing connection from {}: {}", remote, code);
+                handle.close(code, code.reason());
+                return Ok(());
             }
-            Ok(None) => {
-                // Client closed connection gracefully (GOAWAY)
-                debug!("Connection closed by client: {}", remote);
-                break;
+            msg = control_rx.recv(), if !draining => {
+                match msg {
+                    Ok(ControlMessage::GoAway { reason }) => {
+                        // Stop accepting new requests; in-flight ones complete.
+                        // A session drains the connection its own way
+                        if let Some(h3_conn) = h3_conn.as_mut() {
+                            debug!("Sending GOAWAY to {} ({})", remote, reason);
+                            h3_conn.shutdown(0).await?;
+                        }
+                        draining = true;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => draining = true,
+                }
+                if draining {
+                    requests.close();
+                }
+                continue;
             }
-            Err(e) => {
-                // Check error type
-                let err_str = format!("{:?}", e);
-                if err_str.contains("Timeout") {
-                    debug!("Connection timed out: {}", remote);
-                } else if err_str.contains("H3_NO_ERROR") || err_str.contains("ApplicationClose") {
-                    // H3_NO_ERROR is a graceful close initiated by client
-                    debug!("Connection closed gracefully: {}", remote);
-                } else if err_str.contains("Reset") || err_str.contains("Closed") {
-                    debug!("Connection reset: {}", remote);
-                } else {
-                    error!("Connection error from {}: {:?}", remote, e);
+            _ = requests.wait(), if draining && session.is_none() => {
+                // Closing discards responses still in flight, so the client
+                // gets a moment to read them and close the connection itself
+                if let Some(h3_conn) = h3_conn.as_mut() {
+                    let _ = tokio::time::timeout(DRAIN_LINGER, h3_conn.accept()).await;
+                }
+                debug!("Drained connection from {}", remote);
+                let code = CloseCode::GoingAway;
+                handle.close(code, code.reason());
+                return Ok(());
+            }
+        };
+
+        let mut req = req;
+        // Released once the request is served, or right away for sessions
+        // and refused requests
+        let running = permit.take();
+        if let Some(cert) = &client_cert {
+            req.extensions_mut().insert(cert.clone());
+        }
+
+        // Check if this is a WebTransport CONNECT request
+        let ext = req.extensions();
+        if req.method() == Method::CONNECT
+            && ext.get::<Protocol>() == Some(&Protocol::WEB_TRANSPORT)
+        {
+            info!("WebTransport CONNECT request from {}", remote);
+
+            if shared.live.is_disabled(req.uri().path()) {
+                let status = StatusCode::SERVICE_UNAVAILABLE;
+                let message = shared.localize(&req, Message::RouteDisabled, &[]);
+                send_error(stream, req.method(), status, &message).await?;
+                continue;
+            }
+            if shared.sessions.is_banned(remote.ip()) {
+                info!("Refusing session from banned {}", remote);
+                let message = shared.localize(&req, Message::Banned, &[]);
+                send_error(stream, req.method(), StatusCode::FORBIDDEN, &message).await?;
+                continue;
+            }
+            let shaping = match shared.live.shaping(req.uri()) {
+                Ok(shaping) => shaping,
+                Err(e) => {
+                    let status = StatusCode::BAD_REQUEST;
+                    let problem = schema::problem(status, &e.to_string());
+                    send_problem(stream, req.method(), status, &problem).await?;
+                    continue;
                 }
-                break;
+            };
+            // The session owns the h3 connection, and h3-webtransport only
+            // sends datagrams for the session that took it, so a second
+            // session could not be served; the settings still advertise the
+            // library's usual limit
+            let Some(conn) = h3_conn.take() else {
+                info!("Refusing second WebTransport session from {}", remote);
+                let message = shared.localize(&req, Message::SessionLimit, &[]);
+                send_error(stream, req.method(), StatusCode::TOO_MANY_REQUESTS, &message).await?;
+                continue;
+            };
+
+            // Sessions opened at an RPC path carry JSON-RPC streams
+            let dispatcher = match router
+                .for_authority(req.uri().authority().map(|a| a.as_str()))
+                .get(req.uri().path())
+            {
+                Some(Handler::Rpc(dispatcher)) => Some(Arc::clone(dispatcher)),
+                _ => None,
+            };
+
+            session = Some(AbortOnDropHandle::new(tokio::spawn(run_session(
+                req,
+                stream,
+                conn,
+                dispatcher,
+                shaping,
+                control_rx.resubscribe(),
+                session_requests.clone(),
+                shared.clone(),
+                handle.clone(),
+            ))));
+            continue;
+        }
+
+        // Regular HTTP/3 request; handlers can inspect the
+        // connection it arrived on (e.g. for QUIC stats)
+        req.extensions_mut().insert(handle.clone());
+        req.extensions_mut().insert(RequestContext::new(closed.child_token()));
+        req.extensions_mut().insert(shared.tasks.clone());
+
+        // Tiny REST responses are cheaper to serve than to spawn
+        let inline = router
+            .for_authority(req.uri().authority().map(|a| a.as_str()))
+            .is_inline(req.uri().path(), shared.config.inline_rest);
+        if inline {
+            let meter = meter.as_deref();
+            if let Err(e) = handle_request(req, stream, &router, &shared, meter).await {
+                debug!("Request handling ended: {:?}", e);
             }
+            continue;
         }
+
+        let Some(slot) = shared.tasks.reserve() else {
+            debug!("Task limit reached; rejecting request from {}", remote);
+            let labels = [("kind", "request")];
+            shared.sinks.metrics.counter("h3_tasks_rejected_total", 1, &labels);
+            let message = shared.localize(&req, Message::ServerBusy, &[]);
+            send_error(stream, req.method(), StatusCode::SERVICE_UNAVAILABLE, &message).await?;
+            continue;
+        };
+        let router = Arc::clone(&router);
+        let shared = shared.clone();
+        let meter = meter.clone();
+        slot.spawn("request", requests.track_future(async move {
+            let _running = running;
+            let meter = meter.as_deref();
+            if let Err(e) = handle_request(req, stream, &router, &shared, meter).await {
+                debug!("Request handling ended: {:?}", e);
+            }
+        }));
     }
 
     Ok(())
 }
 
+/// Accept a WebTransport session on `conn` and serve it until it ends,
+/// passing the HTTP/3 requests that arrive on the connection meanwhile on
+/// to `requests`. Returns the code to close the connection with.
+#[allow(clippy::too_many_arguments)]
+async fn run_session(
+    req: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    conn: h3::server::Connection<h3_quinn::Connection, Bytes>,
+    dispatcher: Option<Arc<Dispatcher>>,
+    shaping: Shaping,
+    control_rx: broadcast::Receiver<ControlMessage>,
+    requests: SessionRequests,
+    shared: Shared,
+    handle: ConnectionHandle,
+) -> CloseCode {
+    let remote = handle.remote_address();
+    let path = req.uri().path().to_string();
+    let request = ConnectRequest::new(&req);
+    let accepted = WebTransportSession::accept(req, stream, conn).await;
+    let established = accepted.is_ok();
+    if established {
+        let path = path.clone();
+        shared.webhooks.emit(Event::SessionStarted { remote, path });
+    }
+    let started = Instant::now();
+    let result = match (accepted, dispatcher) {
+        (Ok(session), Some(dispatcher)) => {
+            webtransport::handle_rpc_session(
+                session,
+                dispatcher,
+                control_rx,
+                handle.clone(),
+                requests,
+            )
+            .await
+        }
+        (Ok(session), None) => {
+            let recorder = start_recording(&shared.config, remote);
+            let limits = StreamLimits::from_config(&shared.config);
+            match Affinity::start(shared.session_store, &request.uri) {
+                Ok(affinity) => {
+                    let context = SessionContext {
+                        topics: shared.topics,
+                        sessions: shared.sessions,
+                        telemetry: shared.telemetry,
+                        connection: handle.clone(),
+                        limits,
+                        shaping,
+                        requests,
+                    };
+                    webtransport::handle_session(
+                        session, request, recorder, control_rx, affinity, context,
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            }
+        }
+        (Err(e), _) => {
+            error!("Failed to accept WebTransport session: {:?}", e);
+            Ok(CloseCode::ProtocolError)
+        }
+    };
+    let code = result.unwrap_or_else(|e| {
+        debug!("WebTransport session error: {:?}", e);
+        CloseCode::InternalError
+    });
+    if established {
+        shared.webhooks.emit(Event::SessionEnded {
+            remote,
+            path,
+            close_code: code.code(),
+            reason: code.reason().to_string(),
+            duration_secs: started.elapsed().as_secs_f64(),
+        });
+    }
+    code
+}
+
+/// Open a recording file for a new WebTransport session, if recording is enabled.
+fn start_recording(
+    config: &ServerConfig,
+    remote: std::net::SocketAddr,
+) -> Option<Arc<SessionRecorder>> {
+    let dir = config.recording_dir.as_ref()?;
+    let timestamp = chrono::Utc::now().format("%Y%m%d-%H%M%S%.3f");
+    let path = dir.join(format!("session-{}-{}.rec", timestamp, remote.port()));
+
+    match SessionRecorder::create(&path) {
+        Ok(recorder) => {
+            info!("Recording WebTransport session to {}", path.display());
+            Some(recorder)
+        }
+        Err(e) => {
+            error!("Failed to create session recording {}: {:?}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Serve a request and report it to the access log and metrics sinks.
 async fn handle_request(
     req: Request<()>,
     stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
     router: &Router,
+    shared: &Shared,
+    meter: Option<&ConnectionMeter>,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let mut entry = AccessLog {
+        remote: req
+            .extensions()
+            .get::<ConnectionHandle>()
+            .map(|conn| conn.remote_address()),
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        status: None,
+        api_key: None,
+        duration: Duration::ZERO,
+        handler_time: None,
+        response_bytes: None,
+        error: None,
+    };
+
+    let result = dispatch_request(req, stream, router, shared, meter, &mut entry).await;
+
+    entry.duration = started.elapsed();
+    entry.error = result.as_ref().err().map(|e| format!("{:#}", e));
+    shared.sinks.access.record(&entry);
+    let status = entry.status.map_or("-".to_string(), |status| status.to_string());
+    let labels = [("method", entry.method.as_str()), ("status", status.as_str())];
+    shared.sinks.metrics.counter("h3_requests_total", 1, &labels);
+    let duration = entry.duration.as_secs_f64();
+    shared.sinks.metrics.histogram("h3_request_duration_seconds", duration, &labels);
+    report_outliers(&entry, shared, &labels);
+
+    result
+}
+
+/// Warn about, and count, a request slower than `ServerConfig::
+/// slow_request_ms` or a response larger than `large_response_bytes`.
+fn report_outliers(entry: &AccessLog, shared: &Shared, labels: &[(&'static str, &str)]) {
+    let slow = shared
+        .config
+        .slow_request_ms
+        .is_some_and(|ms| entry.duration >= Duration::from_millis(ms));
+    let large = shared
+        .config
+        .large_response_bytes
+        .zip(entry.response_bytes)
+        .is_some_and(|(max, bytes)| bytes >= max);
+    if !slow && !large {
+        return;
+    }
+
+    let peer = entry.remote.map_or("-".to_string(), |remote| remote.to_string());
+    let status = entry.status.map_or("-".to_string(), |status| status.to_string());
+    let millis = |time: Duration| time.as_secs_f64() * 1000.0;
+    let total_ms = millis(entry.duration);
+    let handler_ms = entry.handler_time.map(millis);
+    let io_ms = handler_ms.map(|handler_ms| total_ms - handler_ms);
+    if slow {
+        shared.sinks.metrics.counter("h3_slow_requests_total", 1, labels);
+    }
+    if large {
+        shared.sinks.metrics.counter("h3_large_responses_total", 1, labels);
+    }
+    warn!(
+        method = %entry.method,
+        route = %entry.path,
+        peer = %peer,
+        status = %status,
+        total_ms,
+        handler_ms,
+        io_ms,
+        response_bytes = entry.response_bytes,
+        "{}",
+        match (slow, large) {
+            (true, true) => "Slow request with a large response",
+            (true, false) => "Slow request",
+            _ => "Large response",
+        }
+    );
+}
+
+/// Route a request to its handler, filling in `entry` along the way.
+async fn dispatch_request(
+    req: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    router: &Router,
+    shared: &Shared,
+    meter: Option<&ConnectionMeter>,
+    entry: &mut AccessLog,
 ) -> anyhow::Result<()> {
     let method = req.method().clone();
     let path = req.uri().path().to_string();
+    let router = router.for_authority(req.uri().authority().map(|a| a.as_str()));
 
     info!("{} {}", method, path);
 
+    if shared.live.is_disabled(&path) {
+        entry.status = Some(StatusCode::SERVICE_UNAVAILABLE.as_u16());
+        let message = shared.localize(&req, Message::RouteDisabled, &[]);
+        return send_error(stream, &method, StatusCode::SERVICE_UNAVAILABLE, &message).await;
+    }
+
+    // OPTIONS is answered from the route table; browsers send CORS
+    // preflights without credentials, so before the API key check
+    if method == Method::OPTIONS
+        && let Some(allowed) = router.allowed_methods(&path)
+    {
+        entry.status = Some(StatusCode::NO_CONTENT.as_u16());
+        return send_options(stream, &req, &allowed, shared).await;
+    }
+
+    // Server-wide first, then the route's own; ahead of the API key check,
+    // so floods of unauthenticated requests are limited too
+    let limits = [("server", shared.rate_limit.as_ref()), ("route", router.rate_limit_for(&path))];
+    for (scope, limit) in limits {
+        if let Some(Err(retry_after)) = limit.map(|limit| limit.check(&req)) {
+            debug!("Rate limited {} {} ({} limit)", method, path, scope);
+            shared.sinks.metrics.counter("h3_rate_limited_total", 1, &[("scope", scope)]);
+            deny(shared, entry, StatusCode::TOO_MANY_REQUESTS);
+            let message = shared.localize(&req, Message::RateLimited, &[]);
+            return send_rate_limited(stream, &method, retry_after, &message).await;
+        }
+    }
+
+    // Handlers find the authenticated key in the request extensions
+    let mut req = req;
+    if let Some(api_keys) = &shared.api_keys {
+        match api_keys.authorize(&req) {
+            Ok(Some(principal)) => {
+                entry.api_key = Some(principal.key_id.clone());
+                if let Some(quota) = api_keys.quota(&principal.key_id)
+                    && let Err(exceeded) = shared.usage.check_quota(&principal, &quota)
+                {
+                    debug!("API key {} over its {} quota", principal.key_id, exceeded.resource);
+                    deny(shared, entry, StatusCode::TOO_MANY_REQUESTS);
+                    let limit = exceeded.limit.to_string();
+                    let args = [("limit", limit.as_str()), ("resource", exceeded.resource)];
+                    let message = shared.localize(&req, Message::QuotaExceeded, &args);
+                    return send_quota_exceeded(stream, &method, &exceeded, &message).await;
+                }
+                if let Some(meter) = meter {
+                    meter.request(&principal);
+                }
+                req.extensions_mut().insert(principal);
+            }
+            Ok(None) => {}
+            Err(denied) => {
+                deny(shared, entry, denied.status());
+                let message = shared.localize(&req, denied.message(), &[]);
+                return send_error(stream, &method, denied.status(), &message).await;
+            }
+        }
+    }
+
+    // Handlers find the client's deadline in the extensions, and are
+    // cancelled when it passes
+    let deadline = match RequestDeadline::from_headers(req.headers()) {
+        Ok(deadline) => deadline,
+        Err(e) => {
+            entry.status = Some(StatusCode::BAD_REQUEST.as_u16());
+            let problem = schema::problem(StatusCode::BAD_REQUEST, &e.to_string());
+            return send_problem(stream, &method, StatusCode::BAD_REQUEST, &problem).await;
+        }
+    };
+    if let Some(deadline) = deadline {
+        req.extensions_mut().insert(deadline);
+    }
+    let ctx = RequestContext::of(&req).with_deadline(deadline);
+    req.extensions_mut().insert(ctx.clone());
+
+    // HEAD is served as GET where GET is allowed; anywhere else it gets
+    // its 405 before any body checks, which would answer with a body
+    if method == Method::HEAD
+        && router.contains(&path)
+        && let Some(allowed) = router.allowed_methods(&path)
+        && !allowed.contains(&Method::GET)
+    {
+        entry.status = Some(StatusCode::METHOD_NOT_ALLOWED.as_u16());
+        return send_method_not_allowed(stream, &allowed).await;
+    }
+
+    // Bodies over the route's limit are refused by their declared length;
+    // clients waiting for 100 Continue are only told to go ahead once the
+    // request has passed every check that does not need the body
+    let mut stream = stream;
+    if let (Some(limit), Some(length)) = (router.body_limit_for(&path), content_length(&req))
+        && length > limit
+    {
+        debug!("Refusing {} byte body for {} (limit {})", length, path, limit);
+        entry.status = Some(StatusCode::PAYLOAD_TOO_LARGE.as_u16());
+        let limit = limit.to_string();
+        let message = shared.localize(&req, Message::PayloadTooLarge, &[("limit", &limit)]);
+        return send_error(stream, &method, StatusCode::PAYLOAD_TOO_LARGE, &message).await;
+    }
+    if let Some(expect) = req.headers().get(EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            entry.status = Some(StatusCode::EXPECTATION_FAILED.as_u16());
+            let message = shared.localize(&req, Message::ExpectationFailed, &[]);
+            return send_error(stream, &method, StatusCode::EXPECTATION_FAILED, &message).await;
+        }
+        // Unknown paths and methods get their 404 or 405 without it
+        if router.contains(&path)
+            && router.allowed_methods(&path).is_some_and(|m| m.contains(&method))
+        {
+            let interim = Response::builder().status(StatusCode::CONTINUE).body(())?;
+            stream.send_response(interim).await?;
+        }
+    }
+
+    // Bodies that do not match the route's schema never reach its handler
+    if let Some(schema) = router.schema_for(&path)
+        && matches!(method, Method::POST | Method::PUT | Method::PATCH)
+    {
+        match schema.check(&mut stream).await? {
+            Ok(body) => {
+                req.extensions_mut().insert(JsonBody(body));
+            }
+            Err(rejection) => {
+                debug!("Rejected {} body: {}", path, rejection.problem);
+                entry.status = Some(rejection.status.as_u16());
+                return send_problem(stream, &method, rejection.status, &rejection.problem).await;
+            }
+        }
+    }
+
+    // Nor do bodies a typed JSON route cannot deserialize
+    if let Some(check) = router.json_check(&path)
+        && matches!(method, Method::POST | Method::PUT | Method::PATCH)
+    {
+        if req.extensions().get::<JsonBody>().is_none() {
+            match schema::read_json(&mut stream, schema::DEFAULT_MAX_BODY).await? {
+                Ok(body) => {
+                    req.extensions_mut().insert(JsonBody(body));
+                }
+                Err(rejection) => {
+                    debug!("Rejected {} body: {}", path, rejection.problem);
+                    entry.status = Some(rejection.status.as_u16());
+                    let (status, problem) = (rejection.status, &rejection.problem);
+                    return send_problem(stream, &method, status, problem).await;
+                }
+            }
+        }
+        if let Some(JsonBody(body)) = req.extensions().get::<JsonBody>()
+            && let Err(e) = check(body)
+        {
+            debug!("Rejected {} body: {}", path, e);
+            entry.status = Some(StatusCode::BAD_REQUEST.as_u16());
+            let detail = format!("Request body is not valid: {}", e);
+            let problem = schema::problem(StatusCode::BAD_REQUEST, &detail);
+            return send_problem(stream, &method, StatusCode::BAD_REQUEST, &problem).await;
+        }
+    }
+
+    // Reading a validated body may have used up the deadline
+    if deadline.is_some_and(|deadline| deadline.is_expired()) && router.get(&path).is_some() {
+        entry.status = Some(StatusCode::GATEWAY_TIMEOUT.as_u16());
+        let message = shared.localize(&req, Message::DeadlineExceeded, &[]);
+        return send_error(stream, &method, StatusCode::GATEWAY_TIMEOUT, &message).await;
+    }
+
     match router.get(&path) {
         Some(Handler::Rest(handler)) => {
-            handle_rest_request(req, stream, handler).await?;
+            handle_rest_request(req, stream, handler, router, shared, entry).await?;
+        }
+        Some(Handler::Stream(_)) if method == Method::HEAD => {
+            // A streamed body has no length to report, and running the
+            // handler would send it; only the status is known up front
+            entry.status = Some(StatusCode::OK.as_u16());
+            let response = Response::builder().status(StatusCode::OK).body(())?;
+            stream.send_response(response).await?;
+            stream.finish().await?;
         }
         Some(Handler::Stream(handler)) => {
+            // Handlers find how to shape their response in the extensions
+            match shared.live.shaping(req.uri()) {
+                Ok(shaping) => {
+                    req.extensions_mut().insert(shaping);
+                }
+                Err(e) => {
+                    entry.status = Some(StatusCode::BAD_REQUEST.as_u16());
+                    let problem = schema::problem(StatusCode::BAD_REQUEST, &e.to_string());
+                    return send_problem(stream, &method, StatusCode::BAD_REQUEST, &problem).await;
+                }
+            }
             // Stream handler takes ownership and manages the stream
-            handler(req, stream).await?;
+            run_cancellable(&ctx, handler(req, stream), &method, &path).await?;
+        }
+        Some(Handler::Rpc(dispatcher)) => {
+            let serve = Arc::clone(dispatcher).serve_request(stream);
+            run_cancellable(&ctx, serve, &method, &path).await?;
         }
         None => {
-            handle_not_found(stream).await?;
+            entry.status = Some(StatusCode::NOT_FOUND.as_u16());
+            let message = shared.localize(&req, Message::NotFound, &[]);
+            send_error(stream, &method, StatusCode::NOT_FOUND, &message).await?;
         }
     }
 
     Ok(())
 }
 
+/// The `content-length` a request declares, if valid.
+fn content_length<B>(req: &Request<B>) -> Option<u64> {
+    req.headers().get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Record a request turned away by API key checks.
+fn deny(shared: &Shared, entry: &mut AccessLog, status: StatusCode) {
+    entry.status = Some(status.as_u16());
+    shared.sinks.audit.audit(&AuditEvent::AccessDenied {
+        remote: entry.remote,
+        path: entry.path.clone(),
+        status: status.as_u16(),
+    });
+}
+
 async fn handle_rest_request(
     req: Request<()>,
     mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
     handler: &crate::router::BoxedRestHandler,
+    router: &Router,
+    shared: &Shared,
+    entry: &mut AccessLog,
 ) -> anyhow::Result<()> {
-    let resp = handler(req).await;
+    let origin = allowed_origin(&req, shared);
+    let ctx = RequestContext::of(&req);
+    let deadline = ctx.deadline();
+    let path = req.uri().path().to_string();
+    // Worded up front, since the handler takes the request
+    let exceeded = deadline.map(|_| shared.localize(&req, Message::DeadlineExceeded, &[]));
+    let accept_language = req.headers().get(ACCEPT_LANGUAGE).cloned();
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header("content-type", resp.content_type)
-        .header("content-length", resp.body.len())
-        .body(())?;
+    // HEAD runs the handler as for GET and sends everything but the body;
+    // routes that do not take GET have answered it with 405 already
+    let mut req = req;
+    let method = req.method().clone();
+    let head = method == Method::HEAD;
+    if head {
+        *req.method_mut() = Method::GET;
+    }
+    // REST handlers are short; a cancelled one gets no grace. One that
+    // panics is answered like one that fails, rather than losing the
+    // request (or, for inline routes, the connection)
+    let started = Instant::now();
+    let handled = AssertUnwindSafe(handler(req)).catch_unwind();
+    let handled = ctx.run(handled, Duration::ZERO).await;
+    entry.handler_time = Some(started.elapsed());
+    let Some(handled) = handled else {
+        return match exceeded {
+            Some(exceeded) if deadline.is_some_and(|d| d.is_expired()) => {
+                entry.status = Some(StatusCode::GATEWAY_TIMEOUT.as_u16());
+                send_error(stream, &method, StatusCode::GATEWAY_TIMEOUT, &exceeded).await
+            }
+            // The client is gone or the server is stopping
+            _ => {
+                debug!("REST request cancelled");
+                entry.status = None;
+                Ok(())
+            }
+        };
+    };
+    let resp = match handled {
+        Ok(Ok(resp)) => Some(resp),
+        Ok(Err(e)) => failed(&path, &e, router),
+        Err(panic) => {
+            let e = anyhow::anyhow!("handler panicked: {}", panic_message(&*panic));
+            failed(&path, &e, router)
+        }
+    };
+    let Some(resp) = resp else {
+        entry.status = Some(StatusCode::INTERNAL_SERVER_ERROR.as_u16());
+        let accept_language = accept_language.as_ref().and_then(|value| value.to_str().ok());
+        let message = shared.catalog.localize(Message::InternalError, accept_language, &[]);
+        return send_error(stream, &method, StatusCode::INTERNAL_SERVER_ERROR, &message).await;
+    };
+
+    entry.status = Some(resp.status.as_u16());
+    // 204 and 304 responses have no content, and say nothing of its length
+    let bodiless = matches!(resp.status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+    let mut response = Response::new(());
+    *response.status_mut() = resp.status;
+    *response.headers_mut() = resp.headers;
+    let headers = response.headers_mut();
+    if !bodiless {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(resp.body.len()));
+    }
+    if let Some(origin) = origin {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.append(VARY, HeaderValue::from_static("origin"));
+    }
+    if let Some(deadline) = deadline {
+        headers.insert(BUDGET_REMAINING, deadline.header_value());
+    }
 
     stream.send_response(response).await?;
-    stream.send_data(Bytes::from(resp.body)).await?;
+    if !head && !bodiless {
+        entry.response_bytes = Some(resp.body.len() as u64);
+        stream.send_data(Bytes::from(resp.body)).await?;
+    } else {
+        entry.response_bytes = Some(0);
+    }
     stream.finish().await?;
 
     Ok(())
 }
 
-async fn handle_not_found(
+/// Log a REST handler failing with `e`, and answer it with `router`'s
+/// error handler, if it has one.
+fn failed(path: &str, e: &anyhow::Error, router: &Router) -> Option<RestResponse> {
+    error!("Handler for {} failed: {:#}", path, e);
+    router.error_response(e)
+}
+
+/// Run a stream or JSON-RPC handler until it ends, or until its request is
+/// cancelled and [`CANCEL_GRACE`] has passed. A handler that fails cancels
+/// the request, for the tasks it spawned.
+async fn run_cancellable(
+    ctx: &RequestContext,
+    handler: impl Future<Output = anyhow::Result<()>>,
+    method: &Method,
+    path: &str,
+) -> anyhow::Result<()> {
+    match ctx.run(handler, CANCEL_GRACE).await {
+        Some(Ok(())) => Ok(()),
+        Some(Err(e)) => {
+            ctx.cancel();
+            Err(e)
+        }
+        None => {
+            debug!("{} {} cancelled", method, path);
+            Ok(())
+        }
+    }
+}
+
+/// The request's `origin`, if cross-origin requests from it are allowed.
+fn allowed_origin(req: &Request<()>, shared: &Shared) -> Option<HeaderValue> {
+    let origin = req.headers().get(ORIGIN)?;
+    shared
+        .live
+        .allows_origin(origin.to_str().ok()?)
+        .then(|| origin.clone())
+}
+
+/// Answer an `OPTIONS` request with the methods in `allowed`. A CORS
+/// preflight from an allowed origin, for one of those methods, is allowed
+/// along with the headers it asks for.
+async fn send_options(
     mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    req: &Request<()>,
+    allowed: &[Method],
+    shared: &Shared,
 ) -> anyhow::Result<()> {
-    let body = r#"{"error": "Not Found"}"#;
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, &allow);
+
+    let requested = req
+        .headers()
+        .get(ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+    if let (Some(origin), Some(requested)) = (allowed_origin(req, shared), requested) {
+        if allowed.contains(&requested) {
+            response = response
+                .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(ACCESS_CONTROL_ALLOW_METHODS, &allow)
+                .header(ACCESS_CONTROL_MAX_AGE, CORS_MAX_AGE.as_secs());
+            if let Some(headers) = req.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+                response = response.header(ACCESS_CONTROL_ALLOW_HEADERS, headers);
+            }
+        } else {
+            debug!("Refusing CORS preflight for {} {}", requested, req.uri().path());
+        }
+    }
+    let response = response.header(VARY, "origin").body(())?;
 
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Respond with 405 Method Not Allowed and no body, listing `allowed`.
+async fn send_method_not_allowed(
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    allowed: &[Method],
+) -> anyhow::Result<()> {
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
     let response = Response::builder()
-        .status(StatusCode::NOT_FOUND)
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(ALLOW, allow)
+        .body(())?;
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+async fn send_error(
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    method: &Method,
+    status: StatusCode,
+    message: &Localized,
+) -> anyhow::Result<()> {
+    let body = serde_json::json!({ "error": message.text }).to_string();
+
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .header("content-language", &message.language)
+        .header("vary", "accept-language")
+        .body(())?;
+
+    send_with_body(stream, method, response, body).await
+}
+
+/// Respond with an RFC 9457 problem document.
+async fn send_problem(
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    method: &Method,
+    status: StatusCode,
+    problem: &serde_json::Value,
+) -> anyhow::Result<()> {
+    let body = problem.to_string();
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "application/problem+json")
+        .header("content-length", body.len())
+        .body(())?;
+
+    send_with_body(stream, method, response, body).await
+}
+
+/// Respond 429, telling the client to retry after `retry_after` (rounded up
+/// to whole seconds).
+async fn send_rate_limited(
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    method: &Method,
+    retry_after: Duration,
+    message: &Localized,
+) -> anyhow::Result<()> {
+    let body = serde_json::json!({ "error": message.text }).to_string();
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+
+    let response = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
         .header("content-type", "application/json")
         .header("content-length", body.len())
+        .header("content-language", &message.language)
+        .header("vary", "accept-language")
+        .header("retry-after", retry_after)
         .body(())?;
 
+    send_with_body(stream, method, response, body).await
+}
+
+/// Respond 429 with the quota's `RateLimit-*` headers.
+async fn send_quota_exceeded(
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    method: &Method,
+    exceeded: &QuotaExceeded,
+    message: &Localized,
+) -> anyhow::Result<()> {
+    let body = serde_json::json!({
+        "error": message.text,
+        "reset_at": exceeded.reset_at.timestamp(),
+    })
+    .to_string();
+
+    let mut response = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .header("content-language", &message.language)
+        .header("vary", "accept-language");
+    for (name, value) in exceeded.headers() {
+        response = response.header(name, value);
+    }
+
+    send_with_body(stream, method, response.body(())?, body).await
+}
+
+/// Send `response`, then `body` unless it answers a HEAD request, which
+/// only gets the headers.
+async fn send_with_body(
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    method: &Method,
+    response: Response<()>,
+    body: String,
+) -> anyhow::Result<()> {
     stream.send_response(response).await?;
-    stream.send_data(Bytes::from(body)).await?;
+    if method != Method::HEAD {
+        stream.send_data(Bytes::from(body)).await?;
+    }
     stream.finish().await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Buf;
+    use http::header::{CONTENT_LANGUAGE, CONTENT_TYPE, LOCATION};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use std::net::SocketAddr;
+
+    /// Serve `router` on a free loopback port until the returned handle
+    /// shuts it down.
+    fn start(router: Router) -> (SocketAddr, Shutdown) {
+        serve(bind(router))
+    }
+
+    fn bind(router: Router) -> Server {
+        // As main does; other tests may have installed it already
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let config = ServerConfig::new("127.0.0.1:0".parse().unwrap());
+        Server::bind(config, router, Vec::new()).unwrap()
+    }
+
+    fn serve(server: Server) -> (SocketAddr, Shutdown) {
+        let addr = server.local_addr();
+        let shutdown = server.shutdown_handle();
+        tokio::spawn(server.serve());
+        (addr, shutdown)
+    }
+
+    fn uri(addr: SocketAddr, path: &str) -> String {
+        format!("https://localhost:{}{}", addr.port(), path)
+    }
+
+    /// Send `method path` with `body` to the server at `addr`, returning
+    /// the response and its body.
+    async fn request(
+        addr: SocketAddr,
+        method: Method,
+        path: &str,
+        body: Option<&str>,
+    ) -> (Response<()>, Vec<u8>) {
+        let req = Request::builder().method(method).uri(uri(addr, path)).body(()).unwrap();
+        send(addr, req, body).await
+    }
+
+    async fn send(
+        addr: SocketAddr,
+        req: Request<()>,
+        body: Option<&str>,
+    ) -> (Response<()>, Vec<u8>) {
+        let provider = common::tls::crypto_provider(false);
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(common::tls::insecure_verifier())
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let client = QuicClientConfig::try_from(tls).unwrap();
+        let mut endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client)));
+
+        let conn = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+        let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(conn))
+            .await
+            .unwrap();
+        tokio::spawn(async move { futures::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let mut stream = sender.send_request(req).await.unwrap();
+        if let Some(body) = body {
+            stream.send_data(Bytes::from(body.to_string())).await.unwrap();
+        }
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        let mut received = Vec::new();
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            received.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        (response, received)
+    }
+
+    #[tokio::test]
+    async fn head_errors_have_no_body() {
+        let (addr, shutdown) = start(Router::new());
+        let (response, body) = request(addr, Method::HEAD, "/missing", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers().contains_key(CONTENT_LENGTH));
+        assert!(body.is_empty());
+
+        let (response, body) = request(addr, Method::GET, "/missing", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!body.is_empty());
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn reads_json_bodies_of_body_methods_only() {
+        use crate::router::Json;
+        use serde_json::{json, Value};
+
+        #[derive(serde::Deserialize)]
+        struct Item {
+            name: String,
+        }
+
+        let router = Router::new()
+            .json("/items", |_req, Json(item): Json<Option<Value>>| async move {
+                Json(json!({ "item": item }))
+            })
+            .methods("/items", &[Method::GET, Method::POST, Method::DELETE])
+            .json("/named", |_req, Json(item): Json<Item>| async move {
+                Json(json!({ "name": item.name }))
+            })
+            .methods("/named", &[Method::POST, Method::DELETE]);
+        let (addr, shutdown) = start(router);
+
+        let (response, body) = request(addr, Method::GET, "/items", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, br#"{"item":null}"#);
+        let (response, _) = request(addr, Method::DELETE, "/items", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (response, body) = request(addr, Method::POST, "/items", Some(r#"{"a":1}"#)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, br#"{"item":{"a":1}}"#);
+        let (response, _) = request(addr, Method::POST, "/items", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Without a body to read, a type that needs one is a bad request
+        let (response, _) = request(addr, Method::DELETE, "/named", None).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_fallback_and_error_handler() {
+        use serde_json::Value;
+
+        let router = Router::new()
+            .route("/", crate::handlers::api_root)
+            .route("/fails", |_req| async {
+                anyhow::Result::<RestResponse>::Err(anyhow::anyhow!("broken"))
+            })
+            .fallback(crate::handlers::api_not_found)
+            .error_handler(crate::handlers::api_error);
+        let (addr, shutdown) = start(router);
+
+        let (response, body) = request(addr, Method::GET, "/missing", None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["path"], "/missing");
+
+        let (response, body) = request(addr, Method::GET, "/fails", None).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let problem: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["detail"], "the request failed; see the server log");
+
+        let (response, body) = request(addr, Method::GET, "/", None).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "/api/info");
+        assert!(body.is_empty());
+        shutdown.shutdown();
+    }
+
+    #[tokio::test]
+    async fn words_errors_with_the_catalog() {
+        let (addr, shutdown) = serve(bind(Router::new()).with_catalog(crate::catalog()));
+        let req = Request::builder()
+            .uri(uri(addr, "/missing"))
+            .header(ACCEPT_LANGUAGE, "pt-BR, en;q=0.5")
+            .body(())
+            .unwrap();
+        let (response, body) = send(addr, req, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_LANGUAGE], "pt");
+        assert_eq!(body, r#"{"error":"Não encontrado"}"#.as_bytes());
+        shutdown.shutdown();
+    }
+}
@@ -0,0 +1,527 @@
+//! Registry of live WebTransport sessions.
+//!
+//! Sessions register when they are established and unregister when they
+//! end, so code outside a session (e.g. a REST handler) can push messages
+//! to one session or all of them.
+//!
+//! A session opened at a `/room/<name>` path is in that room. Admins can
+//! kick a session, which closes it with [`CloseCode::Kicked`], or ban it,
+//! which also refuses new sessions from its address for a while.
+//!
+//! Room members have a display name (`guest-<id>` until they set one) and
+//! are sent presence events: the roster when they join or anyone in the
+//! room is renamed, and who joins and leaves. Their ephemeral signals (see
+//! [`common::signal`]) are relayed to the rest of the room as datagrams.
+//! Messages they post and the public keys they publish are relayed as
+//! they are: sealed messages (see `common::e2e`) cannot be read here.
+//!
+//! Posted messages are numbered and kept in a bounded in-memory log. The
+//! sender gets an `ACK` once a message is stored, then a `RECEIPT` as it
+//! is delivered to and read by each recipient. Joiners are sent the
+//! latest messages posted to the whole room.
+//!
+//! Each room also has a shared document, kept in sync with binary diffs
+//! (see [`crate::sync`]).
+//!
+//! Session IDs are local to a server instance; with a backplane, messages
+//! pushed to every session reach the sessions of the other instances too
+//! (see [`crate::backplane`]).
+
+use crate::backplane::{Payload, Relay};
+use crate::sync::Documents;
+use common::control::clean_name;
+use common::{CloseCode, ControlMessage, Member, ReceiptStatus, Signal};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+/// Number of pushed messages buffered per session.
+const NOTIFY_CAPACITY: usize = 16;
+
+/// Number of room events (presence, keys and messages) buffered per
+/// session. Events past it are dropped; for presence, the next roster
+/// catches the session up.
+const ROOM_EVENT_CAPACITY: usize = 64;
+
+/// Number of room messages kept, across all rooms. Receipts for older
+/// messages are dropped.
+const MESSAGE_LOG_CAPACITY: usize = 1024;
+
+/// Number of the latest messages to the whole room sent to a joiner.
+const REPLAY_COUNT: usize = 20;
+
+/// Longest an address can be banned for; longer bans are cut to it.
+pub const MAX_BAN: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// How a pushed message is delivered to the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delivery {
+    /// As a single datagram; may be lost.
+    Datagram,
+    /// As a `NOTIFY` control message on a new unidirectional stream.
+    #[default]
+    Stream,
+}
+
+/// A message pushed to a session.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub message: String,
+    pub delivery: Delivery,
+}
+
+/// Why a session is being closed by an admin.
+#[derive(Debug)]
+pub struct Kick {
+    /// [`CloseCode::Kicked`] or [`CloseCode::Banned`].
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// A live session, as listed for admins.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub id: u64,
+    pub room: Option<String>,
+    pub name: String,
+    pub remote: IpAddr,
+}
+
+/// A registered session.
+struct Entry {
+    notify: mpsc::Sender<Notification>,
+    events: mpsc::Sender<ControlMessage>,
+    room: Option<String>,
+    name: String,
+    /// Published with `PUBKEY`, in hex.
+    public_key: Option<String>,
+    remote: IpAddr,
+    /// Taken by the first kick.
+    kick: Option<oneshot::Sender<Kick>>,
+}
+
+/// A message posted to a room.
+struct StoredMessage {
+    msg: u64,
+    room: String,
+    from: u64,
+    /// `None` if posted to the whole room.
+    to: Option<u64>,
+    body: String,
+}
+
+/// The latest room messages, oldest first.
+#[derive(Default)]
+struct MessageLog {
+    last_msg: u64,
+    messages: VecDeque<StoredMessage>,
+}
+
+/// Live sessions, shared by the server and handlers.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<u64, Entry>>>,
+    next_id: Arc<AtomicU64>,
+    /// Banned addresses, with when the ban ends.
+    bans: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+    /// Locked after `sessions` when both are needed.
+    messages: Arc<Mutex<MessageLog>>,
+    documents: Documents,
+    relay: Relay,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a session from `remote`, in `room` if it has one. It stays
+    /// registered until the returned [`Registration`] is dropped; pushed
+    /// messages arrive on the first receiver and room events on the
+    /// second.
+    pub fn register(
+        &self,
+        room: Option<String>,
+        remote: IpAddr,
+    ) -> (
+        Registration,
+        mpsc::Receiver<Notification>,
+        mpsc::Receiver<ControlMessage>,
+    ) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (notify, rx) = mpsc::channel(NOTIFY_CAPACITY);
+        let (events, events_rx) = mpsc::channel(ROOM_EVENT_CAPACITY);
+        let (kick, kicked) = oneshot::channel();
+        let entry = Entry {
+            notify,
+            events,
+            room: room.clone(),
+            name: format!("guest-{}", id),
+            public_key: None,
+            remote,
+            kick: Some(kick),
+        };
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(id, entry);
+        if let Some(room) = &room {
+            let joined = ControlMessage::Joined(member(id, &sessions[&id]));
+            broadcast(&sessions, room, Some(id), &joined);
+            send_roster(&sessions, room, Some(id));
+            send_public_keys(&sessions, room, id);
+            let log = self.messages.lock().unwrap();
+            replay(&log, &sessions[&id], room);
+        }
+        drop(sessions);
+
+        let registration = Registration {
+            id,
+            sessions: self.clone(),
+            kicked,
+        };
+        (registration, rx, events_rx)
+    }
+
+    /// Set session `id`'s display name (see [`clean_name`]) and send its
+    /// room the new roster. Returns the name set, or `None` if the name is
+    /// empty or the session is gone.
+    pub fn set_name(&self, id: u64, name: &str) -> Option<String> {
+        let name = clean_name(name)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get_mut(&id)?;
+        entry.name = name.clone();
+        if let Some(room) = entry.room.clone() {
+            send_roster(&sessions, &room, None);
+        }
+        Some(name)
+    }
+
+    /// Push a message to session `id`, or to every session if `None`,
+    /// including those on other instances.
+    ///
+    /// Returns the number of sessions on this instance the message was
+    /// queued for; sessions whose buffer is full are skipped.
+    pub fn notify(&self, id: Option<u64>, notification: Notification) -> usize {
+        if id.is_none() {
+            self.relay.send(Payload::Notify {
+                message: notification.message.clone(),
+                delivery: notification.delivery,
+            });
+        }
+        self.notify_local(id, notification)
+    }
+
+    /// Push a message to session `id`, or to every session on this instance
+    /// if `None`.
+    pub fn notify_local(&self, id: Option<u64>, notification: Notification) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let targets: Vec<&Entry> = match id {
+            Some(id) => sessions.get(&id).into_iter().collect(),
+            None => sessions.values().collect(),
+        };
+        targets
+            .into_iter()
+            .filter(|entry| entry.notify.try_send(notification.clone()).is_ok())
+            .count()
+    }
+
+    /// Where messages pushed to every session go to reach other instances.
+    pub fn relay(&self) -> &Relay {
+        &self.relay
+    }
+
+    /// The rooms' shared documents.
+    pub fn documents(&self) -> &Documents {
+        &self.documents
+    }
+
+    /// Whether session `id` is registered.
+    pub fn contains(&self, id: u64) -> bool {
+        self.sessions.lock().unwrap().contains_key(&id)
+    }
+
+    /// Publish session `id`'s public key (64 hex digits) to its room.
+    /// Returns whether the session is in a room and the key looks valid.
+    pub fn set_public_key(&self, id: u64, key: &str) -> bool {
+        if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+            return false;
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(entry) = sessions.get_mut(&id) else {
+            return false;
+        };
+        entry.public_key = Some(key.to_string());
+        let Some(room) = entry.room.clone() else {
+            return false;
+        };
+        let event = ControlMessage::PublicKey {
+            from: id,
+            key: key.to_string(),
+        };
+        broadcast(&sessions, &room, Some(id), &event);
+        true
+    }
+
+    /// Store a message from session `from`, which numbers it `id`, and
+    /// relay it to member `to` of its room, or to every other member. The
+    /// sender is acknowledged with the stored message number and the
+    /// number of sessions it was queued for, which are also returned.
+    /// `None` if the session is not in a room.
+    pub fn say(&self, from: u64, to: Option<u64>, id: u64, body: &str) -> Option<(u64, usize)> {
+        let sessions = self.sessions.lock().unwrap();
+        let sender = sessions.get(&from)?;
+        let room = sender.room.as_deref()?;
+        // Control messages are single lines
+        let body = body.replace(['\r', '\n'], " ");
+
+        let mut log = self.messages.lock().unwrap();
+        log.last_msg += 1;
+        let msg = log.last_msg;
+        if log.messages.len() == MESSAGE_LOG_CAPACITY {
+            log.messages.pop_front();
+        }
+        log.messages.push_back(StoredMessage {
+            msg,
+            room: room.to_string(),
+            from,
+            to,
+            body: body.clone(),
+        });
+        drop(log);
+
+        let said = ControlMessage::Said { from, msg, body };
+        let recipients = sessions
+            .iter()
+            .filter(|(id, entry)| **id != from && entry.room.as_deref() == Some(room))
+            .filter(|(id, _)| to.is_none_or(|to| to == **id))
+            .filter(|(_, entry)| entry.events.try_send(said.clone()).is_ok())
+            .count();
+        let _ = sender.events.try_send(ControlMessage::Ack { id, msg, recipients });
+        Some((msg, recipients))
+    }
+
+    /// Tell the sender of message number `msg` that session `by` got this
+    /// far with it. Returns whether the message is still in the log and was
+    /// sent to `by`.
+    pub fn receipt(&self, msg: u64, by: u64, status: ReceiptStatus) -> bool {
+        let log = self.messages.lock().unwrap();
+        let Some(stored) = log.messages.iter().find(|stored| stored.msg == msg) else {
+            return false;
+        };
+        let (from, room, to) = (stored.from, stored.room.clone(), stored.to);
+        drop(log);
+
+        let sessions = self.sessions.lock().unwrap();
+        let in_room = sessions
+            .get(&by)
+            .is_some_and(|entry| entry.room.as_deref() == Some(room.as_str()));
+        if by == from || !in_room || to.is_some_and(|to| to != by) {
+            return false;
+        }
+        if let Some(sender) = sessions.get(&from) {
+            let _ = sender.events.try_send(ControlMessage::Receipt { msg, by, status });
+        }
+        true
+    }
+
+    /// Relay `signal` from session `from` to the other sessions in its
+    /// room as a datagram. Returns the number of sessions it was queued
+    /// for; like any datagram it may still be lost.
+    pub fn signal(&self, from: u64, mut signal: Signal) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let Some(room) = sessions.get(&from).and_then(|entry| entry.room.as_deref()) else {
+            return 0;
+        };
+        signal.from = from;
+        let notification = Notification {
+            message: signal.encode(),
+            delivery: Delivery::Datagram,
+        };
+        sessions
+            .iter()
+            .filter(|(id, entry)| **id != from && entry.room.as_deref() == Some(room))
+            .filter(|(_, entry)| entry.notify.try_send(notification.clone()).is_ok())
+            .count()
+    }
+
+    /// Every live session on this instance, by ID.
+    pub fn list(&self) -> Vec<SessionSummary> {
+        summarize(&self.sessions.lock().unwrap())
+    }
+
+    /// Like [`Sessions::list`], but `None` instead of waiting if the
+    /// registry is locked, e.g. from a panic hook.
+    pub fn try_list(&self) -> Option<Vec<SessionSummary>> {
+        Some(summarize(&*self.sessions.try_lock().ok()?))
+    }
+
+    /// Close session `id` with [`CloseCode::Kicked`]. Returns whether it
+    /// was live.
+    pub fn kick(&self, id: u64, reason: &str) -> bool {
+        self.send_kick(id, CloseCode::Kicked, reason).is_some()
+    }
+
+    /// Close session `id` with [`CloseCode::Banned`] and refuse sessions
+    /// from its address for `duration`, at most [`MAX_BAN`]. Returns the
+    /// address, if the session was live.
+    pub fn ban(&self, id: u64, reason: &str, duration: Duration) -> Option<IpAddr> {
+        let until = Instant::now() + duration.min(MAX_BAN);
+        let remote = self.send_kick(id, CloseCode::Banned, reason)?;
+        self.bans.lock().unwrap().insert(remote, until);
+        Some(remote)
+    }
+
+    /// Whether sessions from `ip` are refused.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let now = Instant::now();
+        bans.retain(|_, until| *until > now);
+        bans.contains_key(&ip)
+    }
+
+    /// Tell session `id` to close, returning its address.
+    fn send_kick(&self, id: u64, code: CloseCode, reason: &str) -> Option<IpAddr> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get_mut(&id)?;
+        let kick = Kick {
+            code,
+            reason: reason.to_string(),
+        };
+        // A session already kicked is on its way out
+        if let Some(sender) = entry.kick.take() {
+            let _ = sender.send(kick);
+        }
+        Some(entry.remote)
+    }
+}
+
+/// Keeps a session registered; unregisters it when dropped.
+pub struct Registration {
+    id: u64,
+    sessions: Sessions,
+    kicked: oneshot::Receiver<Kick>,
+}
+
+impl Registration {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Resolves when an admin kicks or bans the session.
+    pub async fn kicked(&mut self) -> Kick {
+        match (&mut self.kicked).await {
+            Ok(kick) => kick,
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.sessions.lock().unwrap();
+        if let Some(entry) = sessions.remove(&self.id)
+            && let Some(room) = &entry.room
+        {
+            let left = ControlMessage::Left(member(self.id, &entry));
+            broadcast(&sessions, room, None, &left);
+        }
+    }
+}
+
+fn member(id: u64, entry: &Entry) -> Member {
+    Member {
+        id,
+        name: entry.name.clone(),
+    }
+}
+
+/// Every session in `sessions`, by ID.
+fn summarize(sessions: &HashMap<u64, Entry>) -> Vec<SessionSummary> {
+    let mut list: Vec<SessionSummary> = sessions
+        .iter()
+        .map(|(id, entry)| SessionSummary {
+            id: *id,
+            room: entry.room.clone(),
+            name: entry.name.clone(),
+            remote: entry.remote,
+        })
+        .collect();
+    list.sort_by_key(|summary| summary.id);
+    list
+}
+
+/// Send `event` to the sessions in `room` but `except`.
+fn broadcast(
+    sessions: &HashMap<u64, Entry>,
+    room: &str,
+    except: Option<u64>,
+    event: &ControlMessage,
+) {
+    let members = sessions
+        .iter()
+        .filter(|(id, entry)| entry.room.as_deref() == Some(room) && except != Some(**id));
+    for (_, entry) in members {
+        let _ = entry.events.try_send(event.clone());
+    }
+}
+
+/// Send the roster of `room` to its sessions, or to `only` of them.
+fn send_roster(sessions: &HashMap<u64, Entry>, room: &str, only: Option<u64>) {
+    let mut members: Vec<Member> = sessions
+        .iter()
+        .filter(|(_, entry)| entry.room.as_deref() == Some(room))
+        .map(|(id, entry)| member(*id, entry))
+        .collect();
+    members.sort_by_key(|member| member.id);
+    let roster = ControlMessage::Roster { members };
+    for (id, entry) in sessions {
+        if entry.room.as_deref() == Some(room) && only.is_none_or(|only| only == *id) {
+            let _ = entry.events.try_send(roster.clone());
+        }
+    }
+}
+
+/// Send `recipient`, who just joined `room`, the latest messages posted to
+/// the whole room.
+fn replay(log: &MessageLog, recipient: &Entry, room: &str) {
+    let mut recent: Vec<&StoredMessage> = log
+        .messages
+        .iter()
+        .rev()
+        .filter(|stored| stored.room == room && stored.to.is_none())
+        .take(REPLAY_COUNT)
+        .collect();
+    recent.reverse();
+    for stored in recent {
+        let said = ControlMessage::Said {
+            from: stored.from,
+            msg: stored.msg,
+            body: stored.body.clone(),
+        };
+        let _ = recipient.events.try_send(said);
+    }
+}
+
+/// Send session `to` the public keys published in `room` so far.
+fn send_public_keys(sessions: &HashMap<u64, Entry>, room: &str, to: u64) {
+    let Some(recipient) = sessions.get(&to) else {
+        return;
+    };
+    for (id, entry) in sessions {
+        if let Some(key) = &entry.public_key
+            && *id != to
+            && entry.room.as_deref() == Some(room)
+        {
+            let event = ControlMessage::PublicKey {
+                from: *id,
+                key: key.clone(),
+            };
+            let _ = recipient.events.try_send(event);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Artificial delay, jitter and bandwidth limits on responses.
+//!
+//! For demos, and for testing clients against a slow or uneven link,
+//! streaming routes and WebTransport echoes can be shaped: every chunk is
+//! held back for `delay_ms`, give or take up to `jitter_ms`, and chunks are
+//! paced to at most `bandwidth` bytes per second. Chunks on a stream stay
+//! in order, so jitter never reorders them.
+//!
+//! Shaping is set per path in the config file, and a request can ask for
+//! its own with the same names as query parameters, e.g.
+//! `/stream/time?delay_ms=500&jitter_ms=200&bandwidth=2048`. Values past
+//! [`MAX_DELAY_MS`] or [`MAX_JITTER_MS`], or below [`MIN_BANDWIDTH`], are
+//! refused.
+
+use crate::api_keys::random_bytes;
+use bytes::Bytes;
+use http::{Request, Uri};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest delay, in milliseconds.
+pub const MAX_DELAY_MS: u64 = 10_000;
+
+/// Largest jitter, in milliseconds.
+pub const MAX_JITTER_MS: u64 = 5_000;
+
+/// Lowest bandwidth limit, in bytes per second.
+pub const MIN_BANDWIDTH: u64 = 1024;
+
+/// Time a piece of a chunk takes at the bandwidth limit; larger chunks are
+/// split so they trickle out instead of leaving in bursts.
+const PIECE_TIME: Duration = Duration::from_millis(50);
+
+/// How to shape a response. The default leaves it alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shaping {
+    pub delay_ms: u64,
+    /// The delay varies by up to this much either way.
+    pub jitter_ms: u64,
+    /// Bytes per second; unlimited if unset.
+    pub bandwidth: Option<u64>,
+}
+
+impl Shaping {
+    /// Whether responses are sent as they are.
+    pub fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Fail if a value is out of bounds.
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.delay_ms > MAX_DELAY_MS {
+            anyhow::bail!("delay_ms {} is over {}", self.delay_ms, MAX_DELAY_MS);
+        }
+        if self.jitter_ms > MAX_JITTER_MS {
+            anyhow::bail!("jitter_ms {} is over {}", self.jitter_ms, MAX_JITTER_MS);
+        }
+        if let Some(bandwidth) = self.bandwidth
+            && bandwidth < MIN_BANDWIDTH
+        {
+            anyhow::bail!("bandwidth {} is under {}", bandwidth, MIN_BANDWIDTH);
+        }
+        Ok(())
+    }
+
+    /// This shaping with the `delay_ms`, `jitter_ms` and `bandwidth` query
+    /// parameters of `uri` taking precedence, checked.
+    pub fn with_query(mut self, uri: &Uri) -> anyhow::Result<Self> {
+        let query = uri.query().unwrap_or("");
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            let parse = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| anyhow::anyhow!("invalid {}: {:?}", name, value))
+            };
+            match name.as_ref() {
+                "delay_ms" => self.delay_ms = parse()?,
+                "jitter_ms" => self.jitter_ms = parse()?,
+                "bandwidth" => self.bandwidth = Some(parse()?),
+                _ => {}
+            }
+        }
+        self.check()?;
+        Ok(self)
+    }
+
+    /// The shaping the server picked for `req`, found in its extensions.
+    pub fn of<B>(req: &Request<B>) -> Self {
+        req.extensions().get::<Shaping>().copied().unwrap_or_default()
+    }
+}
+
+/// Paces the chunks written to one stream.
+#[derive(Debug)]
+pub struct Shaper {
+    shaping: Shaping,
+    /// When the bandwidth used so far allows the next piece out.
+    next: Instant,
+}
+
+impl Shaper {
+    pub fn new(shaping: Shaping) -> Self {
+        Self {
+            shaping,
+            next: Instant::now(),
+        }
+    }
+
+    /// Whether chunks are sent as they are.
+    pub fn is_none(&self) -> bool {
+        self.shaping.is_none()
+    }
+
+    /// `data` split into pieces to be paced one by one.
+    pub fn pieces(&self, mut data: Bytes) -> Vec<Bytes> {
+        let Some(bandwidth) = self.shaping.bandwidth else {
+            return vec![data];
+        };
+        let piece = (bandwidth as f64 * PIECE_TIME.as_secs_f64()) as usize;
+        let mut pieces = Vec::new();
+        while data.len() > piece {
+            pieces.push(data.split_to(piece));
+        }
+        pieces.push(data);
+        pieces
+    }
+
+    /// Wait until `len` bytes, ready to send at `ready`, may go out: the
+    /// delay after `ready`, and after the bytes before them have taken
+    /// their time at the bandwidth limit.
+    pub async fn pace(&mut self, ready: Instant, len: usize) {
+        if self.shaping.is_none() {
+            return;
+        }
+        tokio::time::sleep_until((ready + self.delay()).max(self.next)).await;
+        self.next = Instant::now();
+        if let Some(bandwidth) = self.shaping.bandwidth {
+            self.next += Duration::from_secs_f64(len as f64 / bandwidth as f64);
+        }
+    }
+
+    /// The delay, with a random share of the jitter added or taken off.
+    fn delay(&self) -> Duration {
+        let Shaping {
+            delay_ms,
+            jitter_ms,
+            ..
+        } = self.shaping;
+        let offset = match jitter_ms {
+            0 => 0,
+            _ => random_bytes().map_or(0, u64::from_be_bytes) % (2 * jitter_ms + 1),
+        };
+        Duration::from_millis((delay_ms + offset).saturating_sub(jitter_ms))
+    }
+}
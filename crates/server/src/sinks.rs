@@ -0,0 +1,261 @@
+//! Pluggable sinks for access logs, audit events and metrics.
+//!
+//! The server reports what happens through these traits instead of calling
+//! `tracing` directly, so embedders can route events to their own systems
+//! (a log pipeline, a metrics backend, a collector reached over a
+//! WebTransport stream) without depending on how logging is set up. By
+//! default everything goes to `tracing`.
+
+use serde::Serialize;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, trace, warn};
+
+/// One completed HTTP/3 request.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    pub remote: Option<SocketAddr>,
+    pub method: String,
+    pub path: String,
+    /// Response status, when the server sent the response itself; stream
+    /// and RPC handlers send their own.
+    pub status: Option<u16>,
+    /// Id of the API key the request was authenticated with.
+    pub api_key: Option<String>,
+    pub duration: Duration,
+    /// How long the handler took to produce the response, for REST
+    /// handlers; the rest of `duration` went to reading the request and
+    /// sending the response.
+    pub handler_time: Option<Duration>,
+    /// Size of the response body sent, when the server sent the response
+    /// itself.
+    pub response_bytes: Option<u64>,
+    /// Why the request failed after its response was started, e.g. a
+    /// stream handler's error.
+    pub error: Option<String>,
+}
+
+impl AccessLog {
+    /// Whether the request failed: an error status, or an error after it.
+    pub fn failed(&self) -> bool {
+        self.error.is_some() || self.status.is_some_and(|status| status >= 400)
+    }
+}
+
+/// A security-relevant event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    ApiKeyCreated { id: String, name: String },
+    ApiKeyRevoked { id: String },
+    /// A request was rejected by API key checks (401, 403 or 429).
+    AccessDenied {
+        remote: Option<SocketAddr>,
+        path: String,
+        status: u16,
+    },
+}
+
+/// Receives an entry for every completed request.
+pub trait AccessLogSink: Send + Sync {
+    fn record(&self, entry: &AccessLog);
+}
+
+/// Receives audit events.
+pub trait AuditSink: Send + Sync {
+    fn audit(&self, event: &AuditEvent);
+}
+
+/// Receives metric updates. Label values are borrowed for the call only.
+pub trait MetricsSink: Send + Sync {
+    /// Add `value` to a counter.
+    fn counter(&self, name: &'static str, value: u64, labels: &[(&'static str, &str)]);
+
+    /// Record an observation, e.g. a duration in seconds.
+    fn histogram(&self, name: &'static str, value: f64, labels: &[(&'static str, &str)]);
+}
+
+/// Writes everything to `tracing`: access logs at debug level, audit events
+/// at info level and metrics at trace level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingSink;
+
+impl AccessLogSink for TracingSink {
+    fn record(&self, entry: &AccessLog) {
+        let status = entry.status.map_or("-".to_string(), |status| status.to_string());
+        debug!(
+            "{} {} {} {:?} {} ({}){}",
+            entry.remote.map_or("-".to_string(), |remote| remote.to_string()),
+            entry.method,
+            entry.path,
+            entry.duration,
+            status,
+            entry.api_key.as_deref().unwrap_or("-"),
+            entry.error.as_ref().map_or(String::new(), |e| format!(": {}", e))
+        );
+    }
+}
+
+impl AuditSink for TracingSink {
+    fn audit(&self, event: &AuditEvent) {
+        info!("Audit: {:?}", event);
+    }
+}
+
+impl MetricsSink for TracingSink {
+    fn counter(&self, name: &'static str, value: u64, labels: &[(&'static str, &str)]) {
+        trace!("Metric {} {:?} += {}", name, labels, value);
+    }
+
+    fn histogram(&self, name: &'static str, value: f64, labels: &[(&'static str, &str)]) {
+        trace!("Metric {} {:?} observed {}", name, labels, value);
+    }
+}
+
+/// Writes access logs and audit events as JSON lines, e.g. to a file
+/// picked up by a log shipper.
+#[derive(Clone)]
+pub struct JsonLinesSink {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl JsonLinesSink {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out))),
+        }
+    }
+
+    /// Append to the file at `path`, creating it if needed.
+    pub fn append(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("opening {}: {}", path.display(), e))?;
+        Ok(Self::new(file))
+    }
+
+    fn write(&self, kind: &str, mut line: serde_json::Value) {
+        line["type"] = kind.into();
+        line["timestamp"] = chrono::Utc::now().timestamp_millis().into();
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            warn!("Failed to write {} log line: {}", kind, e);
+        }
+    }
+}
+
+impl AccessLogSink for JsonLinesSink {
+    fn record(&self, entry: &AccessLog) {
+        self.write(
+            "access",
+            serde_json::json!({
+                "remote": entry.remote,
+                "method": entry.method,
+                "path": entry.path,
+                "status": entry.status,
+                "api_key": entry.api_key,
+                "duration_ms": entry.duration.as_secs_f64() * 1000.0,
+                "handler_ms": entry.handler_time.map(|time| time.as_secs_f64() * 1000.0),
+                "response_bytes": entry.response_bytes,
+                "error": entry.error,
+            }),
+        );
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn audit(&self, event: &AuditEvent) {
+        match serde_json::to_value(event) {
+            Ok(line) => self.write("audit", line),
+            Err(e) => warn!("Failed to encode audit event: {}", e),
+        }
+    }
+}
+
+/// Passes on only some access log entries, for servers taking too many
+/// requests to log each one: every failed request, every request that took
+/// at least the slow threshold, and one in `every` of the rest.
+pub struct SampledAccessLog {
+    inner: Arc<dyn AccessLogSink>,
+    every: u64,
+    slow: Option<Duration>,
+    /// Entries seen that were neither failed nor slow.
+    seen: AtomicU64,
+}
+
+impl SampledAccessLog {
+    /// Pass on one in `every` successful requests to `inner` (all of them
+    /// if `every` is 0 or 1), and every request taking `slow` or longer.
+    pub fn new(inner: Arc<dyn AccessLogSink>, every: u64, slow: Option<Duration>) -> Self {
+        Self {
+            inner,
+            every: every.max(1),
+            slow,
+            seen: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AccessLogSink for SampledAccessLog {
+    fn record(&self, entry: &AccessLog) {
+        let slow = self.slow.is_some_and(|slow| entry.duration >= slow);
+        let sampled = || self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.every);
+        if entry.failed() || slow || sampled() {
+            self.inner.record(entry);
+        }
+    }
+}
+
+/// The sinks the server reports to.
+#[derive(Clone)]
+pub struct Sinks {
+    pub access: Arc<dyn AccessLogSink>,
+    pub audit: Arc<dyn AuditSink>,
+    pub metrics: Arc<dyn MetricsSink>,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Self {
+            access: Arc::new(TracingSink),
+            audit: Arc::new(TracingSink),
+            metrics: Arc::new(TracingSink),
+        }
+    }
+}
+
+impl Sinks {
+    /// All events to `tracing`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_access_log(mut self, sink: impl AccessLogSink + 'static) -> Self {
+        self.access = Arc::new(sink);
+        self
+    }
+
+    /// Sample the access log, as [`SampledAccessLog`] does; metrics still
+    /// count every request.
+    pub fn with_access_sampling(mut self, every: u64, slow: Option<Duration>) -> Self {
+        self.access = Arc::new(SampledAccessLog::new(self.access, every, slow));
+        self
+    }
+
+    pub fn with_audit(mut self, sink: impl AuditSink + 'static) -> Self {
+        self.audit = Arc::new(sink);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_metrics(mut self, sink: impl MetricsSink + 'static) -> Self {
+        self.metrics = Arc::new(sink);
+        self
+    }
+}
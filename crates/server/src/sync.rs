@@ -0,0 +1,221 @@
+//! Shared documents of rooms, kept in sync with binary diffs.
+//!
+//! Every room has a document, empty until a member edits it. Sessions in
+//! the room are sent it on a sync stream (see [`common::sync`]): a
+//! snapshot, then each change as a patch. Edits arrive as patches against
+//! the version the client had. Those made against an older version are
+//! rebased over the changes since, as long as those are among the last
+//! [`SYNC_CAPACITY`], so members can type at the same time.
+//!
+//! A session that falls more than [`SYNC_CAPACITY`] changes behind, or
+//! whose edit conflicts, is sent a fresh snapshot instead. Documents are
+//! kept in memory until the server stops.
+
+use anyhow::Context;
+use common::sync::{self, Diff, Splice, SyncFrame};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Largest document, in bytes; edits that would make it larger are
+/// rejected.
+pub const MAX_DOCUMENT_SIZE: usize = 64 * 1024;
+
+/// Number of changes kept per document, to rebase late edits over and to
+/// buffer for slow sessions.
+pub const SYNC_CAPACITY: usize = 64;
+
+/// A change to a document, or an edit that could not be made.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Applied {
+        version: u64,
+        patch: Arc<[u8]>,
+        /// Session ID of the editor.
+        author: u64,
+    },
+    Rejected {
+        author: u64,
+    },
+}
+
+struct Document {
+    version: u64,
+    data: Vec<u8>,
+    /// Patches of the most recent versions, oldest first.
+    history: VecDeque<(u64, Arc<[u8]>)>,
+    sender: broadcast::Sender<Change>,
+}
+
+impl Document {
+    fn new() -> Self {
+        Self {
+            version: 0,
+            data: Vec::new(),
+            history: VecDeque::with_capacity(SYNC_CAPACITY),
+            sender: broadcast::channel(SYNC_CAPACITY).0,
+        }
+    }
+
+    fn snapshot(&self) -> SyncFrame {
+        SyncFrame::Snapshot {
+            version: self.version,
+            data: self.data.clone(),
+        }
+    }
+}
+
+/// Documents by room, shared by sessions.
+#[derive(Clone)]
+pub struct Documents {
+    documents: Arc<Mutex<HashMap<String, Document>>>,
+    diff: Arc<dyn Diff>,
+}
+
+impl Default for Documents {
+    fn default() -> Self {
+        Self::new(Arc::new(Splice))
+    }
+}
+
+impl Documents {
+    /// Documents whose patches are made and applied by `diff`.
+    pub fn new(diff: Arc<dyn Diff>) -> Self {
+        Self {
+            documents: Arc::new(Mutex::new(HashMap::new())),
+            diff,
+        }
+    }
+
+    /// `room`'s document now, and its changes from here on.
+    pub fn subscribe(&self, room: &str) -> (SyncFrame, broadcast::Receiver<Change>) {
+        let mut documents = self.documents.lock().unwrap();
+        let document = documents.entry(room.to_string()).or_insert_with(Document::new);
+        (document.snapshot(), document.sender.subscribe())
+    }
+
+    /// `room`'s document now.
+    pub fn snapshot(&self, room: &str) -> SyncFrame {
+        let mut documents = self.documents.lock().unwrap();
+        documents.entry(room.to_string()).or_insert_with(Document::new).snapshot()
+    }
+
+    /// Apply `patch`, made by session `author` against version `base`, to
+    /// `room`'s document, and send the change to the room. Returns the new
+    /// version. If the patch does not apply, the author is sent a snapshot.
+    pub fn edit(&self, room: &str, author: u64, base: u64, patch: &[u8]) -> anyhow::Result<u64> {
+        let mut documents = self.documents.lock().unwrap();
+        let document = documents.entry(room.to_string()).or_insert_with(Document::new);
+        let (data, patch) = match self.rebase_and_apply(document, base, patch) {
+            Ok(result) => result,
+            Err(e) => {
+                let _ = document.sender.send(Change::Rejected { author });
+                return Err(e);
+            }
+        };
+
+        document.version += 1;
+        document.data = data;
+        let patch: Arc<[u8]> = patch.into();
+        if document.history.len() == SYNC_CAPACITY {
+            document.history.pop_front();
+        }
+        document.history.push_back((document.version, Arc::clone(&patch)));
+        let _ = document.sender.send(Change::Applied {
+            version: document.version,
+            patch,
+            author,
+        });
+        Ok(document.version)
+    }
+
+    /// The document after `patch`, and the patch rebased onto the current
+    /// version.
+    fn rebase_and_apply(
+        &self,
+        document: &Document,
+        base: u64,
+        patch: &[u8],
+    ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        if base > document.version {
+            anyhow::bail!("version {} is ahead of {}", base, document.version);
+        }
+        let oldest = document.version - document.history.len() as u64;
+        if base < oldest {
+            anyhow::bail!("version {} is too old to rebase", base);
+        }
+
+        let mut patch = patch.to_vec();
+        for (version, applied) in document.history.iter().filter(|(v, _)| *v > base) {
+            patch = self
+                .diff
+                .rebase(&patch, applied)
+                .ok_or_else(|| anyhow::anyhow!("conflicts with version {}", version))?;
+        }
+        let data = self
+            .diff
+            .apply(&document.data, &patch)
+            .context("patch does not apply")?;
+        if data.len() > MAX_DOCUMENT_SIZE {
+            anyhow::bail!("document would be {} bytes", data.len());
+        }
+        Ok((data, patch))
+    }
+}
+
+/// Send `room`'s document and its changes to session `me` on `stream`
+/// until `stop` fires (or its sender is dropped) or the stream fails.
+pub async fn forward<S>(
+    room: String,
+    me: u64,
+    documents: Documents,
+    mut stream: S,
+    mut stop: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&sync::encode_header(&room)).await?;
+    let (snapshot, mut changes) = documents.subscribe(&room);
+    let mut version = match &snapshot {
+        SyncFrame::Snapshot { version, .. } => *version,
+        _ => 0,
+    };
+    stream.write_all(&snapshot.encode()).await?;
+
+    loop {
+        let frame = tokio::select! {
+            _ = &mut stop => break,
+            change = changes.recv() => match change {
+                // Already in the last snapshot sent
+                Ok(Change::Applied { version: v, .. }) if v <= version => continue,
+                Ok(Change::Applied { version: v, patch, author }) => {
+                    version = v;
+                    let patch = patch.to_vec();
+                    match author == me {
+                        true => SyncFrame::Applied { version: v, patch },
+                        false => SyncFrame::Patch { version: v, patch },
+                    }
+                }
+                Ok(Change::Rejected { author }) if author == me => documents.snapshot(&room),
+                Ok(Change::Rejected { .. }) => continue,
+                Err(RecvError::Lagged(n)) => {
+                    debug!("Session {} fell {} changes behind on {}", me, n, room);
+                    documents.snapshot(&room)
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if let SyncFrame::Snapshot { version: v, .. } = &frame {
+            version = *v;
+        }
+        // Waits for flow control credit; changes queue up meanwhile
+        stream.write_all(&frame.encode()).await?;
+    }
+
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! systemd integration: socket activation and readiness notification.
+//!
+//! With socket activation systemd binds the UDP socket and passes it to the
+//! server (`LISTEN_FDS`), so the server can run without the privileges the
+//! port needs and restarts do not drop packets. With `Type=notify` the
+//! server reports when it is ready to accept connections (`NOTIFY_SOCKET`).
+//! Both are no-ops when the server is not started by systemd.
+
+use std::net::UdpSocket;
+use tracing::{debug, info};
+
+/// The first file descriptor passed by systemd.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// The UDP socket passed by systemd socket activation, if any.
+///
+/// Only the first socket is used when several are passed.
+pub fn listen_socket() -> anyhow::Result<Option<UdpSocket>> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd};
+
+        // The variables are meant for this process only, not e.g. a parent
+        let for_us = std::env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            == Some(std::process::id());
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse::<i32>().ok())
+            .unwrap_or(0);
+        if !for_us || count < 1 {
+            return Ok(None);
+        }
+        if count > 1 {
+            debug!("systemd passed {} sockets; using the first", count);
+        }
+
+        // Fails if the descriptor is not open, before anything owns it
+        // SAFETY: only borrowed for the duration of the check
+        unsafe { BorrowedFd::borrow_raw(LISTEN_FDS_START) }
+            .try_clone_to_owned()
+            .map_err(|e| anyhow::anyhow!("LISTEN_FDS set but fd 3 is unusable: {}", e))?;
+
+        // SAFETY: systemd passes open descriptors starting at 3, owned by
+        // this process, and nothing else in the server uses them
+        let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+        let socket = UdpSocket::from(fd);
+        // Fails if the descriptor is not a socket
+        let addr = socket.local_addr()?;
+        info!("Using socket {} passed by systemd", addr);
+        Ok(Some(socket))
+    }
+    #[cfg(not(unix))]
+    Ok(None)
+}
+
+/// Tell systemd the server is ready to accept connections.
+pub fn notify_ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status));
+}
+
+/// Tell systemd the server is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// Send a state update to the service manager, if there is one.
+fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        if let Err(e) = send_notify(&path, state) {
+            debug!("Failed to notify systemd: {}", e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send_notify(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        _ => SocketAddr::from_pathname(path)?,
+    };
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
@@ -0,0 +1,174 @@
+//! Connection and request tasks.
+//!
+//! Every connection and HTTP/3 request runs in a task spawned through
+//! [`Tasks`], so the server can wait for them all when it stops, log tasks
+//! that panic instead of losing them silently, and cap how many run at
+//! once (`ServerConfig::max_tasks`) and how many of them are connections
+//! (`ServerConfig::max_connections`). Each connection also caps its own
+//! requests with a [`Limit`] from [`Tasks::per_connection`]
+//! (`ServerConfig::max_connection_requests`).
+
+use common::config::ServerConfig;
+use futures::FutureExt;
+use serde::Serialize;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::task::TaskTracker;
+use tracing::error;
+
+/// The server's running tasks.
+#[derive(Clone)]
+pub struct Tasks {
+    tracker: TaskTracker,
+    /// Connection and request tasks.
+    tasks: Limit,
+    /// Connection tasks alone.
+    connections: Limit,
+    /// Requests each connection may have running at once.
+    connection_requests: Option<usize>,
+}
+
+/// Room for one more task, from [`Tasks::reserve`].
+pub struct Slot {
+    tracker: TaskTracker,
+    permits: Vec<OwnedSemaphorePermit>,
+}
+
+/// A count of things running at once, capped or not.
+#[derive(Clone)]
+pub struct Limit {
+    /// One permit per thing that may run; as many as Tokio allows if
+    /// uncapped, so they are counted either way.
+    permits: Arc<Semaphore>,
+    max: Option<usize>,
+}
+
+/// Numbers of running tasks, and their caps, for `GET /admin/stats`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStats {
+    pub tasks: usize,
+    pub max_tasks: Option<usize>,
+    pub connections: usize,
+    pub max_connections: Option<usize>,
+    /// Requests running on tasks of their own, on every connection.
+    pub requests: usize,
+    pub max_connection_requests: Option<usize>,
+}
+
+impl Tasks {
+    /// Tasks capped as `config` says.
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            tracker: TaskTracker::new(),
+            tasks: Limit::new(config.max_tasks),
+            connections: Limit::new(config.max_connections),
+            connection_requests: config.max_connection_requests,
+        }
+    }
+
+    /// Reserve room for a task, or `None` if the cap has been reached.
+    pub fn reserve(&self) -> Option<Slot> {
+        Some(Slot {
+            tracker: self.tracker.clone(),
+            permits: vec![self.tasks.try_acquire()?],
+        })
+    }
+
+    /// Reserve room for a connection's task, or `None` if either the
+    /// connection or the task cap has been reached.
+    pub fn reserve_connection(&self) -> Option<Slot> {
+        let connection = self.connections.try_acquire()?;
+        let mut slot = self.reserve()?;
+        slot.permits.push(connection);
+        Some(slot)
+    }
+
+    /// The cap on a new connection's running requests.
+    pub fn per_connection(&self) -> Limit {
+        Limit::new(self.connection_requests)
+    }
+
+    /// Number of tasks still running.
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    pub fn stats(&self) -> TaskStats {
+        let tasks = self.tasks.running();
+        let connections = self.connections.running();
+        TaskStats {
+            tasks,
+            max_tasks: self.tasks.max,
+            connections,
+            max_connections: self.connections.max,
+            requests: tasks.saturating_sub(connections),
+            max_connection_requests: self.connection_requests,
+        }
+    }
+
+    /// Wait for every task to finish. Tasks can still be spawned in the
+    /// meantime, e.g. for requests on connections that are draining.
+    pub async fn wait(&self) {
+        self.tracker.close();
+        self.tracker.wait().await;
+    }
+}
+
+impl Slot {
+    /// Run `task` in the reserved slot; `kind` names it if it panics.
+    pub fn spawn<F>(self, kind: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let permits = self.permits;
+        self.tracker.spawn(async move {
+            // Held until the task ends
+            let _permits = permits;
+            if let Err(panic) = AssertUnwindSafe(task).catch_unwind().await {
+                error!("{} task panicked: {}", kind, panic_message(&*panic));
+            }
+        });
+    }
+}
+
+impl Limit {
+    /// No cap, or at most `max` at a time.
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max.unwrap_or(Semaphore::MAX_PERMITS))),
+            max,
+        }
+    }
+
+    /// A permit to run one more, or `None` if the cap has been reached.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.permits).try_acquire_owned().ok()
+    }
+
+    /// A permit to run one more, once the cap allows it.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    /// Number running now.
+    pub fn running(&self) -> usize {
+        self.max.unwrap_or(Semaphore::MAX_PERMITS) - self.permits.available_permits()
+    }
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "(no message)"
+    }
+}
@@ -0,0 +1,193 @@
+//! Telemetry ingestion.
+//!
+//! WebTransport sessions send samples as datagrams (see
+//! [`common::telemetry`]); they are neither echoed nor acknowledged. Each
+//! metric is aggregated here (count, sum, min, max, mean and the latest
+//! sample), served at `/api/telemetry`, and every sample is also reported
+//! to the metrics sink.
+//!
+//! The last [`TELEMETRY_HISTORY`] samples are kept for clients to backfill
+//! from. A sample of a metric with the same timestamp as one kept is a
+//! duplicate, e.g. sent again by a client reconciling after a backfill,
+//! and is not counted twice.
+
+use crate::sinks::{MetricsSink, TracingSink};
+use common::telemetry::Sample;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Most metrics aggregated; samples of further metrics are dropped.
+pub const MAX_METRICS: usize = 1024;
+
+/// Number of samples kept for backfills, across all metrics.
+pub const TELEMETRY_HISTORY: usize = 10_000;
+
+/// What has been received of one metric.
+#[derive(Debug, Clone, Serialize)]
+pub struct Aggregate {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub last: f64,
+    /// Timestamp of the latest sample, in milliseconds since the Unix
+    /// epoch.
+    pub last_timestamp: u64,
+}
+
+impl Aggregate {
+    fn new(sample: &Sample) -> Self {
+        Self {
+            count: 1,
+            sum: sample.value,
+            min: sample.value,
+            max: sample.value,
+            mean: sample.value,
+            last: sample.value,
+            last_timestamp: sample.timestamp,
+        }
+    }
+
+    fn add(&mut self, sample: &Sample) {
+        self.count += 1;
+        self.sum += sample.value;
+        self.min = self.min.min(sample.value);
+        self.max = self.max.max(sample.value);
+        self.mean = self.sum / self.count as f64;
+        // Datagrams arrive out of order; the latest is by timestamp
+        if sample.timestamp >= self.last_timestamp {
+            self.last = sample.value;
+            self.last_timestamp = sample.timestamp;
+        }
+    }
+}
+
+/// All metrics' aggregates, as served at `/api/telemetry`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub metrics: BTreeMap<String, Aggregate>,
+    /// Samples dropped: malformed, not finite, or past [`MAX_METRICS`].
+    pub dropped: u64,
+    /// Samples received again and ignored.
+    pub duplicates: u64,
+}
+
+/// The samples kept, in arrival order, and which they are.
+#[derive(Default)]
+struct History {
+    samples: VecDeque<Sample>,
+    keys: HashSet<(String, u64)>,
+}
+
+/// Telemetry aggregates, shared by sessions and the handler serving them.
+#[derive(Clone)]
+pub struct Telemetry {
+    metrics: Arc<Mutex<BTreeMap<String, Aggregate>>>,
+    /// Locked after `metrics` when both are needed.
+    history: Arc<Mutex<History>>,
+    dropped: Arc<AtomicU64>,
+    duplicates: Arc<AtomicU64>,
+    sink: Arc<dyn MetricsSink>,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            metrics: Arc::new(Mutex::new(BTreeMap::new())),
+            history: Arc::new(Mutex::new(History::default())),
+            dropped: Arc::new(AtomicU64::new(0)),
+            duplicates: Arc::new(AtomicU64::new(0)),
+            sink: Arc::new(TracingSink),
+        }
+    }
+}
+
+impl Telemetry {
+    /// Aggregate samples; they are reported to `tracing` too.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report every sample to `sink` as `telemetry_samples_total` and
+    /// `telemetry_value`, labelled with the metric name.
+    pub fn with_metrics(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Add samples to their metrics' aggregates, skipping duplicates.
+    /// Returns the number added.
+    pub fn record(&self, samples: &[Sample]) -> usize {
+        let mut metrics = self.metrics.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        let mut added = 0;
+        for sample in samples {
+            if !sample.value.is_finite() {
+                self.drop_samples(1);
+                continue;
+            }
+            let key = (sample.name.clone(), sample.timestamp);
+            if history.keys.contains(&key) {
+                self.duplicates.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let full = metrics.len() >= MAX_METRICS;
+            match metrics.get_mut(&sample.name) {
+                Some(aggregate) => aggregate.add(sample),
+                None if !full => {
+                    metrics.insert(sample.name.clone(), Aggregate::new(sample));
+                }
+                None => {
+                    self.drop_samples(1);
+                    continue;
+                }
+            }
+            if history.samples.len() == TELEMETRY_HISTORY
+                && let Some(oldest) = history.samples.pop_front()
+            {
+                history.keys.remove(&(oldest.name, oldest.timestamp));
+            }
+            history.keys.insert(key);
+            history.samples.push_back(sample.clone());
+
+            let labels = [("metric", sample.name.as_str())];
+            self.sink.counter("telemetry_samples_total", 1, &labels);
+            self.sink.histogram("telemetry_value", sample.value, &labels);
+            added += 1;
+        }
+        added
+    }
+
+    /// Count samples that could not be recorded, e.g. in a malformed
+    /// datagram.
+    pub fn drop_samples(&self, n: u64) {
+        self.dropped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Every metric's aggregate.
+    pub fn report(&self) -> Report {
+        Report {
+            metrics: self.metrics.lock().unwrap().clone(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The samples kept with a timestamp of `since` or later, of `metric`
+    /// or all, oldest first.
+    pub fn since(&self, since: u64, metric: Option<&str>) -> Vec<Sample> {
+        let history = self.history.lock().unwrap();
+        let mut samples: Vec<Sample> = history
+            .samples
+            .iter()
+            .filter(|sample| sample.timestamp >= since)
+            .filter(|sample| metric.is_none_or(|metric| sample.name == metric))
+            .cloned()
+            .collect();
+        samples.sort_by_key(|sample| sample.timestamp);
+        samples
+    }
+}
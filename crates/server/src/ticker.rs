@@ -0,0 +1,47 @@
+//! Building blocks for streaming handlers that send at a steady pace.
+//!
+//! [`ticker`] yields a tick at an interval, and [`stream_until_disconnect`]
+//! ends any stream once its request is cancelled, so a handler pushing
+//! events is a loop over the ticks rather than a hand-rolled sleep loop
+//! that checks for cancellation between sends. Once the loop ends, either
+//! way, the handler can still send a final event and finish the response:
+//!
+//! ```ignore
+//! let ticks = stream_until_disconnect(&req, ticker(Duration::from_secs(1)).take(5));
+//! let mut ticks = std::pin::pin!(ticks);
+//! while let Some(n) = ticks.next().await {
+//!     stream.send(format!("data: {}\n\n", n)).await?;
+//! }
+//! stream.send("event: done\n\n").await?;
+//! ```
+
+use crate::context::RequestContext;
+use futures::{Stream, StreamExt};
+use http::Request;
+use std::time::Duration;
+
+/// Ticks numbered from 1: the first at once, each next one `period` after
+/// the previous was taken, so a slow consumer delays the ticks rather than
+/// getting a burst of them.
+pub fn ticker(period: Duration) -> impl Stream<Item = u64> + Send {
+    futures::stream::unfold(0u64, move |n| async move {
+        if n > 0 {
+            tokio::time::sleep(period).await;
+        }
+        Some((n + 1, n + 1))
+    })
+}
+
+/// `stream`, ended early when `req` is cancelled (see [`RequestContext`]),
+/// e.g. because the client disconnected or the deadline passed. Check
+/// [`RequestContext::is_cancelled`] afterwards to tell which way it ended.
+pub fn stream_until_disconnect<B, S>(
+    req: &Request<B>,
+    stream: S,
+) -> impl Stream<Item = S::Item> + Send
+where
+    S: Stream + Send,
+{
+    let ctx = RequestContext::of(req);
+    stream.take_until(async move { ctx.cancelled().await })
+}
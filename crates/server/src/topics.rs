@@ -0,0 +1,240 @@
+//! Publish/subscribe topics for WebTransport sessions.
+//!
+//! Sessions subscribe to a topic with a `SUBSCRIBE` control message and
+//! receive its events on a dedicated server-initiated unidirectional stream.
+//! Each subscription forwards events independently: a subscriber whose
+//! stream is blocked by flow control falls behind without slowing down
+//! publishers or other subscribers, and once it is more than
+//! [`TOPIC_CAPACITY`] events behind the oldest events are dropped and it is
+//! sent a `LAGGED` frame.
+//!
+//! The last [`TOPIC_CAPACITY`] events of a topic are kept, so a
+//! subscription can resume after the last event a session was sent (see
+//! [`crate::affinity`]).
+//!
+//! With a backplane, published events are relayed to the topics of the
+//! other server instances too (see [`crate::backplane`]). Events keep the
+//! ID they were published with, so a session can resume on any instance.
+
+use crate::backplane::{Payload, Relay};
+use common::TopicFrame;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Number of events buffered per topic for slow subscribers.
+pub const TOPIC_CAPACITY: usize = 64;
+
+/// A published event.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Position in the topic on this instance, starting at 1.
+    pub seq: u64,
+    /// Identifies the event on every instance: its position where it was
+    /// published, followed by `@<instance>` with a backplane.
+    pub id: Arc<str>,
+    pub data: Arc<str>,
+}
+
+/// ID of the last event a subscriber has been sent, if any.
+pub type Cursor = Arc<Mutex<Option<Arc<str>>>>;
+
+/// Registry of topics, shared by publishers and sessions.
+#[derive(Clone, Default)]
+pub struct Topics {
+    topics: Arc<Mutex<HashMap<String, Topic>>>,
+    relay: Relay,
+}
+
+struct Topic {
+    sender: broadcast::Sender<Event>,
+    /// The most recent events, oldest first.
+    history: VecDeque<Event>,
+    /// Sequence number of the last event published.
+    last_seq: u64,
+}
+
+impl Topic {
+    fn new() -> Self {
+        Self {
+            sender: broadcast::channel(TOPIC_CAPACITY).0,
+            history: VecDeque::with_capacity(TOPIC_CAPACITY),
+            last_seq: 0,
+        }
+    }
+}
+
+/// A new subscription to a topic, from [`Topics::subscribe`].
+pub struct Subscription {
+    /// Retained events after the resumed position, to send first.
+    pub backlog: Vec<Event>,
+    /// Events after the resumed position that are no longer retained.
+    pub missed: u64,
+    pub events: broadcast::Receiver<Event>,
+    /// Updated by [`forward`].
+    pub cursor: Cursor,
+}
+
+impl Topics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publish an event to every subscriber of `topic`, on this instance
+    /// and, with a backplane, the others.
+    ///
+    /// Events are single lines; newlines are replaced with spaces. Returns
+    /// the number of subscribers on this instance the event was queued for.
+    pub fn publish(&self, topic: &str, event: &str) -> usize {
+        let data: Arc<str> = event.replace(['\r', '\n'], " ").into();
+        let (delivered, id) = self.push(topic, None, Arc::clone(&data));
+        self.relay.send(Payload::Topic {
+            topic: topic.to_string(),
+            id: id.to_string(),
+            event: data.to_string(),
+        });
+        delivered
+    }
+
+    /// Publish an event relayed from another instance to the subscribers
+    /// of `topic` on this instance.
+    pub fn publish_relayed(&self, topic: &str, id: Arc<str>, event: &str) -> usize {
+        let data = event.replace(['\r', '\n'], " ").into();
+        self.push(topic, Some(id), data).0
+    }
+
+    /// Append an event to `topic`, with a new ID unless it has one, and
+    /// queue it for subscribers.
+    fn push(&self, topic: &str, id: Option<Arc<str>>, data: Arc<str>) -> (usize, Arc<str>) {
+        let mut topics = self.topics.lock().unwrap();
+        let topic = topics.entry(topic.to_string()).or_insert_with(Topic::new);
+        topic.last_seq += 1;
+        let seq = topic.last_seq;
+        let id = id.unwrap_or_else(|| match self.relay.instance() {
+            Some(instance) => format!("{}@{}", seq, instance).into(),
+            None => seq.to_string().into(),
+        });
+        let event = Event {
+            seq,
+            id: Arc::clone(&id),
+            data,
+        };
+        if topic.history.len() == TOPIC_CAPACITY {
+            topic.history.pop_front();
+        }
+        topic.history.push_back(event.clone());
+        (topic.sender.send(event).unwrap_or(0), id)
+    }
+
+    /// Position on this instance of an event published here, from its ID.
+    fn local_seq(&self, id: &str) -> Option<u64> {
+        let (seq, instance) = match id.split_once('@') {
+            Some((seq, instance)) => (seq, Some(instance)),
+            None => (id, None),
+        };
+        (instance == self.relay.instance()).then(|| seq.parse().ok())?
+    }
+
+    /// Subscribe to `topic`, creating it if needed.
+    ///
+    /// With `after` (the ID of the last event the subscriber saw), the
+    /// subscription starts right after it: retained events since then make
+    /// up the backlog. If that event is not retained here, all retained
+    /// events do. Otherwise it starts with the next event published.
+    pub fn subscribe(&self, topic: &str, after: Option<&str>) -> Subscription {
+        let mut topics = self.topics.lock().unwrap();
+        let topic = topics.entry(topic.to_string()).or_insert_with(Topic::new);
+
+        let (backlog, missed) = match after {
+            Some(id) => match topic.history.iter().position(|e| &*e.id == id) {
+                Some(i) => (topic.history.iter().skip(i + 1).cloned().collect(), 0),
+                // Dropped from the history, or published before this
+                // instance saw the topic; only the former can be counted
+                None => {
+                    let oldest = topic.history.front().map_or(topic.last_seq + 1, |e| e.seq);
+                    let missed = self
+                        .local_seq(id)
+                        .filter(|&seq| seq < oldest)
+                        .map_or(0, |seq| oldest - seq - 1);
+                    (topic.history.iter().cloned().collect(), missed)
+                }
+            },
+            None => (Vec::new(), 0),
+        };
+        let cursor = match after {
+            Some(id) => Some(id.into()),
+            None => topic.history.back().map(|e| Arc::clone(&e.id)),
+        };
+        Subscription {
+            backlog,
+            missed,
+            events: topic.sender.subscribe(),
+            cursor: Arc::new(Mutex::new(cursor)),
+        }
+    }
+
+    /// Where published events go to reach other instances.
+    pub fn relay(&self) -> &Relay {
+        &self.relay
+    }
+}
+
+/// Forward a topic's events to a subscription stream until `stop` fires (or
+/// its sender is dropped), the topic closes or the stream fails.
+///
+/// The backlog of a resumed subscription is sent first. The stream is
+/// finished cleanly when the subscription ends.
+pub async fn forward<S>(
+    topic: String,
+    subscription: Subscription,
+    mut stream: S,
+    mut stop: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let Subscription {
+        backlog,
+        missed,
+        mut events,
+        cursor,
+    } = subscription;
+
+    stream
+        .write_all(TopicFrame::Header { topic: topic.clone() }.encode().as_bytes())
+        .await?;
+    if missed > 0 {
+        stream.write_all(TopicFrame::Lagged(missed).encode().as_bytes()).await?;
+    }
+    for event in backlog {
+        stream
+            .write_all(TopicFrame::Event(event.data.to_string()).encode().as_bytes())
+            .await?;
+        *cursor.lock().unwrap() = Some(event.id);
+    }
+
+    loop {
+        let (frame, id) = tokio::select! {
+            _ = &mut stop => break,
+            event = events.recv() => match event {
+                Ok(event) => (TopicFrame::Event(event.data.to_string()), Some(event.id)),
+                Err(RecvError::Lagged(n)) => {
+                    debug!("Subscriber to {} lagged by {} events", topic, n);
+                    (TopicFrame::Lagged(n), None)
+                }
+                Err(RecvError::Closed) => break,
+            },
+        };
+        // Waits for flow control credit; events queue up meanwhile
+        stream.write_all(frame.encode().as_bytes()).await?;
+        if let Some(id) = id {
+            *cursor.lock().unwrap() = Some(id);
+        }
+    }
+
+    stream.shutdown().await?;
+    Ok(())
+}
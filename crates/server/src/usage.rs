@@ -0,0 +1,286 @@
+//! Per-key usage accounting.
+//!
+//! Counts requests and bytes for each API key [`Principal`]. Handlers write
+//! to the QUIC streams directly, so bytes are measured per connection at the
+//! UDP level (including QUIC and TLS overhead) and billed to the key that
+//! most recently authenticated a request on that connection.
+//!
+//! Usage is also counted per UTC day and month to enforce [`Quota`]s.
+
+use crate::api_keys::{Principal, Quota, QuotaPeriod};
+use crate::connection::ConnectionHandle;
+use chrono::{DateTime, Datelike, Months, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Usage counters for one key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    pub requests: u64,
+    /// Bytes received from clients using the key.
+    pub bytes_in: u64,
+    /// Bytes sent to clients using the key.
+    pub bytes_out: u64,
+}
+
+/// Requests and bytes used in one quota period.
+#[derive(Debug, Clone, Copy, Default)]
+struct Window {
+    /// Unix timestamp the period started at.
+    start: i64,
+    requests: u64,
+    bytes: u64,
+}
+
+impl Window {
+    /// Start over if the period starting at `start` is a new one.
+    fn roll(&mut self, start: i64) -> &mut Self {
+        if self.start != start {
+            *self = Window {
+                start,
+                ..Default::default()
+            };
+        }
+        self
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    totals: Counters,
+    day: Window,
+    month: Window,
+}
+
+impl Entry {
+    fn windows(&mut self, now: DateTime<Utc>) -> [&mut Window; 2] {
+        let day = period_start(QuotaPeriod::Daily, now).timestamp();
+        let month = period_start(QuotaPeriod::Monthly, now).timestamp();
+        [self.day.roll(day), self.month.roll(month)]
+    }
+}
+
+/// A request turned away because a quota is used up.
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaExceeded {
+    /// `requests` or `bytes`.
+    pub resource: &'static str,
+    /// The quota for the period.
+    pub limit: u64,
+    /// Length of the current period in seconds.
+    pub window_secs: i64,
+    /// When the period ends and usage starts over.
+    pub reset_at: DateTime<Utc>,
+}
+
+impl QuotaExceeded {
+    /// Seconds until the quota resets.
+    pub fn reset_in(&self) -> i64 {
+        (self.reset_at - Utc::now()).num_seconds().max(0)
+    }
+
+    /// `RateLimit-*` and `Retry-After` headers describing the quota.
+    pub fn headers(&self) -> [(&'static str, String); 5] {
+        let reset = self.reset_in().to_string();
+        [
+            ("ratelimit-policy", format!("{};w={}", self.limit, self.window_secs)),
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", "0".to_string()),
+            ("ratelimit-reset", reset.clone()),
+            ("retry-after", reset),
+        ]
+    }
+}
+
+/// Usage of every key, shared by the server and the admin handlers.
+#[derive(Clone, Default)]
+pub struct Usage {
+    entries: Arc<Mutex<HashMap<Principal, Entry>>>,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a request authenticated as `principal`.
+    pub fn record_request(&self, principal: &Principal) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(principal.clone()).or_default();
+        entry.totals.requests += 1;
+        for window in entry.windows(Utc::now()) {
+            window.requests += 1;
+        }
+    }
+
+    /// Add traffic to `principal`'s byte counts.
+    pub fn record_bytes(&self, principal: &Principal, bytes_in: u64, bytes_out: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(principal.clone()).or_default();
+        entry.totals.bytes_in += bytes_in;
+        entry.totals.bytes_out += bytes_out;
+        for window in entry.windows(Utc::now()) {
+            window.bytes += bytes_in + bytes_out;
+        }
+    }
+
+    /// Check whether `principal` may make another request under `quota`.
+    pub fn check_quota(&self, principal: &Principal, quota: &Quota) -> Result<(), QuotaExceeded> {
+        let now = Utc::now();
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get_mut(principal) else {
+            return Ok(());
+        };
+        let [day, month] = entry.windows(now);
+        let window = match quota.period {
+            QuotaPeriod::Daily => day,
+            QuotaPeriod::Monthly => month,
+        };
+
+        let exceeded = |resource, limit| {
+            let start = period_start(quota.period, now);
+            let reset_at = next_period_start(quota.period, start);
+            QuotaExceeded {
+                resource,
+                limit,
+                window_secs: (reset_at - start).num_seconds(),
+                reset_at,
+            }
+        };
+        if let Some(limit) = quota.requests
+            && window.requests >= limit
+        {
+            return Err(exceeded("requests", limit));
+        }
+        if let Some(limit) = quota.bytes
+            && window.bytes >= limit
+        {
+            return Err(exceeded("bytes", limit));
+        }
+        Ok(())
+    }
+
+    /// Usage of every key that has been used, ordered by key id.
+    pub fn snapshot(&self) -> Vec<(Principal, Counters)> {
+        let entries = self.entries.lock().unwrap();
+        let mut snapshot: Vec<_> = entries.iter().map(|(p, e)| (p.clone(), e.totals)).collect();
+        snapshot.sort_by(|(a, _), (b, _)| a.key_id.cmp(&b.key_id));
+        snapshot
+    }
+
+    /// Usage in the Prometheus text format, labelled by key.
+    pub fn prometheus(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        let metrics = [
+            ("requests", "Requests per API key"),
+            ("bytes_in", "Bytes received per API key"),
+            ("bytes_out", "Bytes sent per API key"),
+        ];
+        for (i, (name, help)) in metrics.into_iter().enumerate() {
+            let _ = writeln!(out, "# HELP h3_usage_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE h3_usage_{}_total counter", name);
+            for (principal, counters) in &snapshot {
+                let _ = writeln!(
+                    out,
+                    "h3_usage_{}_total{{api_key=\"{}\",name=\"{}\"}} {}",
+                    name,
+                    principal.key_id,
+                    escape_label(&principal.name),
+                    [counters.requests, counters.bytes_in, counters.bytes_out][i]
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Bills a connection's traffic to the key last used on it.
+///
+/// Traffic since the previous request is billed when the next request is
+/// authenticated, and the rest when the meter is dropped with the
+/// connection's last task.
+pub struct ConnectionMeter {
+    conn: ConnectionHandle,
+    usage: Usage,
+    state: Mutex<MeterState>,
+}
+
+#[derive(Default)]
+struct MeterState {
+    principal: Option<Principal>,
+    /// UDP byte counts already billed.
+    billed_in: u64,
+    billed_out: u64,
+}
+
+impl ConnectionMeter {
+    pub fn new(conn: ConnectionHandle, usage: Usage) -> Self {
+        Self {
+            conn,
+            usage,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Count a request authenticated as `principal` and bill traffic to it
+    /// from now on.
+    pub fn request(&self, principal: &Principal) {
+        let mut state = self.state.lock().unwrap();
+        self.bill(&mut state);
+        state.principal = Some(principal.clone());
+        self.usage.record_request(principal);
+    }
+
+    /// Bill traffic since the last call to the current principal. Traffic
+    /// before the first authenticated request is not billed.
+    fn bill(&self, state: &mut MeterState) {
+        let stats = self.conn.stats();
+        let (bytes_in, bytes_out) = (stats.udp_rx.bytes, stats.udp_tx.bytes);
+        if let Some(principal) = &state.principal {
+            self.usage.record_bytes(
+                principal,
+                bytes_in.saturating_sub(state.billed_in),
+                bytes_out.saturating_sub(state.billed_out),
+            );
+        }
+        state.billed_in = bytes_in;
+        state.billed_out = bytes_out;
+    }
+}
+
+impl Drop for ConnectionMeter {
+    fn drop(&mut self) {
+        let mut state = std::mem::take(self.state.get_mut().unwrap());
+        self.bill(&mut state);
+    }
+}
+
+/// When the period containing `now` started.
+fn period_start(period: QuotaPeriod, now: DateTime<Utc>) -> DateTime<Utc> {
+    let day = match period {
+        QuotaPeriod::Daily => now.day(),
+        QuotaPeriod::Monthly => 1,
+    };
+    Utc.with_ymd_and_hms(now.year(), now.month(), day, 0, 0, 0)
+        .single()
+        .unwrap_or(now)
+}
+
+/// When the period starting at `start` ends.
+fn next_period_start(period: QuotaPeriod, start: DateTime<Utc>) -> DateTime<Utc> {
+    match period {
+        QuotaPeriod::Daily => start + chrono::Duration::days(1),
+        QuotaPeriod::Monthly => start + Months::new(1),
+    }
+}
+
+/// Escape a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,81 @@
+//! Virtual hosting: per-hostname certificates and routers.
+//!
+//! Each virtual host is served with its own certificate, selected by the
+//! SNI hostname in the TLS ClientHello, and its own [`Router`]. Connections
+//! for unknown hostnames (or without SNI) use the default WebTransport
+//! certificate and router.
+
+use crate::router::Router;
+use common::tls::hostname_matches;
+use common::{generate_self_signed_cert, CertificateChain};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A hostname served with its own certificate and router.
+pub struct VirtualHost {
+    /// Hostname or wildcard pattern (e.g. `*.example.com`).
+    pub hostname: String,
+    pub cert: CertificateChain,
+    pub router: Router,
+}
+
+impl VirtualHost {
+    pub fn new(hostname: impl Into<String>, cert: CertificateChain, router: Router) -> Self {
+        Self {
+            hostname: hostname.into(),
+            cert,
+            router,
+        }
+    }
+
+    /// Create a virtual host with a generated self-signed certificate.
+    pub fn self_signed(hostname: impl Into<String>, router: Router) -> anyhow::Result<Self> {
+        let hostname = hostname.into();
+        let cert = generate_self_signed_cert(std::slice::from_ref(&hostname))?;
+        Ok(Self::new(hostname, cert, router))
+    }
+}
+
+/// Routers keyed by SNI hostname, with a default for unknown hosts.
+pub struct HostRouters {
+    default: Arc<Router>,
+    hosts: HashMap<String, Arc<Router>>,
+}
+
+impl HostRouters {
+    pub fn new(default: Router) -> Self {
+        Self {
+            default: Arc::new(default),
+            hosts: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, hostname: &str, router: Router) {
+        self.hosts
+            .insert(hostname.to_ascii_lowercase(), Arc::new(router));
+    }
+
+    /// The router for a connection's SNI hostname.
+    pub fn select(&self, server_name: Option<&str>) -> Arc<Router> {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return Arc::clone(&self.default);
+        };
+        let router = self.hosts.get(&name).or_else(|| {
+            self.hosts
+                .iter()
+                .find(|(pattern, _)| hostname_matches(pattern, &name))
+                .map(|(_, router)| router)
+        });
+        Arc::clone(router.unwrap_or(&self.default))
+    }
+
+    /// The default router.
+    pub fn default_router(&self) -> &Router {
+        &self.default
+    }
+
+    /// Configured virtual hostnames.
+    pub fn hostnames(&self) -> Vec<&str> {
+        self.hosts.keys().map(|s| s.as_str()).collect()
+    }
+}
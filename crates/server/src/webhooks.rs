@@ -0,0 +1,264 @@
+//! Outbound webhooks for server events.
+//!
+//! Events (WebTransport sessions starting and ending, certificate
+//! rotations, bursts of connection errors) are POSTed as JSON to every
+//! configured URL. Each URL has its own queue and delivery task, so a slow
+//! or unreachable receiver does not hold up the others; failed deliveries
+//! are retried with exponential backoff and then dropped.
+
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Events buffered per URL before new ones are dropped.
+const QUEUE_CAPACITY: usize = 256;
+
+/// Delivery attempts per event, including the first.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry; doubled for each further retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Timeout for connecting to a receiver and for each read or write.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection errors within [`ERROR_WINDOW`] that count as a spike.
+const ERROR_SPIKE_THRESHOLD: u32 = 10;
+
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// A server event delivered to webhooks.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    SessionStarted {
+        remote: SocketAddr,
+        path: String,
+    },
+    SessionEnded {
+        remote: SocketAddr,
+        path: String,
+        close_code: u32,
+        reason: String,
+        duration_secs: f64,
+    },
+    CertRotated {
+        hash: String,
+        next_hash: String,
+        /// Unix timestamp the new certificate expires at.
+        not_after: i64,
+    },
+    ErrorSpike {
+        errors: u32,
+        window_secs: u64,
+    },
+}
+
+/// The JSON body POSTed for an event.
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    /// Unix timestamp the event happened at.
+    timestamp: i64,
+}
+
+/// Counts errors in fixed windows to detect spikes.
+struct ErrorWindow {
+    started: Instant,
+    errors: u32,
+}
+
+/// Sends events to the configured webhooks; does nothing without any.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    queues: Arc<Vec<(String, mpsc::Sender<String>)>>,
+    errors: Arc<Mutex<Option<ErrorWindow>>>,
+}
+
+impl Webhooks {
+    /// No webhooks.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Deliver events to `urls` (`http://` or `https://`). Must be called
+    /// within a Tokio runtime.
+    pub fn new(urls: Vec<String>) -> anyhow::Result<Self> {
+        let tls = Arc::new(tls_config()?);
+        let mut queues = Vec::with_capacity(urls.len());
+        for url in urls {
+            let target = Target::parse(&url)?;
+            let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+            tokio::spawn(deliver(target, rx, Arc::clone(&tls)));
+            info!("Sending server events to webhook {}", url);
+            queues.push((url, tx));
+        }
+
+        Ok(Self {
+            queues: Arc::new(queues),
+            errors: Arc::default(),
+        })
+    }
+
+    /// Queue `event` for every webhook.
+    pub fn emit(&self, event: Event) {
+        if self.queues.is_empty() {
+            return;
+        }
+
+        let payload = Payload {
+            event: &event,
+            timestamp: chrono::Utc::now().timestamp(),
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Failed to encode webhook event: {}", e);
+                return;
+            }
+        };
+        for (url, queue) in self.queues.iter() {
+            if queue.try_send(body.clone()).is_err() {
+                warn!("Webhook queue for {} is full; dropping event", url);
+            }
+        }
+    }
+
+    /// Count a connection error, emitting [`Event::ErrorSpike`] when
+    /// [`ERROR_SPIKE_THRESHOLD`] are reached within a minute.
+    pub fn record_error(&self) {
+        if self.queues.is_empty() {
+            return;
+        }
+
+        let mut window = self.errors.lock().unwrap();
+        let window = match &mut *window {
+            Some(window) if window.started.elapsed() < ERROR_WINDOW => window,
+            window => window.insert(ErrorWindow {
+                started: Instant::now(),
+                errors: 0,
+            }),
+        };
+        window.errors += 1;
+        // Once per window
+        if window.errors == ERROR_SPIKE_THRESHOLD {
+            self.emit(Event::ErrorSpike {
+                errors: window.errors,
+                window_secs: ERROR_WINDOW.as_secs(),
+            });
+        }
+    }
+}
+
+/// Deliver queued events to one webhook, in order.
+async fn deliver(target: Target, mut rx: mpsc::Receiver<String>, tls: Arc<rustls::ClientConfig>) {
+    let target = Arc::new(target);
+    while let Some(body) = rx.recv().await {
+        let mut delay = RETRY_BASE_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let (target, tls, body) = (Arc::clone(&target), Arc::clone(&tls), body.clone());
+            let result = tokio::task::spawn_blocking(move || post(&target, &tls, &body)).await;
+
+            match result {
+                Ok(Ok(status)) if (200..300).contains(&status) => {
+                    debug!("Delivered webhook event ({})", status);
+                    break;
+                }
+                Ok(Ok(status)) => debug!("Webhook responded {}", status),
+                Ok(Err(e)) => debug!("Webhook delivery failed: {}", e),
+                Err(e) => debug!("Webhook delivery panicked: {}", e),
+            }
+
+            if attempt == MAX_ATTEMPTS {
+                warn!("Dropping webhook event after {} attempts", MAX_ATTEMPTS);
+            } else {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// Where a webhook is delivered.
+struct Target {
+    https: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Target {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let uri: http::Uri = url.parse()?;
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => anyhow::bail!("webhook URL must be http:// or https://: {}", url),
+        };
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow::anyhow!("webhook URL has no host: {}", url))?
+            .to_string();
+
+        Ok(Self {
+            https,
+            port: uri.port_u16().unwrap_or(if https { 443 } else { 80 }),
+            path: uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+            host,
+        })
+    }
+}
+
+/// POST `body` over HTTP/1.1 and return the response status.
+fn post(target: &Target, tls: &Arc<rustls::ClientConfig>, body: &str) -> anyhow::Result<u16> {
+    let addr = (target.host.as_str(), target.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("{} did not resolve", target.host))?;
+    let tcp = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    tcp.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    tcp.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: simple-http3\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        target.path,
+        target.host,
+        body.len(),
+        body
+    );
+
+    if target.https {
+        let name = rustls::pki_types::ServerName::try_from(target.host.clone())?;
+        let conn = rustls::ClientConnection::new(Arc::clone(tls), name)?;
+        exchange(rustls::StreamOwned::new(conn, tcp), &request)
+    } else {
+        exchange(tcp, &request)
+    }
+}
+
+/// Send `request` and parse the status code from the response.
+fn exchange(mut stream: impl Read + Write, request: &str) -> anyhow::Result<u16> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid response: {:?}", status_line.trim()))
+}
+
+/// TLS settings for `https://` webhooks, verifying against the platform's
+/// trusted roots.
+fn tls_config() -> anyhow::Result<rustls::ClientConfig> {
+    use rustls_platform_verifier::ConfigVerifierExt;
+    Ok(rustls::ClientConfig::with_platform_verifier()?)
+}
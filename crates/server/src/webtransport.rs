@@ -3,46 +3,402 @@
 //! WebTransport provides bidirectional streams and datagrams over QUIC,
 //! accessible from browsers via the WebTransport API.
 
-use bytes::Bytes;
-use h3::quic::BidiStream;
+use crate::affinity::{Affinity, SessionState};
+use crate::chunks::{self, ChunkRead, ChunkWrite};
+use crate::connection::ConnectionHandle;
+use crate::deadline::{Deadline, http3_error_code};
+use crate::fallback::{self, FallbackSender};
+use crate::peer_cert::PeerCertificate;
+use crate::pool;
+use crate::recorder::{Channel, Direction, Recorded, SessionRecorder};
+use crate::rpc::Dispatcher;
+use crate::sessions::{Delivery, Kick, Sessions};
+use crate::shaping::{Shaper, Shaping};
+use crate::sync;
+use crate::telemetry::Telemetry;
+use crate::topics::{self, Cursor, Topics};
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use common::datagram::{SequenceStats, SequenceTracker, decode_sequenced};
+use common::telemetry::{BACKFILL_HEADER, Sample, TELEMETRY_MARKER};
+use common::{ClientControl, CloseCode, ControlMessage, ReceiptStatus, ServerConfig, Signal};
+use h3::quic::{self, BidiStream};
+use h3::server::RequestStream;
+use h3_webtransport::SessionId;
 use h3_webtransport::server::{AcceptedBi, WebTransportSession};
+use http::{HeaderMap, Request, Uri};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_util::task::AbortOnDropHandle;
 use tracing::{debug, error, info};
 
+/// How long a session may stay open after being sent a GOAWAY.
+const GOAWAY_GRACE: Duration = Duration::from_secs(5);
+
+/// WebTransport stream error code of a stream refused because the session
+/// has too many open.
+pub const STREAM_LIMIT_CODE: u32 = 2;
+
+/// Least time between two `STREAMLIMIT` messages to a session, so a flood
+/// of refused streams does not become a flood of control streams.
+const LIMIT_NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server-initiated uni streams a session keeps waiting at most while the
+/// client allows no more; further ones are dropped.
+const UNI_QUEUE: usize = 64;
+
+/// Where a session passes the HTTP/3 requests arriving on its connection,
+/// which it owns, to be served like those that arrived before it. Never
+/// full, so the session does not wait for the connection to take them.
+pub type SessionRequests =
+    mpsc::UnboundedSender<(Request<()>, RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>)>;
+
+/// The extended CONNECT request a session was opened with, so a session
+/// can depend on its path (`/webtransport/room/lobby`), query parameters,
+/// headers or the client's certificate.
+#[derive(Debug, Clone)]
+pub struct ConnectRequest {
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    /// The certificate the client authenticated with, with mutual TLS.
+    pub client_cert: Option<PeerCertificate>,
+}
+
+impl ConnectRequest {
+    pub fn new<B>(req: &Request<B>) -> Self {
+        Self {
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+            client_cert: req.extensions().get::<PeerCertificate>().cloned(),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+
+    /// The query parameters, percent-decoded, in order.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        let query = self.uri.query().unwrap_or("");
+        form_urlencoded::parse(query.as_bytes())
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect()
+    }
+
+    /// The first query parameter called `name`, percent-decoded.
+    pub fn query(&self, name: &str) -> Option<String> {
+        self.query_pairs()
+            .into_iter()
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+
+    /// The room named by a `/room/<name>` path suffix, e.g. `lobby` for
+    /// `/webtransport/room/lobby`.
+    pub fn room(&self) -> Option<String> {
+        let (_, room) = self.path().rsplit_once("/room/")?;
+        (!room.is_empty() && !room.contains('/')).then(|| room.to_string())
+    }
+
+    /// A header's value, if present and valid UTF-8.
+    #[allow(dead_code)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)?.to_str().ok()
+    }
+}
+
+/// Limits on the streams a client opens in a session.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamLimits {
+    /// How long a stream read or write may stall (see [`crate::deadline`]).
+    pub timeout: Option<Duration>,
+    /// Bidi streams open at once.
+    pub max_bidi: usize,
+    /// Uni streams open at once.
+    pub max_uni: usize,
+}
+
+impl StreamLimits {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            timeout: match config.stream_timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_bidi: config.max_session_bidi_streams,
+            max_uni: config.max_session_uni_streams,
+        }
+    }
+}
+
+/// What a session shares with the rest of the server, and the connection
+/// it runs on.
+pub struct SessionContext {
+    pub topics: Topics,
+    pub sessions: Sessions,
+    pub telemetry: Telemetry,
+    /// The QUIC connection carrying the session.
+    pub connection: ConnectionHandle,
+    pub limits: StreamLimits,
+    pub shaping: Shaping,
+    /// Where HTTP/3 requests arriving on the connection meanwhile go.
+    pub requests: SessionRequests,
+}
+
 /// Handle a WebTransport session.
 ///
 /// This demonstrates:
 /// - Server-initiated bidirectional stream
 /// - Echo for client-initiated streams
 /// - Datagram echo
+/// - Topic subscriptions
+///
+/// If a `recorder` is given, all stream and datagram traffic of the session
+/// is captured for later replay (topic streams are not recorded). Messages
+/// received on `control_rx` are forwarded to the client on control streams;
+/// after a GOAWAY the session is closed once [`GOAWAY_GRACE`] has elapsed.
+///
+/// Client uni streams carrying a [`ClientControl`] message manage the
+/// session's topic subscriptions or ask for a `PONG`; any other uni stream
+/// is echoed.
+///
+/// The session is registered in `sessions` while it runs, and messages
+/// pushed to it are sent as datagrams or `NOTIFY` control messages (these
+/// are not recorded).
+///
+/// Clients whose QUIC stack did not negotiate datagrams exchange them on a
+/// fallback stream in each direction instead (see [`crate::fallback`]).
+///
+/// The client is sent the session's affinity token and gets back the
+/// subscriptions `affinity` resumed; they are saved again when the session
+/// ends. `request` is the CONNECT request; a `subscribe` query parameter
+/// (e.g. `?subscribe=time,news`) subscribes the session to those topics
+/// from the start.
+///
+/// With a stream timeout in `limits`, client uni streams must be finished,
+/// and the welcome and echo streams read by the client, without stalling
+/// for that long; otherwise the stream is reset (see [`crate::deadline`]).
+/// Reads on client bidi streams never time out, as a chat stream may sit
+/// idle. Client streams opened past the `limits` on open streams are
+/// refused with [`STREAM_LIMIT_CODE`], and the client is sent a
+/// `STREAMLIMIT` control message.
+///
+/// A session at a `/room/<name>` path is registered in that room, where a
+/// `NAME` control message sets its display name, and is sent the room's
+/// `ROSTER`, `JOINED` and `LEFT` presence events. `SAY` and `PUBKEY` control
+/// messages are relayed to the room as they are. Its signal datagrams (see
+/// [`common::signal`]) are relayed to the room, unrecorded, instead of
+/// echoed. It is also sent the room's shared document on a sync stream,
+/// and changes it with `EDIT` streams (see [`crate::sync`]). If an admin
+/// kicks or bans it (see [`Sessions::kick`]), the session ends and the
+/// connection is closed with [`CloseCode::Kicked`] or
+/// [`CloseCode::Banned`] and the admin's reason.
+///
+/// Telemetry sent in datagrams (unrecorded) or uni streams (see
+/// [`common::telemetry`]) is aggregated in `telemetry` instead of echoed.
+/// A `BACKFILL` control message gets the samples kept since then back on a
+/// stream.
+///
+/// Echoes on streams are shaped by `shaping`, each stream on its own (see
+/// [`crate::shaping`]); datagram echoes are not.
+///
+/// Server-initiated uni streams are opened by a [`UniWriter`], so a client
+/// that allows no more holds up only those, not the session.
+///
+/// HTTP/3 requests arriving on the connection meanwhile are passed on to
+/// `requests`. Returns the code to close the connection with.
 pub async fn handle_session(
     session: WebTransportSession<h3_quinn::Connection, Bytes>,
-) -> anyhow::Result<()> {
+    request: ConnectRequest,
+    recorder: Option<Arc<SessionRecorder>>,
+    mut control_rx: broadcast::Receiver<ControlMessage>,
+    mut affinity: Affinity,
+    context: SessionContext,
+) -> anyhow::Result<CloseCode> {
+    let SessionContext {
+        topics,
+        sessions,
+        telemetry,
+        connection,
+        limits,
+        shaping,
+        requests,
+    } = context;
+    let session = Arc::new(session);
     let session_id = session.session_id();
-    info!("WebTransport session established: {:?}", session_id);
+    let writer = UniWriter::start(Arc::clone(&session));
+    let remote = connection.remote_address().ip();
+    let (mut registration, mut notify_rx, mut room_rx) =
+        sessions.register(request.room(), remote);
+    info!(
+        "WebTransport session established: {:?} (id {}) at {}",
+        session_id,
+        registration.id(),
+        request.path()
+    );
+    if let Some(cert) = &request.client_cert {
+        info!("Session {} authenticated as {}", registration.id(), cert.subject);
+    }
+
+    // Streams are numbered in arrival order for the recording
+    let mut next_stream = 0u64;
+    let mut channel = |kind: fn(u64) -> Channel| {
+        next_stream += 1;
+        kind(next_stream)
+    };
+
+    // Tell the client its token, to resume the session with after reconnecting
+    writer.control(ControlMessage::Session {
+        token: affinity.token.clone(),
+    });
 
     // Open a server-initiated bidirectional stream to send a welcome message
-    let welcome_stream = session.open_bi(session_id).await?;
+    let welcome_stream = Recorded::new(
+        session.open_bi(session_id).await?,
+        recorder.as_ref(),
+        channel(Channel::ServerBidi),
+    );
+    let welcome_stream = Deadline::new(welcome_stream, limits.timeout);
     tokio::spawn(async move {
         if let Err(e) = send_welcome(welcome_stream).await {
             debug!("Welcome stream error: {:?}", e);
         }
     });
 
-    // Set up datagram handlers
+    // Set up datagram handlers, with a stream standing in for datagrams the
+    // client cannot receive
     let mut datagram_reader = session.datagram_reader();
     let mut datagram_sender = session.datagram_sender();
+    let fallback = if connection.supports_datagrams() {
+        None
+    } else {
+        info!("Session {:?} has no datagram support; using a fallback stream", session_id);
+        Some(FallbackSender::start(session.open_uni(session_id).await?))
+    };
+    let mut send_datagram = |payload: Bytes| match &fallback {
+        Some(fallback) => fallback.send(payload),
+        None => datagram_sender
+            .send_datagram(payload)
+            .map_err(|e| anyhow::anyhow!("{:?}", e)),
+    };
+    // Datagrams read from client fallback streams
+    let (fallback_tx, mut fallback_rx) = mpsc::channel::<Bytes>(16);
+    // Loss and reordering of the client's sequence-numbered datagrams
+    let mut sequence = SequenceTracker::new();
+
+    let mut control = Control::new();
+    let mut kicked: Option<Kick> = None;
+
+    // Client uni streams are read to the end in their own task, then
+    // handled here as either a control message or data to echo
+    let (uni_tx, mut uni_rx) = mpsc::channel(16);
+
+    // Each open client stream holds a permit until it is done with
+    let bidi_permits = Arc::new(Semaphore::new(limits.max_bidi));
+    let uni_permits = Arc::new(Semaphore::new(limits.max_uni));
+    let mut limit_noticed: Option<Instant> = None;
+
+    // The room's shared document, until the session ends
+    let mut _sync_stop = None;
+    if let Some(room) = request.room() {
+        let (stop, stop_rx) = oneshot::channel();
+        _sync_stop = Some(stop);
+        let documents = sessions.documents().clone();
+        let me = registration.id();
+        writer.open("sync", move |stream| sync::forward(room, me, documents, stream, stop_rx));
+    }
+
+    // This session's topic subscriptions, including those it resumed
+    let mut subscriptions: HashMap<String, Subscribed> = HashMap::new();
+    let resumed = affinity.resumed.take().unwrap_or_default();
+    for (topic, after) in resumed.subscriptions {
+        let subscribed = subscribe(&writer, &topics, topic.clone(), after.as_deref());
+        let after = after.as_deref().unwrap_or("-");
+        info!("Session {:?} resumed {} after event {}", session_id, topic, after);
+        subscriptions.insert(topic, subscribed);
+    }
+    let requested = request.query("subscribe").unwrap_or_default();
+    for topic in requested.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if subscriptions.contains_key(topic) {
+            continue;
+        }
+        let subscribed = subscribe(&writer, &topics, topic.to_string(), None);
+        info!("Session {:?} subscribed to {}", session_id, topic);
+        subscriptions.insert(topic.to_string(), subscribed);
+    }
 
     loop {
         tokio::select! {
-            // Handle incoming datagrams (echo them back)
+            // Forward control messages to the client
+            msg = control_rx.recv(), if control.open => {
+                control.forward(msg, &writer, session_id);
+            }
+
+            // Close the session once the GOAWAY grace period is over
+            _ = control.closing() => {
+                info!("Closing session {:?} after GOAWAY", session_id);
+                break;
+            }
+
+            // Forward room events: presence, public keys and messages
+            Some(event) = room_rx.recv() => {
+                let sessions = sessions.clone();
+                let me = registration.id();
+                writer.open("room event", move |stream| async move {
+                    send_control(stream, &event).await?;
+                    if let ControlMessage::Said { msg, .. } = event {
+                        sessions.receipt(msg, me, ReceiptStatus::Delivered);
+                    }
+                    Ok(())
+                });
+            }
+
+            // Removed by an admin
+            kick = registration.kicked() => {
+                info!("Session {:?} {}: {}", session_id, kick.code.reason(), kick.reason);
+                kicked = Some(kick);
+                break;
+            }
+
+            // Deliver messages pushed to this session
+            Some(notification) = notify_rx.recv() => {
+                debug!("Pushing message to session {:?}", session_id);
+                match notification.delivery {
+                    Delivery::Datagram => {
+                        let payload = Bytes::from(notification.message);
+                        if let Err(e) = send_datagram(payload) {
+                            error!("Failed to send datagram: {:?}", e);
+                        }
+                    }
+                    Delivery::Stream => {
+                        writer.control(ControlMessage::Notify { message: notification.message });
+                    }
+                }
+            }
+
+            // Handle incoming datagrams (echo them back, or relay signals)
             datagram = datagram_reader.read_datagram() => {
                 match datagram {
                     Ok(datagram) => {
                         let payload = datagram.into_payload();
                         debug!("Received datagram: {} bytes", payload.len());
-                        if let Err(e) = datagram_sender.send_datagram(payload) {
+                        if let Some(signal) = Signal::decode(&payload) {
+                            sessions.signal(registration.id(), signal);
+                            continue;
+                        }
+                        if on_telemetry(&payload, &telemetry) {
+                            continue;
+                        }
+                        let reply = on_datagram(payload, recorder.as_deref(), &mut sequence);
+                        let Some(reply) = reply else {
+                            continue;
+                        };
+                        if let Err(e) = send_datagram(reply) {
                             error!("Failed to send datagram: {:?}", e);
                         }
                     }
@@ -53,24 +409,70 @@ pub async fn handle_session(
                 }
             }
 
+            // Handle datagrams from a client fallback stream the same way
+            Some(payload) = fallback_rx.recv() => {
+                debug!("Received datagram on fallback stream: {} bytes", payload.len());
+                if let Some(signal) = Signal::decode(&payload) {
+                    sessions.signal(registration.id(), signal);
+                    continue;
+                }
+                if on_telemetry(&payload, &telemetry) {
+                    continue;
+                }
+                let Some(reply) = on_datagram(payload, recorder.as_deref(), &mut sequence) else {
+                    continue;
+                };
+                if let Err(e) = send_datagram(reply) {
+                    error!("Failed to send datagram: {:?}", e);
+                }
+            }
+
             // Handle incoming unidirectional streams
             uni_stream = session.accept_uni() => {
                 match uni_stream {
-                    Ok(Some((id, recv_stream))) => {
+                    Ok(Some((id, mut recv_stream))) => {
                         debug!("Accepted uni stream: {:?}", id);
-                        // Open a uni stream back to echo
-                        match session.open_uni(id).await {
-                            Ok(send_stream) => {
-                                tokio::spawn(async move {
-                                    if let Err(e) = echo_uni(send_stream, recv_stream).await {
-                                        debug!("Uni stream echo error: {:?}", e);
+                        let Ok(permit) = Arc::clone(&uni_permits).try_acquire_owned() else {
+                            debug!("Refusing uni stream {:?}: {} open", id, limits.max_uni);
+                            let code = http3_error_code(STREAM_LIMIT_CODE);
+                            quic::RecvStream::stop_sending(&mut recv_stream, code);
+                            let notice = ControlMessage::StreamLimit {
+                                kind: "uni".to_string(),
+                                limit: limits.max_uni,
+                            };
+                            notify_limit(&writer, notice, &mut limit_noticed);
+                            continue;
+                        };
+                        let uni_channel = channel(Channel::Uni);
+                        let recv_stream =
+                            Recorded::new(recv_stream, recorder.as_ref(), uni_channel);
+                        let mut recv_stream = Deadline::new(recv_stream, limits.timeout);
+                        let uni_tx = uni_tx.clone();
+                        let fallback_tx = fallback_tx.clone();
+                        tokio::spawn(async move {
+                            let mut data = pool::buffers().get();
+                            let header = fallback::read_header(&mut recv_stream, &mut data).await;
+                            let result = match header {
+                                Ok(true) => {
+                                    debug!("Uni stream {:?} is a datagram fallback stream", id);
+                                    // Stays open, and idle, for the whole session
+                                    recv_stream.set_timeout(None);
+                                    fallback::read_frames(recv_stream, &mut data, fallback_tx).await
+                                }
+                                Ok(false) => match read_to_end(&mut recv_stream, &mut data).await {
+                                    Ok(()) => {
+                                        let _ = uni_tx.send((uni_channel, data)).await;
+                                        Ok(())
                                     }
-                                });
-                            }
-                            Err(e) => {
-                                error!("Failed to open uni stream: {:?}", e);
+                                    Err(e) => Err(e),
+                                },
+                                Err(e) => Err(e),
+                            };
+                            if let Err(e) = result {
+                                debug!("Uni stream read error: {:?}", e);
                             }
-                        }
+                            drop(permit);
+                        });
                     }
                     Ok(None) => {
                         debug!("No more uni streams");
@@ -83,24 +485,123 @@ pub async fn handle_session(
                 }
             }
 
+            // Handle the contents of finished uni streams
+            Some((uni_channel, data)) = uni_rx.recv() => {
+                // Edits to the room's document are binary
+                if let Some((base, patch)) = common::sync::decode_edit(&data) {
+                    let Some(room) = request.room() else {
+                        continue;
+                    };
+                    let me = registration.id();
+                    match sessions.documents().edit(&room, me, base, patch) {
+                        Ok(version) => {
+                            debug!("Session {:?} edited {} to {}", session_id, room, version)
+                        }
+                        Err(e) => debug!("Session {:?} edit rejected: {}", session_id, e),
+                    }
+                    continue;
+                }
+                // Telemetry sent again reliably, e.g. after a backfill
+                if on_telemetry(&data, &telemetry) {
+                    continue;
+                }
+                match ClientControl::decode(&String::from_utf8_lossy(&data)) {
+                    Some(ClientControl::Subscribe { topic }) => {
+                        if subscriptions.contains_key(&topic) {
+                            continue;
+                        }
+                        let subscribed = subscribe(&writer, &topics, topic.clone(), None);
+                        info!("Session {:?} subscribed to {}", session_id, topic);
+                        subscriptions.insert(topic, subscribed);
+                    }
+                    Some(ClientControl::Unsubscribe { topic }) => {
+                        if let Some(subscribed) = subscriptions.remove(&topic) {
+                            info!("Session {:?} unsubscribed from {}", session_id, topic);
+                            let _ = subscribed.stop.send(());
+                        }
+                    }
+                    Some(ClientControl::Ping) => writer.control(ControlMessage::Pong),
+                    Some(ClientControl::Name { name }) => {
+                        if let Some(name) = sessions.set_name(registration.id(), &name) {
+                            info!("Session {:?} is now {}", session_id, name);
+                        }
+                    }
+                    Some(ClientControl::PublicKey { key }) => {
+                        if sessions.set_public_key(registration.id(), &key) {
+                            debug!("Session {:?} published a public key", session_id);
+                        }
+                    }
+                    Some(ClientControl::Say { to, id, body }) => {
+                        match sessions.say(registration.id(), to, id, &body) {
+                            Some((msg, recipients)) => debug!(
+                                "Session {:?} said message {} to {}",
+                                session_id, msg, recipients
+                            ),
+                            None => debug!("Session {:?} is not in a room", session_id),
+                        }
+                    }
+                    Some(ClientControl::Read { msg }) => {
+                        sessions.receipt(msg, registration.id(), ReceiptStatus::Read);
+                    }
+                    Some(ClientControl::Backfill { since, metric }) => {
+                        let samples = telemetry.since(since, metric.as_deref());
+                        debug!("Backfilling {} samples to {:?}", samples.len(), session_id);
+                        writer.open("backfill", move |stream| async move {
+                            send_backfill(stream, since, &samples).await
+                        });
+                    }
+                    None => {
+                        // Open a uni stream back to echo
+                        let recorder = recorder.clone();
+                        writer.open("uni echo", move |send_stream| async move {
+                            let send_stream =
+                                Recorded::new(send_stream, recorder.as_ref(), uni_channel);
+                            let send_stream = Deadline::new(send_stream, limits.timeout);
+                            echo_uni(send_stream, &data[..], Shaper::new(shaping)).await
+                        });
+                    }
+                }
+            }
+
             // Handle incoming bidirectional streams
             bidi_stream = session.accept_bi() => {
                 match bidi_stream {
                     Ok(Some(accepted)) => {
                         match accepted {
-                            AcceptedBi::BidiStream(id, stream) => {
+                            AcceptedBi::BidiStream(id, mut stream) => {
                                 debug!("Accepted bidi stream: {:?}", id);
+                                let permits = Arc::clone(&bidi_permits);
+                                let Ok(permit) = permits.try_acquire_owned() else {
+                                    debug!(
+                                        "Refusing bidi stream {:?}: {} open",
+                                        id, limits.max_bidi
+                                    );
+                                    let code = http3_error_code(STREAM_LIMIT_CODE);
+                                    quic::SendStream::reset(&mut stream, code);
+                                    quic::RecvStream::stop_sending(&mut stream, code);
+                                    let notice = ControlMessage::StreamLimit {
+                                        kind: "bidi".to_string(),
+                                        limit: limits.max_bidi,
+                                    };
+                                    notify_limit(&writer, notice, &mut limit_noticed);
+                                    continue;
+                                };
+                                let bidi_channel = channel(Channel::Bidi);
                                 let (send, recv) = BidiStream::split(stream);
+                                let send = Recorded::new(send, recorder.as_ref(), bidi_channel);
+                                let send = Deadline::new(send, limits.timeout);
+                                let recv = Recorded::new(recv, recorder.as_ref(), bidi_channel);
                                 tokio::spawn(async move {
-                                    if let Err(e) = echo_bidi(send, recv).await {
+                                    let shaper = Shaper::new(shaping);
+                                    if let Err(e) = echo_bidi(send, recv, shaper).await {
                                         debug!("Bidi stream echo error: {:?}", e);
                                     }
+                                    drop(permit);
                                 });
                             }
                             AcceptedBi::Request(req, stream) => {
-                                // Additional HTTP/3 request within session
-                                debug!("Received HTTP request in session: {:?}", req.uri());
-                                drop(stream);
+                                debug!("Passing on HTTP request in session: {:?}", req.uri());
+                                let _ = requests.send((req, stream));
                             }
                         }
                     }
@@ -122,11 +623,355 @@ pub async fn handle_session(
     }
 
     info!("WebTransport session ended: {:?}", session_id);
+    log_connection_stats(&connection);
+    log_datagram_stats(&sequence.stats());
+
+    // Saved for the next session with this session's token
+    let subscriptions = subscriptions
+        .into_iter()
+        .map(|(topic, subscribed)| {
+            let after = subscribed.cursor.lock().unwrap().as_deref().map(str::to_string);
+            (topic, after)
+        })
+        .collect();
+    affinity.save(SessionState { subscriptions });
+
+    // Closed here, with the reason, before dropping the session closes the
+    // connection without a code
+    if let Some(kick) = kicked {
+        let reason = match kick.reason.as_str() {
+            "" => kick.code.reason(),
+            reason => reason,
+        };
+        connection.close(kick.code, reason);
+        return Ok(kick.code);
+    }
+    Ok(control.close_code())
+}
+
+/// A topic subscription of a session.
+struct Subscribed {
+    /// Ends the subscription.
+    stop: oneshot::Sender<()>,
+    /// ID of the last event sent.
+    cursor: Cursor,
+}
+
+/// Subscribe a session to `topic` on a new stream, resuming after event
+/// `after` if given.
+fn subscribe(
+    writer: &UniWriter,
+    topics: &Topics,
+    topic: String,
+    after: Option<&str>,
+) -> Subscribed {
+    let (stop, stop_rx) = oneshot::channel();
+    let subscription = topics.subscribe(&topic, after);
+    let cursor = Arc::clone(&subscription.cursor);
+    writer.open("topic", move |stream| topics::forward(topic, subscription, stream, stop_rx));
+    Subscribed { stop, cursor }
+}
+
+/// Send the client a `STREAMLIMIT` message, unless it was sent one less
+/// than [`LIMIT_NOTICE_INTERVAL`] ago.
+fn notify_limit(writer: &UniWriter, notice: ControlMessage, last_sent: &mut Option<Instant>) {
+    if last_sent.is_some_and(|sent| sent.elapsed() < LIMIT_NOTICE_INTERVAL) {
+        return;
+    }
+    *last_sent = Some(Instant::now());
+    writer.control(notice);
+}
+
+/// A server-initiated uni stream of a session.
+type UniStream = h3_webtransport::stream::SendStream<h3_quinn::SendStream<Bytes>, Bytes>;
+
+/// What to write on a uni stream once it is open.
+type UniWrite = Box<dyn FnOnce(UniStream) -> BoxFuture<'static, ()> + Send>;
+
+/// Opens a session's server-initiated uni streams in a task of its own, in
+/// the order they were asked for. Opening one waits for the client to allow
+/// another stream, which it may never do; meanwhile up to [`UNI_QUEUE`]
+/// streams wait their turn, and any more are dropped.
+struct UniWriter {
+    queue: mpsc::Sender<(&'static str, UniWrite)>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl UniWriter {
+    fn start(session: Arc<WebTransportSession<h3_quinn::Connection, Bytes>>) -> Self {
+        let (queue, mut queued) = mpsc::channel::<(&'static str, UniWrite)>(UNI_QUEUE);
+        let task = tokio::spawn(async move {
+            let session_id = session.session_id();
+            while let Some((kind, write)) = queued.recv().await {
+                match session.open_uni(session_id).await {
+                    Ok(stream) => {
+                        tokio::spawn(write(stream));
+                    }
+                    Err(e) => error!("Failed to open {} stream: {:?}", kind, e),
+                }
+            }
+        });
+        Self {
+            queue,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Open a uni stream and `write` on it, calling it a `kind` stream in
+    /// logs.
+    fn open<F, Fut>(&self, kind: &'static str, write: F)
+    where
+        F: FnOnce(UniStream) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let write: UniWrite = Box::new(move |stream| {
+            Box::pin(async move {
+                if let Err(e) = write(stream).await {
+                    debug!("Error on {} stream: {:?}", kind, e);
+                }
+            })
+        });
+        if self.queue.try_send((kind, write)).is_err() {
+            debug!("Dropping {} stream: {} streams waiting to open", kind, UNI_QUEUE);
+        }
+    }
+
+    /// Send `msg` on a control stream.
+    fn control(&self, msg: ControlMessage) {
+        self.open("control", move |stream| async move { send_control(stream, &msg).await });
+    }
+}
+
+/// How a session stands with the server's control messages.
+struct Control {
+    /// Whether more may arrive; none do after a GOAWAY.
+    open: bool,
+    /// When to close the session, once it was sent a GOAWAY.
+    close_at: Option<Instant>,
+}
+
+impl Control {
+    fn new() -> Self {
+        Self {
+            open: true,
+            close_at: None,
+        }
+    }
+
+    /// Forward a message from the control channel to the client. After a
+    /// GOAWAY the session is closed once [`GOAWAY_GRACE`] has elapsed.
+    fn forward(
+        &mut self,
+        msg: Result<ControlMessage, RecvError>,
+        writer: &UniWriter,
+        session_id: SessionId,
+    ) {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(RecvError::Lagged(_)) => return,
+            Err(RecvError::Closed) => {
+                self.open = false;
+                return;
+            }
+        };
+        match &msg {
+            ControlMessage::GoAway { reason } => {
+                info!("Sending GOAWAY to session {:?} ({})", session_id, reason);
+                self.close_at = Some(Instant::now() + GOAWAY_GRACE);
+                self.open = false;
+            }
+            ControlMessage::Notify { .. }
+            | ControlMessage::Session { .. }
+            | ControlMessage::Pong
+            | ControlMessage::StreamLimit { .. }
+            | ControlMessage::Roster { .. }
+            | ControlMessage::Joined(_)
+            | ControlMessage::Left(_)
+            | ControlMessage::PublicKey { .. }
+            | ControlMessage::Said { .. }
+            | ControlMessage::Ack { .. }
+            | ControlMessage::Receipt { .. } => {}
+        }
+        writer.control(msg);
+    }
+
+    /// Resolves once the GOAWAY grace period is over; never, if the session
+    /// was not sent a GOAWAY.
+    async fn closing(&self) {
+        match self.close_at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// A session that was sent a GOAWAY is closed as going away.
+    fn close_code(&self) -> CloseCode {
+        match self.close_at {
+            Some(_) => CloseCode::GoingAway,
+            None => CloseCode::NormalClosure,
+        }
+    }
+}
+
+/// Handle a WebTransport session opened at a JSON-RPC path.
+///
+/// Every client-initiated bidi stream carries newline-framed JSON-RPC and is
+/// served by `dispatcher`. After a GOAWAY the session is closed once
+/// [`GOAWAY_GRACE`] has elapsed.
+///
+/// HTTP/3 requests arriving on the connection meanwhile are passed on to
+/// `requests`. Returns the code to close the connection with.
+pub async fn handle_rpc_session(
+    session: WebTransportSession<h3_quinn::Connection, Bytes>,
+    dispatcher: Arc<Dispatcher>,
+    mut control_rx: broadcast::Receiver<ControlMessage>,
+    connection: ConnectionHandle,
+    requests: SessionRequests,
+) -> anyhow::Result<CloseCode> {
+    let session_id = session.session_id();
+    info!("WebTransport RPC session established: {:?}", session_id);
+    debug!("RPC methods: {:?}", dispatcher.methods());
+
+    let session = Arc::new(session);
+    let writer = UniWriter::start(Arc::clone(&session));
+    let mut control = Control::new();
+
+    loop {
+        tokio::select! {
+            msg = control_rx.recv(), if control.open => {
+                control.forward(msg, &writer, session_id);
+            }
+
+            _ = control.closing() => {
+                info!("Closing session {:?} after GOAWAY", session_id);
+                break;
+            }
+
+            bidi_stream = session.accept_bi() => {
+                match bidi_stream {
+                    Ok(Some(AcceptedBi::BidiStream(id, stream))) => {
+                        debug!("Accepted RPC stream: {:?}", id);
+                        let (send, recv) = BidiStream::split(stream);
+                        let dispatcher = Arc::clone(&dispatcher);
+                        tokio::spawn(async move {
+                            if let Err(e) = dispatcher.serve(recv, send).await {
+                                debug!("RPC stream error: {:?}", e);
+                            }
+                        });
+                    }
+                    Ok(Some(AcceptedBi::Request(req, stream))) => {
+                        debug!("Passing on HTTP request in session: {:?}", req.uri());
+                        let _ = requests.send((req, stream));
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("Bidi stream accept error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    info!("WebTransport RPC session ended: {:?}", session_id);
+    log_connection_stats(&connection);
+    Ok(control.close_code())
+}
+
+/// Log transport stats of a session's connection when it ends.
+fn log_connection_stats(connection: &ConnectionHandle) {
+    let stats = connection.stats();
+    debug!(
+        "Connection {}: rtt {:?}, {} packets sent, {} lost, path MTU {}",
+        connection.remote_address(),
+        connection.rtt(),
+        stats.path.sent_packets,
+        stats.path.lost_packets,
+        stats.path.current_mtu
+    );
+}
+
+/// Log loss and reordering of the sequence-numbered datagrams a session
+/// received, if it sent any.
+fn log_datagram_stats(stats: &SequenceStats) {
+    if stats.received == 0 {
+        return;
+    }
+    info!(
+        "Datagrams received: {}, lost {:.1}%, reordered {:.1}%, {} gaps",
+        stats.received,
+        stats.loss_percent(),
+        stats.reorder_percent(),
+        stats.gaps
+    );
+}
+
+/// Send a control message on a server-initiated unidirectional stream.
+async fn send_control<S>(mut stream: S, msg: &ControlMessage) -> anyhow::Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    stream.write_all(msg.encode().as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Send telemetry `samples` kept since `since` on a backfill stream.
+async fn send_backfill<S>(mut stream: S, since: u64, samples: &[Sample]) -> anyhow::Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    stream.write_all(BACKFILL_HEADER).await?;
+    stream.write_all(format!("{}\n", since).as_bytes()).await?;
+    // Names were checked when the samples arrived
+    if let Some(data) = common::telemetry::encode_samples(samples) {
+        stream.write_all(&data).await?;
+    }
+    stream.shutdown().await?;
     Ok(())
 }
 
+/// Record a telemetry datagram in `telemetry`. Returns whether it was one.
+fn on_telemetry(payload: &[u8], telemetry: &Telemetry) -> bool {
+    if payload.first() != Some(&TELEMETRY_MARKER) {
+        return false;
+    }
+    match common::telemetry::decode(payload) {
+        Some(samples) => {
+            telemetry.record(&samples);
+        }
+        None => telemetry.drop_samples(1),
+    }
+    true
+}
+
+/// Count an incoming datagram if it is sequence-numbered, and reply to it,
+/// recording both if the session is being recorded.
+fn on_datagram(
+    payload: Bytes,
+    recorder: Option<&SessionRecorder>,
+    sequence: &mut SequenceTracker,
+) -> Option<Bytes> {
+    if let Some((seq, _)) = decode_sequenced(&payload) {
+        sequence.record(seq);
+    }
+    if let Some(recorder) = recorder {
+        recorder.record(Direction::Inbound, Channel::Datagram, &payload);
+    }
+    let reply = datagram_reply(payload)?;
+    if let Some(recorder) = recorder {
+        recorder.record(Direction::Outbound, Channel::Datagram, &reply);
+    }
+    Some(reply)
+}
+
+/// Reply to an incoming datagram. Returns `None` if no reply should be sent.
+pub fn datagram_reply(payload: Bytes) -> Option<Bytes> {
+    Some(payload)
+}
+
 /// Send a welcome message on a server-initiated stream.
-async fn send_welcome<S>(mut stream: S) -> anyhow::Result<()>
+pub async fn send_welcome<S>(mut stream: S) -> anyhow::Result<()>
 where
     S: AsyncWriteExt + AsyncReadExt + Unpin,
 {
@@ -148,20 +993,22 @@ where
     Ok(())
 }
 
-/// Echo data on a unidirectional stream pair.
-async fn echo_uni<S, R>(mut send: S, mut recv: R) -> anyhow::Result<()>
+/// Echo data on a unidirectional stream pair, paced by `shaper`.
+pub async fn echo_uni<S, R>(mut send: S, mut recv: R, mut shaper: Shaper) -> anyhow::Result<()>
 where
     S: AsyncWriteExt + Unpin,
     R: AsyncReadExt + Unpin,
 {
     let mut buf = Vec::new();
     recv.read_to_end(&mut buf).await?;
+    let received = Instant::now();
 
     debug!("Echoing {} bytes on uni stream", buf.len());
 
     // Send back in chunks to demonstrate streaming
     for chunk in buf.chunks(64) {
         tokio::time::sleep(Duration::from_millis(10)).await;
+        shaper.pace(received, chunk.len()).await;
         send.write_all(chunk).await?;
     }
 
@@ -169,36 +1016,67 @@ where
 }
 
 /// Echo data on a bidirectional stream.
-/// Reads messages incrementally and echoes them back immediately.
-async fn echo_bidi<S, R>(mut send: S, mut recv: R) -> anyhow::Result<()>
+/// Reads messages incrementally and echoes them back immediately, passing
+/// each received chunk straight back to the stream without copying it.
+///
+/// Echoes are paced by `shaper`, counting each chunk's delay from when it
+/// was read: reading goes on while earlier chunks wait their turn.
+pub async fn echo_bidi<S, R>(mut send: S, mut recv: R, mut shaper: Shaper) -> anyhow::Result<()>
 where
-    S: AsyncWriteExt + Unpin,
-    R: AsyncReadExt + Unpin,
+    S: ChunkWrite,
+    R: ChunkRead,
 {
-    let mut buf = [0u8; 4096];
-    
-    loop {
-        match recv.read(&mut buf).await {
-            Ok(0) => {
-                // Stream closed
-                debug!("Bidi stream closed by client");
-                break;
+    let (tx, mut rx) = mpsc::channel::<(Instant, Bytes)>(16);
+    let read = async move {
+        loop {
+            match chunks::read_chunk(&mut recv).await {
+                Ok(None) => {
+                    // Stream closed
+                    debug!("Bidi stream closed by client");
+                    break;
+                }
+                Ok(Some(data)) => {
+                    if tx.send((Instant::now(), data)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    debug!("Bidi stream read error: {:?}", e);
+                    break;
+                }
             }
-            Ok(n) => {
-                let data = &buf[..n];
-                debug!("Echoing {} bytes on bidi stream: {:?}", n, String::from_utf8_lossy(data));
-                
-                // Echo back immediately with prefix
-                send.write_all(b"[echo] ").await?;
-                send.write_all(data).await?;
-                send.flush().await?;
-            }
-            Err(e) => {
-                debug!("Bidi stream read error: {:?}", e);
-                break;
+        }
+    };
+    let write = async move {
+        while let Some((received, data)) = rx.recv().await {
+            let n = data.len();
+            debug!("Echoing {} bytes on bidi stream: {:?}", n, String::from_utf8_lossy(&data));
+
+            // Echo back immediately with prefix
+            let prefix = Bytes::from_static(b"[echo] ");
+            shaper.pace(received, prefix.len()).await;
+            chunks::write_chunk(&mut send, prefix).await?;
+            for piece in shaper.pieces(data) {
+                shaper.pace(received, piece.len()).await;
+                chunks::write_chunk(&mut send, piece).await?;
             }
         }
+        anyhow::Ok(())
+    };
+
+    // A failed write ends the echo without waiting for the client
+    tokio::pin!(write);
+    tokio::select! {
+        written = &mut write => written,
+        () = read => write.await,
     }
+}
 
+/// Read `stream` to the end into `buf`.
+async fn read_to_end<R>(stream: &mut R, buf: &mut BytesMut) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+{
+    while stream.read_buf(buf).await? != 0 {}
     Ok(())
 }
@@ -0,0 +1,76 @@
+//! The web client, served by the server itself with the `web-ui` feature.
+//!
+//! `crates/web/dist`, as `trunk build` leaves it, is built into the binary,
+//! and [`routes`] serves each of its files at its own path, with
+//! `index.html` at `/` as well. One binary then runs both the demo UI and
+//! the WebTransport backend. Run `trunk build --release` in `crates/web`
+//! before building the server; changes to the client need a rebuild of the
+//! server too.
+
+use crate::router::Router;
+use bytes::Bytes;
+use h3::server::RequestStream;
+use http::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{Request, Response, StatusCode};
+use include_dir::{include_dir, Dir, File};
+
+static DIST: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/../web/dist");
+
+/// Add a route to `router` for each file of the web client.
+pub fn routes(mut router: Router) -> Router {
+    let mut files = Vec::new();
+    collect(&DIST, &mut files);
+    for file in files {
+        let path = format!("/{}", file.path().display());
+        if path == "/index.html" {
+            router = router.stream("/", move |req, stream| serve(req, stream, file));
+        }
+        router = router.stream(&path, move |req, stream| serve(req, stream, file));
+    }
+    router
+}
+
+/// Every file in `dir` and the directories in it.
+fn collect(dir: &'static Dir<'static>, files: &mut Vec<&'static File<'static>>) {
+    files.extend(dir.files());
+    for dir in dir.dirs() {
+        collect(dir, files);
+    }
+}
+
+async fn serve(
+    _req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    file: &'static File<'static>,
+) -> anyhow::Result<()> {
+    // Trunk names every other file after a hash of its contents
+    let cache = if file.path().ends_with("index.html") {
+        "no-cache"
+    } else {
+        "public, max-age=31536000, immutable"
+    };
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type(file))
+        .header(CONTENT_LENGTH, file.contents().len())
+        .header(CACHE_CONTROL, cache)
+        .body(())?;
+    stream.send_response(response).await?;
+    stream.send_data(Bytes::from_static(file.contents())).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+fn content_type(file: &File<'_>) -> &'static str {
+    match file.path().extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,214 @@
+//! JavaScript and TypeScript bindings.
+//!
+//! [`WtClient`], [`WtStream`] and [`WtRecvStream`] export the transport to
+//! apps that are not written in Rust. `wasm-pack build` generates the
+//! module with its `.d.ts` typings; every async method returns a promise,
+//! rejected with the browser's error or a message.
+//!
+//! ```ts
+//! import init, { WtClient } from "web-transport-wasm";
+//!
+//! await init();
+//! const client = await WtClient.connect("https://localhost:4433/webtransport", {
+//!     serverCertificateHashes: [hash],
+//!     timeoutMs: 10_000,
+//! });
+//! const stream = await client.openStream();
+//! await stream.send(new TextEncoder().encode("hello"));
+//! const echo: Uint8Array = await stream.recv();
+//! ```
+
+use crate::{BidiStream, CallOptions, CloseInfo, RecvStream, WebTransportClient};
+use common::CloseCode;
+use js_sys::{Array, Uint8Array};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_CONNECT_OPTIONS: &str = r#"
+/** Options for `WtClient.connect`. */
+export interface WtConnectOptions {
+    /** SHA-256 hashes of self-signed server certificates to accept. */
+    serverCertificateHashes?: Uint8Array[];
+    /** Give up connecting after this many milliseconds. */
+    timeoutMs?: number;
+    /**
+     * Share an HTTP/3 connection the browser already has to the server.
+     * Not with `serverCertificateHashes`.
+     */
+    allowPooling?: boolean;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    /// Options for [`WtClient::connect`], a plain JavaScript object.
+    #[wasm_bindgen(typescript_type = "WtConnectOptions")]
+    pub type WtConnectOptions;
+
+    #[wasm_bindgen(method, getter, js_name = serverCertificateHashes)]
+    fn server_certificate_hashes(this: &WtConnectOptions) -> Option<Array>;
+
+    #[wasm_bindgen(method, getter, js_name = timeoutMs)]
+    fn timeout_ms(this: &WtConnectOptions) -> Option<f64>;
+
+    #[wasm_bindgen(method, getter, js_name = allowPooling)]
+    fn allow_pooling(this: &WtConnectOptions) -> Option<bool>;
+}
+
+/// A WebTransport session.
+#[wasm_bindgen]
+pub struct WtClient {
+    inner: WebTransportClient,
+}
+
+#[wasm_bindgen]
+impl WtClient {
+    /// Connect to a WebTransport server.
+    pub async fn connect(
+        url: String,
+        options: Option<WtConnectOptions>,
+    ) -> Result<WtClient, JsValue> {
+        let mut hashes = Vec::new();
+        let mut call = CallOptions::new();
+        if let Some(options) = options {
+            if let Some(array) = options.server_certificate_hashes() {
+                for hash in array.iter() {
+                    hashes.push(hash.dyn_into::<Uint8Array>()?.to_vec());
+                }
+            }
+            if let Some(millis) = options.timeout_ms() {
+                call = call.with_timeout(Duration::from_millis(millis as u64));
+            }
+            call = call.with_pooling(options.allow_pooling().unwrap_or(false));
+        }
+        let inner = WebTransportClient::connect_with_options(&url, &hashes, &call).await?;
+        Ok(Self { inner })
+    }
+
+    /// Whether the transport supports datagrams; without them, datagrams
+    /// go over a stream in each direction.
+    #[wasm_bindgen(getter, js_name = supportsDatagrams)]
+    pub fn supports_datagrams(&self) -> bool {
+        self.inner.supports_datagrams()
+    }
+
+    /// Open a bidirectional stream.
+    #[wasm_bindgen(js_name = openStream)]
+    pub async fn open_stream(&self) -> Result<WtStream, JsValue> {
+        let inner = self.inner.open_bidi_stream().await?;
+        Ok(WtStream { inner })
+    }
+
+    /// Send `data` on a new unidirectional stream and finish it.
+    #[wasm_bindgen(js_name = sendUni)]
+    pub async fn send_uni(&self, data: Vec<u8>) -> Result<(), JsValue> {
+        self.inner.send_uni(&data).await
+    }
+
+    /// Accept the next unidirectional stream the server opens.
+    #[wasm_bindgen(js_name = acceptUni)]
+    pub async fn accept_uni(&self) -> Result<WtRecvStream, JsValue> {
+        let inner = self.inner.accept_uni().await?;
+        Ok(WtRecvStream { inner })
+    }
+
+    #[wasm_bindgen(js_name = sendDatagram)]
+    pub async fn send_datagram(&self, data: Vec<u8>) -> Result<(), JsValue> {
+        self.inner.send_datagram(&data).await
+    }
+
+    #[wasm_bindgen(js_name = recvDatagram)]
+    pub async fn recv_datagram(&self) -> Result<Vec<u8>, JsValue> {
+        self.inner.recv_datagram().await
+    }
+
+    /// Close the transport at once.
+    pub fn close(&self) {
+        self.inner.close();
+    }
+
+    /// Finish the streams this client opened, then close with `code`
+    /// (1000, a normal closure, by default) and `reason`, and wait for the
+    /// transport to report it closed.
+    #[wasm_bindgen(js_name = closeGracefully)]
+    pub async fn close_gracefully(
+        &self,
+        code: Option<u32>,
+        reason: Option<String>,
+    ) -> Result<WtCloseInfo, JsValue> {
+        let code = match code {
+            Some(code) => CloseCode::from_code(code)
+                .ok_or_else(|| JsValue::from_str(&format!("Unknown close code {}", code)))?,
+            None => CloseCode::NormalClosure,
+        };
+        let reason = reason.unwrap_or_else(|| code.reason().to_string());
+        Ok(self.inner.close_gracefully(code, &reason).await?.into())
+    }
+
+    /// Wait for the transport to close. Rejects if the connection was lost
+    /// rather than closed.
+    pub async fn closed(&self) -> Result<WtCloseInfo, JsValue> {
+        Ok(self.inner.closed().await?.into())
+    }
+}
+
+/// A bidirectional stream.
+#[wasm_bindgen]
+pub struct WtStream {
+    inner: BidiStream,
+}
+
+#[wasm_bindgen]
+impl WtStream {
+    pub async fn send(&self, data: Vec<u8>) -> Result<(), JsValue> {
+        self.inner.send(&data).await
+    }
+
+    /// The next chunk; rejects once the server finishes the stream.
+    pub async fn recv(&self) -> Result<Vec<u8>, JsValue> {
+        self.inner.recv().await
+    }
+
+    /// Finish the send side of the stream.
+    #[wasm_bindgen(js_name = closeSend)]
+    pub async fn close_send(&self) -> Result<(), JsValue> {
+        self.inner.close_send().await
+    }
+}
+
+/// A unidirectional stream opened by the server.
+#[wasm_bindgen]
+pub struct WtRecvStream {
+    inner: RecvStream,
+}
+
+#[wasm_bindgen]
+impl WtRecvStream {
+    /// The next chunk, or `undefined` once the server finishes the stream.
+    pub async fn read(&self) -> Result<Option<Vec<u8>>, JsValue> {
+        self.inner.read().await
+    }
+
+    /// Everything up to the end of the stream.
+    #[wasm_bindgen(js_name = readToEnd)]
+    pub async fn read_to_end(&self) -> Result<Vec<u8>, JsValue> {
+        self.inner.read_to_end().await
+    }
+}
+
+/// How the transport closed.
+#[wasm_bindgen(getter_with_clone)]
+pub struct WtCloseInfo {
+    pub code: u32,
+    pub reason: String,
+}
+
+impl From<CloseInfo> for WtCloseInfo {
+    fn from(info: CloseInfo) -> Self {
+        Self {
+            code: info.code,
+            reason: info.reason,
+        }
+    }
+}
@@ -0,0 +1,1076 @@
+//! WebTransport wrapper for browser API using JavaScript interop.
+//!
+//! Since WebTransport is a relatively new API, we use direct JS interop
+//! rather than web-sys bindings which may not be complete.
+//!
+//! This crate is only the transport: the demo UI lives in the `web` crate,
+//! which re-exports it. Pages that embed WebTransport without the demo
+//! depend on this crate directly and leave Leptos and the end-to-end
+//! encryption out of their bundle. JavaScript and TypeScript apps use the
+//! classes in [`bindings`]. Outside the browser, see [`set_web_transport`].
+
+pub mod bindings;
+mod runtime;
+
+pub use bindings::{WtClient, WtCloseInfo, WtRecvStream, WtStream};
+pub use runtime::{is_supported, set_web_transport};
+
+use common::{ClientControl, CloseCode};
+use common::datagram::{
+    FALLBACK_HEADER, FrameDecoder, SequenceStats, SequenceTracker, decode_sequenced,
+    encode_frame, encode_sequenced,
+};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::lock::Mutex;
+use futures::StreamExt;
+use js_sys::{Array, Function, Object, Promise, Uint8Array};
+use std::fmt;
+use std::future::Future;
+use std::cell::{Cell, RefCell};
+use std::pin::pin;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AbortController, AbortSignal};
+
+// Import WebTransport from JavaScript
+#[wasm_bindgen]
+extern "C" {
+    /// WebTransport interface, constructed by [`runtime::construct`]
+    #[wasm_bindgen(js_name = WebTransport)]
+    pub type WebTransport;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn ready(this: &WebTransport) -> Promise;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn closed(this: &WebTransport) -> Promise;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn datagrams(this: &WebTransport) -> DatagramDuplex;
+
+    #[wasm_bindgen(method, js_name = createBidirectionalStream)]
+    pub fn create_bidirectional_stream(this: &WebTransport) -> Promise;
+
+    #[wasm_bindgen(method, js_name = createUnidirectionalStream)]
+    pub fn create_unidirectional_stream(this: &WebTransport) -> Promise;
+
+    #[wasm_bindgen(method, getter, js_name = incomingUnidirectionalStreams)]
+    pub fn incoming_unidirectional_streams(this: &WebTransport) -> ReadableStream;
+
+    #[wasm_bindgen(method)]
+    pub fn close(this: &WebTransport);
+
+    /// Close with a `WebTransportCloseInfo` (`closeCode` and `reason`).
+    #[wasm_bindgen(method, js_name = close)]
+    pub fn close_with_info(this: &WebTransport, info: &Object);
+
+    /// WebTransportBidirectionalStream interface
+    #[wasm_bindgen(js_name = WebTransportBidirectionalStream)]
+    pub type BidiStreamJs;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn readable(this: &BidiStreamJs) -> ReadableStream;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn writable(this: &BidiStreamJs) -> WritableStream;
+
+    /// WebTransportDatagramDuplexStream
+    pub type DatagramDuplex;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn readable(this: &DatagramDuplex) -> ReadableStream;
+
+    #[wasm_bindgen(method, getter)]
+    pub fn writable(this: &DatagramDuplex) -> WritableStream;
+
+    /// ReadableStream
+    pub type ReadableStream;
+
+    #[wasm_bindgen(method, js_name = getReader)]
+    pub fn get_reader(this: &ReadableStream) -> ReadableStreamReader;
+
+    /// ReadableStreamDefaultReader
+    pub type ReadableStreamReader;
+
+    #[wasm_bindgen(method)]
+    pub fn read(this: &ReadableStreamReader) -> Promise;
+
+    #[wasm_bindgen(method, js_name = releaseLock)]
+    pub fn release_lock(this: &ReadableStreamReader);
+
+    /// WritableStream
+    pub type WritableStream;
+
+    #[wasm_bindgen(method, js_name = getWriter)]
+    pub fn get_writer(this: &WritableStream) -> WritableStreamWriter;
+
+    #[wasm_bindgen(method)]
+    pub fn close(this: &WritableStream) -> Promise;
+
+    /// Whether a writer currently holds the stream.
+    #[wasm_bindgen(method, getter)]
+    pub fn locked(this: &WritableStream) -> bool;
+
+    /// WritableStreamDefaultWriter
+    pub type WritableStreamWriter;
+
+    #[wasm_bindgen(method)]
+    pub fn write(this: &WritableStreamWriter, chunk: &JsValue) -> Promise;
+
+    #[wasm_bindgen(method)]
+    pub fn abort(this: &WritableStreamWriter) -> Promise;
+
+    /// Resolves when the stream's queue drops below its high-water mark.
+    #[wasm_bindgen(method, getter)]
+    pub fn ready(this: &WritableStreamWriter) -> Promise;
+
+    /// Bytes that can be queued before the stream applies backpressure.
+    #[wasm_bindgen(method, getter, js_name = desiredSize)]
+    pub fn desired_size(this: &WritableStreamWriter) -> Option<f64>;
+
+    #[wasm_bindgen(method, js_name = releaseLock)]
+    pub fn release_lock(this: &WritableStreamWriter);
+
+    #[wasm_bindgen(method)]
+    pub fn close(this: &WritableStreamWriter) -> Promise;
+}
+
+/// WebTransport client wrapper.
+pub struct WebTransportClient {
+    transport: Rc<WebTransport>,
+    /// Bidi streams opened by this client, finished by a graceful close.
+    streams: Rc<RefCell<Vec<Weak<BidiStreamJs>>>>,
+    fallback: Rc<Fallback>,
+    /// Sequence number of the next sequenced datagram sent.
+    next_seq: Rc<Cell<u32>>,
+    /// Sequenced datagrams received.
+    received: Rc<RefCell<SequenceTracker>>,
+    /// When the server was last heard from, in ms since the epoch.
+    last_heard: Rc<Cell<f64>>,
+    liveness: Option<Liveness>,
+}
+
+impl Clone for WebTransportClient {
+    fn clone(&self) -> Self {
+        Self {
+            transport: Rc::clone(&self.transport),
+            streams: Rc::clone(&self.streams),
+            fallback: Rc::clone(&self.fallback),
+            next_seq: Rc::clone(&self.next_seq),
+            received: Rc::clone(&self.received),
+            last_heard: Rc::clone(&self.last_heard),
+            liveness: self.liveness,
+        }
+    }
+}
+
+/// How the client checks that a silent connection is still alive.
+///
+/// Every `interval` without hearing from the server, the client sends it a
+/// `PING` control message, which the server answers with a `PONG`. After
+/// `timeout` without anything from the server (a datagram, a stream or a
+/// `PONG`), the connection is considered dead, well before the browser
+/// would notice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Liveness {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Datagrams carried on fallback streams, for transports without datagram
+/// support (see [`common::datagram`]).
+struct Fallback {
+    /// Whether datagrams go through the fallback streams.
+    enabled: bool,
+    /// The outgoing fallback stream, opened by the first datagram sent.
+    writer: Mutex<Option<WritableStreamWriter>>,
+    /// Datagrams read from the server's fallback stream.
+    inbox_tx: mpsc::UnboundedSender<Vec<u8>>,
+    inbox: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+impl Fallback {
+    fn new(enabled: bool) -> Self {
+        let (inbox_tx, inbox) = mpsc::unbounded();
+        Self {
+            enabled,
+            writer: Mutex::new(None),
+            inbox_tx,
+            inbox: Mutex::new(inbox),
+        }
+    }
+}
+
+/// How the transport closed, from the `closed` promise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseInfo {
+    pub code: u32,
+    pub reason: String,
+}
+
+impl CloseInfo {
+    /// The application close code, if `code` is one.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        CloseCode::from_code(self.code)
+    }
+}
+
+impl WebTransportClient {
+    /// Connect to a WebTransport server.
+    /// 
+    /// Any `cert_hashes` provided (SHA-256 hashes of server certificates)
+    /// will be used to allow self-signed certificates. Pinning both the
+    /// current and the next certificate lets reconnects succeed across a
+    /// server certificate rotation.
+    pub async fn connect(url: &str, cert_hashes: &[Vec<u8>]) -> Result<Self, JsValue> {
+        let transport = Self::create(url, cert_hashes, false)?;
+
+        // Wait for the connection to be ready
+        JsFuture::from(transport.ready()).await?;
+
+        Self::from_transport(transport, None).await
+    }
+
+    /// Connect like [`connect`](Self::connect), giving up and closing the
+    /// half-open transport if `token` is cancelled first.
+    pub async fn connect_with_cancel(
+        url: &str,
+        cert_hashes: &[Vec<u8>],
+        token: &CancelToken,
+    ) -> Result<Self, JsValue> {
+        let options = CallOptions::new().with_cancel(token.clone());
+        Ok(Self::connect_with_options(url, cert_hashes, &options).await?)
+    }
+
+    /// Connect like [`connect`](Self::connect), giving up and closing the
+    /// half-open transport on timeout or cancellation. With
+    /// [`CallOptions::with_liveness`], [`unresponsive`](Self::unresponsive)
+    /// watches the connection; with [`CallOptions::with_pooling`], the
+    /// session may share one.
+    pub async fn connect_with_options(
+        url: &str,
+        cert_hashes: &[Vec<u8>],
+        options: &CallOptions,
+    ) -> Result<Self, TransportError> {
+        let transport = Self::create(url, cert_hashes, options.pooling)?;
+        options
+            .run(JsFuture::from(transport.ready()), || transport.close())
+            .await?;
+
+        Ok(Self::from_transport(transport, options.liveness).await?)
+    }
+
+    async fn from_transport(
+        transport: WebTransport,
+        liveness: Option<Liveness>,
+    ) -> Result<Self, JsValue> {
+        let fallback = Fallback::new(!has_datagrams(&transport));
+        let client = Self {
+            transport: Rc::new(transport),
+            streams: Rc::new(RefCell::new(Vec::new())),
+            fallback: Rc::new(fallback),
+            next_seq: Rc::new(Cell::new(0)),
+            received: Rc::new(RefCell::new(SequenceTracker::new())),
+            last_heard: Rc::new(Cell::new(js_sys::Date::now())),
+            liveness,
+        };
+        // The server's HTTP/3 stack only takes up a stream once more data
+        // follows its header, so the fallback stream is opened ahead of the
+        // first datagram rather than with it
+        if client.fallback.enabled {
+            let mut writer = client.fallback.writer.lock().await;
+            *writer = Some(client.open_fallback().await?);
+        }
+        Ok(client)
+    }
+
+    /// Whether the transport supports datagrams. Without them, the
+    /// datagram methods transparently use a fallback stream in each
+    /// direction, which [`accept_uni`](Self::accept_uni) picks up.
+    pub fn supports_datagrams(&self) -> bool {
+        !self.fallback.enabled
+    }
+
+    fn create(url: &str, cert_hashes: &[Vec<u8>], pooling: bool) -> Result<WebTransport, JsValue> {
+        if pooling {
+            if !cert_hashes.is_empty() {
+                return Err(JsValue::from_str("Pooled connections cannot pin certificates"));
+            }
+            let options = Object::new();
+            js_sys::Reflect::set(&options, &"allowPooling".into(), &true.into())?;
+            return runtime::construct(url, Some(&options));
+        }
+        let transport = if !cert_hashes.is_empty() {
+            // Create options with serverCertificateHashes for self-signed certs
+            let options = Object::new();
+            let hashes = Array::new();
+            
+            for hash in cert_hashes {
+                let hash_obj = Object::new();
+                js_sys::Reflect::set(&hash_obj, &"algorithm".into(), &"sha-256".into())?;
+
+                let hash_array = Uint8Array::from(hash.as_slice());
+                js_sys::Reflect::set(&hash_obj, &"value".into(), &hash_array.buffer())?;
+
+                hashes.push(&hash_obj);
+            }
+            js_sys::Reflect::set(&options, &"serverCertificateHashes".into(), &hashes)?;
+            
+            runtime::construct(url, Some(&options))?
+        } else {
+            runtime::construct(url, None)?
+        };
+        Ok(transport)
+    }
+
+    /// Open a bidirectional stream.
+    pub async fn open_bidi_stream(&self) -> Result<BidiStream, JsValue> {
+        let promise = self.transport.create_bidirectional_stream();
+        let stream: BidiStreamJs = JsFuture::from(promise).await?.unchecked_into();
+        Ok(self.track(BidiStream::new(stream)))
+    }
+
+    /// Open a bidirectional stream, giving up on timeout or cancellation.
+    pub async fn open_bidi_stream_with_options(
+        &self,
+        options: &CallOptions,
+    ) -> Result<BidiStream, TransportError> {
+        let promise = self.transport.create_bidirectional_stream();
+        let stream: BidiStreamJs = options
+            .run(JsFuture::from(promise), || {})
+            .await?
+            .unchecked_into();
+        Ok(self.track(BidiStream::new(stream)))
+    }
+
+    fn track(&self, stream: BidiStream) -> BidiStream {
+        let mut streams = self.streams.borrow_mut();
+        streams.retain(|s| s.strong_count() > 0);
+        streams.push(Rc::downgrade(&stream.stream));
+        stream
+    }
+
+    /// Resolve once the server has been silent for the liveness timeout,
+    /// pinging it in the meantime; never resolves if the client was
+    /// connected without [`CallOptions::with_liveness`]. Replies are only
+    /// noticed while [`accept_uni`](Self::accept_uni) is being called.
+    pub async fn unresponsive(&self) -> Duration {
+        let Some(liveness) = self.liveness else {
+            return future::pending().await;
+        };
+        loop {
+            gloo_timers::future::sleep(liveness.interval).await;
+            let silent = Duration::from_millis(self.silence_ms() as u64);
+            if silent >= liveness.timeout {
+                return silent;
+            }
+            if silent >= liveness.interval {
+                // Failures show up as silence
+                let _ = self.send_uni(ClientControl::Ping.encode().as_bytes()).await;
+            }
+        }
+    }
+
+    /// Milliseconds since the server was last heard from.
+    fn silence_ms(&self) -> f64 {
+        (js_sys::Date::now() - self.last_heard.get()).max(0.0)
+    }
+
+    fn heard(&self) {
+        self.last_heard.set(js_sys::Date::now());
+    }
+
+    /// Send `data` on a new unidirectional stream and finish it.
+    pub async fn send_uni(&self, data: &[u8]) -> Result<(), JsValue> {
+        let promise = self.transport.create_unidirectional_stream();
+        let stream: WritableStream = JsFuture::from(promise).await?.unchecked_into();
+        let writer = stream.get_writer();
+
+        let array = Uint8Array::from(data);
+        JsFuture::from(writer.write(&array.into())).await?;
+        JsFuture::from(writer.close()).await?;
+        Ok(())
+    }
+
+    /// Accept the next server-initiated unidirectional stream.
+    ///
+    /// A datagram fallback stream from the server is not returned; its
+    /// datagrams are read in the background for
+    /// [`recv_datagram`](Self::recv_datagram).
+    pub async fn accept_uni(&self) -> Result<RecvStream, JsValue> {
+        loop {
+            let incoming = self.transport.incoming_unidirectional_streams();
+            let reader = incoming.get_reader();
+
+            let result = JsFuture::from(reader.read()).await?;
+            reader.release_lock();
+
+            let done = js_sys::Reflect::get(&result, &"done".into())?;
+            if done.as_bool().unwrap_or(false) {
+                return Err(JsValue::from_str("Transport closed"));
+            }
+
+            self.heard();
+            let value = js_sys::Reflect::get(&result, &"value".into())?;
+            let stream: ReadableStream = value.unchecked_into();
+            let stream = RecvStream {
+                reader: stream.get_reader(),
+                pending: RefCell::new(Vec::new()),
+            };
+            if !stream.starts_with(FALLBACK_HEADER).await? {
+                return Ok(stream);
+            }
+            let inbox = self.fallback.inbox_tx.clone();
+            wasm_bindgen_futures::spawn_local(read_fallback(stream, inbox));
+        }
+    }
+
+    /// Send a datagram. Without datagram support it is sent on the
+    /// fallback stream, or dropped while that stream is backed up.
+    pub async fn send_datagram(&self, data: &[u8]) -> Result<(), JsValue> {
+        if self.fallback.enabled {
+            return self.send_fallback(data).await;
+        }
+        let datagrams = self.transport.datagrams();
+        let writable = datagrams.writable();
+        let writer = writable.get_writer();
+
+        let array = Uint8Array::from(data);
+        JsFuture::from(writer.write(&array.into())).await?;
+        writer.release_lock();
+
+        Ok(())
+    }
+
+    /// Receive a datagram.
+    pub async fn recv_datagram(&self) -> Result<Vec<u8>, JsValue> {
+        let datagram = if self.fallback.enabled {
+            self.recv_fallback().await?
+        } else {
+            let datagrams = self.transport.datagrams();
+            let readable = datagrams.readable();
+            let reader = readable.get_reader();
+
+            let result = JsFuture::from(reader.read()).await;
+            reader.release_lock();
+
+            read_datagram(result?)?
+        };
+        self.heard();
+        self.count_sequenced(&datagram);
+        Ok(datagram)
+    }
+
+    /// Receive a datagram, giving up on timeout or cancellation.
+    pub async fn recv_datagram_with_options(
+        &self,
+        options: &CallOptions,
+    ) -> Result<Vec<u8>, TransportError> {
+        let datagram = if self.fallback.enabled {
+            options.run(self.recv_fallback(), || {}).await?
+        } else {
+            let datagrams = self.transport.datagrams();
+            let readable = datagrams.readable();
+            let reader = readable.get_reader();
+
+            let result = options.run(JsFuture::from(reader.read()), || {}).await;
+            // Also rejects a read left pending by a timeout
+            reader.release_lock();
+
+            read_datagram(result?)?
+        };
+        self.heard();
+        self.count_sequenced(&datagram);
+        Ok(datagram)
+    }
+
+    /// Send `payload` as a datagram with the next sequence number (see
+    /// [`common::datagram::encode_sequenced`]), returning the number.
+    pub async fn send_sequenced_datagram(&self, payload: &[u8]) -> Result<u32, JsValue> {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq.wrapping_add(1));
+        self.send_datagram(&encode_sequenced(seq, payload)).await?;
+        Ok(seq)
+    }
+
+    /// Loss, reordering and gaps among the sequenced datagrams received,
+    /// e.g. the server's echoes of those sent with
+    /// [`send_sequenced_datagram`](Self::send_sequenced_datagram).
+    pub fn datagram_stats(&self) -> SequenceStats {
+        self.received.borrow().stats()
+    }
+
+    fn count_sequenced(&self, datagram: &[u8]) {
+        if let Some((seq, _)) = decode_sequenced(datagram) {
+            self.received.borrow_mut().record(seq);
+        }
+    }
+
+    /// Send a datagram on the fallback stream, opening it first if needed.
+    async fn send_fallback(&self, data: &[u8]) -> Result<(), JsValue> {
+        let frame = encode_frame(data).ok_or_else(|| JsValue::from_str("Datagram too large"))?;
+        let mut writer = self.fallback.writer.lock().await;
+        if writer.is_none() {
+            *writer = Some(self.open_fallback().await?);
+        }
+        let writer = writer.as_ref().expect("fallback stream was opened");
+
+        // Lost, as a datagram would be on a congested path
+        if writer.desired_size().is_some_and(|size| size <= 0.0) {
+            return Ok(());
+        }
+        let array = Uint8Array::from(frame.as_slice());
+        JsFuture::from(writer.write(&array.into())).await?;
+        Ok(())
+    }
+
+    /// Open the outgoing fallback stream and write its header.
+    async fn open_fallback(&self) -> Result<WritableStreamWriter, JsValue> {
+        let promise = self.transport.create_unidirectional_stream();
+        let stream: WritableStream = JsFuture::from(promise).await?.unchecked_into();
+        let writer = stream.get_writer();
+        let header = Uint8Array::from(FALLBACK_HEADER);
+        JsFuture::from(writer.write(&header.into())).await?;
+        Ok(writer)
+    }
+
+    /// Receive a datagram from the server's fallback stream.
+    async fn recv_fallback(&self) -> Result<Vec<u8>, JsValue> {
+        let mut inbox = self.fallback.inbox.lock().await;
+        inbox
+            .next()
+            .await
+            .ok_or_else(|| JsValue::from_str("Transport closed"))
+    }
+
+    /// Close the transport.
+    pub fn close(&self) {
+        self.transport.close();
+    }
+
+    /// Close the transport gracefully.
+    ///
+    /// Finishes the send side of every bidi stream this client opened that
+    /// is still alive, waiting for queued writes to flush (streams with a
+    /// write in progress are left to the transport close), then closes with
+    /// `code` and `reason` and waits for the transport to report it closed.
+    /// Browsers limit `reason` to 1024 bytes.
+    pub async fn close_gracefully(
+        &self,
+        code: CloseCode,
+        reason: &str,
+    ) -> Result<CloseInfo, JsValue> {
+        let streams: Vec<BidiStream> = self
+            .streams
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|stream| stream.upgrade())
+            .map(|stream| BidiStream { stream })
+            .filter(|stream| !stream.stream.writable().locked())
+            .collect();
+        // Streams the server already closed fail here; that's fine
+        future::join_all(streams.iter().map(BidiStream::close_send)).await;
+
+        let info = Object::new();
+        js_sys::Reflect::set(&info, &"closeCode".into(), &code.code().into())?;
+        js_sys::Reflect::set(&info, &"reason".into(), &reason.into())?;
+        self.transport.close_with_info(&info);
+
+        self.closed().await
+    }
+
+    /// Wait for the transport to close and report how it closed. Fails if
+    /// the connection was lost rather than closed.
+    pub async fn closed(&self) -> Result<CloseInfo, JsValue> {
+        let closed = JsFuture::from(self.transport.closed()).await?;
+        let code = js_sys::Reflect::get(&closed, &"closeCode".into())?;
+        let reason = js_sys::Reflect::get(&closed, &"reason".into())?;
+        Ok(CloseInfo {
+            code: code.as_f64().unwrap_or_default() as u32,
+            reason: reason.as_string().unwrap_or_default(),
+        })
+    }
+}
+
+/// Cancels pending transport operations, e.g. when the user disconnects.
+///
+/// Backed by an `AbortController`: cancelling aborts the JS promise or
+/// reader an operation is waiting on instead of leaving it pending. Clones
+/// share the same cancellation.
+#[derive(Clone)]
+pub struct CancelToken {
+    controller: AbortController,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self {
+            controller: AbortController::new().expect("AbortController is supported"),
+        }
+    }
+
+    /// Cancel every operation using this token, now and in the future.
+    pub fn cancel(&self) {
+        self.controller.abort();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.controller.signal().aborted()
+    }
+
+    /// The underlying signal, for passing to other JS APIs.
+    pub fn signal(&self) -> AbortSignal {
+        self.controller.signal()
+    }
+}
+
+/// Error from a transport call made with [`CallOptions`].
+#[derive(Debug, Clone)]
+pub enum TransportError {
+    /// The call did not complete within its timeout.
+    Timeout,
+    /// The call's [`CancelToken`] was cancelled.
+    Cancelled,
+    /// The browser API failed.
+    Js(JsValue),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Timeout => write!(f, "Timed out"),
+            TransportError::Cancelled => write!(f, "Cancelled"),
+            TransportError::Js(e) => match e.as_string() {
+                Some(message) => write!(f, "{}", message),
+                None => write!(f, "{:?}", e),
+            },
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<JsValue> for TransportError {
+    fn from(e: JsValue) -> Self {
+        TransportError::Js(e)
+    }
+}
+
+impl From<TransportError> for JsValue {
+    fn from(e: TransportError) -> Self {
+        match e {
+            TransportError::Js(e) => e,
+            e => JsValue::from_str(&e.to_string()),
+        }
+    }
+}
+
+/// Timeout and cancellation for transport calls.
+///
+/// # Example
+/// ```ignore
+/// let options = CallOptions::new()
+///     .with_timeout(Duration::from_secs(10))
+///     .with_cancel(token.clone());
+/// let client = WebTransportClient::connect_with_options(url, &hashes, &options).await?;
+/// ```
+#[derive(Clone, Default)]
+pub struct CallOptions {
+    timeout: Option<Duration>,
+    cancel: Option<CancelToken>,
+    liveness: Option<Liveness>,
+    pooling: bool,
+}
+
+impl CallOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail calls that take longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fail calls when `token` is cancelled.
+    pub fn with_cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Watch connections made with these options for silence (see
+    /// [`WebTransportClient::unresponsive`]). Other calls ignore it.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = Some(liveness);
+        self
+    }
+
+    /// Let the browser carry connections made with these options over an
+    /// HTTP/3 connection it already has to the server, shared with other
+    /// sessions (`allowPooling`). Other calls ignore it.
+    ///
+    /// A pooled session shares congestion control, and the server's
+    /// limits, with the others, and closing it leaves the connection up.
+    /// Browsers only pool connections to servers with publicly trusted
+    /// certificates, so this cannot be combined with certificate hashes.
+    pub fn with_pooling(mut self, allow: bool) -> Self {
+        self.pooling = allow;
+        self
+    }
+
+    /// Run `op` until it completes, times out or is cancelled. In the
+    /// latter cases `abort` is called to release the JS side of the
+    /// operation.
+    async fn run<T>(
+        &self,
+        op: impl Future<Output = Result<T, JsValue>>,
+        abort: impl FnOnce(),
+    ) -> Result<T, TransportError> {
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            abort();
+            return Err(TransportError::Cancelled);
+        }
+
+        let listener = self
+            .cancel
+            .as_ref()
+            .map(|token| AbortListener::new(token.signal()))
+            .transpose()?;
+        let cancelled = async move {
+            match listener {
+                Some(mut listener) => {
+                    let _ = (&mut listener.aborted).await;
+                }
+                None => future::pending().await,
+            }
+        };
+        let timeout = self.timeout;
+        let timed_out = async move {
+            match timeout {
+                Some(timeout) => gloo_timers::future::sleep(timeout).await,
+                None => future::pending().await,
+            }
+        };
+
+        let (cancelled, timed_out) = (pin!(cancelled), pin!(timed_out));
+        match future::select(pin!(op), future::select(cancelled, timed_out)).await {
+            Either::Left((result, _)) => Ok(result?),
+            Either::Right((stopped, _)) => {
+                abort();
+                Err(match stopped {
+                    Either::Left(_) => TransportError::Cancelled,
+                    Either::Right(_) => TransportError::Timeout,
+                })
+            }
+        }
+    }
+}
+
+/// A promise resolved by a signal's `abort` event. The event listener is
+/// removed when dropped so long-lived tokens don't accumulate listeners.
+struct AbortListener {
+    signal: AbortSignal,
+    resolve: Function,
+    aborted: JsFuture,
+}
+
+impl AbortListener {
+    fn new(signal: AbortSignal) -> Result<Self, JsValue> {
+        let mut resolve = None;
+        let promise = Promise::new(&mut |res, _| resolve = Some(res));
+        let resolve = resolve.expect("Promise executor runs synchronously");
+        signal.add_event_listener_with_callback("abort", &resolve)?;
+        Ok(Self {
+            signal,
+            resolve,
+            aborted: JsFuture::from(promise),
+        })
+    }
+}
+
+impl Drop for AbortListener {
+    fn drop(&mut self) {
+        let _ = self
+            .signal
+            .remove_event_listener_with_callback("abort", &self.resolve);
+    }
+}
+
+/// Size of the chunks written by [`write_with_progress`].
+const PROGRESS_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Progress of a send.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Bytes accepted by the stream so far.
+    pub sent: usize,
+    /// Total bytes to send.
+    pub total: usize,
+    /// Bytes the stream can take before applying backpressure, if known.
+    pub desired_size: Option<f64>,
+}
+
+/// Write `data` in chunks, waiting for the writer to become ready before
+/// each one so `on_progress` follows flow control rather than the rate at
+/// which chunks are queued.
+async fn write_with_progress(
+    writer: &WritableStreamWriter,
+    data: &[u8],
+    mut on_progress: impl FnMut(Progress),
+) -> Result<(), JsValue> {
+    let total = data.len();
+    let mut sent = 0;
+    for chunk in data.chunks(PROGRESS_CHUNK_SIZE) {
+        JsFuture::from(writer.ready()).await?;
+        let array = Uint8Array::from(chunk);
+        JsFuture::from(writer.write(&array.into())).await?;
+        sent += chunk.len();
+        on_progress(Progress {
+            sent,
+            total,
+            desired_size: writer.desired_size(),
+        });
+    }
+    Ok(())
+}
+
+/// Receive-only stream wrapper for server-initiated unidirectional streams.
+pub struct RecvStream {
+    reader: ReadableStreamReader,
+    /// Bytes already read by [`starts_with`](Self::starts_with).
+    pending: RefCell<Vec<u8>>,
+}
+
+impl RecvStream {
+    /// Read the next chunk, or `None` once the server finishes the stream.
+    pub async fn read(&self) -> Result<Option<Vec<u8>>, JsValue> {
+        let pending = self.pending.take();
+        if !pending.is_empty() {
+            return Ok(Some(pending));
+        }
+        let result = JsFuture::from(self.reader.read()).await?;
+        let done = js_sys::Reflect::get(&result, &"done".into())?;
+        if done.as_bool().unwrap_or(false) {
+            return Ok(None);
+        }
+        let value = js_sys::Reflect::get(&result, &"value".into())?;
+        let array: Uint8Array = value.dyn_into()?;
+        Ok(Some(array.to_vec()))
+    }
+
+    /// Read the stream until the server finishes it.
+    pub async fn read_to_end(&self) -> Result<Vec<u8>, JsValue> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.read().await? {
+            data.extend(chunk);
+        }
+        Ok(data)
+    }
+
+    /// Whether the stream starts with `prefix`. What is read to find out
+    /// is still returned by the next [`read`](Self::read).
+    async fn starts_with(&self, prefix: &[u8]) -> Result<bool, JsValue> {
+        let mut data = Vec::new();
+        while data.len() < prefix.len() && prefix.starts_with(&data) {
+            match self.read().await? {
+                Some(chunk) => data.extend(chunk),
+                None => break,
+            }
+        }
+        let matches = data.starts_with(prefix);
+        *self.pending.borrow_mut() = data;
+        Ok(matches)
+    }
+}
+
+/// Pass the datagrams on the server's fallback stream to `inbox`.
+async fn read_fallback(stream: RecvStream, inbox: mpsc::UnboundedSender<Vec<u8>>) {
+    let mut decoder = FrameDecoder::new();
+    let mut header = FALLBACK_HEADER.len();
+    while let Ok(Some(chunk)) = stream.read().await {
+        // The first chunk starts with the header
+        let skip = header.min(chunk.len());
+        header -= skip;
+        decoder.push(&chunk[skip..]);
+        while let Some(payload) = decoder.next_frame() {
+            if inbox.unbounded_send(payload).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Whether `transport` can send datagrams: browsers without support leave
+/// out `datagrams`, or report a `maxDatagramSize` of 0.
+fn has_datagrams(transport: &WebTransport) -> bool {
+    let Ok(datagrams) = js_sys::Reflect::get(transport, &"datagrams".into()) else {
+        return false;
+    };
+    if datagrams.is_undefined() || datagrams.is_null() {
+        return false;
+    }
+    js_sys::Reflect::get(&datagrams, &"maxDatagramSize".into())
+        .ok()
+        .and_then(|size| size.as_f64())
+        .is_none_or(|size| size > 0.0)
+}
+
+impl Drop for RecvStream {
+    fn drop(&mut self) {
+        self.reader.release_lock();
+    }
+}
+
+/// Bidirectional stream wrapper.
+pub struct BidiStream {
+    stream: Rc<BidiStreamJs>,
+}
+
+impl Clone for BidiStream {
+    fn clone(&self) -> Self {
+        Self {
+            stream: Rc::clone(&self.stream),
+        }
+    }
+}
+
+impl BidiStream {
+    fn new(stream: BidiStreamJs) -> Self {
+        Self {
+            stream: Rc::new(stream),
+        }
+    }
+
+    /// Send data on the stream.
+    pub async fn send(&self, data: &[u8]) -> Result<(), JsValue> {
+        let writable = self.stream.writable();
+        let writer = writable.get_writer();
+
+        let array = Uint8Array::from(data);
+        JsFuture::from(writer.write(&array.into())).await?;
+        writer.release_lock();
+
+        Ok(())
+    }
+
+    /// Send data on the stream unless `token` is cancelled first, in which
+    /// case the writable side is aborted.
+    pub async fn send_with_cancel(&self, data: &[u8], token: &CancelToken) -> Result<(), JsValue> {
+        let options = CallOptions::new().with_cancel(token.clone());
+        Ok(self.send_with_options(data, &options).await?)
+    }
+
+    /// Send data on the stream, aborting the writable side on timeout or
+    /// cancellation.
+    pub async fn send_with_options(
+        &self,
+        data: &[u8],
+        options: &CallOptions,
+    ) -> Result<(), TransportError> {
+        let writable = self.stream.writable();
+        let writer = writable.get_writer();
+
+        let array = Uint8Array::from(data);
+        let result = options
+            .run(JsFuture::from(writer.write(&array.into())), || {
+                let _ = writer.abort();
+            })
+            .await;
+        writer.release_lock();
+
+        result.map(|_| ())
+    }
+
+    /// Send data on the stream, reporting progress as it is accepted.
+    pub async fn send_with_progress(
+        &self,
+        data: &[u8],
+        on_progress: impl FnMut(Progress),
+    ) -> Result<(), JsValue> {
+        let writable = self.stream.writable();
+        let writer = writable.get_writer();
+        let result = write_with_progress(&writer, data, on_progress).await;
+        writer.release_lock();
+        result
+    }
+
+    /// Receive data from the stream.
+    pub async fn recv(&self) -> Result<Vec<u8>, JsValue> {
+        let readable = self.stream.readable();
+        let reader = readable.get_reader();
+
+        let result = JsFuture::from(reader.read()).await;
+        reader.release_lock();
+
+        read_chunk(result?)
+    }
+
+    /// Receive data from the stream unless `token` is cancelled first.
+    pub async fn recv_with_cancel(&self, token: &CancelToken) -> Result<Vec<u8>, JsValue> {
+        let options = CallOptions::new().with_cancel(token.clone());
+        Ok(self.recv_with_options(&options).await?)
+    }
+
+    /// Receive data from the stream, giving up on timeout or cancellation.
+    pub async fn recv_with_options(&self, options: &CallOptions) -> Result<Vec<u8>, TransportError> {
+        let readable = self.stream.readable();
+        let reader = readable.get_reader();
+
+        let result = options.run(JsFuture::from(reader.read()), || {}).await;
+        // Also rejects a read left pending by a timeout or cancellation
+        reader.release_lock();
+
+        Ok(read_chunk(result?)?)
+    }
+
+    /// Close the send side of the stream.
+    pub async fn close_send(&self) -> Result<(), JsValue> {
+        let writable = self.stream.writable();
+        let writer = writable.get_writer();
+        JsFuture::from(writer.close()).await?;
+        Ok(())
+    }
+}
+
+
+/// Extract the chunk from a bidi stream read result.
+fn read_chunk(result: JsValue) -> Result<Vec<u8>, JsValue> {
+    let done = js_sys::Reflect::get(&result, &"done".into())?;
+    if done.as_bool().unwrap_or(false) {
+        return Err(JsValue::from_str("Stream closed"));
+    }
+
+    let value = js_sys::Reflect::get(&result, &"value".into())?;
+    let array: Uint8Array = value.dyn_into()?;
+    Ok(array.to_vec())
+}
+
+/// Extract the payload from a datagram read result.
+fn read_datagram(result: JsValue) -> Result<Vec<u8>, JsValue> {
+    let value = js_sys::Reflect::get(&result, &"value".into())?;
+    if value.is_undefined() {
+        return Err(JsValue::from_str("Stream closed"));
+    }
+
+    let array: Uint8Array = value.dyn_into()?;
+    Ok(array.to_vec())
+}
@@ -0,0 +1,53 @@
+//! Where the `WebTransport` constructor comes from.
+//!
+//! Browsers and Deno provide `WebTransport` as a global. Other runtimes,
+//! e.g. Node.js, get one from a polyfill installed as the global, or from
+//! any constructor implementing the same interface handed to
+//! [`set_web_transport`]: a polyfill module, a shim carrying the streams
+//! over a WebSocket to a proxy, or an in-memory fake in tests. Objects the
+//! constructor returns are duck-typed; only their methods and properties
+//! are used, never `instanceof`.
+
+use crate::WebTransport;
+use js_sys::{Array, Function, Object};
+use std::cell::RefCell;
+use wasm_bindgen::prelude::*;
+
+thread_local! {
+    static CONSTRUCTOR: RefCell<Option<Function>> = const { RefCell::new(None) };
+}
+
+/// Use `constructor` instead of the global `WebTransport` for connections
+/// made from now on.
+#[wasm_bindgen(js_name = setWebTransport)]
+pub fn set_web_transport(constructor: Function) {
+    CONSTRUCTOR.with(|c| *c.borrow_mut() = Some(constructor));
+}
+
+/// Whether there is a `WebTransport` to connect with, set or global.
+#[wasm_bindgen(js_name = isWebTransportSupported)]
+pub fn is_supported() -> bool {
+    constructor().is_some()
+}
+
+/// The constructor given to [`set_web_transport`], or else the global one.
+fn constructor() -> Option<Function> {
+    if let Some(constructor) = CONSTRUCTOR.with(|c| c.borrow().clone()) {
+        return Some(constructor);
+    }
+    js_sys::Reflect::get(&js_sys::global(), &"WebTransport".into())
+        .ok()?
+        .dyn_into()
+        .ok()
+}
+
+/// `new WebTransport(url, options)` with the runtime's constructor.
+pub fn construct(url: &str, options: Option<&Object>) -> Result<WebTransport, JsValue> {
+    let constructor = constructor()
+        .ok_or_else(|| JsValue::from_str("WebTransport is not available in this runtime"))?;
+    let args = Array::of1(&url.into());
+    if let Some(options) = options {
+        args.push(options);
+    }
+    Ok(js_sys::Reflect::construct(&constructor, &args)?.unchecked_into())
+}
@@ -0,0 +1,85 @@
+//! The browser code path against an in-memory `WebTransport` whose streams
+//! and datagrams echo what is written, run headless under Node.js:
+//!
+//! ```bash
+//! wasm-pack test --node crates/web-transport-wasm
+//! ```
+
+#![cfg(target_arch = "wasm32")]
+
+use common::CloseCode;
+use js_sys::Function;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+use web_transport_wasm::{WebTransportClient, is_supported, set_web_transport};
+
+const URL: &str = "https://localhost:4433/webtransport";
+
+/// Implements as much of `WebTransport` as the client uses.
+const ECHO_TRANSPORT: &str = r#"
+return class EchoTransport {
+    constructor(url, options) {
+        this.ready = Promise.resolve();
+        this.closed = new Promise((resolve) => { this.resolveClosed = resolve; });
+        const datagrams = new TransformStream();
+        this.datagrams = {
+            readable: datagrams.readable,
+            writable: datagrams.writable,
+            maxDatagramSize: 1200,
+        };
+        this.incomingUnidirectionalStreams = new ReadableStream();
+    }
+
+    async createBidirectionalStream() {
+        const echo = new TransformStream();
+        return { readable: echo.readable, writable: echo.writable };
+    }
+
+    async createUnidirectionalStream() {
+        return new WritableStream();
+    }
+
+    close(info) {
+        this.resolveClosed(info ?? { closeCode: 0, reason: "" });
+    }
+};
+"#;
+
+async fn connect() -> WebTransportClient {
+    let constructor = Function::new_no_args(ECHO_TRANSPORT)
+        .call0(&JsValue::NULL)
+        .unwrap();
+    set_web_transport(constructor.into());
+    WebTransportClient::connect(URL, &[]).await.unwrap()
+}
+
+#[wasm_bindgen_test]
+async fn connects_with_the_constructor_set() {
+    let client = connect().await;
+    assert!(is_supported());
+    assert!(client.supports_datagrams());
+}
+
+#[wasm_bindgen_test]
+async fn bidi_stream_round_trip() {
+    let client = connect().await;
+    let stream = client.open_bidi_stream().await.unwrap();
+    stream.send(b"hello").await.unwrap();
+    assert_eq!(stream.recv().await.unwrap(), b"hello");
+}
+
+#[wasm_bindgen_test]
+async fn datagram_round_trip() {
+    let client = connect().await;
+    client.send_datagram(b"ping").await.unwrap();
+    assert_eq!(client.recv_datagram().await.unwrap(), b"ping");
+}
+
+#[wasm_bindgen_test]
+async fn close_reports_the_code() {
+    let client = connect().await;
+    let code = CloseCode::NormalClosure;
+    let info = client.close_gracefully(code, code.reason()).await.unwrap();
+    assert_eq!(info.close_code(), Some(code));
+    assert_eq!(info.reason, code.reason());
+}
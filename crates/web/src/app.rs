@@ -1,19 +1,144 @@
 //! Leptos WebTransport Demo Application.
 
-use crate::transport::{BidiStream, WebTransportClient};
+use crate::queue::{OfflineQueue, QueueStats};
+use crate::rpc::RpcClient;
+use web_transport_wasm::{
+    BidiStream, CallOptions, CancelToken, CloseInfo, Liveness, RecvStream, TransportError,
+    WebTransportClient,
+};
+#[cfg(feature = "file-transfer")]
+use web_transport_wasm::Progress;
+use common::datagram::{SequenceStats, decode_sequenced};
+use common::e2e::{self, KeyPair};
+use common::rpc::LineBuffer;
+use common::signal::TYPING;
+use common::sync::{self, Diff, Splice, SyncDecoder, SyncFrame};
+use common::{
+    ClientControl, CloseCode, ControlMessage, Member, ReceiptStatus, Signal, TopicFrame,
+};
+use futures::future::{self, Either};
 use leptos::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::pin::pin;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
 use wasm_bindgen_futures::spawn_local;
 
+/// WebTransport endpoint of the demo server.
+const SERVER_URL: &str = "https://127.0.0.1:4433/webtransport";
+// const SERVER_URL: &str = "https://localhost:4433/webtransport";
+
+/// JSON-RPC endpoint of the demo server.
+const RPC_URL: &str = "https://127.0.0.1:4433/rpc";
+
+/// Topic the demo subscribes to.
+const TIME_TOPIC: &str = "time";
+
+/// Room the demo joins, whose members are shown in the sidebar.
+const ROOM: &str = "lobby";
+
+/// localStorage key of the session affinity token.
+const SESSION_KEY: &str = "simple-http3.session";
+
+/// How long to wait for the server when connecting, opening streams and
+/// awaiting replies.
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes the benchmark sends through the server's echo.
+#[cfg(feature = "bench")]
+const BENCH_BYTES: usize = 1024 * 1024;
+
+/// Delay before reconnecting after the server sends a GOAWAY.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Ping the server after this long without hearing from it...
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// ...and reconnect after this long.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Repeat the typing signal this often while the user types...
+const TYPING_REPEAT: Duration = Duration::from_secs(2);
+
+/// ...and stop showing someone as typing after this long without one.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the datagram reader stops to expire typing signals.
+const DATAGRAM_POLL: Duration = Duration::from_secs(1);
+
 /// Shared client state using Rc<RefCell<>> for non-Clone types
 type SharedClient = Rc<RefCell<Option<WebTransportClient>>>;
 type SharedStream = Rc<RefCell<Option<BidiStream>>>;
+type SharedQueue = Rc<RefCell<OfflineQueue>>;
+type SharedCancel = Rc<RefCell<Option<CancelToken>>>;
+type SharedRoom = Rc<RefCell<RoomState>>;
+
+/// Keys for end-to-end encrypted room messages, and the messages sent.
+/// All of it belongs to one session.
+#[derive(Default)]
+struct RoomState {
+    /// This session's key pair.
+    keys: Option<KeyPair>,
+    /// Public keys of the other members, in hex, by session ID.
+    peers: HashMap<u64, String>,
+    /// Number of the last `SAY` sent.
+    last_id: u64,
+    sent: Vec<SentMessage>,
+    /// Index into `sent` by `SAY` number...
+    by_id: HashMap<u64, usize>,
+    /// ...and by the server's message number.
+    by_msg: HashMap<u64, usize>,
+    /// The room's shared document, as last sent by the server...
+    document: Vec<u8>,
+    /// ...and its version.
+    version: u64,
+    /// Whether an edit to the document is on its way to the server.
+    editing: bool,
+}
+
+/// A room message we sent, with how far it has got. An encrypted one is
+/// sent as a separate copy to each member.
+struct SentMessage {
+    /// Index of its line in the message list.
+    line: usize,
+    text: String,
+    encrypted: bool,
+    copies: usize,
+    /// Copies the server acknowledged storing...
+    stored: usize,
+    /// ...and the number of sessions they went to.
+    recipients: usize,
+    delivered: HashSet<u64>,
+    read: HashSet<u64>,
+}
+
+impl SentMessage {
+    /// Its line in the message list, with a receipt badge.
+    fn render(&self) -> String {
+        let total = self.recipients;
+        let badge = if self.stored < self.copies {
+            "⋯".to_string()
+        } else if total == 0 {
+            "✓ (no one else here)".to_string()
+        } else if self.read.len() >= total {
+            "✓✓ read".to_string()
+        } else if !self.read.is_empty() {
+            format!("✓✓ read by {}/{}", self.read.len(), total)
+        } else if self.delivered.len() >= total {
+            "✓✓".to_string()
+        } else {
+            format!("✓ {}/{}", self.delivered.len(), total)
+        };
+        let lock = if self.encrypted { "🔒 " } else { "" };
+        format!("[room] {}You: {}  {}", lock, self.text, badge)
+    }
+}
 
 /// Parse a hex string to bytes
 fn parse_hex(hex: &str) -> Option<Vec<u8>> {
-    let hex = hex.trim().replace(' ', "").replace(':', "");
-    if hex.len() % 2 != 0 {
+    let hex = hex.trim().replace([' ', ':'], "");
+    if !hex.len().is_multiple_of(2) {
         return None;
     }
     (0..hex.len())
@@ -22,6 +147,822 @@ fn parse_hex(hex: &str) -> Option<Vec<u8>> {
         .collect()
 }
 
+/// Parse a list of certificate hashes separated by commas or newlines, or
+/// the server's `/api/cert-hashes` or `/api/connect-info` response with the
+/// list in `hashes`.
+fn parse_hashes(input: &str) -> Result<Vec<Vec<u8>>, String> {
+    let listed: String;
+    let input = if input.trim_start().starts_with('{') {
+        let response: serde_json::Value =
+            serde_json::from_str(input).map_err(|e| format!("Invalid hashes JSON: {}", e))?;
+        let hashes = response["hashes"]
+            .as_array()
+            .ok_or("Hashes JSON has no \"hashes\" list")?;
+        listed = hashes.iter().filter_map(|hash| hash.as_str()).collect::<Vec<_>>().join(",");
+        &listed
+    } else {
+        input
+    };
+    input
+        .split([',', '\n'])
+        .filter(|hash| !hash.trim().is_empty())
+        .map(|hash| match parse_hex(hash) {
+            Some(bytes) if bytes.len() == 32 => Ok(bytes),
+            Some(_) => Err("Certificate hash must be 32 bytes (64 hex chars)".to_string()),
+            None => Err("Invalid hex format for certificate hash".to_string()),
+        })
+        .collect()
+}
+
+/// How to reach the server from one browser, from `/api/connect-info`.
+#[derive(Clone, Debug, PartialEq)]
+struct BrowserSetup {
+    name: String,
+    notes: String,
+    /// Command-line flags, if the browser takes any.
+    flags: Option<String>,
+    snippet: String,
+}
+
+/// The browser instructions of a pasted `/api/connect-info` response; none
+/// for anything else.
+fn parse_setup(input: &str) -> Vec<BrowserSetup> {
+    let Ok(response) = serde_json::from_str::<serde_json::Value>(input) else {
+        return Vec::new();
+    };
+    let field = |browser: &serde_json::Value, name: &str| {
+        browser[name].as_str().map(str::to_string)
+    };
+    response["browsers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|browser| BrowserSetup {
+            name: field(browser, "name").unwrap_or_default(),
+            notes: field(browser, "notes").unwrap_or_default(),
+            flags: field(browser, "flags"),
+            snippet: field(browser, "snippet").unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Signals a session updates as it connects, reconnects and closes.
+#[derive(Clone, Copy)]
+struct Ui {
+    set_status: WriteSignal<String>,
+    set_messages: WriteSignal<Vec<String>>,
+    set_connected: WriteSignal<bool>,
+    set_has_stream: WriteSignal<bool>,
+    subscribed: ReadSignal<bool>,
+    set_subscribed: WriteSignal<bool>,
+    set_queue_stats: WriteSignal<QueueStats>,
+    name: ReadSignal<String>,
+    roster: ReadSignal<Vec<Member>>,
+    set_roster: WriteSignal<Vec<Member>>,
+    /// Room members typing, with when their last typing signal arrived.
+    set_typing: WriteSignal<HashMap<u64, f64>>,
+    set_datagram_stats: WriteSignal<SequenceStats>,
+    /// The shared document as shown, with any edits not yet applied.
+    document: ReadSignal<String>,
+    set_document: WriteSignal<String>,
+}
+
+/// Why a session ended.
+enum SessionEnd {
+    /// The server asked us to reconnect.
+    GoAway(String),
+    /// Nothing was heard from the server for this long.
+    Unresponsive(Duration),
+    /// The transport closed.
+    Closed,
+}
+
+/// Connect and keep the session alive, reconnecting when the server sends
+/// a GOAWAY (e.g. before rotating its certificate) or goes silent.
+/// Cancelling `token` interrupts whatever the session is waiting on.
+async fn run_session(
+    hashes: Vec<Vec<u8>>,
+    client: SharedClient,
+    stream: SharedStream,
+    queue: SharedQueue,
+    room: SharedRoom,
+    token: CancelToken,
+    ui: Ui,
+) {
+    let options = call_options(&token);
+    loop {
+        let Some(c) = open_session(&hashes, &client, &stream, &options, ui).await else {
+            return;
+        };
+
+        // Datagram echoes and room signals, until the session closes
+        spawn_local(read_datagrams(c.clone(), options.clone(), ui));
+
+        // Send messages queued while offline
+        let chat = stream.borrow().clone();
+        if let Some(s) = chat {
+            flush_queue(&queue, &s, &options, ui).await;
+        }
+
+        // Names and keys belong to a session too
+        send_name(&c, &ui.name.get_untracked(), ui.set_messages).await;
+        publish_key(&c, &room, ui.set_messages).await;
+
+        // Subscriptions belong to a session; restore them after reconnecting
+        if ui.subscribed.get_untracked() {
+            let subscribe = ClientControl::Subscribe {
+                topic: TIME_TOPIC.to_string(),
+            };
+            if let Err(e) = c.send_uni(subscribe.encode().as_bytes()).await {
+                add_message(&ui.set_messages, &format!("Subscribe error: {:?}", e));
+            }
+        }
+
+        // Stop waiting on a server that went silent without closing
+        let watch = pin!(watch_control(&c, &room, ui));
+        let end = match future::select(watch, pin!(c.unresponsive())).await {
+            Either::Left((end, _)) => end,
+            Either::Right((silent, _)) => SessionEnd::Unresponsive(silent),
+        };
+        let reconnect = match end {
+            SessionEnd::GoAway(reason) => {
+                Some(format!("⟳ Server going away ({}), reconnecting...", reason))
+            }
+            SessionEnd::Unresponsive(silent) => Some(format!(
+                "⚠ No response from server in {}s, reconnecting...",
+                silent.as_secs()
+            )),
+            SessionEnd::Closed => None,
+        };
+        ui.set_roster.set(Vec::new());
+        match reconnect {
+            Some(msg) => {
+                add_message(&ui.set_messages, &msg);
+                ui.set_status.set("Reconnecting...".to_string());
+                c.close();
+                *client.borrow_mut() = None;
+                *stream.borrow_mut() = None;
+                ui.set_has_stream.set(false);
+
+                gloo_timers::future::sleep(RECONNECT_DELAY).await;
+
+                // The user may have disconnected in the meantime
+                if token.is_cancelled() {
+                    return;
+                }
+            }
+            None => {
+                // Closed by the server rather than the Disconnect button
+                if client.borrow().is_some() {
+                    *client.borrow_mut() = None;
+                    *stream.borrow_mut() = None;
+                    ui.set_connected.set(false);
+                    ui.set_has_stream.set(false);
+                    ui.set_status.set("Disconnected".to_string());
+                    let msg = match c.closed().await {
+                        Ok(info) => {
+                            format!("Connection closed by server: {}", describe_close(&info))
+                        }
+                        Err(_) => "Connection closed by server".to_string(),
+                    };
+                    add_message(&ui.set_messages, &msg);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Connect to the server and open the chat stream.
+async fn open_session(
+    hashes: &[Vec<u8>],
+    client: &SharedClient,
+    stream: &SharedStream,
+    options: &CallOptions,
+    ui: Ui,
+) -> Option<WebTransportClient> {
+    let set_messages = ui.set_messages;
+    ui.set_status.set("Connecting...".to_string());
+    // Lets the Disconnect button interrupt the connection attempt
+    ui.set_connected.set(true);
+
+    // Resume the previous session's subscriptions if we have its token
+    let room_url = format!("{}/room/{}", SERVER_URL, ROOM);
+    let url = match session_token() {
+        Some(token) => format!("{}?session={}", room_url, token),
+        None => room_url,
+    };
+    let c = match WebTransportClient::connect_with_options(&url, hashes, options).await {
+        Ok(c) => c,
+        // Disconnect already updated the UI
+        Err(TransportError::Cancelled) => return None,
+        Err(e) => {
+            ui.set_connected.set(false);
+            ui.set_status.set("Connection failed".to_string());
+            add_message(&set_messages, &format!("✗ Connection error: {}", e));
+            return None;
+        }
+    };
+
+    add_message(&set_messages, "✓ Connected to server");
+    ui.set_status.set("Connected".to_string());
+    if !c.supports_datagrams() {
+        add_message(&set_messages, "Datagrams unsupported; sending them on a stream instead");
+    }
+
+    // Store the client
+    *client.borrow_mut() = Some(c.clone());
+
+    // Open a bidirectional stream
+    match c.open_bidi_stream_with_options(options).await {
+        Ok(s) => {
+            add_message(&set_messages, "✓ Opened bidirectional stream");
+
+            // Store the stream before using it
+            *stream.borrow_mut() = Some(s.clone());
+            ui.set_has_stream.set(true);
+
+            // Read welcome message
+            let options = options.clone();
+            spawn_local(async move {
+                match s.recv_with_options(&options).await {
+                    Ok(data) => {
+                        let msg = String::from_utf8_lossy(&data);
+                        add_message(&set_messages, &format!("Server: {}", msg));
+                    }
+                    Err(e) => {
+                        add_message(&set_messages, &format!("Read error: {}", e));
+                    }
+                }
+            });
+        }
+        Err(e) => {
+            add_message(&set_messages, &format!("Stream error: {}", e));
+        }
+    }
+
+    Some(c)
+}
+
+/// Read control messages from server-initiated streams until the server
+/// sends a GOAWAY or the transport closes. Topic subscription streams are
+/// read in the background.
+async fn watch_control(client: &WebTransportClient, room: &SharedRoom, ui: Ui) -> SessionEnd {
+    let set_messages = ui.set_messages;
+    loop {
+        let Ok(stream) = client.accept_uni().await else {
+            return SessionEnd::Closed;
+        };
+        let Ok(Some(mut data)) = stream.read().await else {
+            continue;
+        };
+
+        if data.starts_with(b"TOPIC ") {
+            spawn_local(read_topic(stream, data, ui));
+            continue;
+        }
+        if data.starts_with(sync::SYNC_PREFIX) {
+            spawn_local(read_sync(client.clone(), stream, data, Rc::clone(room), ui));
+            continue;
+        }
+        match stream.read_to_end().await {
+            Ok(rest) => data.extend(rest),
+            Err(_) => continue,
+        }
+
+        match ControlMessage::decode(&String::from_utf8_lossy(&data)) {
+            Some(ControlMessage::GoAway { reason }) => return SessionEnd::GoAway(reason),
+            Some(ControlMessage::Notify { message }) => {
+                add_message(&set_messages, &format!("🔔 {}", message));
+            }
+            Some(ControlMessage::Session { token }) => set_session_token(Some(&token)),
+            Some(ControlMessage::StreamLimit { kind, limit }) => {
+                let msg = format!("⚠ Server refused a {} stream ({} already open)", kind, limit);
+                add_message(&set_messages, &msg);
+            }
+            Some(ControlMessage::Roster { members }) => ui.set_roster.set(members),
+            Some(ControlMessage::Joined(member)) => {
+                add_message(&set_messages, &format!("→ {} joined", member.name));
+                ui.set_roster.update(|roster| {
+                    roster.retain(|m| m.id != member.id);
+                    roster.push(member);
+                    roster.sort_by_key(|m| m.id);
+                });
+            }
+            Some(ControlMessage::Left(member)) => {
+                add_message(&set_messages, &format!("← {} left", member.name));
+                ui.set_roster.update(|roster| roster.retain(|m| m.id != member.id));
+                room.borrow_mut().peers.remove(&member.id);
+            }
+            Some(ControlMessage::PublicKey { from, key }) => {
+                let msg = format!(
+                    "🔑 {}'s key: {}",
+                    member_name(ui.roster, from),
+                    e2e::fingerprint(&key)
+                );
+                add_message(&set_messages, &msg);
+                room.borrow_mut().peers.insert(from, key);
+            }
+            Some(ControlMessage::Said { from, msg: msg_number, body }) => {
+                let name = member_name(ui.roster, from);
+                let msg = if e2e::is_sealed(&body) {
+                    let room = room.borrow();
+                    let opened = room.keys.as_ref().zip(room.peers.get(&from));
+                    match opened.and_then(|(keys, key)| keys.open(key, &body)) {
+                        Some(text) => format!("[room] 🔒 {}: {}", name, text),
+                        None => format!("[room] 🔒 {}: (could not decrypt)", name),
+                    }
+                } else {
+                    format!("[room] {}: {}", name, body)
+                };
+                add_message(&set_messages, &msg);
+                // Shown, so read
+                let read = ClientControl::Read { msg: msg_number };
+                let _ = client.send_uni(read.encode().as_bytes()).await;
+            }
+            Some(ControlMessage::Ack { id, msg, recipients }) => {
+                let mut room = room.borrow_mut();
+                let Some(&index) = room.by_id.get(&id) else {
+                    continue;
+                };
+                room.by_msg.insert(msg, index);
+                let sent = &mut room.sent[index];
+                sent.stored += 1;
+                sent.recipients += recipients;
+                set_line(&set_messages, sent.line, sent.render());
+            }
+            Some(ControlMessage::Receipt { msg, by, status }) => {
+                let mut room = room.borrow_mut();
+                let Some(&index) = room.by_msg.get(&msg) else {
+                    continue;
+                };
+                let sent = &mut room.sent[index];
+                // A read receipt may overtake the delivery one
+                sent.delivered.insert(by);
+                if status == ReceiptStatus::Read {
+                    sent.read.insert(by);
+                }
+                set_line(&set_messages, sent.line, sent.render());
+            }
+            Some(ControlMessage::Pong) | None => continue,
+        }
+    }
+}
+
+/// Show datagram echoes and track room members' typing signals until the
+/// session closes.
+async fn read_datagrams(c: WebTransportClient, options: CallOptions, ui: Ui) {
+    let poll = options.with_timeout(DATAGRAM_POLL);
+    loop {
+        match c.recv_datagram_with_options(&poll).await {
+            Ok(data) => match Signal::decode(&data) {
+                Some(signal) if signal.kind == TYPING => {
+                    let now = js_sys::Date::now();
+                    ui.set_typing.update(|typing| match signal.value.as_str() {
+                        "0" => drop(typing.remove(&signal.from)),
+                        _ => drop(typing.insert(signal.from, now)),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    let msg = match decode_sequenced(&data) {
+                        Some((seq, payload)) => {
+                            format!("#{} {}", seq, String::from_utf8_lossy(payload))
+                        }
+                        None => String::from_utf8_lossy(&data).into_owned(),
+                    };
+                    add_message(&ui.set_messages, &format!("Datagram received: {}", msg));
+                    ui.set_datagram_stats.set(c.datagram_stats());
+                }
+            },
+            Err(TransportError::Timeout) => {}
+            Err(_) => {
+                ui.set_typing.set(HashMap::new());
+                return;
+            }
+        }
+
+        // Signals are lost like any datagram; a stop may never come
+        let expired = js_sys::Date::now() - TYPING_TIMEOUT.as_millis() as f64;
+        ui.set_typing.update(|typing| {
+            if typing.values().any(|heard| *heard < expired) {
+                typing.retain(|_, heard| *heard >= expired);
+            }
+        });
+    }
+}
+
+/// Tell the room whether the user is typing.
+async fn send_typing(c: &WebTransportClient, typing: bool) {
+    let signal = Signal::new(TYPING, if typing { "1" } else { "0" });
+    // Ephemeral: a lost signal is repeated or expires anyway
+    let _ = c.send_datagram(signal.encode().as_bytes()).await;
+}
+
+/// Display name of room member `id`.
+fn member_name(roster: ReadSignal<Vec<Member>>, id: u64) -> String {
+    roster
+        .get_untracked()
+        .into_iter()
+        .find(|member| member.id == id)
+        .map_or_else(|| format!("#{}", id), |member| member.name)
+}
+
+/// Make a key pair for the session and publish its public key to the room.
+async fn publish_key(
+    c: &WebTransportClient,
+    room: &SharedRoom,
+    set_messages: WriteSignal<Vec<String>>,
+) {
+    let keys = KeyPair::generate();
+    let public = keys.public_hex();
+    *room.borrow_mut() = RoomState {
+        keys: Some(keys),
+        ..RoomState::default()
+    };
+    let msg = ClientControl::PublicKey { key: public.clone() };
+    match c.send_uni(msg.encode().as_bytes()).await {
+        Ok(()) => {
+            let msg = format!("🔑 Your key: {}", e2e::fingerprint(&public));
+            add_message(&set_messages, &msg);
+        }
+        Err(e) => add_message(&set_messages, &format!("Key error: {:?}", e)),
+    }
+}
+
+/// Post a message to the room. Encrypted, it is sealed for each member
+/// whose key we have, so the server only relays ciphertext. Its line gets
+/// a badge that follows the receipts.
+async fn say_to_room(
+    c: &WebTransportClient,
+    room: &SharedRoom,
+    text: &str,
+    encrypt: bool,
+    set_messages: WriteSignal<Vec<String>>,
+) {
+    let messages = {
+        let mut room = room.borrow_mut();
+        let bodies: Vec<(Option<u64>, String)> = if encrypt {
+            let Some(keys) = &room.keys else {
+                return;
+            };
+            room.peers
+                .iter()
+                .filter_map(|(id, key)| Some((Some(*id), keys.seal(key, text)?)))
+                .collect()
+        } else {
+            vec![(None, text.to_string())]
+        };
+        if bodies.is_empty() {
+            drop(room);
+            add_message(&set_messages, "🔒 No one in the room has published a key yet");
+            return;
+        }
+
+        let sent = SentMessage {
+            line: 0,
+            text: text.to_string(),
+            encrypted: encrypt,
+            copies: bodies.len(),
+            stored: 0,
+            recipients: 0,
+            delivered: HashSet::new(),
+            read: HashSet::new(),
+        };
+        let Some(line) = set_messages.try_update(|msgs| {
+            msgs.push(sent.render());
+            msgs.len() - 1
+        }) else {
+            return;
+        };
+        let index = room.sent.len();
+        room.sent.push(SentMessage { line, ..sent });
+
+        let mut messages = Vec::new();
+        for (to, body) in bodies {
+            room.last_id += 1;
+            let id = room.last_id;
+            room.by_id.insert(id, index);
+            messages.push(ClientControl::Say { to, id, body });
+        }
+        messages
+    };
+
+    for msg in &messages {
+        if let Err(e) = c.send_uni(msg.encode().as_bytes()).await {
+            add_message(&set_messages, &format!("Room message error: {:?}", e));
+            return;
+        }
+    }
+}
+
+/// Set the session's display name in the room, if one was entered.
+async fn send_name(c: &WebTransportClient, name: &str, set_messages: WriteSignal<Vec<String>>) {
+    let name = name.trim();
+    if name.is_empty() {
+        return;
+    }
+    let msg = ClientControl::Name {
+        name: name.to_string(),
+    };
+    if let Err(e) = c.send_uni(msg.encode().as_bytes()).await {
+        add_message(&set_messages, &format!("Name error: {:?}", e));
+    }
+}
+
+/// Send a chat message and show the server's echo.
+async fn send_chat(
+    s: &BidiStream,
+    msg: &str,
+    options: &CallOptions,
+    set_messages: WriteSignal<Vec<String>>,
+) -> Result<(), TransportError> {
+    add_message(&set_messages, &format!("You: {}", msg));
+    s.send_with_options(msg.as_bytes(), options).await?;
+
+    // Wait for echo response
+    match s.recv_with_options(options).await {
+        Ok(data) => {
+            let response = String::from_utf8_lossy(&data);
+            add_message(&set_messages, &format!("Server: {}", response));
+        }
+        Err(e) => {
+            add_message(&set_messages, &format!("Recv error: {}", e));
+        }
+    }
+    Ok(())
+}
+
+/// Describe a close, naming the close code when it is a known one.
+fn describe_close(info: &CloseInfo) -> String {
+    match info.close_code() {
+        Some(code) if info.reason.is_empty() => code.to_string(),
+        Some(code) => format!("{} ({})", info.reason, code),
+        None => format!("code {}: {}", info.code, info.reason),
+    }
+}
+
+/// Options for calls made on behalf of a session.
+fn call_options(token: &CancelToken) -> CallOptions {
+    CallOptions::new()
+        .with_timeout(CALL_TIMEOUT)
+        .with_cancel(token.clone())
+        .with_liveness(Liveness {
+            interval: PING_INTERVAL,
+            timeout: LIVENESS_TIMEOUT,
+        })
+}
+
+/// Queue a message while there is no chat stream.
+fn queue_message(queue: &SharedQueue, msg: String, ui: Ui) {
+    let mut queue = queue.borrow_mut();
+    match queue.push(msg) {
+        Some(dropped) => add_message(
+            &ui.set_messages,
+            &format!("⚠ Queue full, dropped: {}", dropped),
+        ),
+        None => add_message(
+            &ui.set_messages,
+            &format!("⧗ Offline, queued message ({} pending)", queue.stats().pending),
+        ),
+    }
+    ui.set_queue_stats.set(queue.stats());
+}
+
+/// Send every queued message on a newly opened chat stream. Messages that
+/// fail to send are queued again.
+async fn flush_queue(queue: &SharedQueue, s: &BidiStream, options: &CallOptions, ui: Ui) {
+    let messages = queue.borrow_mut().drain();
+    if messages.is_empty() {
+        return;
+    }
+
+    let mut sent = 0;
+    let mut pending = messages.into_iter();
+    for msg in pending.by_ref() {
+        if let Err(e) = send_chat(s, &msg, options, ui.set_messages).await {
+            add_message(&ui.set_messages, &format!("Send error: {}", e));
+            queue_message(queue, msg, ui);
+            break;
+        }
+        sent += 1;
+    }
+    for msg in pending {
+        queue_message(queue, msg, ui);
+    }
+
+    queue.borrow_mut().flushed(sent);
+    ui.set_queue_stats.set(queue.borrow().stats());
+    add_message(&ui.set_messages, &format!("✓ Flushed {} queued messages", sent));
+}
+
+/// Upload a file on a new bidi stream, tracking how much the stream has
+/// accepted and how much of the echo has come back.
+#[cfg(feature = "file-transfer")]
+async fn send_file(
+    client: WebTransportClient,
+    file: web_sys::File,
+    set_progress: WriteSignal<Option<Progress>>,
+    set_received: WriteSignal<usize>,
+    set_messages: WriteSignal<Vec<String>>,
+) -> Result<(), wasm_bindgen::JsValue> {
+    let buffer = wasm_bindgen_futures::JsFuture::from(file.array_buffer()).await?;
+    let data = js_sys::Uint8Array::new(&buffer).to_vec();
+    add_message(
+        &set_messages,
+        &format!("Sending {} ({} bytes)", file.name(), data.len()),
+    );
+
+    let s = client.open_bidi_stream().await?;
+    set_received.set(0);
+
+    // Drain the echo concurrently so the server never stalls on flow control
+    let reader = s.clone();
+    spawn_local(async move {
+        while let Ok(chunk) = reader.recv().await {
+            set_received.update(|received| *received += chunk.len());
+        }
+    });
+
+    s.send_with_progress(&data, |progress| set_progress.set(Some(progress)))
+        .await?;
+    s.close_send().await?;
+    add_message(&set_messages, &format!("✓ Sent {}", file.name()));
+    Ok(())
+}
+
+/// Send [`BENCH_BYTES`] on a new bidi stream and time how long the echo
+/// takes to come back in full.
+#[cfg(feature = "bench")]
+async fn run_bench(
+    client: WebTransportClient,
+    set_messages: WriteSignal<Vec<String>>,
+) -> Result<(), wasm_bindgen::JsValue> {
+    let s = client.open_bidi_stream().await?;
+    let data = vec![0u8; BENCH_BYTES];
+    let start = js_sys::Date::now();
+
+    // Read the echo while sending so the server never stalls on flow control
+    let send = async {
+        s.send(&data).await?;
+        s.close_send().await
+    };
+    let echo = async {
+        let mut received = 0;
+        while received < BENCH_BYTES {
+            received += s.recv().await?.len();
+        }
+        Ok(())
+    };
+    future::try_join(send, echo).await?;
+
+    let millis = js_sys::Date::now() - start;
+    add_message(
+        &set_messages,
+        &format!(
+            "Benchmark: {} bytes echoed in {:.0} ms ({:.2} MB/s)",
+            BENCH_BYTES,
+            millis,
+            BENCH_BYTES as f64 / millis / 1000.0
+        ),
+    );
+    Ok(())
+}
+
+/// Show the events of a topic subscription stream until it finishes.
+async fn read_topic(stream: RecvStream, first: Vec<u8>, ui: Ui) {
+    let set_messages = ui.set_messages;
+    let mut buffer = LineBuffer::default();
+    let mut topic = String::new();
+    let mut chunk = Some(first);
+
+    while let Some(data) = chunk {
+        for line in buffer.push(&data) {
+            match TopicFrame::decode(&line) {
+                Some(TopicFrame::Header { topic: name }) => {
+                    // Subscriptions resumed by the server arrive unasked
+                    if name == TIME_TOPIC {
+                        ui.set_subscribed.set(true);
+                    }
+                    topic = name;
+                }
+                Some(TopicFrame::Event(event)) => {
+                    add_message(&set_messages, &format!("[{}] {}", topic, event));
+                }
+                Some(TopicFrame::Lagged(n)) => {
+                    add_message(&set_messages, &format!("[{}] missed {} events", topic, n));
+                }
+                None => {}
+            }
+        }
+        chunk = stream.read().await.ok().flatten();
+    }
+}
+
+/// Keep the room's shared document in step with the sync stream until it
+/// ends. Edits not yet applied by the server are kept on top of changes
+/// from other members, unless they overlap.
+async fn read_sync(
+    c: WebTransportClient,
+    stream: RecvStream,
+    first: Vec<u8>,
+    room: SharedRoom,
+    ui: Ui,
+) {
+    let mut decoder = SyncDecoder::new();
+    let mut header = Some(first);
+    let mut chunk = None;
+    // Skip the header line
+    while let Some(data) = header.take() {
+        match data.iter().position(|&b| b == b'\n') {
+            Some(newline) => chunk = Some(data[newline + 1..].to_vec()),
+            None => match stream.read().await {
+                Ok(Some(more)) => header = Some([data, more].concat()),
+                _ => return,
+            },
+        }
+    }
+
+    while let Some(data) = chunk {
+        decoder.push(&data);
+        while let Some(frame) = decoder.next_frame() {
+            let shown = ui.document.get_untracked();
+            let shown = {
+                let mut room = room.borrow_mut();
+                let old = std::mem::take(&mut room.document);
+                let unchanged = shown.as_bytes() == old;
+                let (document, shown) = match frame {
+                    SyncFrame::Snapshot { version, data } => {
+                        room.version = version;
+                        room.editing = false;
+                        (data.clone(), data)
+                    }
+                    SyncFrame::Applied { version, patch } => {
+                        room.version = version;
+                        room.editing = false;
+                        let document = Splice.apply(&old, &patch).unwrap_or(old);
+                        // Already shown, unless dropped after a conflict
+                        let shown = match unchanged {
+                            true => document.clone(),
+                            false => shown.into_bytes(),
+                        };
+                        (document, shown)
+                    }
+                    SyncFrame::Patch { version, patch } => {
+                        room.version = version;
+                        let pending = Splice.diff(&old, shown.as_bytes());
+                        let document = Splice.apply(&old, &patch).unwrap_or(old);
+                        let shown = Splice
+                            .rebase(&pending, &patch)
+                            .and_then(|pending| Splice.apply(&document, &pending))
+                            .unwrap_or_else(|| document.clone());
+                        (document, shown)
+                    }
+                };
+                room.document = document;
+                String::from_utf8_lossy(&shown).into_owned()
+            };
+            ui.set_document.set(shown.clone());
+            send_edit(&c, &room, &shown).await;
+        }
+        chunk = stream.read().await.ok().flatten();
+    }
+}
+
+/// Send the changes between the shared document and `text`, unless there
+/// are none or an edit is already on its way.
+async fn send_edit(c: &WebTransportClient, room: &SharedRoom, text: &str) {
+    let edit = {
+        let mut room = room.borrow_mut();
+        if room.editing || text.as_bytes() == room.document {
+            return;
+        }
+        room.editing = true;
+        let patch = Splice.diff(&room.document, text.as_bytes());
+        sync::encode_edit(room.version, &patch)
+    };
+    if c.send_uni(&edit).await.is_err() {
+        room.borrow_mut().editing = false;
+    }
+}
+
+/// The affinity token of the last session, kept across page reloads.
+fn session_token() -> Option<String> {
+    let storage = web_sys::window()?.local_storage().ok()??;
+    storage.get_item(SESSION_KEY).ok()?
+}
+
+/// Remember `token` for the next session, or forget it.
+fn set_session_token(token: Option<&str>) {
+    let Some(Ok(Some(storage))) = web_sys::window().map(|w| w.local_storage()) else {
+        return;
+    };
+    let _ = match token {
+        Some(token) => storage.set_item(SESSION_KEY, token),
+        None => storage.remove_item(SESSION_KEY),
+    };
+}
+
 /// Main application component.
 #[component]
 pub fn App() -> impl IntoView {
@@ -31,130 +972,138 @@ pub fn App() -> impl IntoView {
     let (cert_hash, set_cert_hash) = signal(String::new());
     let (connected, set_connected) = signal(false);
     let (has_stream, set_has_stream) = signal(false);
+    let (subscribed, set_subscribed) = signal(false);
+    let (queue_stats, set_queue_stats) = signal(QueueStats::default());
+    let (datagram_stats, set_datagram_stats) = signal(SequenceStats::default());
+    #[cfg(feature = "file-transfer")]
+    let (progress, set_progress) = signal(None::<Progress>);
+    #[cfg(feature = "file-transfer")]
+    let (received, set_received) = signal(0usize);
+    let (name, set_name) = signal(String::new());
+    let (roster, set_roster) = signal(Vec::<Member>::new());
+    let (document, set_document) = signal(String::new());
+    let (typing, set_typing) = signal(HashMap::<u64, f64>::new());
+    let (encrypt, set_encrypt) = signal(true);
 
     // Use Rc<RefCell> for non-Clone client and stream
     let client: SharedClient = Rc::new(RefCell::new(None));
     let stream: SharedStream = Rc::new(RefCell::new(None));
+    let queue: SharedQueue = Rc::new(RefCell::new(OfflineQueue::default()));
+    let cancel: SharedCancel = Rc::new(RefCell::new(None));
+    let room: SharedRoom = Rc::new(RefCell::new(RoomState::default()));
+
+    let ui = Ui {
+        set_status,
+        set_messages,
+        set_connected,
+        set_has_stream,
+        subscribed,
+        set_subscribed,
+        set_queue_stats,
+        name,
+        roster,
+        set_roster,
+        set_typing,
+        set_datagram_stats,
+        document,
+        set_document,
+    };
 
     // Connect handler
     let client_connect = Rc::clone(&client);
     let stream_connect = Rc::clone(&stream);
+    let queue_connect = Rc::clone(&queue);
+    let cancel_connect = Rc::clone(&cancel);
+    let room_connect = Rc::clone(&room);
     let connect = move |_| {
         let client = Rc::clone(&client_connect);
         let stream = Rc::clone(&stream_connect);
+        let queue = Rc::clone(&queue_connect);
+        let room = Rc::clone(&room_connect);
         let hash_input = cert_hash.get();
 
-        spawn_local(async move {
-            set_status.set("Connecting...".to_string());
-
-            // Parse cert hash if provided
-            let cert_hash_bytes = if !hash_input.is_empty() {
-                match parse_hex(&hash_input) {
-                    Some(bytes) if bytes.len() == 32 => Some(bytes),
-                    Some(_) => {
-                        add_message(&set_messages, "✗ Certificate hash must be 32 bytes (64 hex chars)");
-                        set_status.set("Connection failed".to_string());
-                        return;
-                    }
-                    None => {
-                        add_message(&set_messages, "✗ Invalid hex format for certificate hash");
-                        set_status.set("Connection failed".to_string());
-                        return;
-                    }
-                }
-            } else {
-                None
-            };
-
-            let result = WebTransportClient::connect(
-                "https://127.0.0.1:4433/webtransport",
-                // "https://localhost:4433/webtransport",
-                cert_hash_bytes.as_deref(),
-            ).await;
-
-            match result {
-                Ok(c) => {
-                    add_message(&set_messages, "✓ Connected to server");
-                    set_status.set("Connected".to_string());
-                    set_connected.set(true);
-
-                    // Store the client
-                    *client.borrow_mut() = Some(c.clone());
-
-                    // Open a bidirectional stream
-                    match c.open_bidi_stream().await {
-                        Ok(s) => {
-                            add_message(&set_messages, "✓ Opened bidirectional stream");
-
-                            // Store the stream before using it
-                            *stream.borrow_mut() = Some(s.clone());
-                            set_has_stream.set(true);
-
-                            // Read welcome message
-                            match s.recv().await {
-                                Ok(data) => {
-                                    let msg = String::from_utf8_lossy(&data);
-                                    add_message(&set_messages, &format!("Server: {}", msg));
-                                }
-                                Err(e) => {
-                                    add_message(
-                                        &set_messages,
-                                        &format!("Read error: {:?}", e),
-                                    );
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            add_message(&set_messages, &format!("Stream error: {:?}", e));
-                        }
-                    }
-                }
-                Err(e) => {
-                    set_status.set("Connection failed".to_string());
-                    add_message(&set_messages, &format!("✗ Connection error: {:?}", e));
-                }
+        // Parse cert hashes if provided
+        let hashes = match parse_hashes(&hash_input) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                add_message(&set_messages, &format!("✗ {}", e));
+                set_status.set("Connection failed".to_string());
+                return;
             }
-        });
+        };
+
+        // A fresh token per connection; Disconnect cancels it
+        let token = CancelToken::new();
+        *cancel_connect.borrow_mut() = Some(token.clone());
+        spawn_local(run_session(hashes, client, stream, queue, room, token, ui));
     };
 
-    // Send message handler
+    // Typing handler: signal the room at most every TYPING_REPEAT
+    let client_typing = Rc::clone(&client);
+    let last_typing = Rc::new(Cell::new(0.0));
+    let typed = move |text: &str| {
+        let Some(c) = client_typing.borrow().clone() else {
+            return;
+        };
+        let now = js_sys::Date::now();
+        let typing = !text.is_empty();
+        if typing && now - last_typing.get() < TYPING_REPEAT.as_millis() as f64 {
+            return;
+        }
+        last_typing.set(if typing { now } else { 0.0 });
+        spawn_local(async move { send_typing(&c, typing).await });
+    };
+    let typed_send = typed.clone();
+    let typed_say = typed.clone();
+
+    // Send message handler; queues the message while offline
     let stream_send = Rc::clone(&stream);
+    let queue_send = Rc::clone(&queue);
+    let cancel_send = Rc::clone(&cancel);
     let send_message = move |_| {
         let msg = input.get();
         if msg.is_empty() {
             return;
         }
         set_input.set(String::new());
-        
+        typed_send("");
+
         // Clone the stream out of RefCell before the async block
-        let stream_clone = stream_send.borrow().clone();
+        let Some(s) = stream_send.borrow().clone() else {
+            queue_message(&queue_send, msg, ui);
+            return;
+        };
 
+        let queue = Rc::clone(&queue_send);
+        let options = call_options(&cancel_send.borrow().clone().unwrap_or_default());
         spawn_local(async move {
-            if let Some(s) = stream_clone {
-                add_message(&set_messages, &format!("You: {}", msg));
-
-                if let Err(e) = s.send(msg.as_bytes()).await {
-                    add_message(&set_messages, &format!("Send error: {:?}", e));
-                    return;
-                }
-
-                // Wait for echo response
-                match s.recv().await {
-                    Ok(data) => {
-                        let response = String::from_utf8_lossy(&data);
-                        add_message(&set_messages, &format!("Server: {}", response));
-                    }
-                    Err(e) => {
-                        add_message(&set_messages, &format!("Recv error: {:?}", e));
-                    }
-                }
-            } else {
-                add_message(&set_messages, "Not connected");
+            if let Err(e) = send_chat(&s, &msg, &options, set_messages).await {
+                add_message(&set_messages, &format!("Send error: {}", e));
+                queue_message(&queue, msg, ui);
             }
         });
     };
     let send_message_clone = send_message.clone();
 
+    // Room message handler: post the input to the room, sealed if enabled
+    let client_say = Rc::clone(&client);
+    let room_say = Rc::clone(&room);
+    let say = move |_| {
+        let text = input.get();
+        let Some(c) = client_say.borrow().clone() else {
+            add_message(&set_messages, "Not connected");
+            return;
+        };
+        if text.is_empty() {
+            return;
+        }
+        set_input.set(String::new());
+        typed_say("");
+        let room = Rc::clone(&room_say);
+        let encrypt = encrypt.get_untracked();
+        spawn_local(async move { say_to_room(&c, &room, &text, encrypt, set_messages).await });
+    };
+
     // Send datagram handler
     let client_datagram = Rc::clone(&client);
     let send_datagram = move |_| {
@@ -164,22 +1113,15 @@ pub fn App() -> impl IntoView {
         spawn_local(async move {
             if let Some(c) = client_clone {
                 let data = b"Hello via datagram!";
-                add_message(&set_messages, "Datagram sent: Hello via datagram!");
 
-                if let Err(e) = c.send_datagram(data).await {
-                    add_message(&set_messages, &format!("Datagram error: {:?}", e));
-                    return;
-                }
-
-                // Try to receive datagram response
-                match c.recv_datagram().await {
-                    Ok(data) => {
-                        let msg = String::from_utf8_lossy(&data);
-                        add_message(&set_messages, &format!("Datagram received: {}", msg));
-                    }
-                    Err(e) => {
-                        add_message(&set_messages, &format!("Datagram recv error: {:?}", e));
-                    }
+                // Numbered so lost and reordered echoes show up in the stats;
+                // the echo is shown by read_datagrams
+                match c.send_sequenced_datagram(data).await {
+                    Ok(seq) => add_message(
+                        &set_messages,
+                        &format!("Datagram #{} sent: Hello via datagram!", seq),
+                    ),
+                    Err(e) => add_message(&set_messages, &format!("Datagram error: {:?}", e)),
                 }
             } else {
                 add_message(&set_messages, "Not connected");
@@ -187,19 +1129,210 @@ pub fn App() -> impl IntoView {
         });
     };
 
+    // Subscription handler: toggle the time topic subscription
+    let client_subscribe = Rc::clone(&client);
+    let toggle_subscription = move |_| {
+        let client_clone = client_subscribe.borrow().clone();
+        let topic = TIME_TOPIC.to_string();
+        let (msg, now_subscribed) = if subscribed.get_untracked() {
+            (ClientControl::Unsubscribe { topic }, false)
+        } else {
+            (ClientControl::Subscribe { topic }, true)
+        };
+
+        spawn_local(async move {
+            let Some(c) = client_clone else {
+                add_message(&set_messages, "Not connected");
+                return;
+            };
+            match c.send_uni(msg.encode().as_bytes()).await {
+                Ok(()) => set_subscribed.set(now_subscribed),
+                Err(e) => add_message(&set_messages, &format!("Subscribe error: {:?}", e)),
+            }
+        });
+    };
+
+    // Name handler: rename the session in the room
+    let client_name = Rc::clone(&client);
+    let change_name = move |_| {
+        let Some(c) = client_name.borrow().clone() else {
+            return;
+        };
+        spawn_local(async move { send_name(&c, &name.get_untracked(), set_messages).await });
+    };
+
+    // Document handler: send the change, or leave it for when the edit on
+    // its way is applied
+    let client_edit = Rc::clone(&client);
+    let room_edit = Rc::clone(&room);
+    let edit_document = move |e| {
+        let text = event_target_value(&e);
+        set_document.set(text.clone());
+        let Some(c) = client_edit.borrow().clone() else {
+            return;
+        };
+        let room = Rc::clone(&room_edit);
+        spawn_local(async move { send_edit(&c, &room, &text).await });
+    };
+
+    // File transfer handler
+    #[cfg(feature = "file-transfer")]
+    let file_transfer = {
+        let client_file = Rc::clone(&client);
+        let choose_file = move |e: leptos::ev::Event| {
+            let input: web_sys::HtmlInputElement = event_target(&e);
+            let Some(file) = input.files().and_then(|files| files.get(0)) else {
+                return;
+            };
+            input.set_value("");
+
+            let Some(c) = client_file.borrow().clone() else {
+                add_message(&set_messages, "Not connected");
+                return;
+            };
+            spawn_local(async move {
+                if let Err(e) = send_file(c, file, set_progress, set_received, set_messages).await {
+                    add_message(&set_messages, &format!("File transfer error: {:?}", e));
+                }
+            });
+        };
+
+        view! {
+            <div class="file-transfer">
+                <label>"Send File: "</label>
+                <input type="file" on:change=choose_file disabled=move || !connected.get() />
+                {move || progress.get().map(|p| view! {
+                    <progress max=p.total value=p.sent></progress>
+                    <span class="progress-text">
+                        {format!(
+                            "{} / {} bytes sent, {} bytes echoed",
+                            p.sent, p.total, received.get()
+                        )}
+                    </span>
+                })}
+            </div>
+        }
+    };
+    #[cfg(not(feature = "file-transfer"))]
+    let file_transfer = ();
+
+    // RPC handler: ask the server for its time over a separate RPC session
+    let call_rpc = move |_| {
+        let hashes = match parse_hashes(&cert_hash.get()) {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                add_message(&set_messages, &format!("✗ {}", e));
+                return;
+            }
+        };
+
+        spawn_local(async move {
+            let rpc = match RpcClient::connect(RPC_URL, &hashes).await {
+                Ok(rpc) => rpc,
+                Err(e) => {
+                    add_message(&set_messages, &format!("RPC connection error: {:?}", e));
+                    return;
+                }
+            };
+            match rpc.call("time", None).await {
+                Ok(time) => add_message(&set_messages, &format!("RPC time: {}", time)),
+                Err(e) => add_message(&set_messages, &format!("RPC error: {}", e)),
+            }
+            rpc.close().await;
+        });
+    };
+
+    // Offline queue and datagram counters
+    #[cfg(feature = "stats")]
+    let stats = view! {
+        <div class="queue-stats">
+            {move || {
+                let stats = queue_stats.get();
+                format!(
+                    "Offline queue: {} pending, {} flushed, {} dropped",
+                    stats.pending, stats.flushed, stats.dropped
+                )
+            }}
+        </div>
+
+        <div class="datagram-stats">
+            {move || {
+                let stats = datagram_stats.get();
+                format!(
+                    "Datagram echoes: {} received, {:.1}% lost, {:.1}% reordered, {} gaps",
+                    stats.received,
+                    stats.loss_percent(),
+                    stats.reorder_percent(),
+                    stats.gaps
+                )
+            }}
+        </div>
+    };
+    #[cfg(not(feature = "stats"))]
+    let _ = (queue_stats, datagram_stats);
+    #[cfg(not(feature = "stats"))]
+    let stats = ();
+
+    // Benchmark handler: time 1 MiB through the server's echo
+    #[cfg(feature = "bench")]
+    let bench = {
+        let client_bench = Rc::clone(&client);
+        let run = move |_| {
+            let Some(c) = client_bench.borrow().clone() else {
+                add_message(&set_messages, "Not connected");
+                return;
+            };
+            spawn_local(async move {
+                if let Err(e) = run_bench(c, set_messages).await {
+                    add_message(&set_messages, &format!("Benchmark error: {:?}", e));
+                }
+            });
+        };
+        view! {
+            <button on:click=run disabled=move || !connected.get()>
+                "Benchmark"
+            </button>
+        }
+    };
+    #[cfg(not(feature = "bench"))]
+    let bench = ();
+
     // Disconnect handler
     let client_disconnect = Rc::clone(&client);
     let stream_disconnect = Rc::clone(&stream);
+    let cancel_disconnect = Rc::clone(&cancel);
     let disconnect = move |_| {
-        if let Some(c) = client_disconnect.borrow().as_ref() {
-            c.close();
-        }
-        *client_disconnect.borrow_mut() = None;
+        let token = cancel_disconnect.borrow_mut().take();
+        // Detach the session first so it doesn't report a server close, and
+        // new messages are queued
+        let client = client_disconnect.borrow_mut().take();
         *stream_disconnect.borrow_mut() = None;
-        set_connected.set(false);
         set_has_stream.set(false);
-        set_status.set("Disconnected".to_string());
-        add_message(&set_messages, "Disconnected");
+        set_status.set("Disconnecting...".to_string());
+
+        spawn_local(async move {
+            if let Some(c) = client {
+                let code = CloseCode::NormalClosure;
+                match c.close_gracefully(code, code.reason()).await {
+                    Ok(info) => {
+                        add_message(&set_messages, &format!("Closed: {}", describe_close(&info)))
+                    }
+                    Err(e) => add_message(&set_messages, &format!("Close error: {:?}", e)),
+                }
+            }
+            // Interrupt whatever the session is still waiting on, e.g. a
+            // connection attempt
+            if let Some(token) = token {
+                token.cancel();
+            }
+            set_connected.set(false);
+            set_subscribed.set(false);
+            set_roster.set(Vec::new());
+            // A deliberate disconnect starts afresh next time
+            set_session_token(None);
+            set_status.set("Disconnected".to_string());
+            add_message(&set_messages, "Disconnected");
+        });
     };
 
     view! {
@@ -211,15 +1344,74 @@ pub fn App() -> impl IntoView {
                 <span class="value">{move || status.get()}</span>
             </div>
 
+            <aside class="roster">
+                <h2>{format!("Room: {}", ROOM)}</h2>
+                <div class="name-row">
+                    <input
+                        type="text"
+                        placeholder="Your name"
+                        maxlength=common::control::MAX_NAME_LEN
+                        prop:value=move || name.get()
+                        on:input=move |e| set_name.set(event_target_value(&e))
+                    />
+                    <button on:click=change_name disabled=move || !connected.get()>
+                        "Set"
+                    </button>
+                </div>
+                <label class="encrypt">
+                    <input
+                        type="checkbox"
+                        prop:checked=move || encrypt.get()
+                        on:change=move |e| set_encrypt.set(event_target_checked(&e))
+                    />
+                    " Encrypt room messages"
+                </label>
+                <ul>
+                    <For
+                        each=move || roster.get()
+                        key=|member| (member.id, member.name.clone())
+                        children=move |member| {
+                            let id = member.id;
+                            view! {
+                                <li>
+                                    {member.name}
+                                    {move || typing.get().contains_key(&id).then_some(" ✎")}
+                                </li>
+                            }
+                        }
+                    />
+                </ul>
+                <h3>"Shared document"</h3>
+                <textarea
+                    class="document"
+                    placeholder="Everyone in the room can edit this"
+                    prop:value=move || document.get()
+                    on:input=edit_document
+                    disabled=move || !connected.get()
+                ></textarea>
+            </aside>
+
             <div class="cert-hash">
-                <label>"Certificate SHA-256 Hash (from server output):"</label>
+                <label>"Certificate SHA-256 Hashes (from server output, comma-separated):"</label>
                 <input
                     type="text"
-                    placeholder="e.g. a1b2c3d4..."
+                    placeholder="current hash, next hash, or /api/connect-info JSON"
                     prop:value=move || cert_hash.get()
                     on:input=move |e| set_cert_hash.set(event_target_value(&e))
                     disabled=move || connected.get()
                 />
+                <For
+                    each=move || parse_setup(&cert_hash.get())
+                    key=|setup| setup.name.clone()
+                    children=|setup| view! {
+                        <details class="browser-setup">
+                            <summary>{setup.name}</summary>
+                            <p>{setup.notes}</p>
+                            {setup.flags.map(|flags| view! { <pre>{flags}</pre> })}
+                            <pre>{setup.snippet}</pre>
+                        </details>
+                    }
+                />
             </div>
 
             <div class="controls">
@@ -232,6 +1424,13 @@ pub fn App() -> impl IntoView {
                 <button on:click=send_datagram disabled=move || !connected.get()>
                     "Send Datagram"
                 </button>
+                <button on:click=toggle_subscription disabled=move || !connected.get()>
+                    {move || if subscribed.get() { "Unsubscribe Time" } else { "Subscribe Time" }}
+                </button>
+                <button on:click=call_rpc>
+                    "Call RPC"
+                </button>
+                {bench}
             </div>
 
             <div class="input-row">
@@ -239,28 +1438,35 @@ pub fn App() -> impl IntoView {
                     type="text"
                     placeholder="Type a message..."
                     prop:value=move || input.get()
-                    on:input=move |e| set_input.set(event_target_value(&e))
+                    on:input=move |e| {
+                        let text = event_target_value(&e);
+                        typed(&text);
+                        set_input.set(text);
+                    }
                     on:keypress=move |e| {
                         if e.key() == "Enter" {
                             send_message_clone(());
                         }
                     }
-                    disabled=move || !has_stream.get()
                 />
-                <button
-                    on:click=move |_| send_message(())
-                    disabled=move || !has_stream.get()
-                >
-                    "Send"
+                <button on:click=move |_| send_message(())>
+                    {move || if has_stream.get() { "Send" } else { "Queue" }}
+                </button>
+                <button on:click=say disabled=move || !connected.get()>
+                    "Say to Room"
                 </button>
             </div>
 
+            {file_transfer}
+
+            {stats}
+
             <div class="messages">
                 <h2>"Messages"</h2>
                 <div class="message-list">
                     <For
                         each=move || messages.get().into_iter().enumerate()
-                        key=|(i, _)| *i
+                        key=|(i, msg)| (*i, msg.clone())
                         children=|(_, msg)| view! {
                             <div class="message">{msg}</div>
                         }
@@ -274,3 +1480,12 @@ pub fn App() -> impl IntoView {
 fn add_message(set_messages: &WriteSignal<Vec<String>>, msg: &str) {
     set_messages.update(|msgs| msgs.push(msg.to_string()));
 }
+
+/// Replace line `index` of the message list, e.g. to update a badge.
+fn set_line(set_messages: &WriteSignal<Vec<String>>, index: usize, msg: String) {
+    set_messages.update(|msgs| {
+        if let Some(line) = msgs.get_mut(index) {
+            *line = msg;
+        }
+    });
+}
@@ -2,15 +2,35 @@
 //!
 //! This crate provides a Leptos-based WASM application that connects
 //! to an HTTP/3 server using the WebTransport API.
+//!
+//! The UI is split into features (`chat`, `file-transfer`, `bench` and
+//! `stats`), all on by default. With none of them only the transport (the
+//! `web-transport-wasm` crate), the RPC proxy and the offline queue are
+//! compiled.
 
+#[cfg(feature = "chat")]
 mod app;
-mod transport;
+mod queue;
+mod rpc;
+#[cfg(feature = "chat")]
+mod shared;
 
+#[cfg(feature = "chat")]
 pub use app::App;
+pub use queue::{DropPolicy, OfflineQueue, QueueStats};
+pub use rpc::RpcClient;
+#[cfg(feature = "chat")]
+pub use shared::{SharedClient, provide_shared_client, use_shared_client};
+pub use web_transport_wasm::{
+    BidiStream, CallOptions, CancelToken, CloseInfo, Liveness, Progress, RecvStream,
+    TransportError, WebTransportClient,
+};
 
+#[cfg(feature = "chat")]
 use wasm_bindgen::prelude::*;
 
 /// Entry point for the WASM application
+#[cfg(feature = "chat")]
 #[wasm_bindgen(start)]
 pub fn main() {
     // Set up panic hook for better error messages
@@ -0,0 +1,92 @@
+//! Outbound message queue used while the client is disconnected.
+
+use std::collections::VecDeque;
+
+/// Default number of messages kept while offline.
+pub const DEFAULT_CAPACITY: usize = 32;
+
+/// Which message to discard when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+    /// Discard the message being queued.
+    DropNewest,
+}
+
+/// Running totals shown in the UI.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Messages currently waiting to be sent.
+    pub pending: usize,
+    /// Messages sent after a reconnect.
+    pub flushed: usize,
+    /// Messages discarded because the queue was full.
+    pub dropped: usize,
+}
+
+/// A bounded queue of messages to send once reconnected.
+#[derive(Debug)]
+pub struct OfflineQueue {
+    messages: VecDeque<String>,
+    capacity: usize,
+    policy: DropPolicy,
+    stats: QueueStats,
+}
+
+impl Default for OfflineQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY, DropPolicy::DropOldest)
+    }
+}
+
+impl OfflineQueue {
+    pub fn new(capacity: usize, policy: DropPolicy) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            stats: QueueStats::default(),
+        }
+    }
+
+    /// Queue a message, returning the message dropped to respect the
+    /// capacity, if any.
+    pub fn push(&mut self, message: String) -> Option<String> {
+        let dropped = if self.messages.len() < self.capacity {
+            self.messages.push_back(message);
+            None
+        } else {
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    let oldest = self.messages.pop_front();
+                    self.messages.push_back(message);
+                    oldest
+                }
+                DropPolicy::DropNewest => Some(message),
+            }
+        };
+
+        if dropped.is_some() {
+            self.stats.dropped += 1;
+        }
+        self.stats.pending = self.messages.len();
+        dropped
+    }
+
+    /// Take every queued message, oldest first.
+    pub fn drain(&mut self) -> Vec<String> {
+        let messages: Vec<String> = self.messages.drain(..).collect();
+        self.stats.pending = 0;
+        messages
+    }
+
+    /// Record that queued messages were sent.
+    pub fn flushed(&mut self, count: usize) {
+        self.stats.flushed += count;
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+}
@@ -0,0 +1,84 @@
+//! JSON-RPC 2.0 client proxy over a WebTransport bidi stream.
+
+use web_transport_wasm::{BidiStream, WebTransportClient};
+use common::rpc::{Calls, LineBuffer, RpcError};
+use serde_json::Value;
+use std::rc::Rc;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_futures::spawn_local;
+
+/// A JSON-RPC client using one bidi stream of a WebTransport session opened
+/// at the server's RPC path.
+pub struct RpcClient {
+    transport: WebTransportClient,
+    stream: BidiStream,
+    calls: Rc<Calls>,
+}
+
+impl RpcClient {
+    /// Open a session at `url` and a stream for RPC calls.
+    pub async fn connect(url: &str, cert_hashes: &[Vec<u8>]) -> Result<Self, JsValue> {
+        let transport = WebTransportClient::connect(url, cert_hashes).await?;
+        let stream = transport.open_bidi_stream().await?;
+        let calls = Rc::new(Calls::new());
+
+        // Read response lines until the stream closes
+        let reader = stream.clone();
+        let reader_calls = Rc::clone(&calls);
+        spawn_local(async move {
+            let mut buffer = LineBuffer::default();
+            while let Ok(data) = reader.recv().await {
+                for line in buffer.push(&data) {
+                    reader_calls.resolve(&line);
+                }
+            }
+            reader_calls.fail_all();
+        });
+
+        Ok(Self {
+            transport,
+            stream,
+            calls,
+        })
+    }
+
+    /// Call a method and wait for its result.
+    pub async fn call(&self, method: &str, params: Option<Value>) -> Result<Value, RpcError> {
+        let (line, pending) = self.calls.request(method, params);
+        self.write(&line).await?;
+        pending
+            .await
+            .unwrap_or_else(|_| Err(RpcError::disconnected()))
+    }
+
+    /// Send a notification.
+    pub async fn notify(&self, method: &str, params: Option<Value>) -> Result<(), RpcError> {
+        self.write(&self.calls.notification(method, params)).await
+    }
+
+    /// Send several calls as one batch. Results are returned in call order.
+    pub async fn batch(&self, calls: Vec<(String, Option<Value>)>) -> Vec<Result<Value, RpcError>> {
+        let (line, pending) = self.calls.batch(calls);
+        if let Err(e) = self.write(&line).await {
+            return pending.iter().map(|_| Err(e.clone())).collect();
+        }
+        futures::future::join_all(pending)
+            .await
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|_| Err(RpcError::disconnected())))
+            .collect()
+    }
+
+    /// Finish the stream and close the session.
+    pub async fn close(self) {
+        let _ = self.stream.close_send().await;
+        self.transport.close();
+    }
+
+    async fn write(&self, line: &str) -> Result<(), RpcError> {
+        self.stream
+            .send(line.as_bytes())
+            .await
+            .map_err(|_| RpcError::disconnected())
+    }
+}
@@ -0,0 +1,119 @@
+//! One WebTransport session shared by the components of a page.
+//!
+//! Every component that connects on its own costs a handshake and, unless
+//! the browser pools them (see [`CallOptions::with_pooling`]), a
+//! connection of its own. Instead, the page provides a [`SharedClient`]
+//! once with [`provide_shared_client`], and components get the session
+//! from [`use_shared_client`]: the first to ask connects, those asking
+//! meanwhile wait for the same connection, and once the session closes the
+//! next to ask connects again.
+
+use futures::future::{FutureExt, LocalBoxFuture, Shared};
+use leptos::prelude::*;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use wasm_bindgen_futures::spawn_local;
+use web_transport_wasm::{CallOptions, TransportError, WebTransportClient};
+
+/// A connection attempt, awaited by everyone who asked for the session.
+type Connecting = Shared<LocalBoxFuture<'static, Result<WebTransportClient, TransportError>>>;
+
+/// A session connected on first use; clones share it.
+#[derive(Clone)]
+pub struct SharedClient {
+    url: Rc<str>,
+    cert_hashes: Rc<[Vec<u8>]>,
+    options: CallOptions,
+    session: Rc<RefCell<Option<Connecting>>>,
+}
+
+impl SharedClient {
+    pub fn new(url: &str, cert_hashes: &[Vec<u8>]) -> Self {
+        Self {
+            url: url.into(),
+            cert_hashes: cert_hashes.into(),
+            options: CallOptions::new(),
+            session: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// Connect with `options`, e.g. a timeout, liveness checks or pooling.
+    pub fn with_options(mut self, options: CallOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The session, connecting first if there is none.
+    pub async fn get(&self) -> Result<WebTransportClient, TransportError> {
+        let connecting = self.connecting();
+        let result = connecting.clone().await;
+        // The next call tries again
+        if result.is_err() {
+            forget(&self.session, &connecting);
+        }
+        result
+    }
+
+    /// Close the session, if there is one; the next [`get`](Self::get)
+    /// connects again.
+    pub fn close(&self) {
+        let connecting = self.session.borrow_mut().take();
+        if let Some(Ok(client)) = connecting.as_ref().and_then(Shared::peek) {
+            client.close();
+        }
+    }
+
+    /// The connection attempt under way or made, starting one if needed.
+    fn connecting(&self) -> Connecting {
+        let mut session = self.session.borrow_mut();
+        if let Some(connecting) = session.as_ref() {
+            return connecting.clone();
+        }
+        let (url, cert_hashes, options) = (
+            Rc::clone(&self.url),
+            Rc::clone(&self.cert_hashes),
+            self.options.clone(),
+        );
+        let connecting = async move {
+            WebTransportClient::connect_with_options(&url, &cert_hashes, &options).await
+        }
+        .boxed_local()
+        .shared();
+        *session = Some(connecting.clone());
+
+        // Forget the session once it closes, so it is not handed out dead
+        let watched = connecting.clone();
+        let session = Rc::downgrade(&self.session);
+        spawn_local(async move {
+            if let Ok(client) = watched.clone().await {
+                let _ = client.closed().await;
+            }
+            if let Some(session) = Weak::upgrade(&session) {
+                forget(&session, &watched);
+            }
+        });
+        connecting
+    }
+}
+
+/// Clear `session` if it still holds `connecting` rather than a newer
+/// attempt.
+fn forget(session: &RefCell<Option<Connecting>>, connecting: &Connecting) {
+    let mut session = session.borrow_mut();
+    if session.as_ref().is_some_and(|current| current.ptr_eq(connecting)) {
+        *session = None;
+    }
+}
+
+/// Share `client` with every component below the caller.
+pub fn provide_shared_client(client: SharedClient) {
+    provide_context(StoredValue::new_local(client));
+}
+
+/// The session provided by an ancestor with [`provide_shared_client`].
+///
+/// # Panics
+/// If no ancestor provided one.
+pub fn use_shared_client() -> SharedClient {
+    expect_context::<StoredValue<SharedClient, LocalStorage>>().get_value()
+}